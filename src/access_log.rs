@@ -0,0 +1,313 @@
+use crate::error::json_escape;
+use crate::log_rotation::{RotatingFile, Rotation};
+use clap::ValueEnum;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Requests numbered so far, the second half of their IDs
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Layout of access log records.
+#[derive(ValueEnum, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// `key=value` pairs on one line
+    Text,
+    /// One JSON object per line
+    Json,
+    /// Apache's Common Log Format
+    Clf,
+    /// Apache's Combined Log Format: CLF plus the Referer and User-Agent
+    Combined,
+}
+
+/// How a request or tunnel ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    /// The exchange ran to completion and both sides closed or kept the connection alive
+    Completed,
+    /// The proxy answered the request itself with an error status
+    Rejected,
+    /// The upstream or destination could not be reached
+    UpstreamError,
+    /// The connection failed part way through
+    Error,
+}
+
+impl Termination {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Rejected => "rejected",
+            Self::UpstreamError => "upstream_error",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// What is known about one request (or one forward-mode connection) when it ends.
+pub struct Record {
+    /// Identifies the request in the logs, the access log and, with `--request-id-header`,
+    /// the responses to it
+    pub request_id: String,
+    pub client: SocketAddr,
+    /// The authenticated user, when credentials are required
+    pub user: Option<String>,
+    pub method: Option<String>,
+    /// The request line as the client sent it, e.g. `GET http://example.com/ HTTP/1.1`, for
+    /// HTTP requests
+    pub request_line: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Destination as `host:port`
+    pub target: Option<String>,
+    /// `direct` or the SOCKS server the tunnel went through
+    pub upstream: Option<String>,
+    /// Status sent to the client, if the exchange was HTTP
+    pub status: Option<u16>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub started: Instant,
+    pub termination: Option<Termination>,
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(client: SocketAddr, started: Instant) -> Self {
+        Self {
+            request_id: next_request_id(),
+            client,
+            user: None,
+            method: None,
+            request_line: None,
+            referer: None,
+            user_agent: None,
+            target: None,
+            upstream: None,
+            status: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            started,
+            termination: None,
+            error: None,
+        }
+    }
+
+    /// Marks the request as answered by the proxy itself with `status`.
+    pub fn reject(&mut self, status: u16) {
+        self.status = Some(status);
+        self.termination = Some(Termination::Rejected);
+    }
+
+    /// Fills in the termination reason from the handler's result unless one was already set.
+    pub fn finish<T, E: std::fmt::Display>(&mut self, result: &Result<T, E>) {
+        if let Err(e) = result {
+            self.error = Some(e.to_string());
+        }
+        if self.termination.is_none() {
+            self.termination = Some(match result {
+                Ok(_) => Termination::Completed,
+                Err(_) => Termination::Error,
+            });
+        }
+    }
+}
+
+// 16 hex digits: a prefix picked at random when the process starts, so IDs from different
+// runs don't collide, then the request's number
+fn next_request_id() -> String {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    let prefix = PREFIX.get_or_init(|| {
+        use std::hash::{BuildHasher, Hasher};
+        std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish() as u32
+    });
+    let number = REQUESTS.fetch_add(1, Ordering::Relaxed) as u32;
+    format!("{prefix:08x}{number:08x}")
+}
+
+/// An append-only access log file receiving one line per record, rotated as configured.
+pub struct AccessLog {
+    file: Mutex<RotatingFile>,
+    format: LogFormat,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path, format: LogFormat, rotation: Rotation) -> std::io::Result<Self> {
+        let file = RotatingFile::open(path, rotation)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            format,
+        })
+    }
+
+    /// Appends `record`; write failures are logged rather than failing the connection.
+    pub fn write(&self, record: &Record) {
+        let mut line = match self.format {
+            LogFormat::Text => text(record),
+            LogFormat::Json => json(record),
+            LogFormat::Clf => clf(record, false),
+            LogFormat::Combined => clf(record, true),
+        };
+        line.push('\n');
+        // A single write per line keeps records from interleaving
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::warn!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+// Seconds since the Unix epoch with millisecond precision
+fn timestamp() -> f64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_millis() as f64) / 1000.0
+}
+
+fn duration_ms(record: &Record) -> f64 {
+    record.started.elapsed().as_micros() as f64 / 1000.0
+}
+
+fn termination(record: &Record) -> &'static str {
+    record
+        .termination
+        .unwrap_or(Termination::Completed)
+        .as_str()
+}
+
+fn text(record: &Record) -> String {
+    let mut line = format!(
+        "time={:.3} id={} client={} method={} target={} upstream={} status={} bytes_up={} bytes_down={} duration_ms={:.3} reason={}",
+        timestamp(),
+        record.request_id,
+        record.client,
+        record.method.as_deref().unwrap_or("-"),
+        record.target.as_deref().unwrap_or("-"),
+        record.upstream.as_deref().unwrap_or("-"),
+        record.status.map_or("-".to_string(), |status| status.to_string()),
+        record.bytes_up,
+        record.bytes_down,
+        duration_ms(record),
+        termination(record),
+    );
+    if let Some(user) = &record.user {
+        line.push_str(&format!(" user=\"{}\"", json_escape(user)));
+    }
+    if let Some(error) = &record.error {
+        line.push_str(&format!(" error=\"{}\"", json_escape(error)));
+    }
+    line
+}
+
+fn json(record: &Record) -> String {
+    let string = |value: &Option<String>| {
+        value.as_ref().map_or("null".to_string(), |value| {
+            format!("\"{}\"", json_escape(value))
+        })
+    };
+    format!(
+        "{{\"time\":{:.3},\"id\":\"{}\",\"client\":\"{}\",\"user\":{},\"method\":{},\"target\":{},\"upstream\":{},\"status\":{},\"bytes_up\":{},\"bytes_down\":{},\"duration_ms\":{:.3},\"reason\":\"{}\",\"error\":{}}}",
+        timestamp(),
+        record.request_id,
+        record.client,
+        string(&record.user),
+        string(&record.method),
+        string(&record.target),
+        string(&record.upstream),
+        record.status.map_or("null".to_string(), |status| status.to_string()),
+        record.bytes_up,
+        record.bytes_down,
+        duration_ms(record),
+        termination(record),
+        string(&record.error),
+    )
+}
+
+// An Apache-style line. Tunnels and SOCKS requests have no request line of their own, so
+// theirs is made up of the method and target, like `CONNECT example.com:443`.
+fn clf(record: &Record, combined: bool) -> String {
+    let request = record.request_line.clone().unwrap_or_else(|| {
+        format!(
+            "{} {}",
+            record.method.as_deref().unwrap_or("-"),
+            record.target.as_deref().unwrap_or("-")
+        )
+    });
+    let mut line = format!(
+        "{} - {} [{}] \"{}\" {} {}",
+        record.client.ip(),
+        record.user.as_deref().map_or("-".to_string(), clf_escape),
+        clf_time(record),
+        clf_escape(&request),
+        record
+            .status
+            .map_or("-".to_string(), |status| status.to_string()),
+        match record.bytes_down {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        },
+    );
+    if combined {
+        let quoted = |value: &Option<String>| value.as_deref().map_or("-".to_string(), clf_escape);
+        line.push_str(&format!(
+            " \"{}\" \"{}\"",
+            quoted(&record.referer),
+            quoted(&record.user_agent)
+        ));
+    }
+    line
+}
+
+// Escapes quotes, backslashes and control characters as Apache does
+fn clf_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// When the request started, as `10/Oct/2000:13:55:36 +0000` in UTC
+fn clf_time(record: &Record) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let started = SystemTime::now() - record.started.elapsed();
+    let secs = started
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, time) = (secs / 86400, secs % 86400);
+
+    // The civil date of a day count, with years shifted to start in March
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let m = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * m + 2) / 5 + 1;
+    let month = if m < 10 { m + 3 } else { m - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
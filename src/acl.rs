@@ -0,0 +1,122 @@
+use crate::routing::{cidr_contains, HostPattern};
+use std::net::IpAddr;
+
+/// Source networks allowed to use the proxy, from `--allow` and `--deny`.
+#[derive(Debug)]
+pub struct ClientAcl {
+    allow: Vec<(IpAddr, u8)>,
+    deny: Vec<(IpAddr, u8)>,
+}
+
+impl ClientAcl {
+    /// Parses the `--allow` and `--deny` networks, each an IP address or CIDR block.
+    /// Returns `None` when neither is given, i.e. every client is allowed.
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Option<Self>, String> {
+        if allow.is_empty() && deny.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            allow: parse_networks("allow", allow)?,
+            deny: parse_networks("deny", deny)?,
+        }))
+    }
+
+    /// Allows only the `networks` given to `--option`, or `None` when there are none.
+    pub fn allowing(option: &str, networks: &[String]) -> Result<Option<Self>, String> {
+        if networks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            allow: parse_networks(option, networks)?,
+            deny: Vec::new(),
+        }))
+    }
+
+    /// Whether a client at `ip` may connect: it must match no `--deny` network and, if any
+    /// `--allow` networks are given, at least one of them.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let matches = |networks: &[(IpAddr, u8)]| {
+            networks
+                .iter()
+                .any(|&(network, prefix)| cidr_contains(network, prefix, ip))
+        };
+        !matches(&self.deny) && (self.allow.is_empty() || matches(&self.allow))
+    }
+}
+
+fn parse_networks(option: &str, networks: &[String]) -> Result<Vec<(IpAddr, u8)>, String> {
+    networks
+        .iter()
+        .map(|network| parse_network(network).map_err(|e| format!("--{option}: {e}")))
+        .collect()
+}
+
+// Parses `10.0.0.0/8`, `fd00::/8` or a single address as a network and prefix length
+fn parse_network(network: &str) -> Result<(IpAddr, u8), String> {
+    if let Ok(ip) = network.trim().parse::<IpAddr>() {
+        return Ok((ip, if ip.is_ipv4() { 32 } else { 128 }));
+    }
+    match HostPattern::parse(network)? {
+        HostPattern::Cidr(network, prefix) => Ok((network, prefix)),
+        _ => Err(format!(
+            "expected an IP address or CIDR block, got '{network}'"
+        )),
+    }
+}
+
+/// Destinations clients may not reach, from `--block-host` and `--allow-ports`.
+#[derive(Debug)]
+pub struct DestinationAcl {
+    blocked: Vec<HostPattern>,
+    // Inclusive port ranges; `None` allows every port
+    ports: Option<Vec<(u16, u16)>>,
+}
+
+impl DestinationAcl {
+    /// Parses the `--block-host` patterns and the `--allow-ports` list of ports and
+    /// `LOW-HIGH` ranges. Returns `None` when neither is given.
+    pub fn new(block_host: &[String], allow_ports: Option<&str>) -> Result<Option<Self>, String> {
+        if block_host.is_empty() && allow_ports.is_none() {
+            return Ok(None);
+        }
+        let blocked = block_host
+            .iter()
+            .map(|pattern| HostPattern::parse(pattern).map_err(|e| format!("--block-host: {e}")))
+            .collect::<Result<_, _>>()?;
+        let ports = allow_ports
+            .map(|list| {
+                list.split(',')
+                    .map(|entry| {
+                        parse_port_range(entry.trim()).ok_or_else(|| {
+                            format!("--allow-ports: invalid port or range '{entry}'")
+                        })
+                    })
+                    .collect::<Result<_, _>>()
+            })
+            .transpose()?;
+        Ok(Some(Self { blocked, ports }))
+    }
+
+    /// Checks a destination, returning why it is refused.
+    pub fn check(&self, host: &str, port: u16) -> Result<(), String> {
+        if let Some(ports) = &self.ports {
+            if !ports
+                .iter()
+                .any(|&(low, high)| (low..=high).contains(&port))
+            {
+                return Err(format!("port {port} is not in --allow-ports"));
+            }
+        }
+        match self.blocked.iter().any(|pattern| pattern.matches(host)) {
+            true => Err(format!("{host} matches --block-host")),
+            false => Ok(()),
+        }
+    }
+}
+
+// Parses `443` or `8000-8999`
+fn parse_port_range(entry: &str) -> Option<(u16, u16)> {
+    let (low, high) = entry.split_once('-').unwrap_or((entry, entry));
+    let (low, high) = (low.trim().parse().ok()?, high.trim().parse().ok()?);
+    (low <= high).then_some((low, high))
+}
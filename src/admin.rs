@@ -0,0 +1,379 @@
+use crate::access_log::Record;
+use crate::config::Config;
+use crate::error::{json_escape, FatalError};
+use crate::http::{BufferedStream, RequestHead};
+use crate::log_filter;
+use crate::metrics;
+use crate::relay::{self, Progress};
+use crate::stats::{LatencyHistogram, DESTINATIONS, STATS};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tracing::{debug, info};
+
+/// Tunnels currently relaying data, as listed by the admin endpoint.
+pub static TUNNELS: Tunnels = Tunnels::new();
+
+/// A registry of running tunnels.
+pub struct Tunnels {
+    next_id: AtomicU64,
+    open: Mutex<BTreeMap<u64, Tunnel>>,
+}
+
+// What is known about one running tunnel
+struct Tunnel {
+    client: SocketAddr,
+    method: Option<String>,
+    target: Option<String>,
+    upstream: Option<String>,
+    started: Instant,
+    progress: Arc<Progress>,
+    stop: Arc<Notify>,
+}
+
+/// A tunnel's entry in [`TUNNELS`], removed when dropped.
+pub struct Registration {
+    id: u64,
+}
+
+impl Tunnels {
+    const fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            open: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Lists the tunnel described by `record` until the returned registration is dropped.
+    /// The relay keeps the returned progress up to date, and stops once the returned
+    /// [`Notify`] is notified.
+    pub fn register(&self, record: &Record) -> (Registration, Arc<Progress>, Arc<Notify>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(Progress::default());
+        let stop = Arc::new(Notify::new());
+        let tunnel = Tunnel {
+            client: record.client,
+            method: record.method.clone(),
+            target: record.target.clone(),
+            upstream: record.upstream.clone(),
+            started: record.started,
+            progress: progress.clone(),
+            stop: stop.clone(),
+        };
+        self.open.lock().unwrap().insert(id, tunnel);
+        (Registration { id }, progress, stop)
+    }
+
+    // Tells the tunnel listed under `id` to close; false if there is none
+    fn close(&self, id: u64) -> bool {
+        match self.open.lock().unwrap().get(&id) {
+            Some(tunnel) => {
+                tunnel.stop.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The running tunnels as a JSON array, oldest first
+    fn json(&self) -> String {
+        let open = self.open.lock().unwrap();
+        let string = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", json_escape(value)),
+            None => "null".to_string(),
+        };
+        let tunnels: Vec<String> = open
+            .iter()
+            .map(|(id, tunnel)| {
+                format!(
+                    "{{\"id\":{id},\"client\":\"{}\",\"method\":{},\"target\":{},\"upstream\":{},\"bytes_up\":{},\"bytes_down\":{},\"age_secs\":{:.3}}}",
+                    tunnel.client,
+                    string(&tunnel.method),
+                    string(&tunnel.target),
+                    string(&tunnel.upstream),
+                    tunnel.progress.a_to_b.load(Ordering::Relaxed),
+                    tunnel.progress.b_to_a.load(Ordering::Relaxed),
+                    tunnel.started.elapsed().as_secs_f64()
+                )
+            })
+            .collect();
+        format!("[{}]", tunnels.join(","))
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        TUNNELS.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Binds the admin listener.
+pub async fn bind(listen: &str) -> Result<TcpListener, FatalError> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|source| FatalError::Bind {
+            addr: listen.to_string(),
+            source,
+        })?;
+    info!("Admin endpoint listening on: http://{}/", listen);
+    Ok(listener)
+}
+
+// Serves /tunnels, /stats, /stats/destinations and /config as JSON, /loglevel,
+// DELETE /connections/{id}, and the /healthz and /readyz probes. `config` renders the
+// configuration in use; `ready` tells whether the upstreams can be reached.
+pub async fn serve(
+    listener: TcpListener,
+    config: impl Fn() -> String + Send + Sync + 'static,
+    ready: impl Fn() -> bool + Send + Sync + 'static,
+) {
+    let config = Arc::new(config);
+    let ready = Arc::new(ready);
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let config = config.clone();
+        let ready = ready.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &*config, &*ready).await {
+                debug!("Admin request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(
+    stream: TcpStream,
+    config: &(dyn Fn() -> String + Send + Sync),
+    ready: &(dyn Fn() -> bool + Send + Sync),
+) -> Result<(), crate::http::HeadError> {
+    let mut stream = BufferedStream::new(stream);
+    let Some(head) = stream
+        .read_head(8192, RequestHead::parse, |head| head.len)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    let path = head.target.split('?').next().unwrap_or_default();
+    if let Some(response) = metrics::probe_response(path, ready) {
+        stream.inner.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+    if path == "/loglevel" {
+        stream.inner.write_all(log_level(&head).as_bytes()).await?;
+        return Ok(());
+    }
+    if let Some(id) = path.strip_prefix("/connections/") {
+        stream.inner.write_all(close(&head, id).as_bytes()).await?;
+        return Ok(());
+    }
+    let body = match path {
+        "/tunnels" => Some(TUNNELS.json()),
+        "/stats" => Some(stats()),
+        "/stats/destinations" => Some(destinations()),
+        "/config" => Some(config()),
+        _ => None,
+    };
+    let response = match body {
+        Some(body) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        ),
+        None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string(),
+    };
+    stream.inner.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+// `GET /loglevel` shows the log filter in effect and `PUT /loglevel?filter=...` replaces it
+fn log_level(head: &RequestHead) -> String {
+    let response = |status: &str, body: &str| {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}\n",
+            body.len() + 1
+        )
+    };
+    let Some(current) = log_filter::current() else {
+        return response("501 Not Implemented", "the log filter cannot be changed");
+    };
+    match head.method.as_str() {
+        "GET" => response("200 OK", &current),
+        "PUT" | "POST" => {
+            let Some(filter) = query_param(&head.target, "filter") else {
+                return response("400 Bad Request", "missing filter parameter");
+            };
+            match log_filter::set(&filter) {
+                Some(Ok(())) => {
+                    info!("Log filter changed from '{}' to '{}'", current, filter);
+                    response("200 OK", &filter)
+                }
+                Some(Err(e)) => response("400 Bad Request", &e),
+                None => response("501 Not Implemented", "the log filter cannot be changed"),
+            }
+        }
+        _ => response("405 Method Not Allowed", "use GET or PUT"),
+    }
+}
+
+// `DELETE /connections/{id}` closes the tunnel listed under that ID in /tunnels
+fn close(head: &RequestHead, id: &str) -> String {
+    let response = |status: &str, body: &str| {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}\n",
+            body.len() + 1
+        )
+    };
+    if head.method != "DELETE" {
+        return response("405 Method Not Allowed", "use DELETE");
+    }
+    match id.parse() {
+        Ok(id) if TUNNELS.close(id) => {
+            info!("Closing tunnel {} through the admin endpoint", id);
+            response("200 OK", "closing")
+        }
+        _ => response("404 Not Found", "no such tunnel"),
+    }
+}
+
+// The percent-decoded value of `name` in the query string of `target`
+fn query_param(target: &str, name: &str) -> Option<String> {
+    let (_, query) = target.split_once('?')?;
+    let value = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))?;
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (b, hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            (b'+', _) => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// The aggregate counters as a JSON object
+fn stats() -> String {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let latency = |latency: &LatencyHistogram| {
+        format!(
+            "{{\"p50\":{},\"p90\":{},\"p99\":{},\"count\":{}}}",
+            latency.quantile(0.5).as_secs_f64(),
+            latency.quantile(0.9).as_secs_f64(),
+            latency.quantile(0.99).as_secs_f64(),
+            latency.count()
+        )
+    };
+    format!(
+        "{{\"connections\":{},\"rejected_connections\":{},\"denied_connections\":{},\"rate_limited_requests\":{},\"errors\":{},\"upstream_errors\":{},\"direct_fallbacks\":{},\"connect_requests\":{},\"http_requests\":{},\"active_tunnels\":{},\"bytes_from_client\":{},\"bytes_from_upstream\":{},\"relay_buffered_bytes\":{},\"setup_latency_secs\":{},\"handshake_latency_secs\":{},\"first_byte_latency_secs\":{}}}",
+        load(&STATS.connections),
+        load(&STATS.rejected_connections),
+        load(&STATS.denied_connections),
+        load(&STATS.rate_limited_requests),
+        load(&STATS.errors),
+        load(&STATS.upstream_errors),
+        load(&STATS.direct_fallbacks),
+        load(&STATS.connect_requests),
+        load(&STATS.http_requests),
+        load(&STATS.active_tunnels),
+        load(&STATS.bytes_from_client),
+        load(&STATS.bytes_from_upstream),
+        relay::buffered_bytes(),
+        latency(&STATS.setup_latency),
+        latency(&STATS.handshake_latency),
+        latency(&STATS.first_byte_latency),
+    )
+}
+
+// Traffic per destination host as a JSON array, busiest first
+fn destinations() -> String {
+    let destinations: Vec<String> = DESTINATIONS
+        .snapshot()
+        .iter()
+        .map(|(host, stats)| {
+            format!(
+                "{{\"destination\":\"{}\",\"tunnels\":{},\"errors\":{},\"bytes_from_client\":{},\"bytes_from_upstream\":{}}}",
+                json_escape(host),
+                stats.tunnels,
+                stats.errors,
+                stats.bytes_from_client,
+                stats.bytes_from_upstream
+            )
+        })
+        .collect();
+    format!("[{}]", destinations.join(","))
+}
+
+/// `config` as a JSON object keyed by option name, with passwords masked. Unset options
+/// are left out.
+pub fn config_json(config: &Config) -> String {
+    let mut out = String::new();
+    match toml::Value::try_from(config) {
+        Ok(value) => write_json(&mut out, &value),
+        Err(e) => {
+            let _ = write!(out, "{{\"error\":\"{}\"}}", json_escape(&e.to_string()));
+        }
+    }
+    out
+}
+
+fn write_json(out: &mut String, value: &toml::Value) {
+    match value {
+        toml::Value::String(s) => {
+            let _ = write!(out, "\"{}\"", json_escape(s));
+        }
+        toml::Value::Integer(i) => {
+            let _ = write!(out, "{i}");
+        }
+        toml::Value::Float(f) => {
+            let _ = write!(out, "{f}");
+        }
+        toml::Value::Boolean(b) => {
+            let _ = write!(out, "{b}");
+        }
+        toml::Value::Datetime(d) => {
+            let _ = write!(out, "\"{d}\"");
+        }
+        toml::Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, value);
+            }
+            out.push(']');
+        }
+        toml::Value::Table(table) => {
+            out.push('{');
+            for (i, (key, value)) in table.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "\"{}\":", json_escape(key));
+                write_json(out, value);
+            }
+            out.push('}');
+        }
+    }
+}
@@ -0,0 +1,516 @@
+use crate::config::AuthScheme;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_rustls::rustls::crypto::ring;
+
+// Protection space named in challenges
+const REALM: &str = "http2socks";
+// How long a Digest nonce is accepted after it was issued
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+// Most outstanding nonces kept; the oldest is forgotten first
+const MAX_NONCES: usize = 10_000;
+
+/// Username/password pairs accepted on the HTTP listener via `Proxy-Authorization`, as
+/// Basic credentials, Digest responses (RFC 7616) or either.
+#[derive(Debug)]
+pub struct ProxyAuth {
+    users: HashMap<String, String>,
+    scheme: AuthScheme,
+    // Digest nonces handed out, with the highest nonce count each was used with
+    nonces: Mutex<HashMap<String, Nonce>>,
+}
+
+#[derive(Debug)]
+struct Nonce {
+    issued: Instant,
+    count: u32,
+}
+
+/// Why a request's credentials were not accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct Denied {
+    /// The Digest response was right but its nonce expired or was replayed, so the client
+    /// can retry with a fresh one without asking the user again
+    pub stale: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl ProxyAuth {
+    /// Builds the user table from `user:pass` entries and an optional users file
+    /// containing one `user:pass` per line (blank lines and `#` comments are ignored).
+    /// Returns `None` when no credentials are configured, i.e. authentication is disabled.
+    pub fn load(
+        entries: &[String],
+        file: Option<&Path>,
+        scheme: AuthScheme,
+    ) -> Result<Option<Self>, String> {
+        let mut auth = ProxyAuth {
+            users: HashMap::new(),
+            scheme,
+            nonces: Mutex::default(),
+        };
+
+        for entry in entries {
+            auth.add_entry(entry).map_err(|e| format!("--auth: {e}"))?;
+        }
+
+        if let Some(path) = file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("--auth-file {}: {e}", path.display()))?;
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                auth.add_entry(line)
+                    .map_err(|e| format!("--auth-file {}:{}: {e}", path.display(), number + 1))?;
+            }
+        }
+
+        Ok((!auth.users.is_empty()).then_some(auth))
+    }
+
+    fn add_entry(&mut self, entry: &str) -> Result<(), String> {
+        match entry.split_once(':') {
+            Some((user, pass)) if !user.is_empty() => {
+                self.users.insert(user.to_string(), pass.to_string());
+                Ok(())
+            }
+            _ => Err(format!("expected user:pass, got '{entry}'")),
+        }
+    }
+
+    /// Validates the `Proxy-Authorization` header value of a `method` request for `uri`
+    /// (the request target), returning the authenticated username.
+    pub fn authorize(&self, method: &str, uri: &str, header: Option<&str>) -> Result<&str, Denied> {
+        let denied = Denied { stale: false };
+        let (scheme, credentials) = header
+            .and_then(|header| header.trim().split_once(' '))
+            .ok_or(denied)?;
+        if scheme.eq_ignore_ascii_case("basic") && self.scheme != AuthScheme::Digest {
+            return self.basic(credentials).ok_or(denied);
+        }
+        if scheme.eq_ignore_ascii_case("digest") && self.scheme != AuthScheme::Basic {
+            return self.digest(method, uri, credentials);
+        }
+        Err(denied)
+    }
+
+    fn basic(&self, encoded: &str) -> Option<&str> {
+        let decoded = STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, pass) = decoded.split_once(':')?;
+        self.verify(user, pass)
+    }
+
+    // Checks a Digest response with qop=auth; userhash and the -sess algorithms are not
+    // offered, so they are refused
+    fn digest(&self, method: &str, uri: &str, credentials: &str) -> Result<&str, Denied> {
+        let denied = Denied { stale: false };
+        let params = digest_params(credentials).ok_or(denied)?;
+        let param = |name: &str| params.get(name).map(String::as_str);
+        let algorithm = match param("algorithm") {
+            None => Algorithm::Md5,
+            Some(name) if name.eq_ignore_ascii_case("MD5") => Algorithm::Md5,
+            Some(name) if name.eq_ignore_ascii_case("SHA-256") => Algorithm::Sha256,
+            Some(_) => return Err(denied),
+        };
+        let (Some(username), Some(nonce), Some(nc), Some(cnonce), Some(response)) = (
+            param("username"),
+            param("nonce"),
+            param("nc"),
+            param("cnonce"),
+            param("response"),
+        ) else {
+            return Err(denied);
+        };
+        let digest_uri = param("uri").ok_or(denied)?;
+        if param("realm") != Some(REALM)
+            || !same_resource(digest_uri, uri)
+            || param("qop") != Some("auth")
+            || param("userhash").is_some_and(|value| value.eq_ignore_ascii_case("true"))
+        {
+            return Err(denied);
+        }
+        let count = u32::from_str_radix(nc, 16).map_err(|_| denied)?;
+
+        let (name, password) = self.users.get_key_value(username).ok_or(denied)?;
+        let ha1 = algorithm.hash(&format!("{name}:{REALM}:{password}"));
+        let ha2 = algorithm.hash(&format!("{method}:{digest_uri}"));
+        let expected = algorithm.hash(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}"));
+        if !constant_time_eq(
+            response.to_ascii_lowercase().as_bytes(),
+            expected.as_bytes(),
+        ) {
+            return Err(denied);
+        }
+
+        // The credentials are right; the nonce only decides whether a new one is needed. Each
+        // use must raise the nonce count, so a captured request can't be replayed.
+        let mut nonces = self.nonces.lock().unwrap();
+        match nonces.get_mut(nonce) {
+            Some(entry) if entry.issued.elapsed() < NONCE_LIFETIME && count > entry.count => {
+                entry.count = count;
+                Ok(name)
+            }
+            _ => Err(Denied { stale: true }),
+        }
+    }
+
+    /// Checks a username and password, e.g. from SOCKS5 clients, returning the username.
+    pub fn verify(&self, user: &str, pass: &str) -> Option<&str> {
+        let (name, expected) = self.users.get_key_value(user)?;
+        constant_time_eq(pass.as_bytes(), expected.as_bytes()).then_some(name.as_str())
+    }
+
+    /// The `407 Proxy Authentication Required` response asking for credentials in the
+    /// accepted schemes, strongest first. Digest challenges carry a new nonce.
+    pub fn challenge(&self, denied: Denied) -> String {
+        let mut response = String::from("HTTP/1.1 407 Proxy Authentication Required\r\n");
+        if self.scheme != AuthScheme::Basic {
+            let nonce = self.issue_nonce();
+            let stale = if denied.stale { ", stale=true" } else { "" };
+            for algorithm in ["SHA-256", "MD5"] {
+                let _ = write!(
+                    response,
+                    "Proxy-Authenticate: Digest realm=\"{REALM}\", qop=\"auth\", algorithm={algorithm}, nonce=\"{nonce}\"{stale}\r\n"
+                );
+            }
+        }
+        if self.scheme != AuthScheme::Digest {
+            let _ = write!(response, "Proxy-Authenticate: Basic realm=\"{REALM}\"\r\n");
+        }
+        response.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+        response
+    }
+
+    // A fresh random nonce, remembered until it expires
+    fn issue_nonce(&self) -> String {
+        let mut bytes = [0u8; 16];
+        ring::default_provider()
+            .secure_random
+            .fill(&mut bytes)
+            .expect("system random number generator failed");
+        let nonce = hex(&bytes);
+
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, entry| entry.issued.elapsed() < NONCE_LIFETIME);
+        if nonces.len() >= MAX_NONCES {
+            let oldest = nonces
+                .iter()
+                .min_by_key(|(_, entry)| entry.issued)
+                .map(|(nonce, _)| nonce.clone());
+            if let Some(oldest) = oldest {
+                nonces.remove(&oldest);
+            }
+        }
+        nonces.insert(
+            nonce.clone(),
+            Nonce {
+                issued: Instant::now(),
+                count: 0,
+            },
+        );
+        nonce
+    }
+}
+
+/// Static tokens accepted from machine clients that can't answer a 407 challenge, sent as
+/// `Proxy-Authorization: Bearer TOKEN` or in a header of their own.
+#[derive(Debug)]
+pub struct TokenAuth {
+    tokens: Vec<String>,
+    header: String,
+}
+
+impl TokenAuth {
+    /// Returns `None` when no tokens are configured, i.e. token authentication is disabled.
+    pub fn new(tokens: &[String], header: &str) -> Result<Option<Self>, String> {
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+        if tokens
+            .iter()
+            .any(|token| token.is_empty() || token.contains(char::is_whitespace))
+        {
+            return Err("--auth-token: tokens cannot be empty or contain whitespace".to_string());
+        }
+        Ok(Some(Self {
+            tokens: tokens.to_vec(),
+            header: header.to_string(),
+        }))
+    }
+
+    /// The header carrying the token.
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// Whether `value`, the request's token header, holds one of the tokens. The `Bearer`
+    /// scheme is required in Proxy-Authorization and optional in a custom header.
+    pub fn authorize(&self, value: Option<&str>) -> bool {
+        let Some(value) = value.map(str::trim) else {
+            return false;
+        };
+        let token = match value.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+            _ if self.header.eq_ignore_ascii_case("proxy-authorization") => return false,
+            _ => value,
+        };
+        // Every token is compared so the time taken doesn't reveal which one nearly matched
+        self.tokens.iter().fold(false, |found, expected| {
+            constant_time_eq(token.as_bytes(), expected.as_bytes()) | found
+        })
+    }
+
+    /// The `407 Proxy Authentication Required` response asking for a bearer token.
+    pub fn challenge(&self) -> String {
+        format!(
+            "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Bearer realm=\"{REALM}\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+    }
+}
+
+impl Algorithm {
+    // The lowercase hex digest of `input`
+    fn hash(self, input: &str) -> String {
+        match self {
+            Algorithm::Md5 => hex(&Md5::digest(input)),
+            Algorithm::Sha256 => hex(&Sha256::digest(input)),
+        }
+    }
+}
+
+// Splits the comma-separated `name=value` parameters of a Digest authorization, unquoting
+// quoted-string values. Names are lowercased.
+fn digest_params(input: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let value;
+        if let Some(quoted) = after.strip_prefix('"') {
+            let mut unquoted = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => unquoted.push(chars.next()?.1),
+                    (_, c) => unquoted.push(c),
+                }
+            };
+            value = unquoted;
+            rest = &quoted[end + 1..];
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            value = after[..end].trim().to_string();
+            rest = &after[end..];
+        }
+        params.insert(name.trim().to_ascii_lowercase(), value);
+        rest = rest.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Some(params)
+}
+
+// Whether a Digest `uri` names the request `target`. Clients differ on absolute-form
+// targets: some repeat the whole URI, others (curl) send only its origin-form path.
+fn same_resource(digest_uri: &str, target: &str) -> bool {
+    if digest_uri == target {
+        return true;
+    }
+    let Some((_, rest)) = target.split_once("://") else {
+        return false;
+    };
+    let path = rest.find(['/', '?']).map_or("/", |i| &rest[i..]);
+    match path.strip_prefix('?') {
+        Some(_) => digest_uri.strip_prefix('/') == Some(path),
+        None => digest_uri == path,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{byte:02x}");
+        out
+    })
+}
+
+// Compares secrets without short-circuiting on the first differing byte
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(scheme: AuthScheme) -> ProxyAuth {
+        ProxyAuth::load(&["alice:secret".to_string()], None, scheme)
+            .unwrap()
+            .unwrap()
+    }
+
+    // The nonce of the first Digest challenge `auth` sends
+    fn nonce(auth: &ProxyAuth) -> String {
+        let challenge = auth.challenge(Denied { stale: false });
+        let (_, rest) = challenge.split_once("nonce=\"").unwrap();
+        rest.split('"').next().unwrap().to_string()
+    }
+
+    // A Digest authorization for alice with `password`, as a client would compute it
+    fn digest(algorithm: Algorithm, password: &str, uri: &str, nonce: &str, nc: &str) -> String {
+        let name = match algorithm {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        };
+        let ha1 = algorithm.hash(&format!("alice:{REALM}:{password}"));
+        let ha2 = algorithm.hash(&format!("CONNECT:{uri}"));
+        let response = algorithm.hash(&format!("{ha1}:{nonce}:{nc}:0a4f113b:auth:{ha2}"));
+        format!(
+            "Digest username=\"alice\", realm=\"{REALM}\", nonce=\"{nonce}\", uri=\"{uri}\", \
+             algorithm={name}, qop=auth, nc={nc}, cnonce=\"0a4f113b\", response=\"{response}\""
+        )
+    }
+
+    #[test]
+    fn digest_md5_and_sha256() {
+        let auth = auth(AuthScheme::Any);
+        for algorithm in [Algorithm::Md5, Algorithm::Sha256] {
+            let nonce = nonce(&auth);
+            let header = digest(algorithm, "secret", "example.com:443", &nonce, "00000001");
+            let user = auth.authorize("CONNECT", "example.com:443", Some(&header));
+            assert_eq!(user.unwrap(), "alice");
+        }
+    }
+
+    #[test]
+    fn digest_with_wrong_password_is_denied() {
+        let auth = auth(AuthScheme::Digest);
+        let nonce = nonce(&auth);
+        let header = digest(
+            Algorithm::Md5,
+            "guess",
+            "example.com:443",
+            &nonce,
+            "00000001",
+        );
+        let denied = auth
+            .authorize("CONNECT", "example.com:443", Some(&header))
+            .unwrap_err();
+        assert!(!denied.stale);
+    }
+
+    #[test]
+    fn digest_for_another_target_is_denied() {
+        let auth = auth(AuthScheme::Digest);
+        let nonce = nonce(&auth);
+        let header = digest(
+            Algorithm::Md5,
+            "secret",
+            "other.com:443",
+            &nonce,
+            "00000001",
+        );
+        assert!(auth
+            .authorize("CONNECT", "example.com:443", Some(&header))
+            .is_err());
+    }
+
+    #[test]
+    fn replayed_or_unknown_nonce_is_stale() {
+        let auth = auth(AuthScheme::Digest);
+        let nonce = nonce(&auth);
+        let first = digest(
+            Algorithm::Sha256,
+            "secret",
+            "example.com:443",
+            &nonce,
+            "00000002",
+        );
+        assert!(auth
+            .authorize("CONNECT", "example.com:443", Some(&first))
+            .is_ok());
+        // The nonce count has to go up with every use
+        let replay = digest(
+            Algorithm::Sha256,
+            "secret",
+            "example.com:443",
+            &nonce,
+            "00000001",
+        );
+        let denied = auth
+            .authorize("CONNECT", "example.com:443", Some(&replay))
+            .unwrap_err();
+        assert!(denied.stale);
+
+        let unknown = digest(
+            Algorithm::Md5,
+            "secret",
+            "example.com:443",
+            "abc",
+            "00000001",
+        );
+        let denied = auth
+            .authorize("CONNECT", "example.com:443", Some(&unknown))
+            .unwrap_err();
+        assert!(denied.stale);
+    }
+
+    #[test]
+    fn schemes_are_limited_to_the_configured_one() {
+        let basic = format!("Basic {}", STANDARD.encode("alice:secret"));
+        assert!(auth(AuthScheme::Basic)
+            .authorize("GET", "/", Some(&basic))
+            .is_ok());
+        assert!(auth(AuthScheme::Digest)
+            .authorize("GET", "/", Some(&basic))
+            .is_err());
+
+        let digest_only = auth(AuthScheme::Digest).challenge(Denied { stale: true });
+        assert!(digest_only.contains("algorithm=SHA-256"));
+        assert!(digest_only.contains("stale=true"));
+        assert!(!digest_only.contains("Basic"));
+        let basic_only = auth(AuthScheme::Basic).challenge(Denied { stale: false });
+        assert!(!basic_only.contains("Digest"));
+    }
+
+    #[test]
+    fn digest_params_unquote_values() {
+        let params =
+            digest_params(r#"Username="a\"b", qop=auth , nc=00000001,uri="/a,b""#).unwrap();
+        assert_eq!(params["username"], "a\"b");
+        assert_eq!(params["qop"], "auth");
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["uri"], "/a,b");
+        assert!(digest_params(r#"uri="/unterminated"#).is_none());
+    }
+
+    #[test]
+    fn digest_uri_may_be_origin_form() {
+        assert!(same_resource(
+            "http://example.com/a?b",
+            "http://example.com/a?b"
+        ));
+        assert!(same_resource("/a?b", "http://example.com/a?b"));
+        assert!(same_resource("/", "http://example.com"));
+        assert!(!same_resource("/other", "http://example.com/a"));
+        assert!(!same_resource("/a", "example.com:443"));
+    }
+}
@@ -0,0 +1,341 @@
+use crate::http::{self, ResponseHead};
+use crate::tls;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tracing::{info, warn};
+
+// Largest list accepted from a URL
+const MAX_LIST_SIZE: u64 = 64 * 1024 * 1024;
+// Redirects followed when fetching a list
+const MAX_REDIRECTS: usize = 5;
+// Time allowed for fetching one list
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+// Names hosts files map to themselves rather than block
+const HOSTS_FILE_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+/// Where a blocklist is loaded from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    File(PathBuf),
+    Url(String),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Url(url) => f.write_str(url),
+        }
+    }
+}
+
+/// Blocked hosts from one list, in hosts-file or adblock format. Lookups take one hash
+/// probe per label of the host, however long the list.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    // Names blocked exactly, from hosts files and plain lists
+    hosts: HashSet<String>,
+    // Domains blocked with their subdomains, from `||domain^` rules
+    domains: HashSet<String>,
+    // Domains exempted with their subdomains by `@@||domain^` rules
+    exceptions: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Parses a list: hosts-file lines (`0.0.0.0 ads.example.com`), bare host names, and
+    /// adblock network rules for whole domains (`||ads.example.com^`, and `@@` exceptions).
+    /// Comments and rules this can't apply, such as cosmetic filters or paths, are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut list = Self::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', '!', '[']) || line.contains("##") {
+                continue;
+            }
+            if let Some(rule) = line.strip_prefix("@@") {
+                if let Some(domain) = adblock_domain(rule) {
+                    list.exceptions.insert(domain);
+                }
+            } else if line.starts_with("||") {
+                if let Some(domain) = adblock_domain(line) {
+                    list.domains.insert(domain);
+                }
+            } else {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut fields = line.split_whitespace();
+                let first = fields.next().unwrap_or_default();
+                // A hosts-file line names an address first, then the hosts mapped to it
+                let names: Vec<&str> = if first.parse::<std::net::IpAddr>().is_ok() {
+                    fields.collect()
+                } else {
+                    vec![first]
+                };
+                for name in names {
+                    let name = name.trim_end_matches('.').to_ascii_lowercase();
+                    if is_host_name(&name) && !HOSTS_FILE_NAMES.contains(&name.as_str()) {
+                        list.hosts.insert(name);
+                    }
+                }
+            }
+        }
+        list
+    }
+
+    fn len(&self) -> usize {
+        self.hosts.len() + self.domains.len()
+    }
+
+    fn extend(&mut self, other: &Self) {
+        self.hosts.extend(other.hosts.iter().cloned());
+        self.domains.extend(other.domains.iter().cloned());
+        self.exceptions.extend(other.exceptions.iter().cloned());
+    }
+
+    /// Whether `host` is blocked.
+    pub fn matches(&self, host: &str) -> bool {
+        if self.hosts.is_empty() && self.domains.is_empty() {
+            return false;
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let mut suffixes = std::iter::successors(Some(host.as_str()), |name| {
+            name.split_once('.').map(|(_, parent)| parent)
+        });
+        if suffixes.clone().any(|name| self.exceptions.contains(name)) {
+            return false;
+        }
+        self.hosts.contains(&host) || suffixes.any(|name| self.domains.contains(name))
+    }
+}
+
+// The domain of an adblock rule that blocks a whole domain, such as `||ads.example.com^` or
+// `||ads.example.com^$third-party`; rules with a path or wildcard can't be applied to hosts
+fn adblock_domain(rule: &str) -> Option<String> {
+    let rule = rule.strip_prefix("||")?;
+    let rule = rule.split('$').next()?;
+    let domain = rule.strip_suffix('^').unwrap_or(rule);
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    is_host_name(&domain).then_some(domain)
+}
+
+fn is_host_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+}
+
+/// The blocklists of `--blocklist-file` and `--blocklist-url`, merged into one matcher that
+/// is swapped out whenever they are refreshed.
+pub struct Blocklists {
+    sources: Vec<Source>,
+    // The last list loaded from each source, kept when a refresh fails
+    loaded: Mutex<Vec<Option<Blocklist>>>,
+    merged: RwLock<Arc<Blocklist>>,
+}
+
+impl Blocklists {
+    /// Loads the files right away, so a missing one is reported at startup. URLs are only
+    /// fetched by [`refresh`].
+    pub fn new(files: &[PathBuf], urls: &[String]) -> Result<Self, String> {
+        let sources: Vec<Source> = files
+            .iter()
+            .cloned()
+            .map(Source::File)
+            .chain(urls.iter().cloned().map(Source::Url))
+            .collect();
+        let mut loaded = Vec::with_capacity(sources.len());
+        for source in &sources {
+            loaded.push(match source {
+                Source::File(path) => {
+                    let text = std::fs::read_to_string(path)
+                        .map_err(|e| format!("--blocklist-file {}: {e}", path.display()))?;
+                    let list = Blocklist::parse(&text);
+                    info!("Loaded {} blocked hosts from {}", list.len(), source);
+                    Some(list)
+                }
+                Source::Url(url) => {
+                    Url::parse(url).map_err(|e| format!("--blocklist-url {url}: {e}"))?;
+                    None
+                }
+            });
+        }
+        let lists = Self {
+            sources,
+            loaded: Mutex::new(loaded),
+            merged: RwLock::default(),
+        };
+        lists.merge();
+        Ok(lists)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Whether any list blocks `host`.
+    pub fn matches(&self, host: &str) -> bool {
+        self.merged.read().unwrap().matches(host)
+    }
+
+    fn merge(&self) {
+        let mut merged = Blocklist::default();
+        for list in self.loaded.lock().unwrap().iter().flatten() {
+            merged.extend(list);
+        }
+        *self.merged.write().unwrap() = Arc::new(merged);
+    }
+}
+
+/// Fetches the URL lists now, then reloads every list each `interval` (never if zero) for
+/// as long as `lists` is in use. A list that fails to load keeps its previous contents.
+pub async fn refresh(lists: Weak<Blocklists>, interval: Duration) {
+    let mut startup = true;
+    loop {
+        if !startup {
+            if interval.is_zero() {
+                return;
+            }
+            tokio::time::sleep(interval).await;
+        }
+        let Some(lists) = lists.upgrade() else {
+            return;
+        };
+
+        for (index, source) in lists.sources.iter().enumerate() {
+            // Files were already loaded by `Blocklists::new`
+            if startup && matches!(source, Source::File(_)) {
+                continue;
+            }
+            let text = match source {
+                Source::File(path) => std::fs::read_to_string(path).map_err(|e| e.to_string()),
+                Source::Url(url) => match tokio::time::timeout(FETCH_TIMEOUT, fetch(url)).await {
+                    Ok(result) => result,
+                    Err(_) => Err("timed out".to_string()),
+                },
+            };
+            match text {
+                Ok(text) => {
+                    let list = Blocklist::parse(&text);
+                    info!("Loaded {} blocked hosts from {}", list.len(), source);
+                    lists.loaded.lock().unwrap()[index] = Some(list);
+                }
+                Err(e) => warn!("Failed to load blocklist {}: {}", source, e),
+            }
+        }
+        lists.merge();
+        startup = false;
+    }
+}
+
+// An `http://` or `https://` URL split into its parts
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self, String> {
+        let (tls, rest) = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => (true, rest),
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => (false, rest),
+            _ => return Err("must start with http:// or https://".to_string()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) =
+            http::split_host_port(authority, if tls { 443 } else { 80 }).ok_or("invalid host")?;
+        Ok(Self {
+            tls,
+            host,
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+// Downloads a list directly from its server, following redirects
+async fn fetch(url: &str) -> Result<String, String> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let parsed = Url::parse(&url)?;
+        let tcp = TcpStream::connect((parsed.host.as_str(), parsed.port))
+            .await
+            .map_err(|e| e.to_string())?;
+        let (head, body) = if parsed.tls {
+            let server_name =
+                ServerName::try_from(parsed.host.clone()).map_err(|e| e.to_string())?;
+            let stream = tls::connector(None)?
+                .connect(server_name, tcp)
+                .await
+                .map_err(|e| e.to_string())?;
+            get(stream, &parsed).await?
+        } else {
+            get(tcp, &parsed).await?
+        };
+        match head.status {
+            200 => return String::from_utf8(body).map_err(|_| "not UTF-8 text".to_string()),
+            301 | 302 | 303 | 307 | 308 => {
+                url = head
+                    .header("location")
+                    .ok_or("redirect without a Location")?
+                    .to_string();
+            }
+            status => return Err(format!("server answered {status}")),
+        }
+    }
+    Err("too many redirects".to_string())
+}
+
+// Sends an HTTP/1.0 GET, so the body comes unchunked and ends with the connection
+async fn get<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    url: &Url,
+) -> Result<(ResponseHead, Vec<u8>), String> {
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: http2socks/{}\r\n\r\n",
+        url.path,
+        http::join_host_port(&url.host, url.port),
+        env!("CARGO_PKG_VERSION")
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_LIST_SIZE + 1)
+        .read_to_end(&mut response)
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.len() as u64 > MAX_LIST_SIZE {
+        return Err(format!("larger than {MAX_LIST_SIZE} bytes"));
+    }
+    let head = ResponseHead::parse(&response)
+        .map_err(|e| e.to_string())?
+        .ok_or("truncated response")?;
+    let mut body = response.split_off(head.len);
+    if let Some(length) = head
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+    {
+        body.truncate(length);
+    }
+    Ok((head, body))
+}
@@ -0,0 +1,339 @@
+use crate::http::{RequestHead, ResponseHead};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWrite;
+
+// Statuses cacheable by default once the response carries an explicit lifetime
+const CACHEABLE_STATUSES: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// An in-memory LRU cache of plain HTTP GET responses, shared by all clients. Only
+/// responses with an explicit lifetime (`Cache-Control: max-age`/`s-maxage` or `Expires`)
+/// are stored, and they are served until that lifetime runs out.
+pub struct Cache {
+    capacity: usize,
+    max_object: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    // Keys by last use, least recent first
+    by_use: BTreeMap<u64, String>,
+    used: usize,
+    clock: u64,
+}
+
+struct Entry {
+    // Head, without any Age header, and body exactly as the origin sent them
+    response: Vec<u8>,
+    head_len: usize,
+    status: u16,
+    stored: Instant,
+    // Age the response already had when it was stored
+    initial_age: Duration,
+    lifetime: Duration,
+    last_use: u64,
+}
+
+/// How long a response may be served from the cache, and how old it already is.
+pub struct Freshness {
+    lifetime: Duration,
+    age: Duration,
+}
+
+/// A response served from the cache.
+pub struct Hit {
+    /// The complete response, with an `Age` header
+    pub response: Vec<u8>,
+    pub status: u16,
+}
+
+impl Cache {
+    /// A cache holding up to `capacity` bytes of responses, none larger than `max_object`.
+    pub fn new(capacity: usize, max_object: usize) -> Self {
+        Self {
+            capacity,
+            max_object: max_object.min(capacity),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// The key `head` is cached under when its destination is `authority`, or `None` if the
+    /// request must go to the origin and its response must not be stored. Responses in
+    /// different content codings are kept apart.
+    pub fn key(head: &RequestHead, authority: &str) -> Option<String> {
+        if !head.method.eq_ignore_ascii_case("GET")
+            || head.is_upgrade()
+            || head.header("authorization").is_some()
+            || directives(head.header("cache-control")).any(|(name, _)| name == "no-store")
+        {
+            return None;
+        }
+        let encoding = head.header("accept-encoding").unwrap_or_default();
+        Some(format!(
+            "http://{authority}{} {}",
+            head.path(),
+            encoding.to_ascii_lowercase()
+        ))
+    }
+
+    /// Whether the client asks for the response to be revalidated, so the cache can't
+    /// answer it (the response may still be stored).
+    pub fn must_revalidate(head: &RequestHead) -> bool {
+        directives(head.header("cache-control"))
+            .any(|(name, value)| name == "no-cache" || (name == "max-age" && value == Some("0")))
+            || head
+                .header("pragma")
+                .is_some_and(|pragma| pragma.eq_ignore_ascii_case("no-cache"))
+    }
+
+    /// The fresh response stored under `key`, if any.
+    pub fn get(&self, key: &str) -> Option<Hit> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        let entry = entries.by_key.get_mut(key)?;
+        let age = entry.initial_age + entry.stored.elapsed();
+        if age >= entry.lifetime {
+            let entry = entries.by_key.remove(key)?;
+            entries.by_use.remove(&entry.last_use);
+            entries.used -= entry.response.len();
+            return None;
+        }
+
+        entries.clock += 1;
+        entries.by_use.remove(&entry.last_use);
+        entry.last_use = entries.clock;
+        entries.by_use.insert(entry.last_use, key.to_string());
+
+        let head_end = entry.head_len - 2;
+        let mut response = Vec::with_capacity(entry.response.len() + 24);
+        response.extend_from_slice(&entry.response[..head_end]);
+        response.extend_from_slice(format!("Age: {}\r\n", age.as_secs()).as_bytes());
+        response.extend_from_slice(&entry.response[head_end..]);
+        Some(Hit {
+            response,
+            status: entry.status,
+        })
+    }
+
+    /// How long `response` may be served from the cache, or `None` if it must not be stored.
+    pub fn freshness(&self, response: &ResponseHead) -> Option<Freshness> {
+        if !CACHEABLE_STATUSES.contains(&response.status)
+            || response.header("set-cookie").is_some()
+            || !response.keep_alive()
+        {
+            return None;
+        }
+        // Variants by Accept-Encoding are told apart by the key; other Vary headers aren't
+        if let Some(vary) = response.header("vary") {
+            let other = |name: &str| {
+                let name = name.trim();
+                !name.is_empty() && !name.eq_ignore_ascii_case("accept-encoding")
+            };
+            if vary.split(',').any(other) {
+                return None;
+            }
+        }
+
+        let mut max_age = None;
+        let mut shared_max_age = None;
+        for (name, value) in directives(response.header("cache-control")) {
+            match name.as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                "max-age" => max_age = value.and_then(|v| v.parse().ok()),
+                "s-maxage" => shared_max_age = value.and_then(|v| v.parse().ok()),
+                _ => {}
+            }
+        }
+        let lifetime = match shared_max_age.or(max_age) {
+            Some(secs) => secs,
+            None => {
+                let expires = http_date(response.header("expires")?)?;
+                let date = response
+                    .header("date")
+                    .and_then(http_date)
+                    .unwrap_or_else(now);
+                expires.saturating_sub(date)
+            }
+        };
+        let age: u64 = response
+            .header("age")
+            .and_then(|age| age.parse().ok())
+            .unwrap_or_default();
+        (lifetime > age).then(|| Freshness {
+            lifetime: Duration::from_secs(lifetime),
+            age: Duration::from_secs(age),
+        })
+    }
+
+    /// A writer that passes a response body on to `inner` and keeps a copy while it fits
+    /// in the cache.
+    pub fn tee<'a, W: AsyncWrite + Unpin + ?Sized>(&self, inner: &'a mut W) -> Tee<'a, W> {
+        Tee {
+            inner,
+            copy: Some(Vec::new()),
+            limit: self.max_object,
+        }
+    }
+
+    /// Stores the response `head` and `body` under `key` while they stay fresh, evicting
+    /// the least recently used responses to make room.
+    pub fn insert(
+        &self,
+        key: String,
+        status: u16,
+        head: &[u8],
+        body: Vec<u8>,
+        freshness: Freshness,
+    ) {
+        let head = without_age(head);
+        let size = head.len() + body.len();
+        if size > self.max_object {
+            return;
+        }
+        let mut response = head;
+        let head_len = response.len();
+        response.extend_from_slice(&body);
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.by_key.remove(&key) {
+            entries.by_use.remove(&old.last_use);
+            entries.used -= old.response.len();
+        }
+        while entries.used + size > self.capacity {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = entries.by_key.remove(&oldest) {
+                entries.used -= evicted.response.len();
+            }
+        }
+        entries.clock += 1;
+        let last_use = entries.clock;
+        entries.by_use.insert(last_use, key.clone());
+        entries.used += size;
+        entries.by_key.insert(
+            key,
+            Entry {
+                response,
+                head_len,
+                status,
+                stored: Instant::now(),
+                initial_age: freshness.age,
+                lifetime: freshness.lifetime,
+                last_use,
+            },
+        );
+    }
+}
+
+/// Copies what is written to it into a buffer, up to a limit, while passing it on.
+pub struct Tee<'a, W: ?Sized> {
+    inner: &'a mut W,
+    copy: Option<Vec<u8>>,
+    limit: usize,
+}
+
+impl<W: ?Sized> Tee<'_, W> {
+    /// Everything written, unless it grew past the limit.
+    pub fn into_copy(self) -> Option<Vec<u8>> {
+        self.copy
+    }
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> AsyncWrite for Tee<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = std::task::ready!(Pin::new(&mut *this.inner).poll_write(cx, buf))?;
+        if let Some(copy) = &mut this.copy {
+            if copy.len() + written > this.limit {
+                this.copy = None;
+            } else {
+                copy.extend_from_slice(&buf[..written]);
+            }
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Lowercase `Cache-Control` directive names with their unquoted values
+fn directives(header: Option<&str>) -> impl Iterator<Item = (String, Option<&str>)> {
+    header
+        .unwrap_or_default()
+        .split(',')
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"')),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+}
+
+// The head with any Age header lines removed; the cache adds its own when serving
+fn without_age(head: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(head.len());
+    for line in head.split_inclusive(|&b| b == b'\n') {
+        let is_age = line.len() > 4 && line[..4].eq_ignore_ascii_case(b"age:");
+        if !is_age {
+            out.extend_from_slice(line);
+        }
+    }
+    out
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Seconds since the Unix epoch of an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (_, rest) = date.trim().split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|n| n.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if parts.next()? != "GMT" || year < 1970 || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch of a proleptic Gregorian date, shifted to years starting in March
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
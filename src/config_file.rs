@@ -0,0 +1,120 @@
+use clap::error::{ContextKind, ContextValue};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Options read from a TOML configuration file, translated into the equivalent
+/// command line arguments. Keys are the long option names, with `_` or `-`
+/// (`socks_version = "4a"`); repeatable options take arrays and flags take booleans.
+pub struct FileArgs {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+// One file key and the arguments it expands to
+struct Entry {
+    key: String,
+    long: String,
+    args: Vec<OsString>,
+}
+
+impl FileArgs {
+    /// Reads `path`, skipping keys whose option was already given on the command line
+    /// or through its environment variable, since those take precedence over the file.
+    pub fn load(path: &Path, command: &Command, matches: &ArgMatches) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("--config {}: {e}", path.display()))?;
+        let table: toml::Table = contents.parse().map_err(|e: toml::de::Error| {
+            let line = e
+                .span()
+                .map_or(1, |span| contents[..span.start].matches('\n').count() + 1);
+            format!("--config {}:{line}: {}", path.display(), e.message())
+        })?;
+
+        let mut entries = Vec::new();
+        for (key, value) in table {
+            let error = |message: &str| format!("{}: key `{key}`: {message}", path.display());
+
+            let long = key.replace('_', "-");
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(long.as_str()))
+                .filter(|_| !matches!(long.as_str(), "config" | "help" | "version"))
+                .ok_or_else(|| format!("{}: unknown key `{key}`", path.display()))?;
+
+            if matches!(
+                matches.value_source(arg.get_id().as_str()),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                continue;
+            }
+
+            // Options whose value is optional, like `--forward [MODE]`, also take a boolean
+            let optional_value = arg.get_num_args().is_some_and(|n| n.min_values() == 0)
+                && matches!(value, Value::Boolean(_));
+            let args = if matches!(arg.get_action(), ArgAction::SetTrue) || optional_value {
+                match value {
+                    Value::Boolean(true) => vec![OsString::from(format!("--{long}"))],
+                    Value::Boolean(false) => Vec::new(),
+                    _ => return Err(error("expected a boolean")),
+                }
+            } else {
+                let values = match value {
+                    Value::Array(values) => values,
+                    value => vec![value],
+                };
+                values
+                    .into_iter()
+                    .map(|value| {
+                        let value = scalar(value).ok_or_else(|| {
+                            error("expected a string, number, boolean or array of those")
+                        })?;
+                        Ok(OsString::from(format!("--{long}={value}")))
+                    })
+                    .collect::<Result<_, String>>()?
+            };
+
+            entries.push(Entry { key, long, args });
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// The file's options as command line arguments.
+    pub fn args(&self) -> impl Iterator<Item = OsString> + '_ {
+        self.entries
+            .iter()
+            .flat_map(|entry| entry.args.iter().cloned())
+    }
+
+    /// Describes a parse error in terms of the file key that caused it, if a file value is to blame.
+    pub fn describe_error(&self, error: &clap::Error, message: &str) -> Option<String> {
+        let Some(ContextValue::String(invalid)) = error.get(ContextKind::InvalidArg) else {
+            return None;
+        };
+        // clap names the argument as e.g. `--listen <LISTEN>`
+        let flag = invalid.split_whitespace().next()?.strip_prefix("--")?;
+        let entry = self.entries.iter().find(|entry| entry.long == flag)?;
+        Some(format!(
+            "{}: key `{}`: {message}",
+            self.path.display(),
+            entry.key
+        ))
+    }
+}
+
+fn scalar(value: Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        Value::Datetime(d) => Some(d.to_string()),
+        Value::Array(_) | Value::Table(_) => None,
+    }
+}
@@ -0,0 +1,37 @@
+use crate::socks::{self, Upstream, UpstreamStream};
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// What [`Connector::connect`] returns: the open stream, or why it couldn't be opened.
+pub type Connecting<'a> =
+    Pin<Box<dyn Future<Output = Result<UpstreamStream, Box<dyn Error>>> + Send + 'a>>;
+
+/// Opens the connections tunnels and plain HTTP requests are sent over, in place of the
+/// SOCKS client. Set one with [`ProxyBuilder::connector`](crate::ProxyBuilder::connector) to
+/// carry traffic over another transport, such as an SSH channel, a userspace WireGuard
+/// tunnel or in-memory streams in tests; wrap its streams in [`UpstreamStream::Custom`].
+///
+/// Routing, failover and timeouts stay with the proxy: it picks `upstream` as usual and only
+/// asks the connector to reach `host:port` through it. Direct routes, the UDP relay and
+/// upstream health checks don't go through the connector.
+pub trait Connector: Send + Sync + 'static {
+    fn connect<'a>(&'a self, host: &'a str, port: u16, upstream: &'a Upstream) -> Connecting<'a>;
+}
+
+/// The default connector: a SOCKS5 CONNECT over TCP, or whichever protocol and transport
+/// `upstream` is configured with.
+pub struct SocksConnector;
+
+impl Connector for SocksConnector {
+    fn connect<'a>(&'a self, host: &'a str, port: u16, upstream: &'a Upstream) -> Connecting<'a> {
+        Box::pin(socks::connect_upstream(host, port, upstream))
+    }
+}
+
+/// A byte stream a custom [`Connector`] hands back; any Tokio stream that can be shared
+/// between threads is one.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Transport for T {}
@@ -0,0 +1,83 @@
+use http2socks::FatalError;
+use std::path::{Path, PathBuf};
+
+/// Detaches from the controlling terminal the classic way: forks twice with a new session in
+/// between, so the process is reparented to init and can never reacquire a terminal, then
+/// points the standard streams at /dev/null. Must be called before any thread is started.
+///
+/// The working directory is kept, so relative paths in the configuration still resolve when
+/// files are reopened on reload.
+#[cfg(unix)]
+pub fn detach() -> Result<(), FatalError> {
+    let error = |step: &str| {
+        FatalError::Runtime(format!(
+            "failed to daemonize: {step}: {}",
+            std::io::Error::last_os_error()
+        ))
+    };
+
+    // SAFETY: the process is still single-threaded, so the child may keep running Rust code
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(error("fork")),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            return Err(error("setsid"));
+        }
+        match libc::fork() {
+            -1 => return Err(error("fork")),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if null == -1 {
+            return Err(error("/dev/null"));
+        }
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if libc::dup2(null, fd) == -1 {
+                return Err(error("dup2"));
+            }
+        }
+        if null > libc::STDERR_FILENO {
+            libc::close(null);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn detach() -> Result<(), FatalError> {
+    Err(FatalError::Config(
+        "--daemon is only available on Unix".to_string(),
+    ))
+}
+
+/// A file holding the process id, removed when dropped unless a process that took over in
+/// an upgrade has since written its own.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the id of the current process to `path`, replacing any stale file.
+    pub fn create(path: &Path) -> Result<Self, FatalError> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .map_err(|e| FatalError::Config(format!("--pid-file {}: {e}", path.display())))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let ours = std::fs::read_to_string(&self.path)
+            .is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+        if ours {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::encrypted_dns::EncryptedResolver;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Resolves destination hostnames for `--resolve local` and direct connections, through
+/// the system resolver or a `--doh-url`/`--dot-server` one, keeping answers in a bounded
+/// in-process cache.
+pub struct Resolver {
+    // `None` when --dns-cache-size is 0
+    cache: Option<Mutex<Cache>>,
+    ttl: Duration,
+    encrypted: Option<EncryptedResolver>,
+}
+
+struct Cache {
+    entries: HashMap<String, Entry>,
+    capacity: usize,
+}
+
+struct Entry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl Resolver {
+    /// A resolver caching up to `cache_size` hostnames for at most `ttl`. Answers from an
+    /// encrypted resolver expire sooner if their records say so; the system resolver does not
+    /// report record TTLs, so its answers are kept for `ttl`.
+    pub fn new(cache_size: usize, ttl: Duration, encrypted: Option<EncryptedResolver>) -> Self {
+        let cache = (cache_size > 0).then(|| {
+            Mutex::new(Cache {
+                entries: HashMap::new(),
+                capacity: cache_size,
+            })
+        });
+        Self {
+            cache,
+            ttl,
+            encrypted,
+        }
+    }
+
+    /// The addresses `host` resolves to, in resolver order. IP literals are returned as is.
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let key = host.to_ascii_lowercase();
+        if let Some(addrs) = self.cached(&key) {
+            debug!("DNS cache hit for {}", host);
+            return Ok(addrs);
+        }
+
+        let (addrs, ttl) = match &self.encrypted {
+            Some(encrypted) => {
+                let (addrs, ttl) = encrypted.lookup(host).await?;
+                (addrs, ttl.min(self.ttl))
+            }
+            // The port is required by the API but plays no part in the lookup
+            None => {
+                let addrs = tokio::net::lookup_host((host, 0)).await?;
+                (addrs.map(|addr| addr.ip()).collect(), self.ttl)
+            }
+        };
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{host} resolved to no addresses"),
+            ));
+        }
+        self.store(key, &addrs, ttl);
+        Ok(addrs)
+    }
+
+    // A cached answer that has not yet expired
+    fn cached(&self, key: &str) -> Option<Vec<IpAddr>> {
+        let cache = self.cache.as_ref()?.lock().unwrap();
+        let entry = cache.entries.get(key)?;
+        (entry.expires > Instant::now()).then(|| entry.addrs.clone())
+    }
+
+    fn store(&self, key: String, addrs: &[IpAddr], ttl: Duration) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        let mut cache = cache.lock().unwrap();
+        let now = Instant::now();
+        if cache.entries.len() >= cache.capacity && !cache.entries.contains_key(&key) {
+            cache.entries.retain(|_, entry| entry.expires > now);
+        }
+        // Still full of live answers: make room by dropping the one closest to expiry
+        if cache.entries.len() >= cache.capacity && !cache.entries.contains_key(&key) {
+            let oldest = cache
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(host, _)| host.clone());
+            if let Some(oldest) = oldest {
+                cache.entries.remove(&oldest);
+            }
+        }
+        cache.entries.insert(
+            key,
+            Entry {
+                addrs: addrs.to_vec(),
+                expires: now + ttl,
+            },
+        );
+    }
+}
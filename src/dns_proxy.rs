@@ -0,0 +1,140 @@
+use crate::encrypted_dns;
+use crate::error::FatalError;
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{debug, info};
+
+// Largest DNS message over either transport
+const MAX_MESSAGE: usize = 65535;
+
+// Time a query may take to be answered through the upstream
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Time a TCP client may stay idle between queries (RFC 7766 suggests seconds, not minutes)
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Binds the TCP listener of `--dns-listen`.
+pub async fn bind_tcp(listen: &str) -> Result<TcpListener, FatalError> {
+    TcpListener::bind(listen)
+        .await
+        .map_err(|source| FatalError::Bind {
+            addr: listen.to_string(),
+            source,
+        })
+}
+
+/// Answers the DNS queries arriving on `socket` and `listener` with `resolve`, which passes
+/// a query on and returns the resolver's answer. Queries that fail or time out get no
+/// answer, so clients retry or fail over as they would with an unreachable resolver.
+pub async fn serve<F, Fut>(socket: UdpSocket, listener: TcpListener, resolve: F)
+where
+    F: Fn(Vec<u8>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send + 'static,
+{
+    if let Ok(addr) = socket.local_addr() {
+        info!("DNS proxy listening on: {} (UDP and TCP)", addr);
+    }
+    tokio::join!(
+        serve_udp(socket, resolve.clone()),
+        serve_tcp(listener, resolve)
+    );
+}
+
+async fn serve_udp<F, Fut>(socket: UdpSocket, resolve: F)
+where
+    F: Fn(Vec<u8>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send + 'static,
+{
+    let socket = Arc::new(socket);
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    loop {
+        let (n, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // ICMP errors for earlier answers surface here on some platforms
+                debug!("DNS proxy receive failed: {}", e);
+                continue;
+            }
+        };
+        let query = buf[..n].to_vec();
+        let socket = socket.clone();
+        let resolve = resolve.clone();
+        tokio::spawn(async move {
+            let Some(answer) = answer(&resolve, query, client).await else {
+                return;
+            };
+            if let Err(e) = socket.send_to(&answer, client).await {
+                debug!("Sending DNS answer to {} failed: {}", client, e);
+            }
+        });
+    }
+}
+
+async fn serve_tcp<F, Fut>(listener: TcpListener, resolve: F)
+where
+    F: Fn(Vec<u8>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send + 'static,
+{
+    loop {
+        let Ok((stream, client)) = listener.accept().await else {
+            continue;
+        };
+        let resolve = resolve.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_stream(stream, client, &resolve).await {
+                debug!("DNS client {} failed: {}", client, e);
+            }
+        });
+    }
+}
+
+// Answers length-prefixed queries one after another until the client closes or goes idle
+async fn serve_stream<F, Fut>(
+    mut stream: TcpStream,
+    client: SocketAddr,
+    resolve: &F,
+) -> std::io::Result<()>
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>, Box<dyn Error>>>,
+{
+    loop {
+        let Ok(length) = tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await else {
+            return Ok(());
+        };
+        let length = match length {
+            Ok(length) => length,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut query = vec![0u8; length as usize];
+        stream.read_exact(&mut query).await?;
+        let Some(answer) = answer(resolve, query, client).await else {
+            return Ok(());
+        };
+        encrypted_dns::write_message(&mut stream, &answer).await?;
+    }
+}
+
+async fn answer<F, Fut>(resolve: &F, query: Vec<u8>, client: SocketAddr) -> Option<Vec<u8>>
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>, Box<dyn Error>>>,
+{
+    match tokio::time::timeout(QUERY_TIMEOUT, resolve(query)).await {
+        Ok(Ok(answer)) => Some(answer),
+        Ok(Err(e)) => {
+            debug!("DNS query from {} failed: {}", client, e);
+            None
+        }
+        Err(_) => {
+            debug!("DNS query from {} timed out", client);
+            None
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A client connection whose traffic is copied to two files as it passes, for
+/// `--dump-traffic`: `NAME.from-client` gets every byte read from the client and
+/// `NAME.to-client` every byte written to it, exactly as they were on the wire (after TLS).
+pub struct Dump<S> {
+    inner: S,
+    files: DumpFiles,
+}
+
+/// The two files one connection is dumped to.
+pub struct DumpFiles {
+    from_client: BufWriter<File>,
+    to_client: BufWriter<File>,
+}
+
+impl DumpFiles {
+    /// Creates the files for a connection from `client` in `dir`, named after the time and
+    /// the client address so that a directory listing shows connections in order.
+    pub fn create(dir: &Path, client: SocketAddr) -> io::Result<Self> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // Colons of IPv6 addresses aren't allowed in Windows file names
+        let name = format!(
+            "{millis}-{}_{}",
+            client.ip().to_string().replace(':', "-"),
+            client.port()
+        );
+        let create = |suffix: &str| -> io::Result<BufWriter<File>> {
+            Ok(BufWriter::new(File::create(
+                dir.join(format!("{name}.{suffix}")),
+            )?))
+        };
+        Ok(Self {
+            from_client: create("from-client")?,
+            to_client: create("to-client")?,
+        })
+    }
+}
+
+impl<S> Dump<S> {
+    pub fn new(inner: S, files: DumpFiles) -> Self {
+        Self { inner, files }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+// The copies are written synchronously: dumping is for debugging, where simplicity beats
+// throughput. A failing copy never fails the connection itself.
+impl<S: AsyncRead + Unpin> AsyncRead for Dump<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        std::task::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let _ = this.files.from_client.write_all(&buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Dump<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = std::task::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        let _ = this.files.to_client.write_all(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let _ = this.files.from_client.flush();
+        let _ = this.files.to_client.flush();
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,278 @@
+use crate::error::{json_escape, FatalError};
+use std::error::Error;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, instrument, Instrument};
+
+// Largest request head or body the echo server accepts
+const MAX_HEAD_SIZE: usize = 16384;
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+// Runs a small origin server that echoes raw TCP streams and introspects HTTP requests
+pub async fn run(
+    listen: &str,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), FatalError> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|source| FatalError::Bind {
+            addr: listen.to_string(),
+            source,
+        })?;
+    info!("Echo server listening on: {}", listen);
+
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => return Ok(()),
+        };
+        let (client, addr) = accepted
+            .map_err(|e| FatalError::Runtime(format!("failed to accept connection: {e}")))?;
+        let span = tracing::info_span!("echo", client.addr = %addr);
+
+        tokio::spawn(
+            async move {
+                if let Err(e) = handle_echo_client(client, addr).await {
+                    error!("Echo client error: {}", e);
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+// Serves HTTP requests if the client speaks HTTP, otherwise echoes bytes back verbatim
+#[instrument(skip_all)]
+async fn handle_echo_client(mut client: TcpStream, peer: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let mut buffer = Vec::new();
+    let mut temp_buf = [0u8; 4096];
+
+    let n = client.read(&mut temp_buf).await?;
+    if n == 0 {
+        return Ok(());
+    }
+    buffer.extend_from_slice(&temp_buf[..n]);
+
+    if !looks_like_http(&buffer) {
+        client.write_all(&buffer).await?;
+        let (mut reader, mut writer) = client.split();
+        let echoed = tokio::io::copy(&mut reader, &mut writer).await?;
+        info!("Echoed {} bytes", echoed as usize + buffer.len());
+        return Ok(());
+    }
+
+    // Serve requests until the client closes or asks us to
+    loop {
+        let header_end = loop {
+            if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if buffer.len() > MAX_HEAD_SIZE {
+                client
+                    .write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await?;
+                return Ok(());
+            }
+            let n = client.read(&mut temp_buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            buffer.extend_from_slice(&temp_buf[..n]);
+        };
+
+        let request = EchoRequest::parse(&buffer[..header_end]).ok_or("Malformed request")?;
+        let content_length = request.content_length().ok_or("Invalid Content-Length")?;
+        if content_length > MAX_BODY_SIZE {
+            client
+                .write_all(b"HTTP/1.1 413 Content Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Ok(());
+        }
+
+        while buffer.len() < header_end + content_length {
+            let n = client.read(&mut temp_buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            buffer.extend_from_slice(&temp_buf[..n]);
+        }
+        let body: Vec<u8> = buffer
+            .drain(..header_end + content_length)
+            .skip(header_end)
+            .collect();
+
+        info!("{} {}", request.method, request.target);
+        let keep_alive = request.keep_alive();
+        let (status, content_type, response_body) = request.respond(peer, &body).await;
+
+        let mut head = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n",
+            response_body.len()
+        );
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        client.write_all(head.as_bytes()).await?;
+        if request.method != "HEAD" {
+            client.write_all(&response_body).await?;
+        }
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// A request line starts with an uppercase method token followed by a space
+fn looks_like_http(buffer: &[u8]) -> bool {
+    let method_len = buffer.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    method_len > 0 && buffer.get(method_len) == Some(&b' ')
+}
+
+struct EchoRequest {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl EchoRequest {
+    fn parse(head: &[u8]) -> Option<Self> {
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?.to_string();
+        let version = parts.next()?.to_string();
+
+        let headers = lines
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Self {
+            method,
+            target,
+            version,
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        self.header("content-length")
+            .map_or(Some(0), |v| v.parse().ok())
+    }
+
+    fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+
+    // Path component of the target, accepting both origin-form and absolute-form
+    fn path(&self) -> &str {
+        let path = match self.target.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => &self.target,
+        };
+        path.split('?').next().unwrap_or(path)
+    }
+
+    // Dispatches to the introspection endpoints
+    async fn respond(&self, peer: SocketAddr, body: &[u8]) -> (String, &'static str, Vec<u8>) {
+        const JSON: &str = "application/json";
+        const TEXT: &str = "text/plain";
+
+        let path = self.path();
+        let mut segments = path.trim_start_matches('/').splitn(2, '/');
+        match (segments.next().unwrap_or(""), segments.next()) {
+            ("echo", None) => ("200 OK".into(), "application/octet-stream", body.to_vec()),
+            ("headers", None) => ("200 OK".into(), JSON, self.headers_json().into_bytes()),
+            ("ip", None) => (
+                "200 OK".into(),
+                JSON,
+                format!(r#"{{"origin":"{}"}}"#, peer).into_bytes(),
+            ),
+            ("status", Some(code)) => match code.parse::<u16>() {
+                Ok(code) if (200..600).contains(&code) => {
+                    (format!("{code} Status"), TEXT, Vec::new())
+                }
+                _ => (
+                    "400 Bad Request".into(),
+                    TEXT,
+                    b"invalid status code\n".to_vec(),
+                ),
+            },
+            ("bytes", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n <= MAX_BODY_SIZE => (
+                    "200 OK".into(),
+                    "application/octet-stream",
+                    (0..n).map(|i| b'a' + (i % 26) as u8).collect(),
+                ),
+                _ => (
+                    "400 Bad Request".into(),
+                    TEXT,
+                    b"invalid byte count\n".to_vec(),
+                ),
+            },
+            ("delay", Some(ms)) => match ms.parse::<u64>() {
+                Ok(ms) if ms <= 60_000 => {
+                    tokio::time::sleep(Duration::from_millis(ms)).await;
+                    (
+                        "200 OK".into(),
+                        JSON,
+                        self.request_json(peer, body).into_bytes(),
+                    )
+                }
+                _ => ("400 Bad Request".into(), TEXT, b"invalid delay\n".to_vec()),
+            },
+            _ => (
+                "200 OK".into(),
+                JSON,
+                self.request_json(peer, body).into_bytes(),
+            ),
+        }
+    }
+
+    fn headers_json(&self) -> String {
+        let mut json = String::from("{");
+        for (i, (name, value)) in self.headers.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, r#""{}":"{}""#, json_escape(name), json_escape(value));
+        }
+        json.push('}');
+        json
+    }
+
+    fn request_json(&self, peer: SocketAddr, body: &[u8]) -> String {
+        format!(
+            r#"{{"method":"{}","target":"{}","version":"{}","origin":"{}","headers":{},"body":"{}"}}"#,
+            json_escape(&self.method),
+            json_escape(&self.target),
+            json_escape(&self.version),
+            peer,
+            self.headers_json(),
+            json_escape(&String::from_utf8_lossy(body)),
+        )
+    }
+}
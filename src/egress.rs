@@ -0,0 +1,60 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Where direct connections leave from: the source address of `--bind-out` and the
+/// interface of `--bind-interface`, for gateways with more than one way out.
+#[derive(Debug, Default)]
+pub struct Egress {
+    address: Option<IpAddr>,
+    interface: Option<String>,
+}
+
+impl Egress {
+    pub fn new(address: Option<IpAddr>, interface: Option<String>) -> Result<Self, String> {
+        if interface.is_some() && !cfg!(any(target_os = "linux", target_os = "android")) {
+            return Err("--bind-interface is only supported on Linux".into());
+        }
+        if interface.as_deref() == Some("") {
+            return Err("--bind-interface: empty interface name".into());
+        }
+        Ok(Self { address, interface })
+    }
+
+    /// Whether `ip` can be reached from the source address; one of the other family can't.
+    pub fn reaches(&self, ip: IpAddr) -> bool {
+        self.address
+            .is_none_or(|address| address.is_ipv4() == ip.is_ipv4())
+    }
+
+    /// Opens a TCP connection to `addr` from the configured address and interface.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(name) = &self.interface {
+            bind_device(&socket, name)?;
+        }
+        if let Some(address) = self.address {
+            socket.bind(SocketAddr::new(address, 0))?;
+        }
+        socket.connect(addr).await
+    }
+}
+
+// SO_BINDTODEVICE, which needs CAP_NET_RAW
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &TcpSocket, name: &str) -> io::Result<()> {
+    socket2::SockRef::from(socket)
+        .bind_device(Some(name.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("--bind-interface {name}: {e}")))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &TcpSocket, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--bind-interface is only supported on Linux",
+    ))
+}
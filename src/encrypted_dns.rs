@@ -0,0 +1,263 @@
+use crate::http::{self, BufferedStream, ResponseHead};
+use crate::tls;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+// DNS message constants (RFC 1035 section 4.1, RFC 3596)
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RESPONSE: u16 = 0x8000;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NAME_ERROR: u16 = 3;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const DOT_PORT: u16 = 853;
+// Largest DoH response accepted
+const MAX_RESPONSE_SIZE: usize = 65535;
+
+/// A resolver reached over an encrypted transport: DNS-over-HTTPS (RFC 8484) or
+/// DNS-over-TLS (RFC 7858).
+pub struct EncryptedResolver {
+    transport: Transport,
+    host: String,
+    port: u16,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+}
+
+enum Transport {
+    // The request path of the DoH endpoint
+    Https { path: String },
+    Tls,
+}
+
+impl EncryptedResolver {
+    /// A DoH resolver at an `https://host[:port]/path` URL.
+    pub fn https(url: &str) -> Result<Self, String> {
+        let error = |reason: &str| format!("--doh-url {url}: {reason}");
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| error("must start with https://"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) =
+            http::split_host_port(authority, 443).ok_or_else(|| error("invalid host"))?;
+        Self::new(
+            Transport::Https {
+                path: path.to_string(),
+            },
+            host,
+            port,
+        )
+        .map_err(|e| error(&e))
+    }
+
+    /// A DoT resolver at `host[:port]` (default port 853).
+    pub fn tls(server: &str) -> Result<Self, String> {
+        let error = |reason: &str| format!("--dot-server {server}: {reason}");
+        let (host, port) =
+            http::split_host_port(server, DOT_PORT).ok_or_else(|| error("invalid host"))?;
+        Self::new(Transport::Tls, host, port).map_err(|e| error(&e))
+    }
+
+    fn new(transport: Transport, host: String, port: u16) -> Result<Self, String> {
+        let server_name =
+            ServerName::try_from(host.clone()).map_err(|e| format!("invalid server name: {e}"))?;
+        Ok(Self {
+            transport,
+            host,
+            port,
+            server_name,
+            connector: tls::connector(None)?,
+        })
+    }
+
+    /// Looks up the A and AAAA records of `host`, IPv4 addresses first. Returns the
+    /// addresses with the smallest TTL among them; a name that does not exist yields none.
+    pub async fn lookup(&self, host: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let queries = [TYPE_A, TYPE_AAAA].map(|record_type| query(host, record_type));
+        let queries = queries.into_iter().collect::<io::Result<Vec<_>>>()?;
+
+        // The resolver's own name goes to the system resolver
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let mut stream = self
+            .connector
+            .connect(self.server_name.clone(), tcp)
+            .await?;
+        let mut responses = Vec::with_capacity(queries.len());
+        for (id, message) in &queries {
+            let response = match &self.transport {
+                Transport::Https { path } => {
+                    self.exchange_https(&mut stream, path, message).await?
+                }
+                Transport::Tls => exchange_tcp(&mut stream, message).await?,
+            };
+            responses.push(parse_response(&response, *id)?);
+        }
+
+        let mut addrs = Vec::new();
+        let mut ttl: Option<u32> = None;
+        for (answer_addrs, answer_ttl) in responses {
+            addrs.extend(answer_addrs);
+            ttl = match (ttl, answer_ttl) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        Ok((addrs, Duration::from_secs(ttl.unwrap_or(0).into())))
+    }
+
+    // POSTs one query over a kept-alive HTTP/1.1 connection and reads the answer
+    async fn exchange_https(
+        &self,
+        stream: &mut TlsStream<TcpStream>,
+        path: &str,
+        message: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let request = format!(
+            "POST {path} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            self.host,
+            message.len()
+        );
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(message).await?;
+        stream.flush().await?;
+
+        let mut stream = BufferedStream::new(stream);
+        let head = stream
+            .read_head(MAX_RESPONSE_SIZE, ResponseHead::parse, |head| head.len)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+            .ok_or_else(|| invalid("DoH server closed the connection"))?;
+        stream.consume(head.len);
+        if head.status != 200 {
+            return Err(invalid(&format!("DoH server answered {}", head.status)));
+        }
+        let length = head
+            .body_length("POST")
+            .ok_or_else(|| invalid("invalid DoH response framing"))?;
+        let mut body = Vec::new();
+        stream.copy_body(&mut body, length).await?;
+        if !stream.buf.is_empty() {
+            return Err(invalid("unexpected data after DoH response"));
+        }
+        Ok(body)
+    }
+}
+
+/// Sends one length-prefixed query over TCP-style DNS framing (RFC 1035 section 4.2.2)
+/// and reads the answer.
+pub async fn exchange_tcp<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    message: &[u8],
+) -> io::Result<Vec<u8>> {
+    write_message(stream, message).await?;
+    let mut response = vec![0u8; stream.read_u16().await? as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// Writes one DNS message with the two-byte length prefix of TCP-style framing.
+pub async fn write_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    message: &[u8],
+) -> io::Result<()> {
+    let len = u16::try_from(message.len()).map_err(|_| invalid("DNS message too long"))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(message).await?;
+    stream.flush().await
+}
+
+// Builds a recursive query for `host`, returning its ID and wire form
+fn query(host: &str, record_type: u16) -> io::Result<(u16, Vec<u8>)> {
+    let id = RandomState::new().build_hasher().finish() as u16;
+    // Header: ID, flags, one question, no answer, authority or additional records
+    let mut message = Vec::with_capacity(host.len() + 18);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|len| (1..=63).contains(len))
+            .ok_or_else(|| invalid(&format!("invalid DNS name {host}")))?;
+        message.push(len);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&record_type.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok((id, message))
+}
+
+// Extracts the A and AAAA records of an answer and their smallest TTL
+fn parse_response(message: &[u8], id: u16) -> io::Result<(Vec<IpAddr>, Option<u32>)> {
+    let truncated = || invalid("truncated DNS response");
+    let (header, mut rest) = message.split_first_chunk::<12>().ok_or_else(truncated)?;
+    let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+    if field(0) != id || field(2) & FLAG_RESPONSE == 0 {
+        return Err(invalid("DNS response does not match the query"));
+    }
+    match field(2) & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Ok((Vec::new(), None)),
+        rcode => return Err(invalid(&format!("DNS server failed with rcode {rcode}"))),
+    }
+
+    for _ in 0..field(4) {
+        rest = skip_name(rest).ok_or_else(truncated)?;
+        rest = rest.get(4..).ok_or_else(truncated)?; // type, class
+    }
+    let mut addrs = Vec::new();
+    let mut ttl: Option<u32> = None;
+    for _ in 0..field(6) {
+        rest = skip_name(rest).ok_or_else(truncated)?;
+        let (fixed, after) = rest.split_first_chunk::<10>().ok_or_else(truncated)?;
+        let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let record_ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let data = after.get(..len).ok_or_else(truncated)?;
+        rest = &after[len..];
+
+        // CNAMEs leading to the addresses are skipped; the resolver includes their targets
+        let addr = match (record_type, data.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().map_err(|_| truncated())?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => continue,
+        };
+        addrs.push(addr);
+        ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+    }
+    Ok((addrs, ttl))
+}
+
+// Skips a possibly compressed domain name
+fn skip_name(mut rest: &[u8]) -> Option<&[u8]> {
+    loop {
+        let (&len, after) = rest.split_first()?;
+        match len {
+            0 => return Some(after),
+            // A compression pointer ends the name
+            len if len & 0xc0 == 0xc0 => return after.get(1..),
+            len => rest = after.get(len as usize..)?,
+        }
+    }
+}
+
+// A malformed or failed exchange
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,83 @@
+use std::fmt::Write;
+use std::process::ExitCode;
+
+/// Errors that terminate the process, grouped by how a supervisor should react to them.
+#[derive(Debug, thiserror::Error)]
+pub enum FatalError {
+    /// The command line or configuration is invalid; restarting won't help
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    /// A listening socket could not be created
+    #[error("failed to bind {addr}: {source}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The upstream SOCKS server failed startup validation
+    #[error("upstream {addr} failed validation: {reason}")]
+    Upstream { addr: String, reason: String },
+
+    /// The proxy failed while running
+    #[error("runtime failure: {0}")]
+    Runtime(String),
+}
+
+impl FatalError {
+    /// Short machine-readable name of the error category.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FatalError::Config(_) => "config",
+            FatalError::Bind { .. } => "bind",
+            FatalError::Upstream { .. } => "upstream",
+            FatalError::Runtime(_) => "runtime",
+        }
+    }
+
+    /// Process exit status for this category, following sysexits.h.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            FatalError::Config(_) => 78,       // EX_CONFIG
+            FatalError::Bind { .. } => 71,     // EX_OSERR
+            FatalError::Upstream { .. } => 69, // EX_UNAVAILABLE
+            FatalError::Runtime(_) => 70,      // EX_SOFTWARE
+        }
+    }
+
+    /// One-line JSON summary printed to stderr right before exiting.
+    pub fn summary_json(&self) -> String {
+        format!(
+            r#"{{"event":"exit","kind":"{}","exit_code":{},"message":"{}"}}"#,
+            self.kind(),
+            self.exit_code(),
+            json_escape(&self.to_string())
+        )
+    }
+}
+
+impl From<FatalError> for ExitCode {
+    fn from(e: FatalError) -> Self {
+        ExitCode::from(e.exit_code())
+    }
+}
+
+// Escapes a string for embedding in a JSON string literal
+pub fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
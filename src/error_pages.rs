@@ -0,0 +1,123 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+// The statuses the proxy answers requests with itself
+const STATUSES: &[u16] = &[400, 403, 407, 408, 414, 429, 431, 501, 502, 503, 504];
+
+/// Bodies for the error responses the proxy sends itself, from `--error-page STATUS=FILE`
+/// and `--error-message STATUS=TEXT`. `{status}` in a body is replaced with the status and
+/// reason phrase, `{reason}` with why the request failed, and `{request_id}` with the ID the
+/// request is logged under. A body starting with `<` is sent as HTML, with the values
+/// escaped; any other as plain text.
+pub struct ErrorPages {
+    pages: HashMap<u16, Page>,
+}
+
+struct Page {
+    template: String,
+    html: bool,
+}
+
+impl ErrorPages {
+    pub fn load(files: &[String], messages: &[String]) -> Result<Self, String> {
+        let mut pages = HashMap::new();
+        let entries = files
+            .iter()
+            .map(|entry| ("--error-page", entry))
+            .chain(messages.iter().map(|entry| ("--error-message", entry)));
+        for (flag, entry) in entries {
+            let (status, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("{flag} {entry}: expected STATUS=VALUE"))?;
+            let status = status
+                .trim()
+                .parse()
+                .ok()
+                .filter(|status| STATUSES.contains(status))
+                .ok_or_else(|| {
+                    format!("{flag} {entry}: the proxy doesn't send status `{status}` itself")
+                })?;
+            let template = if flag == "--error-page" {
+                std::fs::read_to_string(value).map_err(|e| format!("{flag} {value}: {e}"))?
+            } else {
+                value.to_string()
+            };
+            let html = template.trim_start().starts_with('<');
+            if pages.insert(status, Page { template, html }).is_some() {
+                return Err(format!(
+                    "{flag} {entry}: status {status} is already customized"
+                ));
+            }
+        }
+        Ok(Self { pages })
+    }
+
+    /// `response`, an error response as the proxy sends it without customization, with the
+    /// body set for its status in place of its own. Its other headers are kept.
+    pub fn render<'a>(&self, response: &'a [u8], reason: &str, request_id: &str) -> Cow<'a, [u8]> {
+        if self.pages.is_empty() {
+            return Cow::Borrowed(response);
+        }
+        let head = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .and_then(|end| std::str::from_utf8(&response[..end]).ok());
+        let Some(head) = head else {
+            return Cow::Borrowed(response);
+        };
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or_default();
+        let status = status_line.split_once(' ').map_or("", |(_, status)| status);
+        let page = status
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .and_then(|code: u16| self.pages.get(&code));
+        let Some(page) = page else {
+            return Cow::Borrowed(response);
+        };
+
+        let escape = |value: &str| {
+            if page.html {
+                escape_html(value)
+            } else {
+                value.to_string()
+            }
+        };
+        let body = page
+            .template
+            .replace("{status}", &escape(status))
+            .replace("{reason}", &escape(reason))
+            .replace("{request_id}", &escape(request_id));
+        let content_type = if page.html { "text/html" } else { "text/plain" };
+        let mut rendered = format!("{status_line}\r\n");
+        for line in lines.filter(|line| {
+            let name = line.split(':').next().unwrap_or_default();
+            !name.eq_ignore_ascii_case("content-length")
+                && !name.eq_ignore_ascii_case("content-type")
+        }) {
+            rendered.push_str(line);
+            rendered.push_str("\r\n");
+        }
+        rendered.push_str(&format!(
+            "Content-Type: {content_type}; charset=utf-8\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ));
+        Cow::Owned(rendered.into_bytes())
+    }
+}
+
+/// `value` with the characters that are special in HTML escaped.
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,92 @@
+use crate::dns::Resolver;
+use maxminddb::{geoip2, Reader};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Destination countries from a MaxMind GeoLite2/GeoIP2 Country or City database, for
+/// `country:` routing rules.
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+    // Countries by lowercase host, so a name is only resolved and looked up once per `ttl`
+    cache: Mutex<HashMap<String, (Option<String>, Instant)>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl GeoIp {
+    /// Opens the database at `path`, remembering the countries of up to `capacity` hosts for
+    /// `ttl` each.
+    pub fn open(path: &Path, capacity: usize, ttl: Duration) -> Result<Self, String> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| format!("--geoip-db {}: {e}", path.display()))?;
+        Ok(Self {
+            reader,
+            cache: Mutex::new(HashMap::new()),
+            capacity,
+            ttl,
+        })
+    }
+
+    /// The ISO 3166-1 country code of `host`, looked up from its first address as resolved by
+    /// `resolver`. `None` when the host doesn't resolve or the database doesn't know it.
+    pub async fn country(&self, resolver: &Resolver, host: &str) -> Option<String> {
+        let key = host.to_ascii_lowercase();
+        if let Some((country, expires)) = self.cache.lock().unwrap().get(&key) {
+            if *expires > Instant::now() {
+                return country.clone();
+            }
+        }
+
+        let addr = match resolver.lookup(host).await {
+            Ok(addrs) => *addrs.first()?,
+            Err(e) => {
+                debug!("No country for {}: {}", host, e);
+                return None;
+            }
+        };
+        let country = self.lookup(addr);
+        debug!(
+            "{} ({}) is in {}",
+            host,
+            addr,
+            country.as_deref().unwrap_or("no country")
+        );
+        self.store(key, country.clone());
+        country
+    }
+
+    fn lookup(&self, addr: IpAddr) -> Option<String> {
+        let record = self
+            .reader
+            .lookup(addr)
+            .ok()?
+            .decode::<geoip2::Country>()
+            .ok()??;
+        // Anycast and satellite networks only have the country they are registered in
+        record
+            .country
+            .iso_code
+            .or(record.registered_country.iso_code)
+            .map(str::to_string)
+    }
+
+    fn store(&self, key: String, country: Option<String>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        if cache.len() >= self.capacity && !cache.contains_key(&key) {
+            cache.retain(|_, (_, expires)| *expires > now);
+            // Still full of live entries: start over rather than track their age
+            if cache.len() >= self.capacity {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (country, now + self.ttl));
+    }
+}
@@ -0,0 +1,546 @@
+use crate::socks::UpstreamStream;
+use std::error::Error;
+use std::ffi::c_void;
+use std::io;
+use std::pin::Pin;
+use std::ptr;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// GSSAPI sub-negotiation messages (RFC 1961)
+const GSSAPI_VERSION: u8 = 0x01;
+const MTYP_AUTH: u8 = 0x01;
+const MTYP_PROTECTION: u8 = 0x02;
+const MTYP_ENCAPSULATION: u8 = 0x03;
+const MTYP_ABORT: u8 = 0xff;
+const LEVEL_INTEGRITY: u8 = 0x01;
+const LEVEL_CONFIDENTIALITY: u8 = 0x02;
+// Largest chunk of data wrapped into one message, leaving room for the token overhead within
+// the 16-bit length
+const MAX_CHUNK: usize = 32 * 1024;
+
+/// An established GSSAPI security context and the per-message protection agreed with the
+/// SOCKS server.
+pub struct Security {
+    context: SecurityContext,
+    confidential: bool,
+}
+
+/// Completes the GSSAPI sub-negotiation with the SOCKS server as `target`, a host-based
+/// service name such as `rcmd@socks.example.com`, using the default Kerberos credentials.
+/// Confidentiality is asked for, but the server may settle on integrity only.
+pub async fn authenticate<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
+    socks: &mut S,
+    target: &str,
+) -> Result<Security, Box<dyn Error>> {
+    let mut context = SecurityContext::new(target)?;
+    let mut input = Vec::new();
+    loop {
+        let (token, complete) = context.step(&input)?;
+        if !token.is_empty() {
+            write_message(socks, MTYP_AUTH, &token).await?;
+        }
+        if complete {
+            break;
+        }
+        input = read_message(socks, MTYP_AUTH).await?;
+    }
+
+    let level = context.wrap(&[LEVEL_CONFIDENTIALITY], false)?;
+    write_message(socks, MTYP_PROTECTION, &level).await?;
+    let level = context.unwrap(&read_message(socks, MTYP_PROTECTION).await?)?;
+    let confidential = match level.as_slice() {
+        [LEVEL_INTEGRITY] => false,
+        [LEVEL_CONFIDENTIALITY] => true,
+        _ => return Err("SOCKS5 server chose an unsupported GSSAPI protection level".into()),
+    };
+    Ok(Security {
+        context,
+        confidential,
+    })
+}
+
+async fn write_message<S: AsyncWrite + Unpin + ?Sized>(
+    socks: &mut S,
+    message_type: u8,
+    token: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let len = u16::try_from(token.len()).map_err(|_| "GSSAPI token too long")?;
+    let mut message = Vec::with_capacity(4 + token.len());
+    message.extend_from_slice(&[GSSAPI_VERSION, message_type]);
+    message.extend_from_slice(&len.to_be_bytes());
+    message.extend_from_slice(token);
+    socks.write_all(&message).await?;
+    socks.flush().await?;
+    Ok(())
+}
+
+async fn read_message<S: AsyncRead + Unpin + ?Sized>(
+    socks: &mut S,
+    message_type: u8,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut header = [0u8; 2];
+    socks.read_exact(&mut header).await?;
+    if header[1] == MTYP_ABORT {
+        return Err("SOCKS5 server rejected the GSSAPI authentication".into());
+    }
+    if header != [GSSAPI_VERSION, message_type] {
+        return Err(format!("unexpected GSSAPI message {:#04x}", header[1]).into());
+    }
+    let mut token = vec![0u8; socks.read_u16().await? as usize];
+    socks.read_exact(&mut token).await?;
+    Ok(token)
+}
+
+/// A connection to a SOCKS server whose data is encapsulated in GSSAPI messages, as required
+/// once GSSAPI authentication succeeds.
+pub struct Stream {
+    pub inner: UpstreamStream,
+    security: Security,
+    // The message being read, header included
+    incoming: Vec<u8>,
+    // Unwrapped data the reader has yet to take, from `plaintext_pos` on
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+    // The message being written, sent from `outgoing_pos` on
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+}
+
+impl Stream {
+    pub fn new(inner: UpstreamStream, security: Security) -> Self {
+        Self {
+            inner,
+            security,
+            incoming: Vec::new(),
+            plaintext: Vec::new(),
+            plaintext_pos: 0,
+            outgoing: Vec::new(),
+            outgoing_pos: 0,
+        }
+    }
+
+    /// Whether data already received is waiting to be read.
+    pub fn has_buffered(&self) -> bool {
+        !self.incoming.is_empty() || self.plaintext_pos < self.plaintext.len()
+    }
+
+    // The token length of the message being read, once its header is in
+    fn token_len(&self) -> Option<usize> {
+        (self.incoming.len() >= 4)
+            .then(|| u16::from_be_bytes([self.incoming[2], self.incoming[3]]) as usize)
+    }
+
+    // Writes out the rest of the message being written
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.outgoing_pos < self.outgoing.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.outgoing_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing_pos += n;
+        }
+        self.outgoing.clear();
+        self.outgoing_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.plaintext_pos < this.plaintext.len() {
+                let available = &this.plaintext[this.plaintext_pos..];
+                let n = available.len().min(buf.remaining());
+                buf.put_slice(&available[..n]);
+                this.plaintext_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(len) = this
+                .token_len()
+                .filter(|len| this.incoming.len() == 4 + len)
+            {
+                if this.incoming[..2] != [GSSAPI_VERSION, MTYP_ENCAPSULATION] {
+                    return Poll::Ready(Err(invalid("unexpected GSSAPI message")));
+                }
+                this.plaintext = this
+                    .security
+                    .context
+                    .unwrap(&this.incoming[4..4 + len])
+                    .map_err(|e| invalid(&e))?;
+                this.plaintext_pos = 0;
+                this.incoming.clear();
+                continue;
+            }
+
+            let wanted = this.token_len().map_or(4, |len| 4 + len) - this.incoming.len();
+            let mut chunk = [0u8; 8192];
+            let mut chunk = ReadBuf::new(&mut chunk[..wanted.min(8192)]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                if this.incoming.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed inside a GSSAPI message",
+                )));
+            }
+            this.incoming.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+
+        let data = &buf[..buf.len().min(MAX_CHUNK)];
+        let token = this
+            .security
+            .context
+            .wrap(data, this.security.confidential)
+            .map_err(|e| invalid(&e))?;
+        let len = u16::try_from(token.len()).map_err(|_| invalid("GSSAPI token too long"))?;
+        this.outgoing
+            .extend_from_slice(&[GSSAPI_VERSION, MTYP_ENCAPSULATION]);
+        this.outgoing.extend_from_slice(&len.to_be_bytes());
+        this.outgoing.extend_from_slice(&token);
+        // Whatever the socket doesn't take now goes out on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// The GSSAPI C bindings (RFC 2744), as provided by MIT Kerberos
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::ffi::{c_int, c_void};
+
+    pub type OM_uint32 = u32;
+    pub type gss_name_t = *mut c_void;
+    pub type gss_ctx_id_t = *mut c_void;
+    pub type gss_OID = *mut gss_OID_desc;
+
+    #[repr(C)]
+    pub struct gss_OID_desc {
+        pub length: OM_uint32,
+        pub elements: *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct gss_buffer_desc {
+        pub length: usize,
+        pub value: *mut c_void,
+    }
+
+    pub const GSS_S_COMPLETE: OM_uint32 = 0;
+    pub const GSS_C_GSS_CODE: c_int = 1;
+    pub const GSS_C_MECH_CODE: c_int = 2;
+    pub const GSS_C_MUTUAL_FLAG: OM_uint32 = 2;
+    pub const GSS_C_REPLAY_FLAG: OM_uint32 = 4;
+    pub const GSS_C_SEQUENCE_FLAG: OM_uint32 = 8;
+    pub const GSS_C_CONF_FLAG: OM_uint32 = 16;
+    pub const GSS_C_INTEG_FLAG: OM_uint32 = 32;
+
+    // Calling and routine errors; the low bits carry informational status
+    pub fn is_error(major: OM_uint32) -> bool {
+        major & 0xffff_0000 != 0
+    }
+
+    #[link(name = "gssapi_krb5")]
+    extern "C" {
+        pub static GSS_C_NT_HOSTBASED_SERVICE: gss_OID;
+
+        pub fn gss_import_name(
+            minor_status: *mut OM_uint32,
+            input_name_buffer: *const gss_buffer_desc,
+            input_name_type: gss_OID,
+            output_name: *mut gss_name_t,
+        ) -> OM_uint32;
+
+        pub fn gss_release_name(minor_status: *mut OM_uint32, name: *mut gss_name_t) -> OM_uint32;
+
+        pub fn gss_init_sec_context(
+            minor_status: *mut OM_uint32,
+            initiator_cred_handle: *mut c_void,
+            context_handle: *mut gss_ctx_id_t,
+            target_name: gss_name_t,
+            mech_type: gss_OID,
+            req_flags: OM_uint32,
+            time_req: OM_uint32,
+            input_chan_bindings: *mut c_void,
+            input_token: *const gss_buffer_desc,
+            actual_mech_type: *mut gss_OID,
+            output_token: *mut gss_buffer_desc,
+            ret_flags: *mut OM_uint32,
+            time_rec: *mut OM_uint32,
+        ) -> OM_uint32;
+
+        pub fn gss_delete_sec_context(
+            minor_status: *mut OM_uint32,
+            context_handle: *mut gss_ctx_id_t,
+            output_token: *mut gss_buffer_desc,
+        ) -> OM_uint32;
+
+        pub fn gss_wrap(
+            minor_status: *mut OM_uint32,
+            context_handle: gss_ctx_id_t,
+            conf_req_flag: c_int,
+            qop_req: OM_uint32,
+            input_message_buffer: *const gss_buffer_desc,
+            conf_state: *mut c_int,
+            output_message_buffer: *mut gss_buffer_desc,
+        ) -> OM_uint32;
+
+        pub fn gss_unwrap(
+            minor_status: *mut OM_uint32,
+            context_handle: gss_ctx_id_t,
+            input_message_buffer: *const gss_buffer_desc,
+            output_message_buffer: *mut gss_buffer_desc,
+            conf_state: *mut c_int,
+            qop_state: *mut OM_uint32,
+        ) -> OM_uint32;
+
+        pub fn gss_release_buffer(
+            minor_status: *mut OM_uint32,
+            buffer: *mut gss_buffer_desc,
+        ) -> OM_uint32;
+
+        pub fn gss_display_status(
+            minor_status: *mut OM_uint32,
+            status_value: OM_uint32,
+            status_type: c_int,
+            mech_type: gss_OID,
+            message_context: *mut OM_uint32,
+            status_string: *mut gss_buffer_desc,
+        ) -> OM_uint32;
+    }
+}
+
+// A client security context for one target
+struct SecurityContext {
+    handle: ffi::gss_ctx_id_t,
+    target: ffi::gss_name_t,
+}
+
+// The context is used by one connection at a time
+unsafe impl Send for SecurityContext {}
+unsafe impl Sync for SecurityContext {}
+
+impl SecurityContext {
+    fn new(target: &str) -> Result<Self, String> {
+        let mut minor = 0;
+        let mut name = ptr::null_mut();
+        let buffer = borrowed(target.as_bytes());
+        // SAFETY: the buffer outlives the call, which copies it into the new name
+        let major = unsafe {
+            ffi::gss_import_name(
+                &mut minor,
+                &buffer,
+                ffi::GSS_C_NT_HOSTBASED_SERVICE,
+                &mut name,
+            )
+        };
+        check(
+            major,
+            minor,
+            &format!("invalid GSSAPI service name {target}"),
+        )?;
+        Ok(Self {
+            handle: ptr::null_mut(),
+            target: name,
+        })
+    }
+
+    // Feeds the server's token, empty at first, to the context. Returns the token to send
+    // and whether the context is established.
+    fn step(&mut self, input: &[u8]) -> Result<(Vec<u8>, bool), String> {
+        let mut minor = 0;
+        let input = borrowed(input);
+        let mut output = empty();
+        let flags = ffi::GSS_C_MUTUAL_FLAG
+            | ffi::GSS_C_REPLAY_FLAG
+            | ffi::GSS_C_SEQUENCE_FLAG
+            | ffi::GSS_C_CONF_FLAG
+            | ffi::GSS_C_INTEG_FLAG;
+        // SAFETY: the handles are owned by self, and the input buffer outlives the call
+        let major = unsafe {
+            ffi::gss_init_sec_context(
+                &mut minor,
+                ptr::null_mut(),
+                &mut self.handle,
+                self.target,
+                ptr::null_mut(),
+                flags,
+                0,
+                ptr::null_mut(),
+                if input.length == 0 {
+                    ptr::null()
+                } else {
+                    &input
+                },
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let token = take(&mut output);
+        check(major, minor, "GSSAPI authentication failed")?;
+        Ok((token, major == ffi::GSS_S_COMPLETE))
+    }
+
+    fn wrap(&self, data: &[u8], confidential: bool) -> Result<Vec<u8>, String> {
+        let mut minor = 0;
+        let input = borrowed(data);
+        let mut output = empty();
+        // SAFETY: the context is established, and the input buffer outlives the call
+        let major = unsafe {
+            ffi::gss_wrap(
+                &mut minor,
+                self.handle,
+                confidential.into(),
+                0,
+                &input,
+                ptr::null_mut(),
+                &mut output,
+            )
+        };
+        let token = take(&mut output);
+        check(major, minor, "GSSAPI wrap failed")?;
+        Ok(token)
+    }
+
+    fn unwrap(&self, token: &[u8]) -> Result<Vec<u8>, String> {
+        let mut minor = 0;
+        let input = borrowed(token);
+        let mut output = empty();
+        // SAFETY: the context is established, and the input buffer outlives the call
+        let major = unsafe {
+            ffi::gss_unwrap(
+                &mut minor,
+                self.handle,
+                &input,
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let data = take(&mut output);
+        check(major, minor, "GSSAPI unwrap failed")?;
+        Ok(data)
+    }
+}
+
+impl Drop for SecurityContext {
+    fn drop(&mut self) {
+        let mut minor = 0;
+        // SAFETY: both handles are owned by self and released once
+        unsafe {
+            if !self.handle.is_null() {
+                ffi::gss_delete_sec_context(&mut minor, &mut self.handle, ptr::null_mut());
+            }
+            ffi::gss_release_name(&mut minor, &mut self.target);
+        }
+    }
+}
+
+// A GSSAPI view of `bytes`, which the library only reads
+fn borrowed(bytes: &[u8]) -> ffi::gss_buffer_desc {
+    ffi::gss_buffer_desc {
+        length: bytes.len(),
+        value: bytes.as_ptr() as *mut c_void,
+    }
+}
+
+// A buffer for the library to fill in
+fn empty() -> ffi::gss_buffer_desc {
+    ffi::gss_buffer_desc {
+        length: 0,
+        value: ptr::null_mut(),
+    }
+}
+
+// Copies out a buffer the library allocated and releases it
+fn take(buffer: &mut ffi::gss_buffer_desc) -> Vec<u8> {
+    if buffer.value.is_null() {
+        return Vec::new();
+    }
+    // SAFETY: the library filled in `length` bytes at `value`
+    let bytes = unsafe { std::slice::from_raw_parts(buffer.value as *const u8, buffer.length) };
+    let bytes = bytes.to_vec();
+    let mut minor = 0;
+    // SAFETY: the buffer was allocated by the library and is released once
+    unsafe { ffi::gss_release_buffer(&mut minor, buffer) };
+    bytes
+}
+
+// Turns a failed status into `context` followed by the library's own messages
+fn check(major: ffi::OM_uint32, minor: ffi::OM_uint32, context: &str) -> Result<(), String> {
+    if !ffi::is_error(major) {
+        return Ok(());
+    }
+    let mut message = context.to_string();
+    for (status, kind) in [(major, ffi::GSS_C_GSS_CODE), (minor, ffi::GSS_C_MECH_CODE)] {
+        if status == 0 {
+            continue;
+        }
+        let mut message_context = 0;
+        loop {
+            let mut ignored = 0;
+            let mut text = empty();
+            // SAFETY: the library allocates `text`, which `take` releases
+            let result = unsafe {
+                ffi::gss_display_status(
+                    &mut ignored,
+                    status,
+                    kind,
+                    ptr::null_mut(),
+                    &mut message_context,
+                    &mut text,
+                )
+            };
+            if ffi::is_error(result) {
+                break;
+            }
+            message.push_str(": ");
+            message.push_str(String::from_utf8_lossy(&take(&mut text)).trim_end());
+            if message_context == 0 {
+                break;
+            }
+        }
+    }
+    Err(message)
+}
@@ -0,0 +1,97 @@
+use std::fmt::Display;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::debug;
+
+// Time to wait for an attempt before starting the next one alongside it (RFC 8305 §5)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Races `connect` to each of `addrs` as in Happy Eyeballs (RFC 8305): the addresses are
+/// tried alternating between IPv6 and IPv4, starting with the family the resolver put first,
+/// and each attempt starts once the previous one has failed or been running for 250ms. The
+/// first connection wins and the attempts still running are dropped; if all fail, the error
+/// of the last to fail is returned.
+pub async fn connect<T, E, F, Fut>(addrs: Vec<IpAddr>, connect: F) -> Result<T, E>
+where
+    E: From<io::Error> + Display,
+    F: Fn(IpAddr) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut remaining = interleave(addrs).into_iter();
+    let mut attempts: Vec<(IpAddr, Pin<Box<Fut>>)> = Vec::new();
+    let mut next_attempt = Box::pin(tokio::time::sleep(CONNECTION_ATTEMPT_DELAY));
+    let mut start_now = true;
+
+    poll_fn(|cx| loop {
+        let mut started = false;
+        if std::mem::take(&mut start_now) || next_attempt.as_mut().poll(cx).is_ready() {
+            if let Some(ip) = remaining.next() {
+                attempts.push((ip, Box::pin(connect(ip))));
+                next_attempt
+                    .as_mut()
+                    .reset(Instant::now() + CONNECTION_ATTEMPT_DELAY);
+                started = true;
+            }
+        }
+
+        let mut failed = false;
+        let mut i = 0;
+        while i < attempts.len() {
+            match attempts[i].1.as_mut().poll(cx) {
+                Poll::Ready(Ok(connection)) => return Poll::Ready(Ok(connection)),
+                Poll::Ready(Err(e)) => {
+                    let (ip, _) = attempts.swap_remove(i);
+                    // Errors are not kept, so that the race needn't hold one across polls
+                    if attempts.is_empty() && remaining.len() == 0 {
+                        return Poll::Ready(Err(e));
+                    }
+                    debug!("Connecting to {} failed: {}", ip, e);
+                    failed = true;
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        if attempts.is_empty() && remaining.len() == 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no addresses to connect to",
+            )
+            .into()));
+        }
+        // A failed attempt hands over to the next address at once
+        if failed && remaining.len() > 0 {
+            start_now = true;
+            continue;
+        }
+        // A new attempt rearmed the timer, which has to be polled again to wake this task
+        if !started {
+            return Poll::Pending;
+        }
+    })
+    .await
+}
+
+// `addrs` reordered to alternate between address families, keeping the resolver's order
+// within each and starting with the family of its first address
+fn interleave(addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|ip| ip.is_ipv6() == first_v6);
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    while let Some(ip) = preferred.pop() {
+        interleaved.push(ip);
+        interleaved.extend(other.pop());
+    }
+    interleaved.extend(other.into_iter().rev());
+    interleaved
+}
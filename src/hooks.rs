@@ -0,0 +1,38 @@
+use crate::access_log::Record;
+use std::net::SocketAddr;
+
+/// Callbacks into the life of each connection, for embedders that need their own logging,
+/// quotas or policy on top of the built-in checks. Set them with
+/// [`ProxyBuilder::hooks`](crate::ProxyBuilder::hooks); they are kept across reloads.
+///
+/// Every method has a default that does nothing, so only the events of interest need
+/// implementing. They are called on the connection's task, so they should return quickly;
+/// anything slow belongs on a task of its own.
+pub trait Hooks: Send + Sync + 'static {
+    /// A client connected and passed `--allow`/`--deny`. Returning an error turns it away
+    /// like a denied client, with the error logged as the reason.
+    fn on_accept(&self, client: SocketAddr) -> Result<(), String> {
+        let _ = client;
+        Ok(())
+    }
+
+    /// A request passed the destination checks and is about to be connected: a CONNECT,
+    /// plain HTTP request, SOCKS request or forwarded connection, with its client, user,
+    /// method and target filled in. Returning an error refuses it like a blocked destination
+    /// (`403`, or the SOCKS equivalent).
+    fn on_request(&self, record: &Record) -> Result<(), String> {
+        let _ = record;
+        Ok(())
+    }
+
+    /// The connection to the destination is open; `record.upstream` names the route.
+    fn on_connect_established(&self, record: &Record) {
+        let _ = record;
+    }
+
+    /// The request or connection ended, with the bytes relayed each way and how it ended,
+    /// exactly as it is written to the access log.
+    fn on_close(&self, record: &Record) {
+        let _ = record;
+    }
+}
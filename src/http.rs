@@ -0,0 +1,1194 @@
+use std::fmt::Write;
+use std::io;
+use std::net::Ipv6Addr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Most header lines a request head may carry, whatever `--max-headers` says.
+pub const MAX_HEADERS: usize = 128;
+
+// Headers that describe a single connection rather than the message
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "upgrade",
+    "proxy-authorization",
+    "proxy-authenticate",
+];
+
+// Longest chunk size or trailer line accepted in a chunked body
+const MAX_CHUNK_LINE: usize = 8192;
+
+// How long a head may take before its receive rate is held to HeadPace::min_rate, so a
+// head split across a few slow packets isn't cut off
+const HEAD_RATE_GRACE: Duration = Duration::from_secs(2);
+// How often the pace of a head is re-checked while no bytes arrive
+const HEAD_RATE_CHECK: Duration = Duration::from_secs(1);
+
+/// A parsed HTTP/1.x request head.
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: String,
+    /// The request-target exactly as sent: origin-, absolute- or authority-form
+    pub target: String,
+    /// Minor version of HTTP/1.x
+    pub version: u8,
+    pub headers: Vec<Header>,
+    /// Length of the head in bytes, including the terminating empty line
+    pub len: usize,
+}
+
+/// One header field with the name's original casing preserved.
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl RequestHead {
+    /// Parses a request head from the start of `buf`. Returns `Ok(None)` if more bytes are needed.
+    /// Obsolete line folding is rejected, as RFC 9112 allows a server to do.
+    pub fn parse(buf: &[u8]) -> Result<Option<Self>, httparse::Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let len = match request.parse(buf)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            // A complete parse always fills in the request line
+            method: request.method.unwrap_or_default().to_string(),
+            target: request.path.unwrap_or_default().to_string(),
+            version: request.version.unwrap_or(1),
+            headers: request
+                .headers
+                .iter()
+                .map(|h| Header {
+                    name: h.name.to_string(),
+                    value: h.value.to_vec(),
+                })
+                .collect(),
+            len,
+        }))
+    }
+
+    /// The value of the first header with this name, if present and valid UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn is_connect(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+    }
+
+    /// The request-target in origin-form: the path and query of an absolute URI, or the
+    /// target as sent.
+    pub fn path(&self) -> String {
+        match absolute_uri_authority(&self.target) {
+            Some(_) => origin_form(&self.target),
+            None => self.target.clone(),
+        }
+    }
+
+    /// Whether the client waits for `100 Continue` before sending the body.
+    pub fn expects_continue(&self) -> bool {
+        self.header("expect")
+            .is_some_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Whether the request-target is an absolute URI, as sent to proxies.
+    pub fn is_absolute_form(&self) -> bool {
+        absolute_uri_authority(&self.target).is_some()
+    }
+
+    /// Whether the request-target is an `ftp://` URI, for the FTP gateway.
+    pub fn is_ftp(&self) -> bool {
+        self.is_absolute_form()
+            && self
+                .target
+                .split_once("://")
+                .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("ftp"))
+    }
+
+    /// The host and port the request is for: the CONNECT authority, the absolute URI's
+    /// authority (which takes precedence over Host), or the Host header.
+    pub fn destination(&self) -> Option<(String, u16)> {
+        if self.is_connect() {
+            // CONNECT requires authority-form with an explicit port
+            let (host, port) = split_host_port(&self.target, 0)?;
+            return (port != 0).then_some((host, port));
+        }
+        match absolute_uri_authority(&self.target) {
+            Some(authority) => split_host_port(authority, default_port(&self.target)),
+            None => split_host_port(self.header("host")?, 80),
+        }
+    }
+
+    /// A copy of this plain HTTP request aimed at `host:port` instead: the Host header and
+    /// the authority of an absolute-form target name the new destination.
+    pub fn redirected(&self, host: &str, port: u16) -> Self {
+        let host = if host.contains(':') {
+            format!("[{host}]")
+        } else {
+            host.to_string()
+        };
+        let authority = if port == default_port(&self.target) {
+            host
+        } else {
+            format!("{host}:{port}")
+        };
+        let target = match self.target.split_once("://") {
+            Some((scheme, _)) if self.is_absolute_form() => {
+                format!("{scheme}://{authority}{}", origin_form(&self.target))
+            }
+            _ => self.target.clone(),
+        };
+        let mut headers: Vec<Header> = self
+            .headers
+            .iter()
+            .filter(|header| !header.name.eq_ignore_ascii_case("host"))
+            .cloned()
+            .collect();
+        headers.insert(
+            0,
+            Header {
+                name: "Host".to_string(),
+                value: authority.into_bytes(),
+            },
+        );
+        Self {
+            method: self.method.clone(),
+            target,
+            version: self.version,
+            headers,
+            len: self.len,
+        }
+    }
+
+    /// How the request body is delimited, or `None` if the framing headers are invalid.
+    pub fn body_length(&self) -> Option<BodyLength> {
+        if self.header("transfer-encoding").is_some() {
+            // A request body must end with chunked coding, or its length is unknowable
+            return is_chunked(&self.headers).then_some(BodyLength::Chunked);
+        }
+        match content_length(&self.headers)? {
+            Some(0) | None => Some(BodyLength::Empty),
+            Some(n) => Some(BodyLength::Length(n)),
+        }
+    }
+
+    /// Whether this is a protocol upgrade handshake such as a WebSocket opening request.
+    pub fn is_upgrade(&self) -> bool {
+        self.header("upgrade").is_some() && has_token(&self.headers, "connection", "upgrade")
+    }
+
+    /// Names of the hop-by-hop headers that must not be forwarded (RFC 9110 section 7.6.1):
+    /// the well-known ones plus any listed in Connection. Framing headers are kept because
+    /// the body is passed on in its original encoding, except for a Content-Length sent
+    /// alongside Transfer-Encoding: the body is framed by the latter, and an origin trusting
+    /// the former could be smuggled a second request (RFC 9112 section 6.3).
+    pub fn hop_by_hop(&self) -> Vec<String> {
+        let mut names: Vec<String> = HOP_BY_HOP.iter().map(|name| name.to_string()).collect();
+        let listed = self
+            .headers
+            .iter()
+            .filter(|h| {
+                h.name.eq_ignore_ascii_case("connection")
+                    || h.name.eq_ignore_ascii_case("proxy-connection")
+            })
+            .filter_map(|h| std::str::from_utf8(&h.value).ok())
+            .flat_map(|value| value.split(','))
+            .map(|token| token.trim().to_ascii_lowercase())
+            .filter(|token| {
+                !token.is_empty()
+                    && !matches!(
+                        token.as_str(),
+                        "transfer-encoding" | "content-length" | "host"
+                    )
+            });
+        names.extend(listed);
+        if self.header("transfer-encoding").is_some() {
+            names.push("content-length".to_string());
+        }
+        names
+    }
+
+    /// Whether the client wants the connection kept open after this exchange.
+    pub fn keep_alive(&self) -> bool {
+        // Clients talking to proxies often send the non-standard Proxy-Connection instead
+        let wants = |token| {
+            has_token(&self.headers, "connection", token)
+                || has_token(&self.headers, "proxy-connection", token)
+        };
+        if self.version == 0 {
+            wants("keep-alive")
+        } else {
+            !wants("close")
+        }
+    }
+
+    /// Serializes the head for the origin server: absolute-form targets are turned into
+    /// origin-form with a matching Host header, headers named in `strip` are dropped and
+    /// `add` is appended after the remaining headers.
+    pub fn encode_for_origin(&self, strip: &[&str], add: &[(&str, String)]) -> Vec<u8> {
+        let authority = absolute_uri_authority(&self.target);
+        let target = match authority {
+            Some(_) => origin_form(&self.target),
+            None => self.target.clone(),
+        };
+
+        let mut head = format!("{} {} HTTP/1.{}\r\n", self.method, target, self.version);
+        if let Some(authority) = authority {
+            // The URI's authority replaces whatever Host the client sent
+            let _ = write!(head, "Host: {authority}\r\n");
+        }
+
+        let mut head = head.into_bytes();
+        for header in &self.headers {
+            let skip = strip
+                .iter()
+                .any(|name| header.name.eq_ignore_ascii_case(name))
+                || (authority.is_some() && header.name.eq_ignore_ascii_case("host"));
+            if !skip {
+                head.extend_from_slice(header.name.as_bytes());
+                head.extend_from_slice(b": ");
+                head.extend_from_slice(&header.value);
+                head.extend_from_slice(b"\r\n");
+            }
+        }
+        for (name, value) in add {
+            head.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    /// Serializes the head for an HTTP proxy on the way to `host:port`: like
+    /// [`encode_for_origin`](Self::encode_for_origin), but with the target in absolute form.
+    pub fn encode_for_proxy(
+        &self,
+        host: &str,
+        port: u16,
+        strip: &[&str],
+        add: &[(&str, String)],
+    ) -> Vec<u8> {
+        let mut head = self.encode_for_origin(strip, add);
+        let line_end = head
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .unwrap_or_default();
+        let path = match absolute_uri_authority(&self.target) {
+            Some(_) => origin_form(&self.target),
+            None => self.target.clone(),
+        };
+        let host = if host.contains(':') {
+            format!("[{host}]")
+        } else {
+            host.to_string()
+        };
+        let authority = if port == 80 {
+            host
+        } else {
+            format!("{host}:{port}")
+        };
+        let line = format!(
+            "{} http://{authority}{path} HTTP/1.{}",
+            self.method, self.version
+        );
+        head.splice(..line_end, line.into_bytes());
+        head
+    }
+
+    /// Checks the head, whose raw bytes are `raw`, against `limits`, refusing it if the
+    /// request line or a header line is too long, it has too many headers, or, in strict mode,
+    /// it is ambiguous in a way that best-effort parsing would paper over.
+    pub fn check(&self, raw: &[u8], limits: &RequestLimits) -> Result<(), Refusal> {
+        let refuse = |status, reason: String| Err(Refusal { status, reason });
+
+        // Empty lines before the request line are ignored, as RFC 9112 allows
+        let raw = &raw[raw
+            .iter()
+            .take_while(|&&b| b == b'\r' || b == b'\n')
+            .count()..];
+        let mut lines = raw
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        let request_line = lines.next().unwrap_or_default().len();
+        if request_line > limits.max_request_line {
+            return refuse(
+                "414 URI Too Long",
+                format!(
+                    "request line of {request_line} bytes exceeds {}",
+                    limits.max_request_line
+                ),
+            );
+        }
+        if self.headers.len() > limits.max_headers {
+            return refuse(
+                "431 Request Header Fields Too Large",
+                format!(
+                    "{} headers exceed {}",
+                    self.headers.len(),
+                    limits.max_headers
+                ),
+            );
+        }
+        if let Some(line) = lines
+            .map(<[u8]>::len)
+            .find(|&len| len > limits.max_header_line)
+        {
+            return refuse(
+                "431 Request Header Fields Too Large",
+                format!(
+                    "header line of {line} bytes exceeds {}",
+                    limits.max_header_line
+                ),
+            );
+        }
+        if !limits.strict {
+            return Ok(());
+        }
+
+        for (i, &b) in raw.iter().enumerate() {
+            if b == b'\n' && raw.get(i.wrapping_sub(1)) != Some(&b'\r') {
+                return refuse("400 Bad Request", "line ending in a bare LF".to_string());
+            }
+            if b == b'\r' && raw.get(i + 1) != Some(&b'\n') {
+                return refuse("400 Bad Request", "bare CR".to_string());
+            }
+        }
+        let hosts: Vec<&Header> = self
+            .headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("host"))
+            .collect();
+        match hosts.as_slice() {
+            [] if self.version >= 1 => {
+                return refuse(
+                    "400 Bad Request",
+                    "HTTP/1.1 request without Host".to_string(),
+                )
+            }
+            [_, _, ..] => return refuse("400 Bad Request", "multiple Host headers".to_string()),
+            _ => {}
+        }
+        // The target's authority wins over Host, so a disagreeing Host is dropped silently
+        // by one hop and honoured by another
+        if let (Some(host), true) = (
+            self.header("host"),
+            self.is_connect() || self.is_absolute_form(),
+        ) {
+            let target = self.destination();
+            let agrees = target.as_ref().is_some_and(|(target_host, port)| {
+                split_host_port(host, *port)
+                    .is_some_and(|(h, p)| h.eq_ignore_ascii_case(target_host) && p == *port)
+            });
+            if !agrees {
+                return refuse(
+                    "400 Bad Request",
+                    format!(
+                        "Host '{host}' disagrees with request target {}",
+                        self.target
+                    ),
+                );
+            }
+        }
+        let lengths = self
+            .headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("content-length"))
+            .count();
+        if lengths > 0 && self.header("transfer-encoding").is_some() {
+            return refuse(
+                "400 Bad Request",
+                "both Content-Length and Transfer-Encoding".to_string(),
+            );
+        }
+        if lengths > 1
+            || self
+                .header("content-length")
+                .is_some_and(|v| v.contains(','))
+        {
+            return refuse("400 Bad Request", "repeated Content-Length".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Limits a request head must keep to beyond its total size, from `--max-request-line`,
+/// `--max-headers` and `--max-header-line`, and whether `--strict` refuses ambiguous requests.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    pub max_request_line: usize,
+    pub max_headers: usize,
+    pub max_header_line: usize,
+    pub strict: bool,
+}
+
+/// Why [`RequestHead::check`] refused a request: the status to answer with and what to log.
+#[derive(Debug)]
+pub struct Refusal {
+    /// Status code and reason phrase, e.g. `400 Bad Request`
+    pub status: &'static str,
+    pub reason: String,
+}
+
+/// A parsed HTTP/1.x response head.
+#[derive(Debug)]
+pub struct ResponseHead {
+    /// Minor version of HTTP/1.x
+    pub version: u8,
+    pub status: u16,
+    pub headers: Vec<Header>,
+    /// Length of the head in bytes, including the terminating empty line
+    pub len: usize,
+}
+
+impl ResponseHead {
+    /// Parses a response head from the start of `buf`. Returns `Ok(None)` if more bytes are needed.
+    pub fn parse(buf: &[u8]) -> Result<Option<Self>, httparse::Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        let len = match response.parse(buf)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            version: response.version.unwrap_or(1),
+            status: response.code.unwrap_or_default(),
+            headers: response
+                .headers
+                .iter()
+                .map(|h| Header {
+                    name: h.name.to_string(),
+                    value: h.value.to_vec(),
+                })
+                .collect(),
+            len,
+        }))
+    }
+
+    /// The value of the first header with this name, if present and valid UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Whether this is an interim (1xx) response that will be followed by another head.
+    /// 101 Switching Protocols is final: the connection stops speaking HTTP after it.
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+
+    /// How the response body is delimited, following RFC 9112 section 6.3.
+    /// Returns `None` if the framing headers are invalid.
+    pub fn body_length(&self, request_method: &str) -> Option<BodyLength> {
+        if request_method.eq_ignore_ascii_case("HEAD")
+            || (100..200).contains(&self.status)
+            || self.status == 204
+            || self.status == 304
+        {
+            return Some(BodyLength::Empty);
+        }
+        if self.header("transfer-encoding").is_some() {
+            return Some(if is_chunked(&self.headers) {
+                BodyLength::Chunked
+            } else {
+                BodyLength::UntilClose
+            });
+        }
+        match content_length(&self.headers)? {
+            Some(0) => Some(BodyLength::Empty),
+            Some(n) => Some(BodyLength::Length(n)),
+            None => Some(BodyLength::UntilClose),
+        }
+    }
+
+    /// Whether the origin is willing to serve another request on this connection.
+    pub fn keep_alive(&self) -> bool {
+        if self.version == 0 {
+            has_token(&self.headers, "connection", "keep-alive")
+        } else {
+            !has_token(&self.headers, "connection", "close")
+        }
+    }
+}
+
+/// How the end of a message body is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// The body runs until the sender closes the connection
+    UntilClose,
+}
+
+fn find_header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(&h.value).ok())
+        .map(str::trim)
+}
+
+// Whether any header with this name lists the token in its comma-separated value
+fn has_token(headers: &[Header], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name))
+        .filter_map(|h| std::str::from_utf8(&h.value).ok())
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+// Chunked must be the final transfer coding applied
+fn is_chunked(headers: &[Header]) -> bool {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("transfer-encoding"))
+        .filter_map(|h| std::str::from_utf8(&h.value).ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+// The Content-Length, if any. Repeated headers must agree; `None` means the value is invalid.
+fn content_length(headers: &[Header]) -> Option<Option<u64>> {
+    let mut length = None;
+    for value in headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("content-length"))
+        .map(|h| std::str::from_utf8(&h.value).ok())
+    {
+        for item in value?.split(',') {
+            let item = item.trim();
+            if item.is_empty() || !item.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let n = item.parse::<u64>().ok()?;
+            if length.is_some_and(|length| length != n) {
+                return None;
+            }
+            length = Some(n);
+        }
+    }
+    Some(length)
+}
+
+/// How quickly a message head must arrive once its first byte has, so that a client
+/// dribbling bytes can't hold a connection open indefinitely.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeadPace {
+    /// Time allowed for the whole head, counted from its first byte
+    pub deadline: Option<Duration>,
+    /// Fewest bytes per second the head must average once it has taken longer than a
+    /// couple of seconds (0 disables)
+    pub min_rate: u64,
+}
+
+/// Why a message head could not be read.
+#[derive(Debug, thiserror::Error)]
+pub enum HeadError {
+    #[error("connection closed in the middle of a message head")]
+    Truncated,
+    #[error("message head exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("malformed message head: {0}")]
+    Malformed(httparse::Error),
+    #[error("message head arriving too slowly: {0}")]
+    TooSlow(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A stream together with bytes already read from it but not yet consumed, so that
+/// data arriving after a message head or body is kept for the next message.
+pub struct BufferedStream<S> {
+    pub inner: S,
+    pub buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> BufferedStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Appends whatever the stream has available to the buffer; returns 0 at EOF.
+    pub async fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; 8192];
+        let n = self.inner.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// Reads until `parse` finds a complete head of at most `max_size` bytes. Returns
+    /// `Ok(None)` if the stream ends cleanly before the first byte. The head's bytes stay
+    /// in the buffer; remove them with [`consume`](Self::consume).
+    pub async fn read_head<H>(
+        &mut self,
+        max_size: usize,
+        parse: impl Fn(&[u8]) -> Result<Option<H>, httparse::Error>,
+        head_len: impl Fn(&H) -> usize,
+    ) -> Result<Option<H>, HeadError> {
+        self.read_head_paced(max_size, parse, head_len, HeadPace::default())
+            .await
+    }
+
+    /// Like [`read_head`](Self::read_head), but once the head has started arriving it must
+    /// keep to `pace`, or [`HeadError::TooSlow`] is returned.
+    pub async fn read_head_paced<H>(
+        &mut self,
+        max_size: usize,
+        parse: impl Fn(&[u8]) -> Result<Option<H>, httparse::Error>,
+        head_len: impl Fn(&H) -> usize,
+        pace: HeadPace,
+    ) -> Result<Option<H>, HeadError> {
+        let mut started = None;
+        loop {
+            if !self.buf.is_empty() {
+                match parse(&self.buf) {
+                    Ok(Some(head)) if head_len(&head) <= max_size => return Ok(Some(head)),
+                    Ok(None) if self.buf.len() <= max_size => {}
+                    Ok(_) | Err(httparse::Error::TooManyHeaders) => {
+                        return Err(HeadError::TooLarge(max_size))
+                    }
+                    Err(e) => return Err(HeadError::Malformed(e)),
+                }
+            }
+            let filled = match started {
+                // Waiting for the first byte is the caller's idle timeout to bound
+                None if self.buf.is_empty() => self.fill().await?,
+                None => {
+                    started = Some(Instant::now());
+                    continue;
+                }
+                Some(started) => {
+                    let elapsed = started.elapsed();
+                    let mut wait = HEAD_RATE_CHECK;
+                    if let Some(deadline) = pace.deadline {
+                        if elapsed >= deadline {
+                            return Err(HeadError::TooSlow(format!(
+                                "{} bytes in {}s without completing the head",
+                                self.buf.len(),
+                                deadline.as_secs()
+                            )));
+                        }
+                        wait = wait.min(deadline - elapsed);
+                    }
+                    if pace.min_rate > 0
+                        && elapsed >= HEAD_RATE_GRACE
+                        && (self.buf.len() as f64 / elapsed.as_secs_f64()) < pace.min_rate as f64
+                    {
+                        return Err(HeadError::TooSlow(format!(
+                            "{} bytes in {:.1}s, below {} bytes/s",
+                            self.buf.len(),
+                            elapsed.as_secs_f64(),
+                            pace.min_rate
+                        )));
+                    }
+                    // Wake up regularly to re-check while nothing arrives
+                    match tokio::time::timeout(wait, self.fill()).await {
+                        Ok(filled) => filled?,
+                        Err(_) => continue,
+                    }
+                }
+            };
+            if filled == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(HeadError::Truncated)
+                };
+            }
+        }
+    }
+
+    /// Removes and returns the first `n` buffered bytes.
+    pub fn consume(&mut self, n: usize) -> Vec<u8> {
+        self.buf.drain(..n).collect()
+    }
+
+    /// Forwards one message body to `dst`, returning the number of bytes written.
+    /// Chunked bodies are passed on verbatim, chunk framing and trailers included.
+    pub async fn copy_body<W: AsyncWrite + Unpin + ?Sized>(
+        &mut self,
+        dst: &mut W,
+        length: BodyLength,
+    ) -> io::Result<u64> {
+        match length {
+            BodyLength::Empty => Ok(0),
+            BodyLength::Length(n) => self.copy_exact(dst, n).await,
+            BodyLength::Chunked => self.copy_chunked(dst).await,
+            BodyLength::UntilClose => self.copy_to_end(dst).await,
+        }
+    }
+
+    async fn copy_exact<W: AsyncWrite + Unpin + ?Sized>(
+        &mut self,
+        dst: &mut W,
+        mut remaining: u64,
+    ) -> io::Result<u64> {
+        let total = remaining;
+        while remaining > 0 {
+            if self.buf.is_empty() && self.fill().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let n = self
+                .buf
+                .len()
+                .min(remaining.try_into().unwrap_or(usize::MAX));
+            dst.write_all(&self.buf[..n]).await?;
+            self.buf.drain(..n);
+            remaining -= n as u64;
+        }
+        Ok(total)
+    }
+
+    async fn copy_to_end<W: AsyncWrite + Unpin + ?Sized>(
+        &mut self,
+        dst: &mut W,
+    ) -> io::Result<u64> {
+        let mut copied = 0;
+        while !self.buf.is_empty() || self.fill().await? > 0 {
+            dst.write_all(&self.buf).await?;
+            copied += self.buf.len() as u64;
+            self.buf.clear();
+        }
+        Ok(copied)
+    }
+
+    // Follows the chunk sizes to find the end of the body, then passes on the trailer section
+    async fn copy_chunked<W: AsyncWrite + Unpin + ?Sized>(
+        &mut self,
+        dst: &mut W,
+    ) -> io::Result<u64> {
+        let mut copied = 0;
+        loop {
+            let line = self.read_line().await?;
+            let size = parse_chunk_size(&line)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+            dst.write_all(&line).await?;
+            copied += line.len() as u64;
+            if size == 0 {
+                break;
+            }
+
+            copied += self.copy_exact(dst, size).await?;
+            let line = self.read_line().await?;
+            if !is_empty_line(&line) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chunk data not followed by CRLF",
+                ));
+            }
+            dst.write_all(&line).await?;
+            copied += line.len() as u64;
+        }
+
+        // Trailer fields, if any, end with an empty line
+        loop {
+            let line = self.read_line().await?;
+            dst.write_all(&line).await?;
+            copied += line.len() as u64;
+            if is_empty_line(&line) {
+                return Ok(copied);
+            }
+        }
+    }
+
+    // Takes one line, terminator included, from the stream
+    async fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                return Ok(self.consume(end + 1));
+            }
+            if self.buf.len() > MAX_CHUNK_LINE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chunk header line too long",
+                ));
+            }
+            if self.fill().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+// Reads drain the buffered bytes before reaching the stream, so a BufferedStream can be
+// handed to code that expects the unread remainder of the connection
+impl<S: AsyncRead + Unpin> AsyncRead for BufferedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = this.buf.len().min(buf.remaining());
+        buf.put_slice(&this.buf[..n]);
+        this.buf.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for BufferedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Parses the hex size at the start of a chunk header line, ignoring chunk extensions
+fn parse_chunk_size(line: &[u8]) -> Option<u64> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim();
+    if size.is_empty() {
+        return None;
+    }
+    u64::from_str_radix(size, 16).ok()
+}
+
+fn is_empty_line(line: &[u8]) -> bool {
+    line == b"\r\n" || line == b"\n"
+}
+
+// Extracts the authority component from an absolute-form request URI
+fn absolute_uri_authority(uri: &str) -> Option<&str> {
+    let (scheme, rest) = uri.split_once("://")?;
+    if scheme.is_empty()
+        || !scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        return None;
+    }
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    // Drop any userinfo component
+    Some(authority.rsplit_once('@').map_or(authority, |(_, a)| a))
+}
+
+// Path and query of an absolute URI; an empty path becomes "/"
+fn origin_form(uri: &str) -> String {
+    let rest = uri.split_once("://").map_or(uri, |(_, rest)| rest);
+    let rest = rest.split('#').next().unwrap_or(rest);
+    match rest.find(['/', '?']) {
+        Some(start) if rest[start..].starts_with('/') => rest[start..].to_string(),
+        Some(start) => format!("/{}", &rest[start..]),
+        None => "/".to_string(),
+    }
+}
+
+fn default_port(uri: &str) -> u16 {
+    match uri.split_once("://") {
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("https") => 443,
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("wss") => 443,
+        Some((scheme, _)) if scheme.eq_ignore_ascii_case("ftp") => 21,
+        _ => 80,
+    }
+}
+
+/// `head`, a response or the start of one, with the header `name: value` added after the
+/// status line.
+pub fn insert_header(head: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let at = head
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .map_or(head.len(), |end| end + 2);
+    let mut tagged = Vec::with_capacity(head.len() + name.len() + value.len() + 4);
+    tagged.extend_from_slice(&head[..at]);
+    tagged.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+    tagged.extend_from_slice(&head[at..]);
+    tagged
+}
+
+/// Decodes the %XX escapes of a URI component. Returns `None` for malformed escapes and
+/// for text that isn't UTF-8 once decoded.
+pub fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Splits `host[:port]` (IPv6 literals in brackets) into its parts, falling back to
+/// `default_port`. The returned host has no brackets, and IPv6 literals are normalized so
+/// they compare equal however they were written. An IPv6 literal without brackets is
+/// rejected, as its last group can't be told from a port.
+pub fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        let ip: Ipv6Addr = host.parse().ok()?;
+        let port = match rest {
+            "" => default_port,
+            rest => rest.strip_prefix(':')?.parse().ok()?,
+        };
+        return Some((ip.to_string(), port));
+    }
+
+    match authority.rsplit_once(':') {
+        Some((host, _)) if host.is_empty() || host.contains(':') => None,
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None if !authority.is_empty() => Some((authority.to_string(), default_port)),
+        None => None,
+    }
+}
+
+/// Joins `host` and `port` into an authority, bracketing IPv6 literals.
+pub fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Header changes made to plain HTTP requests before they are forwarded, from
+/// `--remove-header`, `--set-header` and `--add-header`.
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    /// Lowercase names of headers to drop, including those that `--set-header` replaces
+    pub remove: Vec<String>,
+    /// Headers appended after the client's remaining ones
+    pub add: Vec<(String, String)>,
+}
+
+impl HeaderRules {
+    /// Builds the rules from `NAME` entries to remove, `NAME: VALUE` entries that replace
+    /// any header of that name, and `NAME: VALUE` entries that are appended.
+    pub fn load(remove: &[String], set: &[String], add: &[String]) -> Result<Self, String> {
+        let mut rules = Self::default();
+        for name in remove {
+            let name = name.trim();
+            if !is_token(name) {
+                return Err(format!("--remove-header: invalid header name '{name}'"));
+            }
+            rules.remove.push(name.to_ascii_lowercase());
+        }
+        for entry in set {
+            let (name, value) =
+                parse_header_line(entry).map_err(|e| format!("--set-header: {e}"))?;
+            rules.remove.push(name.to_ascii_lowercase());
+            rules.add.push((name, value));
+        }
+        for entry in add {
+            rules
+                .add
+                .push(parse_header_line(entry).map_err(|e| format!("--add-header: {e}"))?);
+        }
+        Ok(rules)
+    }
+}
+
+// Splits a `NAME: VALUE` header line, rejecting anything that would corrupt the head
+fn parse_header_line(line: &str) -> Result<(String, String), String> {
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| format!("expected 'NAME: VALUE', got '{line}'"))?;
+    let name = name.trim();
+    if !is_token(name) {
+        return Err(format!("invalid header name '{name}'"));
+    }
+    let value = value.trim();
+    if value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0) {
+        return Err(format!("invalid value for header '{name}'"));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+// Whether `name` is a non-empty RFC 9110 token, as header names must be
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(head: &str) -> RequestHead {
+        RequestHead::parse(head.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn connect_to_ipv6_literal() {
+        let head = request("CONNECT [2001:db8::1]:443 HTTP/1.1\r\nHost: [2001:db8::1]:443\r\n\r\n");
+        assert_eq!(head.destination(), Some(("2001:db8::1".to_string(), 443)));
+    }
+
+    #[test]
+    fn connect_to_unbracketed_ipv6_is_rejected() {
+        let head = request("CONNECT 2001:db8::1:443 HTTP/1.1\r\n\r\n");
+        assert_eq!(head.destination(), None);
+    }
+
+    #[test]
+    fn connect_needs_a_port() {
+        let head = request("CONNECT [2001:db8::1] HTTP/1.1\r\n\r\n");
+        assert_eq!(head.destination(), None);
+    }
+
+    #[test]
+    fn host_header_with_ipv6_literal() {
+        let head = request("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n");
+        assert_eq!(head.destination(), Some(("::1".to_string(), 8080)));
+
+        let head = request("GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n");
+        assert_eq!(head.destination(), Some(("::1".to_string(), 80)));
+    }
+
+    #[test]
+    fn absolute_uri_with_ipv6_literal() {
+        let head =
+            request("GET http://[2001:DB8:0::1]:8080/path HTTP/1.1\r\nHost: ignored\r\n\r\n");
+        assert_eq!(head.destination(), Some(("2001:db8::1".to_string(), 8080)));
+
+        let head = request("GET https://[::1]/ HTTP/1.1\r\n\r\n");
+        assert_eq!(head.destination(), Some(("::1".to_string(), 443)));
+    }
+
+    #[test]
+    fn split_host_port_forms() {
+        assert_eq!(
+            split_host_port("example.com:8443", 80),
+            Some(("example.com".to_string(), 8443))
+        );
+        assert_eq!(
+            split_host_port("example.com", 80),
+            Some(("example.com".to_string(), 80))
+        );
+        assert_eq!(
+            split_host_port("10.0.0.1:1080", 0),
+            Some(("10.0.0.1".to_string(), 1080))
+        );
+        assert_eq!(
+            split_host_port("[::ffff:10.0.0.1]:1080", 0),
+            Some(("::ffff:10.0.0.1".to_string(), 1080))
+        );
+        assert_eq!(split_host_port("::1", 80), None);
+        assert_eq!(split_host_port("[example.com]:80", 80), None);
+        assert_eq!(split_host_port("[::1]x", 80), None);
+        assert_eq!(split_host_port("[::1]:port", 80), None);
+        assert_eq!(split_host_port(":80", 80), None);
+    }
+
+    #[test]
+    fn join_host_port_brackets_ipv6() {
+        assert_eq!(join_host_port("2001:db8::1", 443), "[2001:db8::1]:443");
+        assert_eq!(join_host_port("example.com", 443), "example.com:443");
+    }
+
+    #[test]
+    fn ipv6_destination_forwarded_in_absolute_form() {
+        let head = request("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n");
+        let encoded = head.encode_for_proxy("::1", 8080, &[], &[]);
+        assert!(encoded.starts_with(b"GET http://[::1]:8080/ HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn header_rules_parse_entries() {
+        let rules = HeaderRules::load(
+            &[" Cookie ".to_string()],
+            &["User-Agent:  curl/8.5.0 ".to_string()],
+            &["X-Team: a:b".to_string()],
+        )
+        .unwrap();
+        assert_eq!(rules.remove, ["cookie", "user-agent"]);
+        assert_eq!(
+            rules.add,
+            [
+                ("User-Agent".to_string(), "curl/8.5.0".to_string()),
+                ("X-Team".to_string(), "a:b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn header_rules_reject_bad_entries() {
+        let e = HeaderRules::load(&["Bad Name".to_string()], &[], &[]).unwrap_err();
+        assert!(e.starts_with("--remove-header: "), "{e}");
+        let e = HeaderRules::load(&[], &["NoColon".to_string()], &[]).unwrap_err();
+        assert!(e.starts_with("--set-header: "), "{e}");
+        let e = HeaderRules::load(&[], &[], &["X-A: b\r\nX-B: c".to_string()]).unwrap_err();
+        assert!(e.starts_with("--add-header: "), "{e}");
+        assert!(HeaderRules::load(&[], &[], &[": value".to_string()]).is_err());
+    }
+
+    #[test]
+    fn header_rules_rewrite_forwarded_head() {
+        let rules = HeaderRules::load(
+            &["cookie".to_string()],
+            &["user-agent: proxy".to_string()],
+            &["X-Added: 1".to_string()],
+        )
+        .unwrap();
+        let head = request(
+            "GET http://example.com/ HTTP/1.1\r\nUser-Agent: curl\r\nCOOKIE: a=b\r\nAccept: */*\r\n\r\n",
+        );
+        let strip: Vec<&str> = rules.remove.iter().map(String::as_str).collect();
+        let add: Vec<(&str, String)> = rules
+            .add
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.clone()))
+            .collect();
+        let encoded = head.encode_for_origin(&strip, &add);
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nuser-agent: proxy\r\nX-Added: 1\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn content_length_is_dropped_beside_transfer_encoding() {
+        let head = request(
+            "POST http://example.com/ HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+        );
+        assert_eq!(head.body_length(), Some(BodyLength::Chunked));
+        let strip = head.hop_by_hop();
+        let strip: Vec<&str> = strip.iter().map(String::as_str).collect();
+        let encoded = String::from_utf8(head.encode_for_origin(&strip, &[])).unwrap();
+        assert!(
+            encoded.contains("Transfer-Encoding: chunked\r\n"),
+            "{encoded}"
+        );
+        assert!(!encoded.contains("Content-Length"), "{encoded}");
+
+        let head = request("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\n");
+        assert!(!head.hop_by_hop().contains(&"content-length".to_string()));
+    }
+}
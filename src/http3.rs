@@ -0,0 +1,429 @@
+use crate::error::FatalError;
+use crate::http::{percent_decode, Header, RequestHead, ResponseHead};
+use crate::upgrade::Upgrade;
+use bytes::{Buf, Bytes};
+use socket2::SockRef;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::ServerConfig;
+use tracing::{debug, info};
+
+// Bytes buffered in each direction between a request stream and the relay reading it
+const BRIDGE_BUFFER: usize = 64 * 1024;
+
+// Response headers that only make sense on an HTTP/1.1 connection, forbidden in HTTP/3
+const CONNECTION_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Where connect-udp requests are served (RFC 9298 section 2)
+const UDP_PATH: &str = "/.well-known/masque/udp/";
+
+// Datagrams queued per UDP flow before new ones are dropped
+const FLOW_QUEUE: usize = 256;
+
+type RequestStream = h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+// The QUIC connection a request arrived on, shared by its requests
+struct Connection {
+    quic: quinn::Connection,
+    // The UDP flows open on the connection by request stream ID, to hand datagrams to
+    flows: Mutex<HashMap<u64, mpsc::Sender<Bytes>>>,
+}
+
+impl Connection {
+    // Delivers the HTTP datagrams the client sends to their flows until the connection closes
+    async fn dispatch_datagrams(&self) {
+        while let Ok(datagram) = self.quic.read_datagram().await {
+            // Datagrams name their request stream by a quarter of its ID (RFC 9297 section 2.1)
+            let Some((quarter_stream_id, len)) = read_varint(&datagram) else {
+                continue;
+            };
+            let flow = self
+                .flows
+                .lock()
+                .unwrap()
+                .get(&(quarter_stream_id * 4))
+                .cloned();
+            if let Some(flow) = flow {
+                let _ = flow.try_send(datagram.slice(len..));
+            }
+        }
+    }
+}
+
+/// Binds the QUIC endpoint for `--h3-listen`, offering `h3` through ALPN with the TLS
+/// settings of the TCP listener. A socket taken over in an upgrade is used instead of a new
+/// one.
+pub fn bind(
+    addr: &str,
+    mut tls: ServerConfig,
+    upgrade: &mut Upgrade,
+) -> Result<quinn::Endpoint, FatalError> {
+    let bind_error = |source| FatalError::Bind {
+        addr: addr.to_string(),
+        source,
+    };
+    let socket_addr = addr
+        .to_socket_addrs()
+        .map_err(bind_error)?
+        .next()
+        .ok_or_else(|| FatalError::Config(format!("--h3-listen: cannot resolve '{addr}'")))?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)
+        .map_err(|e| FatalError::Config(format!("--h3-listen: {e}")))?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    let role = format!("h3 {addr}");
+    let socket = match upgrade.inherited::<std::net::UdpSocket>(&role).pop() {
+        Some(socket) => socket,
+        None => std::net::UdpSocket::bind(socket_addr).map_err(bind_error)?,
+    };
+    upgrade.keep(&role, SockRef::from(&socket))?;
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )
+    .map_err(bind_error)?;
+    info!("HTTP/3 proxy listening on: {}", socket_addr);
+    Ok(endpoint)
+}
+
+/// A request received over HTTP/3, with its head translated to HTTP/1.1 form so it can go
+/// through the same checks as any other.
+pub struct Request {
+    /// The request head; CONNECT targets are in authority-form, and a Host header is added
+    /// from `:authority` when the client sent none
+    pub head: RequestHead,
+    /// The `:protocol` of an extended CONNECT, such as `connect-udp`
+    pub protocol: Option<String>,
+    /// The certificate the client authenticated the QUIC connection with
+    pub peer_certificate: Option<CertificateDer<'static>>,
+    stream: RequestStream,
+    connection: Arc<Connection>,
+}
+
+impl Request {
+    fn new(
+        request: http::Request<()>,
+        stream: RequestStream,
+        peer_certificate: Option<CertificateDer<'static>>,
+        connection: Arc<Connection>,
+    ) -> Self {
+        let authority = request
+            .uri()
+            .authority()
+            .map(|authority| authority.to_string());
+        let protocol = request
+            .extensions()
+            .get::<h3::ext::Protocol>()
+            .map(|protocol| protocol.as_str().to_string());
+        // h3 fills in a scheme and path for plain CONNECT, which only has an authority
+        let target = match (&authority, &protocol) {
+            (Some(authority), None) if request.method() == http::Method::CONNECT => {
+                authority.clone()
+            }
+            _ => request.uri().to_string(),
+        };
+        let mut headers: Vec<Header> = request
+            .headers()
+            .iter()
+            .map(|(name, value)| Header {
+                name: name.to_string(),
+                value: value.as_bytes().to_vec(),
+            })
+            .collect();
+        if let (Some(authority), false) = (&authority, request.headers().contains_key("host")) {
+            headers.push(Header {
+                name: "Host".to_string(),
+                value: authority.clone().into_bytes(),
+            });
+        }
+        Self {
+            head: RequestHead {
+                method: request.method().to_string(),
+                target,
+                version: 1,
+                headers,
+                len: 0,
+            },
+            protocol,
+            peer_certificate,
+            stream,
+            connection,
+        }
+    }
+
+    /// The host and port a connect-udp request asks for, from its
+    /// `/.well-known/masque/udp/{host}/{port}/` path.
+    pub fn udp_target(&self) -> Option<(String, u16)> {
+        let path = self.head.path();
+        let (host, port) = path
+            .strip_prefix(UDP_PATH)?
+            .split('?')
+            .next()?
+            .trim_end_matches('/')
+            .split_once('/')?;
+        // IPv6 literals come with their colons percent-encoded
+        let host = percent_decode(host)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Some((host.to_string(), port.parse().ok()?))
+    }
+
+    /// Answers with the HTTP/1.1 response in `raw`, such as one of the proxy's canned error
+    /// responses, carrying over its status, headers and body.
+    pub async fn respond(&mut self, raw: &[u8]) -> io::Result<()> {
+        let head = ResponseHead::parse(raw)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated response"))?;
+        let mut response = http::Response::builder().status(head.status);
+        for header in &head.headers {
+            if !CONNECTION_HEADERS
+                .iter()
+                .any(|name| header.name.eq_ignore_ascii_case(name))
+            {
+                response = response.header(&header.name, &header.value[..]);
+            }
+        }
+        let response = response.body(()).map_err(io::Error::other)?;
+        self.stream
+            .send_response(response)
+            .await
+            .map_err(io::Error::other)?;
+        let body = &raw[head.len..];
+        if !body.is_empty() {
+            self.stream
+                .send_data(Bytes::copy_from_slice(body))
+                .await
+                .map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the response stream after a final response other than a tunnel's.
+    pub async fn finish(mut self) -> io::Result<()> {
+        self.stream.finish().await.map_err(io::Error::other)
+    }
+
+    /// Turns the request stream of an established tunnel into a byte stream, so it can be
+    /// relayed like a TCP connection. DATA frames are copied to and from it in the background.
+    pub fn into_stream(self) -> DuplexStream {
+        let (ours, theirs) = tokio::io::duplex(BRIDGE_BUFFER);
+        let (mut reader, mut writer) = tokio::io::split(ours);
+        let (mut send, mut recv) = self.stream.split();
+        tokio::spawn(async move {
+            while let Ok(Some(mut data)) = recv.recv_data().await {
+                while data.has_remaining() {
+                    let chunk = data.chunk();
+                    if writer.write_all(chunk).await.is_err() {
+                        return;
+                    }
+                    let len = chunk.len();
+                    data.advance(len);
+                }
+            }
+            // The client finished its side of the tunnel
+            let _ = writer.shutdown().await;
+        });
+        tokio::spawn(async move {
+            let mut buf = vec![0; 16 * 1024];
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if send
+                            .send_data(Bytes::copy_from_slice(&buf[..n]))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            }
+            let _ = send.finish().await;
+        });
+        theirs
+    }
+
+    /// Turns the request stream of an accepted connect-udp request into its UDP flow.
+    pub fn into_udp_flow(self) -> UdpFlow {
+        let stream_id = self.stream.id().into_inner();
+        let (sender, received) = mpsc::channel(FLOW_QUEUE);
+        self.connection
+            .flows
+            .lock()
+            .unwrap()
+            .insert(stream_id, sender);
+        let mut prefix = Vec::new();
+        write_varint(&mut prefix, stream_id / 4);
+        // Context ID 0: the datagram carries a UDP payload
+        write_varint(&mut prefix, 0);
+        UdpFlow {
+            stream: self.stream,
+            connection: self.connection,
+            stream_id,
+            prefix,
+            received,
+        }
+    }
+}
+
+/// The UDP payloads of a connect-udp request (RFC 9298), exchanged as HTTP datagrams in QUIC
+/// DATAGRAM frames. The flow lasts until the client closes the request stream.
+pub struct UdpFlow {
+    stream: RequestStream,
+    connection: Arc<Connection>,
+    stream_id: u64,
+    // Quarter stream ID and context ID that start every datagram sent
+    prefix: Vec<u8>,
+    received: mpsc::Receiver<Bytes>,
+}
+
+impl UdpFlow {
+    /// The next UDP payload from the client, or `None` once it closed the flow.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        loop {
+            tokio::select! {
+                datagram = self.received.recv() => {
+                    let datagram = datagram?;
+                    // Payloads under context IDs the client registered itself are not understood
+                    match read_varint(&datagram) {
+                        Some((0, len)) => return Some(datagram.slice(len..)),
+                        _ => continue,
+                    }
+                }
+                // Capsules on the stream are skipped; its end closes the flow
+                data = self.stream.recv_data() => match data {
+                    Ok(Some(_)) => continue,
+                    Ok(None) | Err(_) => return None,
+                },
+            }
+        }
+    }
+
+    /// Sends a UDP payload to the client. Payloads too large for a DATAGRAM frame are dropped,
+    /// as UDP would.
+    pub fn send(&self, payload: &[u8]) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(self.prefix.len() + payload.len());
+        datagram.extend_from_slice(&self.prefix);
+        datagram.extend_from_slice(payload);
+        match self.connection.quic.send_datagram(datagram.into()) {
+            Ok(()) | Err(quinn::SendDatagramError::TooLarge) => Ok(()),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+impl Drop for UdpFlow {
+    fn drop(&mut self) {
+        self.connection
+            .flows
+            .lock()
+            .unwrap()
+            .remove(&self.stream_id);
+    }
+}
+
+// Decodes the QUIC variable-length integer at the start of `buf`, returning it and its length
+fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = buf.get(..len)?;
+    let value = bytes[1..]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, &b| {
+            (value << 8) | u64::from(b)
+        });
+    Some((value, len))
+}
+
+// Appends `value` as a QUIC variable-length integer
+fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Accepts QUIC connections on `endpoint` for as long as it is open, calling `handle` with
+/// the client address for every request stream. Extended CONNECT and HTTP datagrams are
+/// enabled.
+pub async fn serve<F, Fut>(endpoint: quinn::Endpoint, handle: F)
+where
+    F: Fn(SocketAddr, Request) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    while let Some(incoming) = endpoint.accept().await {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let peer = incoming.remote_address();
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    debug!("QUIC handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let peer_certificate = connection
+                .peer_identity()
+                .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+                .and_then(|certs| certs.into_iter().next());
+            let shared = Arc::new(Connection {
+                quic: connection.clone(),
+                flows: Mutex::default(),
+            });
+            let dispatcher = shared.clone();
+            tokio::spawn(async move { dispatcher.dispatch_datagrams().await });
+            let h3 = h3::server::builder()
+                .enable_extended_connect(true)
+                .enable_datagram(true)
+                .build(h3_quinn::Connection::new(connection))
+                .await;
+            let mut h3 = match h3 {
+                Ok(h3) => h3,
+                Err(e) => {
+                    debug!("HTTP/3 setup with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            loop {
+                match h3.accept().await {
+                    Ok(Some(resolver)) => {
+                        let handle = handle.clone();
+                        let peer_certificate = peer_certificate.clone();
+                        let shared = shared.clone();
+                        tokio::spawn(async move {
+                            match resolver.resolve_request().await {
+                                Ok((request, stream)) => {
+                                    let request =
+                                        Request::new(request, stream, peer_certificate, shared);
+                                    handle(peer, request).await
+                                }
+                                Err(e) => debug!("Bad HTTP/3 request from {}: {}", peer, e),
+                            }
+                        });
+                    }
+                    Ok(None) => return,
+                    Err(e) => {
+                        debug!("HTTP/3 connection with {} ended: {}", peer, e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,90 @@
+use crate::http::{self, ResponseHead};
+use crate::socks::{self, Upstream, UpstreamStream};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::instrument;
+
+// Largest CONNECT response head accepted from the HTTP proxy
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// The HTTP proxy answered CONNECT with a non-2xx status.
+#[derive(Debug, thiserror::Error)]
+#[error("HTTP proxy {addr} answered CONNECT with status {status}")]
+pub struct Refused {
+    pub addr: String,
+    pub status: u16,
+}
+
+/// How to send plain HTTP requests to an HTTP upstream instead of tunnelling them: in
+/// absolute form, with the proxy's own credentials.
+#[derive(Debug, Clone)]
+pub struct ForwardProxy {
+    /// The `Proxy-Authorization` value, when the proxy has credentials
+    pub authorization: Option<String>,
+}
+
+impl ForwardProxy {
+    pub fn new(upstream: &Upstream) -> Self {
+        Self {
+            authorization: authorization(upstream),
+        }
+    }
+}
+
+/// Opens a tunnel to `host:port` through the HTTP proxy with CONNECT.
+#[instrument(skip(upstream), fields(dst = %host, port = %port))]
+pub async fn connect(
+    host: &str,
+    port: u16,
+    upstream: &Upstream,
+) -> Result<UpstreamStream, Box<dyn Error>> {
+    let mut stream = socks::connect_server(upstream).await?;
+
+    let authority = http::join_host_port(host, port);
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(authorization) = authorization(upstream) {
+        request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    let head = read_response_head(&mut stream).await?;
+    if !(200..300).contains(&head.status) {
+        return Err(Refused {
+            addr: upstream.addr.clone(),
+            status: head.status,
+        }
+        .into());
+    }
+    Ok(stream)
+}
+
+// Basic credentials for the proxy
+fn authorization(upstream: &Upstream) -> Option<String> {
+    let credentials = upstream.credentials.as_ref()?;
+    let token = STANDARD.encode(format!("{}:{}", credentials.username, credentials.password));
+    Some(format!("Basic {token}"))
+}
+
+// Reads the response head a byte at a time, so that whatever the destination sends right
+// after it is left in the stream for the relay
+async fn read_response_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<ResponseHead, Box<dyn Error>> {
+    let mut buf = Vec::with_capacity(256);
+    loop {
+        let byte = stream.read_u8().await?;
+        buf.push(byte);
+        if byte == b'\n' && buf.ends_with(b"\r\n\r\n") {
+            if let Some(head) = ResponseHead::parse(&buf)? {
+                return Ok(head);
+            }
+        }
+        if buf.len() >= MAX_RESPONSE_HEAD {
+            return Err(format!("CONNECT response head exceeds {MAX_RESPONSE_HEAD} bytes").into());
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! A bridge that accepts HTTP proxy clients and tunnels their connections through SOCKS
+//! servers. [`Proxy`] runs the same server as the `http2socks` binary inside another Tokio
+//! application; the [`socks`] and [`http`] modules expose the SOCKS client and the HTTP
+//! message parsing it is built on.
+
+mod access_log;
+mod acl;
+mod admin;
+mod auth;
+mod blocklist;
+mod cache;
+mod config;
+mod config_file;
+mod connector;
+mod dns;
+mod dns_proxy;
+mod dump;
+pub mod echo;
+mod egress;
+mod encrypted_dns;
+mod error;
+mod error_pages;
+mod ftp;
+mod geoip;
+#[cfg(feature = "gssapi")]
+mod gssapi;
+mod happy_eyeballs;
+mod hooks;
+pub mod http;
+#[cfg(feature = "http3")]
+mod http3;
+mod http_upstream;
+mod limits;
+mod listener;
+pub mod log_filter;
+mod log_rotation;
+mod metrics;
+mod mitm;
+mod origin_pool;
+mod pac;
+mod proxy;
+mod proxy_protocol;
+mod relay;
+mod reload;
+mod routing;
+mod sni;
+pub mod socks;
+mod socks_server;
+mod splice;
+mod stats;
+mod statsd;
+mod throttle;
+mod tls;
+mod transparent;
+mod udp;
+mod upgrade;
+mod upstream;
+
+pub use access_log::{LogFormat, Record, Termination};
+pub use config::{
+    AbortMode, AuthScheme, Bridge, Command, Config, ForwardMode, HostCheck, Resolve, ServiceAction,
+};
+pub use connector::{Connecting, Connector, SocksConnector, Transport};
+pub use error::FatalError;
+pub use hooks::Hooks;
+pub use log_rotation::{RotatingFile, Rotation, RotationInterval};
+pub use proxy::{Mode, Proxy, ProxyBuilder};
+pub use socks::{
+    connect_socks5, connect_upstream, ConnectRetry, Credentials, Protocol, ReplyError,
+    SocksVersion, Upstream,
+};
+pub use upstream::Balance;
@@ -0,0 +1,225 @@
+use crate::routing::HostPattern;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Clients tracked before buckets that have refilled are forgotten
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Request rates per client IP, shared by all listeners and kept across reloads.
+pub static REQUEST_RATES: RequestRateLimiter = RequestRateLimiter::new();
+
+/// Open connections per `--max-dest-connections` pattern, shared by all listeners and kept
+/// across reloads so that a reload doesn't reset them.
+static DESTINATION_CONNECTIONS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Open client connections, in total and per client IP, for enforcing connection caps.
+/// The counts outlive configuration reloads; the caps are passed in on every check.
+#[derive(Default)]
+pub struct ConnectionLimiter {
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+}
+
+/// Which cap refused a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Total(usize),
+    PerClient(usize),
+}
+
+/// One admitted connection. It counts against the caps until dropped.
+pub struct ConnectionPermit {
+    counts: Arc<Mutex<Counts>>,
+    client: IpAddr,
+}
+
+impl ConnectionLimiter {
+    /// Admits a connection from `client` unless it would exceed `max_total` open connections
+    /// or `max_per_client` from that IP. `None` leaves a cap unlimited.
+    pub fn try_acquire(
+        &self,
+        client: IpAddr,
+        max_total: Option<usize>,
+        max_per_client: Option<usize>,
+    ) -> Result<ConnectionPermit, Refusal> {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(max) = max_total.filter(|max| counts.total >= *max) {
+            return Err(Refusal::Total(max));
+        }
+        let from_client = counts.per_client.get(&client).copied().unwrap_or(0);
+        if let Some(max) = max_per_client.filter(|max| from_client >= *max) {
+            return Err(Refusal::PerClient(max));
+        }
+
+        counts.total += 1;
+        *counts.per_client.entry(client).or_default() += 1;
+        Ok(ConnectionPermit {
+            counts: self.counts.clone(),
+            client,
+        })
+    }
+
+    /// The connections open right now.
+    pub fn open(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(count) = counts.per_client.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_client.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// A token bucket per client IP, refilling at the allowed request rate and holding up to
+/// one second's worth, so short bursts pass while sustained floods are cut to the rate.
+pub struct RequestRateLimiter {
+    buckets: Mutex<BTreeMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RequestRateLimiter {
+    const fn new() -> Self {
+        Self {
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Takes a token for one request from `client`, allowed `per_second` requests a second.
+    /// Returns false when its bucket is empty.
+    pub fn try_acquire(&self, client: IpAddr, per_second: u32) -> bool {
+        let rate = f64::from(per_second);
+        let now = Instant::now();
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * rate).min(rate)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| refill(bucket) < rate);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// Caps on the connections open at once to destinations matching a pattern, from
+/// `--max-dest-connections`. The first matching cap applies.
+#[derive(Debug, Default)]
+pub struct DestinationCaps {
+    // The pattern as given, which its connections are counted under, the pattern and the cap
+    caps: Vec<(String, HostPattern, usize)>,
+}
+
+/// A destination connection refused by its cap.
+#[derive(Debug)]
+pub struct DestinationFull {
+    pub pattern: String,
+    pub max: usize,
+}
+
+impl fmt::Display for DestinationFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connections already open to destinations matching {}",
+            self.max, self.pattern
+        )
+    }
+}
+
+impl std::error::Error for DestinationFull {}
+
+/// One connection counted against a destination cap until dropped.
+pub struct DestinationPermit {
+    pattern: String,
+}
+
+impl DestinationCaps {
+    /// Parses `PATTERN=N` entries.
+    pub fn load(entries: &[String]) -> Result<Self, String> {
+        let caps = entries
+            .iter()
+            .map(|entry| {
+                let error = |reason: String| format!("--max-dest-connections {entry}: {reason}");
+                let (pattern, max) = entry
+                    .rsplit_once('=')
+                    .ok_or_else(|| error("expected PATTERN=N".into()))?;
+                let max = max
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .ok_or_else(|| error("the cap must be a positive number".into()))?;
+                let pattern = pattern.trim();
+                Ok((
+                    pattern.to_string(),
+                    HostPattern::parse(pattern).map_err(error)?,
+                    max,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { caps })
+    }
+
+    /// Counts a connection to `host` against the first cap matching it, unless that many
+    /// are open already. `None` when no cap applies.
+    pub fn try_acquire(&self, host: &str) -> Result<Option<DestinationPermit>, DestinationFull> {
+        let Some((pattern, _, max)) = self.caps.iter().find(|(_, cap, _)| cap.matches(host)) else {
+            return Ok(None);
+        };
+        let mut open = DESTINATION_CONNECTIONS.lock().unwrap();
+        let count = open.entry(pattern.clone()).or_default();
+        if *count >= *max {
+            return Err(DestinationFull {
+                pattern: pattern.clone(),
+                max: *max,
+            });
+        }
+        *count += 1;
+        Ok(Some(DestinationPermit {
+            pattern: pattern.clone(),
+        }))
+    }
+}
+
+impl Drop for DestinationPermit {
+    fn drop(&mut self) {
+        let mut open = DESTINATION_CONNECTIONS.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.pattern) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.pattern);
+            }
+        }
+    }
+}
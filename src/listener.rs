@@ -0,0 +1,260 @@
+use crate::dump::Dump;
+use crate::error::FatalError;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::server::TlsStream;
+
+/// An accepted client connection, possibly wrapped in TLS, and copied to files with
+/// `--dump-traffic`.
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Dumped(Box<Dump<ClientStream>>),
+}
+
+impl ClientStream {
+    /// The underlying TCP connection.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Self::Plain(stream) => stream,
+            Self::Tls(stream) => stream.get_ref().0,
+            Self::Dumped(stream) => stream.get_ref().tcp(),
+        }
+    }
+
+    /// The certificate the client authenticated with, if it connected over TLS with one.
+    pub fn peer_certificate(&self) -> Option<&CertificateDer<'static>> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Tls(stream) => stream.get_ref().1.peer_certificates()?.first(),
+            Self::Dumped(stream) => stream.get_ref().peer_certificate(),
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Dumped(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+// TLS buffers written data, so callers flush before waiting on the client
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Dumped(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Dumped(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Dumped(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Binds the client listener. With more than one acceptor, each gets its own socket bound
+/// to the same address with SO_REUSEPORT so the kernel spreads new connections over them.
+/// `transparent` prepares the sockets for TPROXY, where the kernel supports it.
+pub async fn bind(
+    listen: &str,
+    acceptors: usize,
+    transparent: bool,
+) -> Result<Vec<TcpListener>, FatalError> {
+    let bind_error = |source| FatalError::Bind {
+        addr: listen.to_string(),
+        source,
+    };
+
+    if acceptors <= 1 && !transparent {
+        return Ok(vec![TcpListener::bind(listen).await.map_err(bind_error)?]);
+    }
+
+    let addr = tokio::net::lookup_host(listen)
+        .await
+        .map_err(bind_error)?
+        .next()
+        .ok_or_else(|| {
+            bind_error(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                "address resolved to nothing",
+            ))
+        })?;
+    (0..acceptors)
+        .map(|_| socket_listener(addr, acceptors > 1, transparent).map_err(bind_error))
+        .collect()
+}
+
+/// The listening sockets systemd passed to this process through socket activation
+/// (`LISTEN_PID` and `LISTEN_FDS`), in the order of the socket unit's `ListenStream=` lines,
+/// or `None` when the process was started without them.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn activated() -> Result<Option<Vec<TcpListener>>, FatalError> {
+    use socket2::{Domain, Socket, Type};
+    use std::os::fd::{FromRawFd, RawFd};
+    // The first descriptor systemd passes (SD_LISTEN_FDS_START)
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let var = |name| std::env::var(name).ok();
+    // The variables may have been inherited from a socket-activated parent
+    if var("LISTEN_PID").and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count = match var("LISTEN_FDS").and_then(|count| count.parse::<RawFd>().ok()) {
+        Some(count) if count > 0 => count,
+        _ => return Ok(None),
+    };
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            let error = |source| FatalError::Bind {
+                addr: format!("socket activation descriptor {fd}"),
+                source,
+            };
+            // SAFETY: systemd passes these descriptors to this process, and nothing else
+            // takes ownership of them
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            let domain = socket.domain().map_err(error)?;
+            if socket.r#type().map_err(error)? != Type::STREAM
+                || !(domain == Domain::IPV4 || domain == Domain::IPV6)
+                || !socket.is_listener().map_err(error)?
+            {
+                return Err(error(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not a listening TCP socket",
+                )));
+            }
+            socket.set_nonblocking(true).map_err(error)?;
+            TcpListener::from_std(socket.into()).map_err(error)
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn activated() -> Result<Option<Vec<TcpListener>>, FatalError> {
+    Ok(None)
+}
+
+/// Enables TCP Fast Open on a listening socket for `--tcp-fastopen`, so clients that have
+/// connected before can send their request in the SYN.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+))]
+pub fn set_fast_open(listener: &TcpListener) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // Linux takes the length of the queue of pending Fast Open requests, the BSDs a flag
+    let value: libc::c_int = if cfg!(any(target_os = "linux", target_os = "android")) {
+        256
+    } else {
+        1
+    };
+    // SAFETY: the descriptor stays open while `listener` is borrowed, and the option value
+    // is a c_int of the given size
+    let result = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd"
+)))]
+pub fn set_fast_open(_listener: &TcpListener) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is not available on this platform",
+    ))
+}
+
+// Binds a listener through socket2 for the options TcpListener::bind can't set
+fn socket_listener(
+    addr: SocketAddr,
+    reuse_port: bool,
+    transparent: bool,
+) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    // Pending connection queue length, as tokio uses for its own listeners
+    const BACKLOG: i32 = 1024;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    if transparent {
+        if let Err(e) = crate::transparent::set_transparent(&socket, addr) {
+            tracing::warn!(
+                "Cannot set IP_TRANSPARENT ({}); only REDIRECTed connections will work",
+                e
+            );
+        }
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &socket2::Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &socket2::Socket) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not available on this platform; use --acceptors 1",
+    ))
+}
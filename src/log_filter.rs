@@ -0,0 +1,29 @@
+use std::sync::{Mutex, OnceLock};
+
+// Applies a new filter to the tracing subscriber, as registered by `install`
+type Reload = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+static RELOAD: OnceLock<Reload> = OnceLock::new();
+static CURRENT: Mutex<String> = Mutex::new(String::new());
+
+/// Lets the admin endpoint change the log filter at runtime. Whoever sets up the tracing
+/// subscriber registers `reload`, which parses a filter such as `http2socks=debug,info` and
+/// applies it, along with the filter `current`ly in effect. Only the first call counts.
+pub fn install(current: &str, reload: impl Fn(&str) -> Result<(), String> + Send + Sync + 'static) {
+    if RELOAD.set(Box::new(reload)).is_ok() {
+        *CURRENT.lock().unwrap() = current.to_string();
+    }
+}
+
+/// The filter in effect, or `None` when it cannot be changed.
+pub(crate) fn current() -> Option<String> {
+    RELOAD.get()?;
+    Some(CURRENT.lock().unwrap().clone())
+}
+
+/// Replaces the filter, or returns `None` when it cannot be changed.
+pub(crate) fn set(filter: &str) -> Option<Result<(), String>> {
+    let reload = RELOAD.get()?;
+    let mut current = CURRENT.lock().unwrap();
+    Some(reload(filter).map(|()| *current = filter.to_string()))
+}
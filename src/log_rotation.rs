@@ -0,0 +1,131 @@
+use clap::ValueEnum;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How often log files are rotated regardless of their size.
+#[derive(ValueEnum, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RotationInterval {
+    /// Only rotate by size
+    Never,
+    /// At the start of every hour (UTC)
+    Hourly,
+    /// At midnight UTC
+    Daily,
+}
+
+impl RotationInterval {
+    // Index of the period `time` falls in; files are rotated when it changes
+    fn period(self, time: SystemTime) -> Option<u64> {
+        let secs = match self {
+            Self::Never => return None,
+            Self::Hourly => 3600,
+            Self::Daily => 86400,
+        };
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Some(since_epoch.as_secs() / secs)
+    }
+}
+
+/// When log files are rotated and how many old ones are kept.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    /// Rotate before a write would take the file past this many bytes
+    pub max_size: Option<u64>,
+    pub interval: RotationInterval,
+    /// Rotated files kept as `PATH.1` (newest) to `PATH.N`; older ones are deleted
+    pub keep: usize,
+}
+
+/// A log file that is appended to and rotated by size and time on its own, without an
+/// external logrotate.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    rotation: Rotation,
+    size: u64,
+    period: Option<u64>,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, creating it if needed. A file left over from an earlier
+    /// period is rotated on the first write.
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let modified = match metadata.len() {
+            0 => SystemTime::now(),
+            _ => metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            rotation,
+            size: metadata.len(),
+            period: rotation.interval.period(modified),
+        })
+    }
+
+    fn needs_rotation(&self, len: usize, period: Option<u64>) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size + len as u64 > max);
+        too_big || period != self.period
+    }
+
+    // Shifts PATH.1..PATH.N-1 up by one, dropping PATH.N, moves the current file to PATH.1
+    // and starts a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(keep));
+            for n in (1..keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.rotation.interval.period(SystemTime::now());
+        if self.needs_rotation(buf.len(), period) {
+            // A failed rotation must not lose the record, so keep appending to the old file
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate {}: {}", self.path.display(), e);
+            }
+        }
+        self.period = period;
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -1,369 +1,174 @@
-use clap::Parser;
-use std::error::Error;
-use std::fmt::Write;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, instrument, warn, Instrument, Span};
-
-// SOCKS Protocol Constants
-const SOCKS5_VERSION: u8 = 0x05;
-const SOCKS5_AUTH_NONE: u8 = 0x00;
-const SOCKS5_AUTH_METHODS: u8 = 0x01;
-const SOCKS5_CMD_CONNECT: u8 = 0x01;
-const SOCKS5_RSV: u8 = 0x00;
-const SOCKS5_ATYP_IPV4: u8 = 0x01;
-const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
-const SOCKS5_ATYP_IPV6: u8 = 0x04;
-const SOCKS5_SUCCESS: u8 = 0x00;
-
-// Command line configuration structure using clap
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Config {
-    /// The address and port where the HTTP proxy server will listen for incoming connections
-    #[arg(short, long, default_value = "127.0.0.1:8080")]
-    listen: String,
-
-    /// The address and port of the SOCKS proxy server to forward requests to
-    #[arg(short, long, default_value = "127.0.0.1:1080")]
-    socks: String,
-
-    /// Forward mode: forward raw TCP traffic directly to SOCKS5 (no HTTP protocol handling)
-    #[arg(short, long, default_value_t = false)]
-    forward: bool,
-}
+use http2socks::{
+    echo, log_filter, Command, Config, FatalError, Proxy, RotatingFile, Rotation, ServiceAction,
+};
+use std::future::Future;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Mutex;
+use tracing::{error, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+mod daemon;
+#[cfg(windows)]
+mod service;
 
 // Main entry point - sets up HTTP proxy server and handles incoming connections
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
-
-    let config = Config::parse();
-    let listener = TcpListener::bind(&config.listen).await?;
-
-    if config.forward {
-        info!("TCP forward mode listening on: {}", config.listen);
-        info!("Forwarding all traffic to SOCKS5: {}", config.socks);
-    } else {
-        info!("HTTP proxy listening on: {}", config.listen);
-    }
-
-    while let Ok((client, addr)) = listener.accept().await {
-        let connection_span = tracing::info_span!("connection", client.addr = %addr);
-        let socks_addr = config.socks.clone();
-        let forward_mode = config.forward;
-
-        tokio::spawn(
-            async move {
-                let result = if forward_mode {
-                    handle_forward_client(client, &socks_addr).await
-                } else {
-                    handle_client(client, &socks_addr).await
-                };
-
-                if let Err(e) = result {
-                    error!("Client handling error: {}", e);
-                    // Print the error chain
-                    let mut error_chain = String::new();
-                    let mut source = e.source();
-                    while let Some(e) = source {
-                        let _ = writeln!(error_chain, "Caused by: {e}");
-                        source = e.source();
-                    }
-                    if !error_chain.is_empty() {
-                        error!("Error chain:\n{}", error_chain);
-                    }
-                }
-            }
-            .instrument(connection_span),
-        );
-    }
-
-    Ok(())
-}
-
-// Handles individual client connections and processes HTTP requests
-#[instrument(skip_all, fields(target, mode))]
-async fn handle_client(mut client: TcpStream, socks_addr: &str) -> Result<(), Box<dyn Error>> {
-    let mut buffer = Vec::new();
-    let mut temp_buf = [0u8; 1024];
-    let mut header_end = None;
-
-    // Read until we find the end of HTTP headers (\r\n\r\n)
-    loop {
-        let n = client.read(&mut temp_buf).await.map_err(|e| {
-            error!("Failed to read from client: {}", e);
-            e
-        })?;
-
-        if n == 0 {
-            if buffer.is_empty() {
-                return Ok(());
-            }
-            break;
+fn main() -> ExitCode {
+    let result = match Config::load() {
+        Ok(config) => {
+            // Initialize logging unless running quietly, which the config file may also ask for
+            let logging = if config.quiet {
+                Ok(())
+            } else {
+                init_logging(config.log_file.as_deref(), config.log_rotation())
+            };
+            logging.and_then(|()| start(config))
         }
-
-        buffer.extend_from_slice(&temp_buf[..n]);
-
-        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
-            header_end = Some(pos + 4);
-            break;
-        }
-
-        if buffer.len() > 16384 {
-            return Err("Headers too large".into());
-        }
-    }
-
-    let header_len = header_end.unwrap_or(buffer.len());
-    let (header_part, extra_part) = buffer.split_at(header_len);
-
-    if is_connect_request(header_part) {
-        // Handle CONNECT tunnel (HTTPS)
-        if let Some((host, port)) = parse_connect_request(header_part) {
-            Span::current().record("target", format!("{}:{}", host, port));
-            Span::current().record("mode", "CONNECT");
-
-            let mut socks = connect_socks5(&host, port, socks_addr).await.map_err(|e| {
-                error!("Failed to connect via SOCKS5: {}", e);
-                e
-            })?;
-
-            client
-                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
-                .await
-                .map_err(|e| {
-                    error!("Failed to send connection established: {}", e);
-                    e
-                })?;
-
-            // If we read more than headers (unlikely for CONNECT but possible), forward it
-            if !extra_part.is_empty() {
-                socks.write_all(extra_part).await?;
+        Err(e) => {
+            // The configuration is unusable, so --quiet is peeked from the raw arguments
+            if !std::env::args().any(|arg| arg == "--quiet" || arg == "-q") {
+                tracing_subscriber::fmt::init();
             }
-
-            proxy_data(client, socks).await?;
-        } else {
-            warn!("Failed to parse CONNECT request");
-            client
-                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
-                .await?;
+            Err(e)
         }
-    } else {
-        // Handle regular HTTP request
-        if let Some((method, host, port, path)) = parse_http_request(header_part) {
-            Span::current().record("target", format!("{}:{}", host, port));
-            Span::current().record("mode", "HTTP");
-
-            let mut socks = connect_socks5(&host, port, socks_addr).await?;
-
-            // Rewrite request to absolute-form or original request line
-            let new_request_line = format!("{method} {path} HTTP/1.1\r\n");
-            let mut modified_request = header_part.to_vec();
-            modified_request.splice(..first_line_len(header_part), new_request_line.bytes());
-
-            socks.write_all(&modified_request).await?;
-
-            // Forward any body that might have been read
-            if !extra_part.is_empty() {
-                socks.write_all(extra_part).await?;
-            }
+    };
 
-            proxy_data(client, socks).await?;
-        } else {
-            client
-                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
-                .await?;
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            // Final machine-readable line for supervisors and wrapper scripts
+            eprintln!("{}", e.summary_json());
+            e.into()
         }
     }
-
-    Ok(())
-}
-
-fn is_connect_request(buffer: &[u8]) -> bool {
-    String::from_utf8_lossy(buffer).starts_with("CONNECT")
 }
 
-fn parse_http_request(buffer: &[u8]) -> Option<(String, String, u16, String)> {
-    let request = String::from_utf8_lossy(buffer);
-    let lines: Vec<&str> = request.split("\r\n").collect();
-    let first_line = lines[0];
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-
-    if parts.len() != 3 {
-        return None;
-    }
-
-    let method = parts[0].to_string();
-    let uri = parts[1];
-
-    // Find Host header
-    let host_header = lines
-        .iter()
-        .find(|line| line.to_lowercase().starts_with("host:"))?
-        .split(':')
-        .nth(1)?
-        .trim();
-
-    // Parse host and port from Host header
-    let (host, port) = if let Some(idx) = host_header.rfind(':') {
-        let (h, p) = host_header.split_at(idx);
-        (h.to_string(), p[1..].parse().unwrap_or(80))
-    } else {
-        (host_header.to_string(), 80)
+// Logs to stderr, or appends to `log_file` and rotates it, at the level RUST_LOG asks for
+// (default: info). The admin endpoint may change the filter later. A log file that cannot
+// be opened is reported on stderr.
+fn init_logging(log_file: Option<&Path>, rotation: Rotation) -> Result<(), FatalError> {
+    let (file, result) = match log_file {
+        None => (None, Ok(())),
+        Some(path) => match RotatingFile::open(path, rotation) {
+            Ok(file) => (Some(file), Ok(())),
+            Err(e) => (
+                None,
+                Err(FatalError::Config(format!(
+                    "--log-file {}: {e}",
+                    path.display()
+                ))),
+            ),
+        },
     };
 
-    Some((method, host, port, uri.to_string()))
-}
-
-fn first_line_len(buffer: &[u8]) -> usize {
-    if let Some(pos) = buffer.windows(2).position(|w| w == b"\r\n") {
-        pos + 2
-    } else {
-        0
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let current = filter.to_string();
+    let builder = tracing_subscriber::fmt();
+    let builder = match file {
+        Some(file) => builder
+            .with_writer(BoxMakeWriter::new(Mutex::new(file)))
+            .with_ansi(false),
+        None => builder.with_writer(BoxMakeWriter::new(std::io::stderr)),
     }
+    .with_env_filter(filter)
+    .with_filter_reloading();
+    let handle = builder.reload_handle();
+    builder.init();
+    log_filter::install(&current, move |filter| {
+        let filter = EnvFilter::try_new(filter).map_err(|e| e.to_string())?;
+        handle.reload(filter).map_err(|e| e.to_string())
+    });
+    result
 }
 
-// Parses HTTP CONNECT request to extract target host and port
-fn parse_connect_request(buffer: &[u8]) -> Option<(String, u16)> {
-    // Convert request bytes to string
-    let request = String::from_utf8_lossy(buffer);
-    // Verify it's a CONNECT request
-    if !request.starts_with("CONNECT") {
-        return None;
-    }
-
-    // Parse request line format: "CONNECT host:port HTTP/1.x"
-    let lines: Vec<&str> = request.split("\r\n").collect();
-    let first_line = lines[0];
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-
-    // Validate request format
-    if parts.len() != 3 {
-        return None;
+// Detaches and writes the pid file if asked to, then runs until shutdown. Service control
+// runs outside any runtime; the service builds its own.
+fn start(config: Config) -> Result<(), FatalError> {
+    if let Some(Command::Service { action }) = config.command {
+        return control_service(action);
     }
-
-    // Extract host and port
-    let addr_parts: Vec<&str> = parts[1].split(':').collect();
-    if addr_parts.len() != 2 {
-        return None;
+    if config.daemon {
+        daemon::detach()?;
     }
-
-    let host = addr_parts[0].to_string();
-    let port = addr_parts[1].parse().ok()?;
-
-    Some((host, port))
+    let _pid_file = config
+        .pid_file
+        .as_deref()
+        .map(daemon::PidFile::create)
+        .transpose()?;
+    let runtime = build_runtime(config.threads)?;
+    runtime.block_on(run(config, shutdown_signal()))
 }
 
-// Establishes connection to SOCKS5 proxy server
-#[instrument(skip(socks_addr), fields(dst = %host, port = %port))]
-async fn connect_socks5(
-    host: &str,
-    port: u16,
-    socks_addr: &str,
-) -> Result<TcpStream, Box<dyn Error>> {
-    // Connect to SOCKS5 server
-    let mut socks = TcpStream::connect(socks_addr).await?;
-
-    // Perform SOCKS5 handshake
-    // Send client greeting: version 5, 1 auth method, no auth required
-    socks
-        .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_METHODS, SOCKS5_AUTH_NONE])
-        .await?;
-    let mut response = [0u8; 2];
-    socks.read_exact(&mut response).await?;
-
-    // Send connection request
-    // Format: version 5, connect command, reserved byte, dst address, dst port
-    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, SOCKS5_RSV];
+// A single-threaded runtime for --threads 1, otherwise a multi-threaded one with that many
+// workers (one per CPU by default)
+fn build_runtime(threads: Option<usize>) -> Result<tokio::runtime::Runtime, FatalError> {
+    let threads = threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+    let mut builder = if threads == 1 {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(threads);
+        builder
+    };
+    builder
+        .enable_all()
+        .build()
+        .map_err(|e| FatalError::Runtime(format!("failed to start the Tokio runtime: {e}")))
+}
 
-    // Check if host is an IP address
-    if let Ok(ip) = host.parse::<std::net::IpAddr>() {
-        match ip {
-            std::net::IpAddr::V4(ipv4) => {
-                request.push(SOCKS5_ATYP_IPV4); // IPv4 address type
-                request.extend_from_slice(&ipv4.octets());
+// Resolves when the process is asked to terminate (Ctrl-C or SIGTERM)
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
             }
-            std::net::IpAddr::V6(ipv6) => {
-                request.push(SOCKS5_ATYP_IPV6); // IPv6 address type
-                request.extend_from_slice(&ipv6.octets());
+            Err(e) => {
+                warn!("Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
             }
         }
-    } else {
-        // Domain name type
-        let addr_bytes = host.as_bytes();
-        request.push(SOCKS5_ATYP_DOMAIN); // Domain name type
-        request.push(addr_bytes.len() as u8);
-        request.extend_from_slice(addr_bytes);
     }
-    request.extend_from_slice(&port.to_be_bytes());
-    socks.write_all(&request).await?;
-
-    // Read connection response header
-    let mut header = [0u8; 4];
-    socks.read_exact(&mut header).await?;
-
-    if header[1] != SOCKS5_SUCCESS {
-        return Err("SOCKS5 connection failed".into());
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
+}
 
-    // Read variable-length address data based on atyp
-    match header[3] {
-        SOCKS5_ATYP_IPV4 => {
-            // IPv4
-            let mut addr = [0u8; 4];
-            socks.read_exact(&mut addr).await?;
-        }
-        SOCKS5_ATYP_DOMAIN => {
-            // Domain name
-            let mut len = [0u8; 1];
-            socks.read_exact(&mut len).await?;
-            let mut addr = vec![0u8; len[0] as usize];
-            socks.read_exact(&mut addr).await?;
-        }
-        SOCKS5_ATYP_IPV6 => {
-            // IPv6
-            let mut addr = [0u8; 16];
-            socks.read_exact(&mut addr).await?;
-        }
-        _ => return Err("Unknown address type".into()),
+// Runs the proxy, or the requested auxiliary tool, until `shutdown` resolves
+async fn run(config: Config, shutdown: impl Future<Output = ()>) -> Result<(), FatalError> {
+    if let Some(Command::EchoServer { listen }) = &config.command {
+        return echo::run(listen, shutdown).await;
     }
-
-    // Read port
-    let mut port = [0u8; 2];
-    socks.read_exact(&mut port).await?;
-
-    Ok(socks)
+    let summary = config.summary;
+    Proxy::from_config(config)
+        .reload_with(Config::load)
+        .run(shutdown)
+        .await?;
+    if summary {
+        println!("summary: {}", Proxy::summary());
+    }
+    Ok(())
 }
 
-// Handles forward mode - directly forwards TCP traffic to SOCKS5 proxy
-#[instrument(skip_all, fields(socks_addr = %socks_addr))]
-async fn handle_forward_client(client: TcpStream, socks_addr: &str) -> Result<(), Box<dyn Error>> {
-    // Simply connect to SOCKS5 and forward all traffic
-    let socks = TcpStream::connect(socks_addr).await.map_err(|e| {
-        error!("Failed to connect to SOCKS5 server: {}", e);
-        e
-    })?;
-
-    info!("Forwarding connection to SOCKS5 server");
-    proxy_data(client, socks).await
+#[cfg(windows)]
+fn control_service(action: ServiceAction) -> Result<(), FatalError> {
+    service::control(action)
 }
 
-// Handles bidirectional data transfer between client and SOCKS connection
-#[instrument(skip_all)]
-async fn proxy_data(mut client: TcpStream, mut socks: TcpStream) -> Result<(), Box<dyn Error>> {
-    match tokio::io::copy_bidirectional(&mut client, &mut socks).await {
-        Ok((from_client, from_socks)) => {
-            info!(
-                "Proxied {} bytes from client, {} bytes from socks",
-                from_client, from_socks
-            );
-            Ok(())
-        }
-        Err(e) => {
-            error!("Proxy data error: {}", e);
-            Err(e.into())
-        }
-    }
+// There is no service control manager to talk to
+#[cfg(not(windows))]
+fn control_service(_action: ServiceAction) -> Result<(), FatalError> {
+    Err(FatalError::Config(
+        "the service subcommand is only available on Windows".to_string(),
+    ))
 }
@@ -0,0 +1,250 @@
+use crate::error::json_escape;
+use crate::error::FatalError;
+use crate::http::{BufferedStream, RequestHead};
+use crate::stats::{DestinationStats, LatencyHistogram, DESTINATIONS, STATS};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+// Upper bounds in seconds of the buckets the latency histograms are exported with
+const BUCKET_BOUNDS: [f64; 14] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Binds the metrics listener.
+pub async fn bind(listen: &str) -> Result<TcpListener, FatalError> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|source| FatalError::Bind {
+            addr: listen.to_string(),
+            source,
+        })?;
+    info!("Metrics listening on: http://{}/metrics", listen);
+    Ok(listener)
+}
+
+// Serves the Prometheus text exposition on /metrics, and /healthz and /readyz. `ready` tells
+// whether the upstreams can be reached.
+pub async fn serve(listener: TcpListener, ready: impl Fn() -> bool + Send + Sync + 'static) {
+    let ready = Arc::new(ready);
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let ready = ready.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &*ready).await {
+                debug!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+/// The response to a liveness (`/healthz`) or readiness (`/readyz`) probe, or `None` for
+/// other paths. The process is live whenever it answers; it is ready when `ready` says so.
+pub fn probe_response(path: &str, ready: &(dyn Fn() -> bool + Send + Sync)) -> Option<String> {
+    let (status, body) = match path {
+        "/healthz" => ("200 OK", "ok\n"),
+        "/readyz" if ready() => ("200 OK", "ready\n"),
+        "/readyz" => (
+            "503 Service Unavailable",
+            "not ready: no upstream reachable\n",
+        ),
+        _ => return None,
+    };
+    Some(format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    ))
+}
+
+async fn respond(
+    stream: TcpStream,
+    ready: &(dyn Fn() -> bool + Send + Sync),
+) -> Result<(), crate::http::HeadError> {
+    let mut stream = BufferedStream::new(stream);
+    let Some(head) = stream
+        .read_head(8192, RequestHead::parse, |head| head.len)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    let path = head.target.split('?').next().unwrap_or_default();
+    let response = if let Some(response) = probe_response(path, ready) {
+        response
+    } else if path == "/metrics" {
+        let body = render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.inner.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+// Renders all counters in the Prometheus text format
+fn render() -> String {
+    let mut out = String::new();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+
+    metric(
+        "http2socks_connections_total",
+        "counter",
+        "Client connections accepted.",
+        &[("", load(&STATS.connections))],
+    );
+    metric(
+        "http2socks_rejected_connections_total",
+        "counter",
+        "Client connections refused by --max-connections or --max-per-client.",
+        &[("", load(&STATS.rejected_connections))],
+    );
+    metric(
+        "http2socks_denied_connections_total",
+        "counter",
+        "Client connections refused by --allow or --deny.",
+        &[("", load(&STATS.denied_connections))],
+    );
+    metric(
+        "http2socks_rate_limited_requests_total",
+        "counter",
+        "Requests refused by --max-requests-per-second.",
+        &[("", load(&STATS.rate_limited_requests))],
+    );
+    metric(
+        "http2socks_requests_total",
+        "counter",
+        "Requests handled, by kind.",
+        &[
+            ("{kind=\"connect\"}", load(&STATS.connect_requests)),
+            ("{kind=\"http\"}", load(&STATS.http_requests)),
+        ],
+    );
+    metric(
+        "http2socks_upstream_errors_total",
+        "counter",
+        "Tunnels that could not be opened: SOCKS connect or handshake failures.",
+        &[("", load(&STATS.upstream_errors))],
+    );
+    metric(
+        "http2socks_direct_fallbacks_total",
+        "counter",
+        "Connections made directly, without the proxy, because no upstream was reachable (--fallback-direct).",
+        &[("", load(&STATS.direct_fallbacks))],
+    );
+    metric(
+        "http2socks_errors_total",
+        "counter",
+        "Client connections that ended with an error.",
+        &[("", load(&STATS.errors))],
+    );
+    metric(
+        "http2socks_bytes_total",
+        "counter",
+        "Bytes proxied, by direction.",
+        &[
+            (
+                "{direction=\"from_client\"}",
+                load(&STATS.bytes_from_client),
+            ),
+            (
+                "{direction=\"from_upstream\"}",
+                load(&STATS.bytes_from_upstream),
+            ),
+        ],
+    );
+    metric(
+        "http2socks_active_tunnels",
+        "gauge",
+        "Currently open upstream connections.",
+        &[("", load(&STATS.active_tunnels))],
+    );
+
+    // One sample per tracked destination host
+    let destinations = DESTINATIONS.snapshot();
+    let mut per_destination = |name: &str, help: &str, value: fn(&DestinationStats) -> u64| {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for (host, stats) in &destinations {
+            let _ = writeln!(
+                out,
+                "{name}{{destination=\"{}\"}} {}",
+                json_escape(host),
+                value(stats)
+            );
+        }
+    };
+    per_destination(
+        "http2socks_destination_tunnels_total",
+        "Requests, tunnels and forwarded connections, by destination host.",
+        |stats| stats.tunnels,
+    );
+    per_destination(
+        "http2socks_destination_errors_total",
+        "Requests, tunnels and forwarded connections that failed, by destination host.",
+        |stats| stats.errors,
+    );
+    let _ = writeln!(
+        out,
+        "# HELP http2socks_destination_bytes_total Bytes proxied, by destination host and direction.\n# TYPE http2socks_destination_bytes_total counter"
+    );
+    for (host, stats) in &destinations {
+        let host = json_escape(host);
+        let _ = writeln!(
+            out,
+            "http2socks_destination_bytes_total{{destination=\"{host}\",direction=\"from_client\"}} {}\nhttp2socks_destination_bytes_total{{destination=\"{host}\",direction=\"from_upstream\"}} {}",
+            stats.bytes_from_client, stats.bytes_from_upstream
+        );
+    }
+
+    let mut latency = |name: &str, help: &str, latency: &LatencyHistogram| {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        // Buckets are cumulative, and the last one counts everything; values recorded while
+        // this is written are left out of all of them
+        let count = latency.count();
+        for bound in BUCKET_BOUNDS {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{le=\"{bound}\"}} {}",
+                latency
+                    .count_at_most(Duration::from_secs_f64(bound))
+                    .min(count)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{le=\"+Inf\"}} {count}\n{name}_sum {}\n{name}_count {count}",
+            latency.sum().as_secs_f64()
+        );
+    };
+    latency(
+        "http2socks_setup_latency_seconds",
+        "Time from request to established tunnel.",
+        &STATS.setup_latency,
+    );
+    latency(
+        "http2socks_handshake_latency_seconds",
+        "Time to connect to an upstream and complete the SOCKS handshake (or HTTP CONNECT) for a tunnel.",
+        &STATS.handshake_latency,
+    );
+    latency(
+        "http2socks_first_byte_latency_seconds",
+        "Time from sending a plain HTTP request through the tunnel to receiving the response head.",
+        &STATS.first_byte_latency,
+    );
+    out
+}
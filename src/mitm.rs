@@ -0,0 +1,225 @@
+use crate::http::{BodyLength, BufferedStream, Header, RequestHead, ResponseHead};
+use crate::proxy::timed;
+use crate::relay::{self, RelayConfig, RelayStats};
+use crate::tls;
+use rcgen::{CertificateParams, Issuer, KeyPair};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::{LazyConfigAcceptor, TlsConnector};
+use tracing::info;
+
+// Generated certificates kept before the cache is cleared
+const MAX_CACHED_CONFIGS: usize = 1024;
+
+/// TLS interception for CONNECT tunnels. Certificates for intercepted hosts are issued on
+/// the fly from a user-provided CA; origins are verified against the bundled Mozilla roots.
+pub struct Mitm {
+    issuer: Issuer<'static, KeyPair>,
+    ca_cert: CertificateDer<'static>,
+    // Every generated certificate shares this key
+    key: KeyPair,
+    configs: Mutex<HashMap<String, Arc<ServerConfig>>>,
+    origin: TlsConnector,
+}
+
+impl Mitm {
+    /// Loads the signing CA from a PEM certificate and private key.
+    pub fn load(ca: &Path, ca_key: &Path) -> Result<Self, String> {
+        let ca_error = |e: &dyn std::fmt::Display| format!("--mitm-ca {}: {e}", ca.display());
+        let key_error =
+            |e: &dyn std::fmt::Display| format!("--mitm-ca-key {}: {e}", ca_key.display());
+
+        let ca_cert = CertificateDer::from_pem_file(ca).map_err(|e| ca_error(&e))?;
+        let signing_key = std::fs::read_to_string(ca_key)
+            .map_err(|e| key_error(&e))
+            .and_then(|pem| KeyPair::from_pem(&pem).map_err(|e| key_error(&e)))?;
+        let issuer = Issuer::from_ca_cert_der(&ca_cert, signing_key).map_err(|e| ca_error(&e))?;
+        let key = KeyPair::generate().map_err(|e| format!("generating MITM key: {e}"))?;
+
+        Ok(Self {
+            issuer,
+            ca_cert,
+            key,
+            configs: Mutex::default(),
+            origin: tls::connector(None)?,
+        })
+    }
+
+    // The server side of an intercepted connection, presenting a certificate for `name`
+    fn server_config(&self, name: &str) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+        let mut configs = self.configs.lock().unwrap();
+        if let Some(config) = configs.get(name) {
+            return Ok(config.clone());
+        }
+
+        let cert =
+            CertificateParams::new(vec![name.to_string()])?.signed_by(&self.key, &self.issuer)?;
+        let key = PrivatePkcs8KeyDer::from(self.key.serialize_der()).into();
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone(), self.ca_cert.clone()], key)?;
+        // Only HTTP/1.1 can be decoded, so clients must not negotiate HTTP/2
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let config = Arc::new(config);
+        if configs.len() >= MAX_CACHED_CONFIGS {
+            configs.clear();
+        }
+        configs.insert(name.to_string(), config.clone());
+        Ok(config)
+    }
+}
+
+/// Terminates the client's TLS inside a CONNECT tunnel to `host`, logs every decrypted
+/// request and response head, and re-encrypts the traffic toward the origin over `origin`.
+/// Returns the decrypted byte counts.
+pub async fn intercept<C, O>(
+    client: C,
+    origin: O,
+    host: &str,
+    mitm: &Mitm,
+    max_header_size: usize,
+    relay_config: &RelayConfig,
+) -> Result<RelayStats, Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = LazyConfigAcceptor::new(Acceptor::default(), client).await?;
+    // Clients connecting to an IP address usually send no SNI
+    let name = start
+        .client_hello()
+        .server_name()
+        .unwrap_or(host)
+        .to_string();
+    let config = mitm.server_config(&name)?;
+    let client = start.into_stream(config).await?;
+    let server_name = ServerName::try_from(name.clone())?;
+    let origin = mitm.origin.connect(server_name, origin).await?;
+
+    let mut client = BufferedStream::new(client);
+    let mut origin = BufferedStream::new(origin);
+    let stats = exchange(
+        &mut client,
+        &mut origin,
+        &name,
+        max_header_size,
+        relay_config,
+    )
+    .await?;
+    // Best effort close_notify; the client may already be gone
+    let _ = client.inner.shutdown().await;
+    Ok(stats)
+}
+
+// Relays HTTP/1.x exchanges between the decrypted streams until either side stops
+async fn exchange<C, O>(
+    client: &mut BufferedStream<C>,
+    origin: &mut BufferedStream<O>,
+    name: &str,
+    max_header_size: usize,
+    relay_config: &RelayConfig,
+) -> Result<RelayStats, Box<dyn Error>>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    let idle = relay_config.idle_timeout;
+    let mut stats = RelayStats::default();
+    loop {
+        let request = timed(
+            idle,
+            "request",
+            client.read_head(max_header_size, RequestHead::parse, |head| head.len),
+        )
+        .await??;
+        let Some(request) = request else {
+            return Ok(stats);
+        };
+        let request_line = format!(
+            "{} {} HTTP/1.{}",
+            request.method, request.target, request.version
+        );
+        log_head(name, '>', &request_line, &request.headers);
+        let request_body = request
+            .body_length()
+            .ok_or("Invalid request body framing")?;
+
+        let head = client.consume(request.len);
+        origin.inner.write_all(&head).await?;
+        let sent = client.copy_body(&mut origin.inner, request_body).await?;
+        origin.inner.flush().await?;
+        stats.a_to_b += head.len() as u64 + sent;
+
+        // Interim responses such as 100 Continue are passed on until the final one arrives
+        let response = loop {
+            let response = timed(
+                idle,
+                "response",
+                origin.read_head(max_header_size, ResponseHead::parse, |head| head.len),
+            )
+            .await??
+            .ok_or("Origin closed the connection before responding")?;
+            let status_line = format!("HTTP/1.{} {}", response.version, response.status);
+            log_head(name, '<', &status_line, &response.headers);
+            let head = origin.consume(response.len);
+            client.inner.write_all(&head).await?;
+            stats.b_to_a += head.len() as u64;
+            if !response.is_interim() {
+                break response;
+            }
+            client.inner.flush().await?;
+        };
+
+        // After 101 Switching Protocols the connection no longer speaks HTTP
+        if response.status == 101 {
+            let to_origin = std::mem::take(&mut client.buf);
+            origin.inner.write_all(&to_origin).await?;
+            origin.inner.flush().await?;
+            let to_client = std::mem::take(&mut origin.buf);
+            client.inner.write_all(&to_client).await?;
+            client.inner.flush().await?;
+            let relayed = relay::relay(&mut client.inner, &mut origin.inner, relay_config).await?;
+            stats.a_to_b += to_origin.len() as u64 + relayed.a_to_b;
+            stats.b_to_a += to_client.len() as u64 + relayed.b_to_a;
+            return Ok(stats);
+        }
+
+        let response_body = response
+            .body_length(&request.method)
+            .ok_or("Invalid response body framing")?;
+        stats.b_to_a += origin.copy_body(&mut client.inner, response_body).await?;
+        client.inner.flush().await?;
+
+        if response_body == BodyLength::UntilClose
+            || !request.keep_alive()
+            || !response.keep_alive()
+        {
+            return Ok(stats);
+        }
+    }
+}
+
+// Logs a decrypted message head, one header per line
+fn log_head(name: &str, direction: char, first_line: &str, headers: &[Header]) {
+    let mut message = format!("{name} {direction} {first_line}");
+    for header in headers {
+        let _ = write!(
+            message,
+            "\n  {}: {}",
+            header.name,
+            String::from_utf8_lossy(&header.value)
+        );
+    }
+    info!("{}", message);
+}
@@ -0,0 +1,96 @@
+use crate::http::BufferedStream;
+use crate::http_upstream::ForwardProxy;
+use crate::limits::DestinationPermit;
+use crate::socks::UpstreamStream;
+use crate::stats::ActiveTunnel;
+use crate::upstream::Lease;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A kept-alive connection to an origin, reused while requests go to the same destination.
+pub struct Origin {
+    pub host: String,
+    pub port: u16,
+    /// `direct` or the upstream the connection goes through
+    pub upstream: String,
+    /// Set when the connection leads to an HTTP upstream that takes the requests itself
+    pub forward_proxy: Option<ForwardProxy>,
+    pub conn: BufferedStream<UpstreamStream>,
+    pub opened: Instant,
+    pub _lease: Option<Lease>,
+    /// Idle connections still count against their destination's cap
+    pub _permit: Option<DestinationPermit>,
+    pub _active: ActiveTunnel,
+}
+
+impl Origin {
+    /// Whether the connection can carry another request: a connection that the origin has
+    /// since closed, or that holds unsolicited data, is stale.
+    pub fn is_open(&self) -> bool {
+        self.conn.buf.is_empty() && self.conn.inner.is_idle()
+    }
+}
+
+/// Idle origin connections left by finished requests, shared by all clients so a later
+/// request to the same destination skips the connect and SOCKS handshake.
+pub struct OriginPool {
+    idle: Mutex<HashMap<(String, u16), Vec<Origin>>>,
+    max_idle: usize,
+    max_age: Option<Duration>,
+}
+
+impl OriginPool {
+    /// A pool keeping up to `max_idle` connections per destination (0 disables pooling).
+    /// Connections opened more than `max_age` ago are not reused.
+    pub fn new(max_idle: usize, max_age: Option<Duration>) -> Self {
+        Self {
+            idle: Mutex::default(),
+            max_idle,
+            max_age,
+        }
+    }
+
+    /// Whether `origin` is young enough to carry another request.
+    pub fn is_fresh(&self, origin: &Origin) -> bool {
+        self.max_age
+            .is_none_or(|max_age| origin.opened.elapsed() < max_age)
+    }
+
+    /// Leaves `origin` for a later request, unless it is stale or its destination already has
+    /// `max_idle` connections waiting. Stale connections of other destinations are dropped too.
+    pub fn put(&self, origin: Origin) {
+        if self.max_idle == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|_, origins| {
+            origins.retain(|origin| self.is_fresh(origin) && origin.is_open());
+            !origins.is_empty()
+        });
+        if !self.is_fresh(&origin) || !origin.is_open() {
+            return;
+        }
+        let origins = idle.entry((origin.host.clone(), origin.port)).or_default();
+        if origins.len() < self.max_idle {
+            origins.push(origin);
+        }
+    }
+
+    /// The most recently used live connection to `host:port` whose upstream satisfies
+    /// `routable`.
+    pub fn take(&self, host: &str, port: u16, routable: impl Fn(&str) -> bool) -> Option<Origin> {
+        let mut idle = self.idle.lock().unwrap();
+        let key = (host.to_string(), port);
+        let origins = idle.get_mut(&key)?;
+        origins.retain(|origin| self.is_fresh(origin) && origin.is_open());
+        let found = origins
+            .iter()
+            .rposition(|origin| routable(&origin.upstream))
+            .map(|i| origins.remove(i));
+        if origins.is_empty() {
+            idle.remove(&key);
+        }
+        found
+    }
+}
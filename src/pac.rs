@@ -0,0 +1,145 @@
+use crate::error::FatalError;
+use crate::http::{BufferedStream, RequestHead};
+use crate::routing::{HostPattern, Route, Rule};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// Path the PAC file is served at.
+pub const PATH: &str = "/proxy.pac";
+
+/// Generates a proxy auto-config script that mirrors the routing rules: destinations with a
+/// `DIRECT` rule, `--no-proxy` entries included, go straight to the origin, and everything
+/// else to `proxy`, e.g. `PROXY 127.0.0.1:8080`.
+pub fn script(rules: &[Rule], proxy: &str) -> String {
+    let mut out = String::from(
+        "// Generated by http2socks from its routing rules\n\
+         function FindProxyForURL(url, host) {\n    \
+         host = host.toLowerCase().replace(/^\\[|\\]$/g, \"\").replace(/\\.$/, \"\");\n",
+    );
+    for rule in rules {
+        let target = match &rule.route {
+            Route::Direct
+                if rule.user.is_none() && !matches!(rule.pattern, HostPattern::Country(_)) =>
+            {
+                "DIRECT"
+            }
+            // Rules naming another upstream still go through this proxy, but must keep their
+            // place so that later DIRECT rules don't shadow them. So do rules for one user,
+            // since the browser doesn't know who will log in, and country rules, which only
+            // the proxy can look up.
+            _ => proxy,
+        };
+        let _ = writeln!(
+            out,
+            "    if ({}) return \"{}\";",
+            condition(&rule.pattern),
+            target
+        );
+    }
+    let _ = writeln!(out, "    return \"{proxy}\";\n}}");
+    out
+}
+
+// A JavaScript expression matching `pattern` against the normalized `host`
+fn condition(pattern: &HostPattern) -> String {
+    match pattern {
+        HostPattern::Any => "true".to_string(),
+        HostPattern::Exact(name) => format!("host == {}", quote(name)),
+        HostPattern::Suffix {
+            suffix,
+            include_apex,
+        } => {
+            let subdomains = format!("dnsDomainIs(host, {})", quote(&format!(".{suffix}")));
+            if *include_apex {
+                format!("host == {} || {subdomains}", quote(suffix))
+            } else {
+                subdomains
+            }
+        }
+        // isInNet resolves hostnames, but rules only match address literals
+        HostPattern::Cidr(IpAddr::V4(network), prefix) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+            format!(
+                "/^[0-9.]+$/.test(host) && isInNet(host, \"{network}\", \"{}\")",
+                std::net::Ipv4Addr::from(mask)
+            )
+        }
+        HostPattern::Cidr(IpAddr::V6(address), 128) => format!("host == \"{address}\""),
+        // PAC has no portable IPv6 network test; such hosts reach the proxy, which still
+        // routes them by the rule
+        HostPattern::Cidr(IpAddr::V6(network), prefix) => {
+            format!("false /* {network}/{prefix} */")
+        }
+        HostPattern::Country(code) => format!("true /* country:{code} */"),
+    }
+}
+
+// A JavaScript string literal
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The complete HTTP response carrying `script`.
+pub fn response(script: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{script}",
+        script.len()
+    )
+}
+
+/// Binds the dedicated PAC listener.
+pub async fn bind(listen: &str) -> Result<TcpListener, FatalError> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|source| FatalError::Bind {
+            addr: listen.to_string(),
+            source,
+        })?;
+    info!("PAC file served at: http://{}{}", listen, PATH);
+    Ok(listener)
+}
+
+// Serves the PAC file on its own listener. `script` builds it for the Host header of the request.
+pub async fn serve(
+    listener: TcpListener,
+    script: impl Fn(Option<&str>) -> String + Send + Sync + 'static,
+) {
+    let script = Arc::new(script);
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let script = script.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &*script).await {
+                debug!("PAC request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(
+    stream: TcpStream,
+    script: &(dyn Fn(Option<&str>) -> String + Send + Sync),
+) -> Result<(), crate::http::HeadError> {
+    let mut stream = BufferedStream::new(stream);
+    let Some(head) = stream
+        .read_head(8192, RequestHead::parse, |head| head.len)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    let path = head.target.split('?').next().unwrap_or_default();
+    let response = if path == PATH {
+        response(&script(head.header("host")))
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.inner.write_all(response.as_bytes()).await?;
+    Ok(())
+}
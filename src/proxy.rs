@@ -0,0 +1,3581 @@
+use crate::access_log::{self, Record, Termination};
+use crate::blocklist::{self, Blocklists};
+use crate::cache::Cache;
+use crate::config::{
+    AbortMode, Bridge, Config, DnsTransport, ForwardMode, HostCheck, PortMapping, Resolve,
+};
+use crate::connector::{Connector, SocksConnector};
+use crate::dump::{Dump, DumpFiles};
+use crate::egress::Egress;
+use crate::encrypted_dns::{self, EncryptedResolver};
+use crate::error::FatalError;
+use crate::error_pages::ErrorPages;
+use crate::geoip::GeoIp;
+use crate::hooks::Hooks;
+use crate::http::{
+    self, BodyLength, BufferedStream, HeadError, HeadPace, HeaderRules, RequestHead, RequestLimits,
+    ResponseHead,
+};
+#[cfg(feature = "http3")]
+use crate::http3;
+use crate::http_upstream::{self, ForwardProxy};
+use crate::listener::ClientStream;
+use crate::origin_pool::{Origin, OriginPool};
+use crate::relay::{self, RelayConfig, RelayStats};
+use crate::routing::{self, Route};
+use crate::socks::{self, Protocol, SocksVersion, Upstream, UpstreamStream};
+use crate::stats::{self, ActiveTunnel, Stats, STATS};
+use crate::upgrade::{self, Upgrade};
+use crate::upstream::{self, Lease, UpstreamPool};
+use crate::{
+    acl, admin, auth, dns, dns_proxy, ftp, happy_eyeballs, limits, listener, metrics, mitm, pac,
+    proxy_protocol, reload, sni, socks_server, splice, statsd, throttle, tls, transparent, udp,
+};
+use clap::{CommandFactory, FromArgMatches};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, instrument, warn, Instrument, Span};
+
+// Role of the socket-activated listeners handed over in an upgrade
+const SYSTEMD_ROLE: &str = "listen systemd";
+
+// Sent when the request head exceeds --max-header-size
+const HEADERS_TOO_LARGE_RESPONSE: &[u8] =
+    b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Sent for malformed requests and ones without a usable target
+const BAD_REQUEST_RESPONSE: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Sent for HTTP/3 requests other than CONNECT, and ftp:// requests other than GET
+const NOT_IMPLEMENTED_RESPONSE: &[u8] =
+    b"HTTP/1.1 501 Not Implemented\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Sent to clients refused by --max-connections or --max-per-client
+const SERVICE_UNAVAILABLE_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Sent to clients refused by --allow or --deny, and for destinations refused by
+// --block-host or --allow-ports
+const FORBIDDEN_RESPONSE: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Sent when no tunnel to the destination could be opened
+const BAD_GATEWAY_RESPONSE: &[u8] =
+    b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Sent to clients over --max-requests-per-second
+const TOO_MANY_REQUESTS_RESPONSE: &[u8] =
+    b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Sent when a client doesn't finish its request head within --handshake-timeout
+const REQUEST_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Response header naming the request's ID with --request-id-header
+const REQUEST_ID_HEADER: &str = "X-Proxy-Request-Id";
+
+// Destination port for `--forward sni`, which only learns the host from the ClientHello
+const SNI_PORT: u16 = 443;
+
+// Pause after a failed accept before trying again, so that running out of file descriptors
+// doesn't spin the acceptor while connections close and free some
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Runtime state shared by all connections: the configuration plus everything derived from it
+struct ProxyState {
+    config: Config,
+    auth: Option<auth::ProxyAuth>,
+    tokens: Option<auth::TokenAuth>,
+    client_acl: Option<acl::ClientAcl>,
+    // Peers whose PROXY protocol headers are believed, from --proxy-protocol-from
+    proxy_protocol_peers: Option<acl::ClientAcl>,
+    destination_acl: Option<acl::DestinationAcl>,
+    blocklists: Option<Arc<Blocklists>>,
+    upstreams: Arc<UpstreamPool>,
+    router: routing::Router,
+    // Present when routing rules match destinations by country
+    geoip: Option<GeoIp>,
+    host_map: routing::HostMap,
+    header_rules: HeaderRules,
+    resolver: dns::Resolver,
+    egress: Egress,
+    error_pages: ErrorPages,
+    // Idle origin connections for plain HTTP requests, shared by all clients
+    origins: OriginPool,
+    cache: Option<Cache>,
+    access_log: Option<access_log::AccessLog>,
+    // Shared by all tunnels, one bucket for each direction
+    global_rate_limit: Option<[throttle::SharedBucket; 2]>,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    mitm: Option<mitm::Mitm>,
+    // Parsed --forward-target
+    forward_target: Option<(String, u16)>,
+    // The resolver --dns-listen passes queries on to
+    dns_server: (String, u16),
+    // Where --mode socks2http tunnels connections that no rule routes elsewhere
+    http_upstream: Option<Upstream>,
+    destination_caps: limits::DestinationCaps,
+    // The embedder's replacement for the SOCKS client, which opens tunnels through upstreams;
+    // kept across reloads
+    connector: Option<Arc<dyn Connector>>,
+    // The embedder's callbacks, likewise kept across reloads
+    hooks: Option<Arc<dyn Hooks>>,
+}
+
+impl ProxyState {
+    fn new(
+        config: Config,
+        connector: Option<Arc<dyn Connector>>,
+        hooks: Option<Arc<dyn Hooks>>,
+    ) -> Result<Self, FatalError> {
+        if config.relay_high_watermark == 0
+            || config.relay_low_watermark >= config.relay_high_watermark
+        {
+            return Err(FatalError::Config(
+                "--relay-low-watermark must be below a non-zero --relay-high-watermark".into(),
+            ));
+        }
+
+        if [&config.socks_user, &config.socks_pass]
+            .into_iter()
+            .flatten()
+            .any(|value| value.len() > 255)
+        {
+            return Err(FatalError::Config(
+                "--socks-user and --socks-pass must be at most 255 bytes".into(),
+            ));
+        }
+
+        if config.forward_target.is_some() && config.forward == Some(ForwardMode::Sni) {
+            return Err(FatalError::Config(
+                "--forward-target cannot be combined with --forward sni".into(),
+            ));
+        }
+        let forward_target = config
+            .forward_target
+            .as_deref()
+            .map(|target| {
+                http::split_host_port(target, 0)
+                    .filter(|(_, port)| *port != 0)
+                    .ok_or_else(|| {
+                        FatalError::Config(format!(
+                            "--forward-target must be HOST:PORT, got '{target}'"
+                        ))
+                    })
+            })
+            .transpose()?;
+        let dns_server = http::split_host_port(&config.dns_server, 53)
+            .filter(|(host, port)| !host.is_empty() && *port != 0)
+            .ok_or_else(|| {
+                FatalError::Config(format!(
+                    "--dns-server must be HOST[:PORT], got '{}'",
+                    config.dns_server
+                ))
+            })?;
+
+        let destination_caps = limits::DestinationCaps::load(&config.max_dest_connections)
+            .map_err(FatalError::Config)?;
+        let http_upstream = config.http_upstream().map_err(FatalError::Config)?;
+        match (config.mode, &http_upstream) {
+            (Bridge::Socks2http, None) => {
+                return Err(FatalError::Config(
+                    "--mode socks2http requires --http-upstream".into(),
+                ));
+            }
+            (Bridge::Http2socks, Some(_)) => {
+                return Err(FatalError::Config(
+                    "--http-upstream is only used with --mode socks2http".into(),
+                ));
+            }
+            _ => {}
+        }
+        if config.mode == Bridge::Socks2http
+            && (forward_target.is_some()
+                || config.forward.is_some()
+                || config.transparent
+                || config.detect_protocol
+                || config.pac
+                || config.mitm)
+        {
+            return Err(FatalError::Config(
+                "--mode socks2http cannot be combined with --forward, --forward-target, --transparent, --detect-protocol, --pac or --mitm".into(),
+            ));
+        }
+
+        let auth = auth::ProxyAuth::load(
+            &config.auth,
+            config.auth_file.as_deref(),
+            config.auth_scheme,
+        )
+        .map_err(FatalError::Config)?;
+        if config.h3_listen.is_some() && !cfg!(feature = "http3") {
+            return Err(FatalError::Config(
+                "--h3-listen needs http2socks built with the http3 feature".into(),
+            ));
+        }
+        let tokens = auth::TokenAuth::new(&config.auth_token, &config.auth_token_header)
+            .map_err(FatalError::Config)?;
+        // SOCKS5 has nowhere to carry a token, so its clients would go unchecked
+        if tokens.is_some()
+            && auth.is_none()
+            && (config.detect_protocol || config.mode == Bridge::Socks2http)
+        {
+            return Err(FatalError::Config(
+                "--auth-token can't be checked for SOCKS5 clients; add --auth or --auth-file"
+                    .into(),
+            ));
+        }
+        let client_acl =
+            acl::ClientAcl::new(&config.allow, &config.deny).map_err(FatalError::Config)?;
+        let proxy_protocol_peers =
+            acl::ClientAcl::allowing("proxy-protocol-from", &config.proxy_protocol_from)
+                .map_err(FatalError::Config)?;
+        let destination_acl =
+            acl::DestinationAcl::new(&config.block_host, config.allow_ports.as_deref())
+                .map_err(FatalError::Config)?;
+        let blocklists = Blocklists::new(&config.blocklist_file, &config.blocklist_url)
+            .map_err(FatalError::Config)?;
+        let blocklists = (!blocklists.is_empty()).then(|| Arc::new(blocklists));
+        if let Some(blocklists) = &blocklists {
+            tokio::spawn(blocklist::refresh(
+                Arc::downgrade(blocklists),
+                Duration::from_secs(config.blocklist_refresh),
+            ));
+        }
+        let upstreams = Arc::new(UpstreamPool::new(
+            config.upstreams().map_err(FatalError::Config)?,
+            config.balance,
+        ));
+        // UDP queries need a UDP ASSOCIATE, which only SOCKS5 offers
+        if config.dns_listen.is_some() && config.dns_transport == DnsTransport::Udp {
+            if let Some(upstream) = upstreams.upstreams().find(|upstream| {
+                upstream.protocol != Protocol::Socks || upstream.version != SocksVersion::V5
+            }) {
+                return Err(FatalError::Config(format!(
+                    "--dns-transport udp needs SOCKS5 upstreams, but {} is not one",
+                    upstream.addr
+                )));
+            }
+        }
+        if config.balance == upstream::Balance::Latency && config.health_check_interval == 0 {
+            return Err(FatalError::Config(
+                "--balance latency needs health checks to measure the upstreams".into(),
+            ));
+        }
+        if Self::probes_upstreams(&config) {
+            tokio::spawn(upstream::check_health(
+                Arc::downgrade(&upstreams),
+                Duration::from_secs(config.health_check_interval),
+                seconds(config.connect_timeout),
+            ));
+        }
+        let mut router = routing::Router::load(&config.rule, config.rules.as_deref())
+            .map_err(FatalError::Config)?;
+        router
+            .bypass(&config.no_proxy)
+            .map_err(FatalError::Config)?;
+        router
+            .user_routes(&config.user_route)
+            .map_err(FatalError::Config)?;
+        if router.has_user_rules() && auth.is_none() && config.tls_client_user.is_none() {
+            return Err(FatalError::Config(
+                "per-user routes and rules need --auth, --auth-file or --tls-client-user".into(),
+            ));
+        }
+        router.retry(config.connect_retry());
+        router.fast_open(config.tcp_fastopen);
+        if let Some(ca) = &config.socks_ca {
+            router.tls_connector(&tls::connector(Some(ca)).map_err(FatalError::Config)?);
+        }
+        let geoip = match &config.geoip_db {
+            Some(path) if router.has_country_rules() => Some(
+                GeoIp::open(
+                    path,
+                    config.dns_cache_size,
+                    Duration::from_secs(config.dns_cache_ttl),
+                )
+                .map_err(FatalError::Config)?,
+            ),
+            Some(_) => None,
+            None if router.has_country_rules() => {
+                return Err(FatalError::Config(
+                    "routing rules matching a country need --geoip-db".into(),
+                ))
+            }
+            None => None,
+        };
+        let host_map = routing::HostMap::load(&config.map).map_err(FatalError::Config)?;
+        let header_rules = HeaderRules::load(
+            &config.remove_header,
+            &config.set_header,
+            &config.add_header,
+        )
+        .map_err(FatalError::Config)?;
+        let encrypted_dns = match (&config.doh_url, &config.dot_server) {
+            (Some(url), _) => Some(EncryptedResolver::https(url).map_err(FatalError::Config)?),
+            (_, Some(server)) => Some(EncryptedResolver::tls(server).map_err(FatalError::Config)?),
+            _ => None,
+        };
+        let resolver = dns::Resolver::new(
+            config.dns_cache_size,
+            Duration::from_secs(config.dns_cache_ttl),
+            encrypted_dns,
+        );
+        let egress = Egress::new(config.bind_out, config.bind_interface.clone())
+            .map_err(FatalError::Config)?;
+        let error_pages = ErrorPages::load(&config.error_page, &config.error_message)
+            .map_err(FatalError::Config)?;
+        let origins = OriginPool::new(config.pool_max_idle, seconds(config.pool_max_age));
+        if let Some(dir) = &config.dump_traffic {
+            std::fs::create_dir_all(dir).map_err(|e| {
+                FatalError::Config(format!("--dump-traffic {}: {e}", dir.display()))
+            })?;
+            warn!("Dumping all client traffic to {}", dir.display());
+        }
+        let cache = config
+            .cache_size
+            .map(|size| Cache::new(size, config.cache_max_object));
+        // Reopened on every reload, so a rotated log file is picked up after SIGHUP
+        let access_log = config
+            .access_log
+            .as_deref()
+            .map(|path| {
+                access_log::AccessLog::open(path, config.log_format, config.log_rotation()).map_err(
+                    |e| FatalError::Config(format!("--access-log {}: {e}", path.display())),
+                )
+            })
+            .transpose()?;
+        let global_rate_limit = config
+            .global_rate_limit
+            .map(|rate| [(); 2].map(|_| throttle::TokenBucket::shared(rate)));
+        // Reloading also picks up a renewed certificate
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(
+                tls::acceptor(cert, key, config.tls_client_ca.as_deref())
+                    .map_err(FatalError::Config)?,
+            ),
+            _ => None,
+        };
+        let mitm = match (config.mitm, &config.mitm_ca, &config.mitm_ca_key) {
+            (true, Some(ca), Some(key)) => {
+                Some(mitm::Mitm::load(ca, key).map_err(FatalError::Config)?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            config,
+            auth,
+            tokens,
+            client_acl,
+            proxy_protocol_peers,
+            destination_acl,
+            blocklists,
+            upstreams,
+            router,
+            geoip,
+            host_map,
+            header_rules,
+            resolver,
+            egress,
+            error_pages,
+            origins,
+            cache,
+            access_log,
+            global_rate_limit,
+            tls,
+            mitm,
+            forward_target,
+            dns_server,
+            http_upstream,
+            destination_caps,
+            connector,
+            hooks,
+        })
+    }
+}
+
+impl ProxyState {
+    // Where --map sends a request for host:port instead, if anywhere
+    fn map_destination(&self, host: &str, port: u16) -> Option<(String, u16)> {
+        let mapped = self.host_map.map(host, port)?;
+        debug!("Mapping {}:{} to {}:{}", host, port, mapped.0, mapped.1);
+        Some(mapped)
+    }
+
+    // Counts a finished request or connection against its destination and writes it to the
+    // access log
+    fn finish_record<T, E: std::fmt::Display>(&self, record: &mut Record, result: &Result<T, E>) {
+        record.finish(result);
+        if let Some(target) = &record.target {
+            if record.termination != Some(Termination::Rejected) {
+                let host =
+                    http::split_host_port(target, 0).map_or(target.clone(), |(host, _)| host);
+                let failed = matches!(
+                    record.termination,
+                    Some(Termination::UpstreamError | Termination::Error)
+                );
+                stats::DESTINATIONS.record(
+                    &host,
+                    record.bytes_up,
+                    record.bytes_down,
+                    failed,
+                    self.config.max_tracked_destinations,
+                );
+            }
+        }
+        if let Some(access_log) = &self.access_log {
+            access_log.write(record);
+        }
+        if let Some(hooks) = &self.hooks {
+            hooks.on_close(record);
+        }
+    }
+
+    // Checks a request's destination like `check_destination`, then lets the on_request hook
+    // veto it
+    fn check_request(&self, record: &Record, host: &str, port: u16) -> Result<(), String> {
+        self.check_destination(host, port)?;
+        match &self.hooks {
+            Some(hooks) => hooks.on_request(record).inspect_err(|reason| {
+                warn!("Refusing connection to {}:{}: {}", host, port, reason);
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Lets the on_accept hook turn away a client
+    fn accept_hook(&self, client: SocketAddr) -> Result<(), String> {
+        match &self.hooks {
+            Some(hooks) => hooks.on_accept(client),
+            None => Ok(()),
+        }
+    }
+
+    // Records the setup latency of a request whose destination connection just opened and
+    // tells the on_connect_established hook
+    fn connected(&self, record: &Record) {
+        STATS.setup_latency.record(record.started.elapsed());
+        if let Some(hooks) = &self.hooks {
+            hooks.on_connect_established(record);
+        }
+    }
+
+    // Checks a destination against --block-host, --allow-ports and the blocklists, logging
+    // refusals
+    fn check_destination(&self, host: &str, port: u16) -> Result<(), String> {
+        let mut result = match &self.destination_acl {
+            Some(acl) => acl.check(host, port),
+            None => Ok(()),
+        };
+        if let (Ok(()), Some(blocklists)) = (&result, &self.blocklists) {
+            if blocklists.matches(host) {
+                result = Err(format!("{host} is on a blocklist"));
+            }
+        }
+        result.inspect_err(|reason| {
+            warn!("Blocking connection to {}:{}: {}", host, port, reason);
+        })
+    }
+
+    // Checks the token or credentials a request carries, returning the user they name if
+    // any, or the 407 response to refuse the request with
+    fn authenticate(&self, head: &RequestHead) -> Result<Option<String>, String> {
+        // A valid token stands in for credentials; without --auth it is required
+        let token_valid = match &self.tokens {
+            Some(tokens) if tokens.authorize(head.header(tokens.header())) => true,
+            Some(tokens) if self.auth.is_none() => {
+                warn!("Rejecting request without a valid token");
+                return Err(tokens.challenge());
+            }
+            _ => false,
+        };
+        let Some(auth) = self.auth.as_ref().filter(|_| !token_valid) else {
+            return Ok(None);
+        };
+        match auth.authorize(
+            &head.method,
+            &head.target,
+            head.header("proxy-authorization"),
+        ) {
+            Ok(user) => Ok(Some(user.to_string())),
+            Err(denied) => {
+                if denied.stale {
+                    debug!("Asking the client to retry with a fresh Digest nonce");
+                } else {
+                    warn!("Rejecting request without valid proxy credentials");
+                }
+                Err(auth.challenge(denied))
+            }
+        }
+    }
+
+    // The user named by the client's certificate, with --tls-client-user
+    fn certificate_user(&self, client: &ClientStream) -> Option<String> {
+        tls::certificate_user(client.peer_certificate()?, self.config.tls_client_user?)
+    }
+
+    // Whether `client` may make another request under --max-requests-per-second
+    fn within_request_rate(&self, client: SocketAddr) -> bool {
+        let Some(per_second) = self.config.max_requests_per_second else {
+            return true;
+        };
+        if limits::REQUEST_RATES.try_acquire(client.ip(), per_second) {
+            return true;
+        }
+        Stats::inc(&STATS.rate_limited_requests);
+        warn!(
+            "Refusing request from {}: over {} requests per second",
+            client.ip(),
+            per_second
+        );
+        false
+    }
+
+    // The country of `host` when routing rules ask for it
+    async fn country(&self, host: &str) -> Option<String> {
+        self.geoip.as_ref()?.country(&self.resolver, host).await
+    }
+
+    // Whether the routing would send a new tunnel by `user` to `host` in `country` through
+    // `upstream`, so that a pooled connection through it may serve the request instead
+    fn routes_through(
+        &self,
+        user: Option<&str>,
+        host: &str,
+        country: Option<&str>,
+        upstream: &str,
+    ) -> bool {
+        match self.router.route(user, host, country) {
+            Some(Route::Direct) => upstream == "direct",
+            Some(Route::Upstream(rule_upstream)) => rule_upstream.addr == upstream,
+            None => self
+                .upstreams
+                .upstreams()
+                .any(|pooled| pooled.addr == upstream),
+        }
+    }
+
+    // Only a choice between several upstreams gains from knowing which are down, unless
+    // /readyz reports whether they can be reached
+    fn probes_upstreams(config: &Config) -> bool {
+        config.health_check_interval > 0
+            && config.mode == Bridge::Http2socks
+            && (config.socks.len() > 1
+                || config.admin_listen.is_some()
+                || config.metrics_listen.is_some())
+    }
+
+    // Whether a health check reached an upstream within the last interval, give or take the
+    // time a round of probes takes. Without health checks there is nothing to wait for.
+    fn is_ready(&self) -> bool {
+        if !Self::probes_upstreams(&self.config) {
+            return true;
+        }
+        let interval = Duration::from_secs(self.config.health_check_interval);
+        let probe_time = seconds(self.config.connect_timeout).unwrap_or(interval);
+        upstream::reachable_within(interval + probe_time)
+    }
+
+    // The PAC file for clients that reached us as `request_host`, pointing them at the
+    // first listen address, or at the host they used when that address is a wildcard
+    fn pac_script(&self, request_host: Option<&str>) -> String {
+        let (mut host, port) = http::split_host_port(&self.config.listen[0], 8080)
+            .unwrap_or_else(|| ("127.0.0.1".to_string(), 8080));
+        if host.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified()) {
+            if let Some((request_host, _)) = request_host.and_then(|h| http::split_host_port(h, 0))
+            {
+                host = request_host;
+            }
+        }
+        if host.contains(':') {
+            host = format!("[{host}]");
+        }
+        let kind = if self.tls.is_some() { "HTTPS" } else { "PROXY" };
+        pac::script(self.router.rules(), &format!("{kind} {host}:{port}"))
+    }
+
+    // Whether clients are forwarded without HTTP handling
+    fn forwarding(&self) -> bool {
+        self.config.forward.is_some() || self.forward_target.is_some() || self.config.transparent
+    }
+
+    // Whether a refused client can be told so with a plain HTTP response, rather than having
+    // its connection closed: not in forward mode, behind TLS or to SOCKS5 clients
+    fn answers_refusals(&self) -> bool {
+        !self.forwarding() && self.tls.is_none() && self.config.mode == Bridge::Http2socks
+    }
+
+    // `response` with the --error-page or --error-message body for its status, if one is
+    // set, telling the client `reason`, and the ID of the request it answers, if any
+    fn error_page<'a>(
+        &self,
+        response: &'a [u8],
+        reason: &str,
+        request_id: Option<&str>,
+    ) -> Cow<'a, [u8]> {
+        let page = self
+            .error_pages
+            .render(response, reason, request_id.unwrap_or("-"));
+        match request_id {
+            Some(id) if self.config.request_id_header => {
+                Cow::Owned(http::insert_header(&page, REQUEST_ID_HEADER, id))
+            }
+            _ => page,
+        }
+    }
+
+    // `response`, or the head of one, that the proxy sends or relays for the request of
+    // `record`, with the --request-id-header header
+    fn tag_response<'a>(&self, response: &'a [u8], record: &Record) -> Cow<'a, [u8]> {
+        if self.config.request_id_header {
+            Cow::Owned(http::insert_header(
+                response,
+                REQUEST_ID_HEADER,
+                &record.request_id,
+            ))
+        } else {
+            Cow::Borrowed(response)
+        }
+    }
+
+    // Applies --buffer-size, --tcp-nodelay and --tcp-keepalive to a client or upstream socket
+    fn tune_socket(&self, stream: &TcpStream) {
+        let config = &self.config;
+        let socket = socket2::SockRef::from(stream);
+        let mut result = Ok(());
+        if let Some(size) = config.buffer_size {
+            result = result
+                .and_then(|()| socket.set_recv_buffer_size(size))
+                .and_then(|()| socket.set_send_buffer_size(size));
+        }
+        if config.tcp_nodelay {
+            result = result.and_then(|()| socket.set_tcp_nodelay(true));
+        }
+        if let Some(idle) = config.tcp_keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(Duration::from_secs(idle));
+            result = result.and_then(|()| socket.set_tcp_keepalive(&keepalive));
+        }
+        if let Err(e) = result {
+            warn!("Failed to set socket options: {}", e);
+        }
+    }
+
+    fn relay_config(&self) -> RelayConfig {
+        let config = &self.config;
+        RelayConfig {
+            high_watermark: config.relay_high_watermark,
+            low_watermark: config.relay_low_watermark,
+            idle_timeout: seconds(config.idle_timeout),
+            rate_limit: config.rate_limit,
+            global_rate_limit: self.global_rate_limit.clone(),
+            progress: None,
+            max_bytes: (config.max_bytes_per_connection > 0)
+                .then_some(config.max_bytes_per_connection),
+            max_duration: seconds(config.max_tunnel_duration),
+            stop: None,
+        }
+    }
+
+    // The relay settings for the tunnel described by `record`, which is listed on the admin
+    // endpoint, and can be closed from it, while the returned registration is held
+    fn tracked_relay(&self, record: &Record) -> (Option<admin::Registration>, RelayConfig) {
+        let mut relay_config = self.relay_config();
+        if self.config.admin_listen.is_none() {
+            // The byte quota is checked against the relay's progress
+            if relay_config.max_bytes.is_some() {
+                relay_config.progress = Some(Arc::default());
+            }
+            return (None, relay_config);
+        }
+        let (registration, progress, stop) = admin::TUNNELS.register(record);
+        relay_config.progress = Some(progress);
+        relay_config.stop = Some(stop);
+        (Some(registration), relay_config)
+    }
+
+    // Opens tunnels through upstreams
+    fn connector(&self) -> &dyn Connector {
+        self.connector.as_deref().unwrap_or(&SocksConnector)
+    }
+
+    // Checks every upstream the state may route to; --socks servers are unused when
+    // socks2http sends connections to the HTTP proxy instead. Upstreams reached through
+    // the embedder's connector may not be reachable by the SOCKS client at all, so they are
+    // left to it.
+    async fn validate_upstreams(&self) -> Result<(), FatalError> {
+        if self.connector.is_some() {
+            debug!("Skipping upstream validation for the custom connector");
+            return Ok(());
+        }
+        let pooled = self
+            .http_upstream
+            .is_none()
+            .then(|| self.upstreams.upstreams())
+            .into_iter()
+            .flatten();
+        let upstreams = pooled
+            .chain(self.http_upstream.iter())
+            .chain(self.router.upstreams());
+        for upstream in upstreams {
+            validate_upstream(upstream, self.config.check_upstream).await?;
+        }
+        Ok(())
+    }
+}
+
+// A timeout option in seconds, where 0 means no timeout
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+// Runs `future` under an optional timeout, failing with `TimedOut` naming `what` when it expires
+pub(crate) async fn timed<F: std::future::Future>(
+    timeout: Option<Duration>,
+    what: &str,
+    future: F,
+) -> std::io::Result<F::Output> {
+    let Some(timeout) = timeout else {
+        return Ok(future.await);
+    };
+    tokio::time::timeout(timeout, future).await.map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("{what} timed out after {}s", timeout.as_secs()),
+        )
+    })
+}
+
+/// Whether clients speak HTTP or SOCKS5 to the proxy, or have their TCP streams forwarded as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// HTTP proxy: CONNECT tunnels and plain HTTP requests
+    Http,
+    /// Forward raw TCP connections to the SOCKS server without HTTP handling
+    Forward,
+    /// Forward TLS connections to the host named in their ClientHello (SNI), port 443
+    ForwardSni,
+    /// SOCKS5 proxy: CONNECT requests tunnelled through the HTTP proxy set with
+    /// `configure(|config| config.http_upstream = ...)`
+    Socks2Http,
+}
+
+// Produces the configuration to switch to on reload
+type Reloader = Box<dyn Fn() -> Result<Config, FatalError> + Send + Sync>;
+
+/// An HTTP to SOCKS proxy server.
+///
+/// Build one with [`Proxy::builder`] for the common options, or from a full [`Config`]
+/// with [`Proxy::from_config`], then drive it with [`Proxy::run`] on a Tokio runtime.
+///
+/// Only one `Proxy` per process is supported. Some of its state lives in process-wide
+/// statics rather than in the instance: the statistics and per-destination counters behind
+/// the admin endpoint and `/metrics`, the tunnel list the admin endpoint shows and closes,
+/// the per-client request rates of `--max-requests-per-second`, the open connections counted
+/// against `--max-dest-connections`, and when a health check last reached an
+/// upstream. Two proxies in one process would share all of these, mixing their metrics and
+/// counting each other's clients against their limits.
+pub struct Proxy {
+    config: Config,
+    reloader: Option<Reloader>,
+    connector: Option<Arc<dyn Connector>>,
+    hooks: Option<Arc<dyn Hooks>>,
+}
+
+/// Builder for a [`Proxy`], starting from the command line defaults.
+pub struct ProxyBuilder {
+    config: Config,
+    // The first upstream replaces the default SOCKS address instead of adding to it
+    upstream_set: bool,
+    // Likewise for the first listen address
+    listen_set: bool,
+    connector: Option<Arc<dyn Connector>>,
+    hooks: Option<Arc<dyn Hooks>>,
+}
+
+impl Proxy {
+    pub fn builder() -> ProxyBuilder {
+        // Environment variables are for the CLI; an embedded proxy only gets what it is given
+        let command = Config::command().mut_args(|arg| arg.env(None));
+        let matches = command
+            .try_get_matches_from(["http2socks"])
+            .expect("the default options are valid");
+        ProxyBuilder {
+            config: Config::from_arg_matches(&matches).expect("the default options are valid"),
+            upstream_set: false,
+            listen_set: false,
+            connector: None,
+            hooks: None,
+        }
+    }
+
+    /// A proxy using every option in `config`, e.g. as parsed by [`Config::load`].
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            reloader: None,
+            connector: None,
+            hooks: None,
+        }
+    }
+
+    /// Opens tunnels through the upstreams with `connector` instead of the SOCKS client.
+    pub fn connector(mut self, connector: impl Connector) -> Self {
+        self.connector = Some(Arc::new(connector));
+        self
+    }
+
+    /// Calls `hooks` as connections are accepted, connected and closed.
+    pub fn hooks(mut self, hooks: impl Hooks) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Re-reads the configuration with `reload` on SIGHUP, and when `--watch` is set,
+    /// whenever the config, rules or auth file changes. Without a reloader the proxy
+    /// leaves SIGHUP alone.
+    pub fn reload_with(
+        mut self,
+        reload: impl Fn() -> Result<Config, FatalError> + Send + Sync + 'static,
+    ) -> Self {
+        self.reloader = Some(Box::new(reload));
+        self
+    }
+
+    /// Validates the configuration, binds the listeners and serves connections until
+    /// `shutdown` resolves.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> Result<(), FatalError> {
+        let reloader = self.reloader;
+        let mut state = Arc::new(ProxyState::new(self.config, self.connector, self.hooks)?);
+        state.validate_upstreams().await?;
+        let config = &state.config;
+        let mut upgrade = Upgrade::start(config.upgrade_socket.as_deref())?;
+        // Under systemd socket activation the socket unit owns the listening addresses; a
+        // process started by an upgrade gets them from the one it replaces
+        let activated = match listener::activated()? {
+            Some(listeners) => Some(listeners),
+            None => {
+                let listeners = upgrade
+                    .tcp_listeners(SYSTEMD_ROLE, async { Ok(Vec::new()) })
+                    .await?;
+                (!listeners.is_empty()).then_some(listeners)
+            }
+        };
+        let from_systemd = activated.is_some();
+        let (listeners, listen) = match activated {
+            Some(listeners) => {
+                let addrs: Vec<String> = listeners
+                    .iter()
+                    .filter_map(|listener| listener.local_addr().ok())
+                    .map(|addr| addr.to_string())
+                    .collect();
+                let listen = format!("{} (from systemd)", addrs.join(", "));
+                for listener in &listeners {
+                    upgrade.keep(SYSTEMD_ROLE, socket2::SockRef::from(listener))?;
+                }
+                (listeners, listen)
+            }
+            None => {
+                let mut listeners = Vec::new();
+                for listen in &config.listen {
+                    let bind = listener::bind(listen, config.acceptors, config.transparent);
+                    listeners.extend(
+                        upgrade
+                            .tcp_listeners(&format!("listen {listen}"), bind)
+                            .await?,
+                    );
+                }
+                (listeners, config.listen.join(", "))
+            }
+        };
+        let mut mapping_listeners = Vec::new();
+        for mapping in &config.forward_port {
+            let mapping =
+                PortMapping::parse("--forward-port", mapping).map_err(FatalError::Config)?;
+            let bind = listener::bind(&mapping.listen, config.acceptors, false);
+            let bound = upgrade
+                .tcp_listeners(&format!("forward-port {}", mapping.listen), bind)
+                .await?;
+            info!(
+                "Forwarding {} to {}:{} through SOCKS",
+                mapping.listen, mapping.host, mapping.port
+            );
+            let mapping = Arc::new(mapping);
+            mapping_listeners.extend(
+                bound
+                    .into_iter()
+                    .map(|listener| (listener, mapping.clone())),
+            );
+        }
+        if config.tcp_fastopen {
+            let mapped = mapping_listeners.iter().map(|(listener, _)| listener);
+            for listener in listeners.iter().chain(mapped) {
+                if let Err(e) = listener::set_fast_open(listener) {
+                    warn!("Cannot enable TCP Fast Open on the listener: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let metrics_listener = match &config.metrics_listen {
+            Some(metrics_listen) => Some(
+                upgrade
+                    .tcp_listener(
+                        &format!("metrics {metrics_listen}"),
+                        metrics::bind(metrics_listen),
+                    )
+                    .await?,
+            ),
+            None => None,
+        };
+
+        if let Some(statsd) = &config.statsd {
+            tokio::spawn(statsd::push(
+                statsd::connect(statsd).await?,
+                config.statsd_prefix.clone(),
+                Duration::from_secs(config.statsd_interval),
+            ));
+        }
+
+        #[cfg(feature = "http3")]
+        let h3_endpoint = match (&config.h3_listen, &config.tls_cert, &config.tls_key) {
+            (Some(h3_listen), Some(cert), Some(key)) => {
+                let tls = tls::server_config(cert, key, config.tls_client_ca.as_deref())
+                    .map_err(FatalError::Config)?;
+                Some(http3::bind(h3_listen, tls, &mut upgrade)?)
+            }
+            _ => None,
+        };
+
+        // Servers beside the acceptors, stopped when a new process takes over
+        let mut servers = Vec::new();
+        if let Some(udp_listen) = &config.udp_listen {
+            let socket = upgrade
+                .udp_socket(&format!("udp {udp_listen}"), udp::bind(udp_listen))
+                .await?;
+            servers.push(tokio::spawn(udp::run_relay(
+                socket,
+                state.upstreams.clone(),
+                Duration::from_secs(config.udp_timeout),
+            )));
+        }
+        for forward in &config.forward_udp {
+            let forward =
+                PortMapping::parse("--forward-udp", forward).map_err(FatalError::Config)?;
+            let role = format!("forward-udp {}", forward.listen);
+            let socket = upgrade
+                .udp_socket(&role, udp::bind(&forward.listen))
+                .await?;
+            servers.push(tokio::spawn(udp::run_forward(
+                socket,
+                forward,
+                state.upstreams.clone(),
+                Duration::from_secs(config.udp_timeout),
+            )));
+        }
+
+        if config.mode == Bridge::Socks2http {
+            info!("SOCKS5 proxy listening on: {}", listen);
+            info!(
+                "Tunnelling through HTTP proxy: {}",
+                config.http_upstream.as_deref().unwrap_or_default()
+            );
+        } else if config.transparent {
+            info!("Transparent proxy listening on: {}", listen);
+        } else if let Some(target) = &config.forward_target {
+            info!("TCP forward mode listening on: {}", listen);
+            info!("Forwarding all traffic to {} through SOCKS", target);
+        } else if config.forward == Some(ForwardMode::Sni) {
+            info!("SNI forward mode listening on: {}", listen);
+        } else if config.forward.is_some() {
+            info!("TCP forward mode listening on: {}", listen);
+            info!(
+                "Forwarding all traffic to SOCKS5: {}",
+                config.socks.join(", ")
+            );
+        } else {
+            info!("HTTP proxy listening on: {}", listen);
+        }
+        if config.acceptors > 1 && !from_systemd {
+            info!(
+                "Accepting on {} SO_REUSEPORT sockets per address",
+                config.acceptors
+            );
+        }
+
+        let mut reload =
+            match &reloader {
+                Some(_) => Some(reload::ReloadTrigger::new(config.watched_files()).map_err(
+                    |e| FatalError::Runtime(format!("failed to install SIGHUP handler: {e}")),
+                )?),
+                None => None,
+            };
+        tokio::pin!(shutdown);
+
+        // Acceptors pick up the current state for each new connection
+        let (current, watched_state) = tokio::sync::watch::channel(state.clone());
+        if let Some(admin_listen) = &config.admin_listen {
+            let watched_state = watched_state.clone();
+            let ready_state = watched_state.clone();
+            let listener = upgrade
+                .tcp_listener(&format!("admin {admin_listen}"), admin::bind(admin_listen))
+                .await?;
+            servers.push(tokio::spawn(admin::serve(
+                listener,
+                move || admin::config_json(&watched_state.borrow().config),
+                move || ready_state.borrow().is_ready(),
+            )));
+        }
+        if let Some(metrics_listener) = metrics_listener {
+            let watched_state = watched_state.clone();
+            servers.push(tokio::spawn(metrics::serve(metrics_listener, move || {
+                watched_state.borrow().is_ready()
+            })));
+        }
+        if let Some(dns_listen) = &config.dns_listen {
+            let watched_state = watched_state.clone();
+            let socket = upgrade
+                .udp_socket(&format!("dns udp {dns_listen}"), udp::bind(dns_listen))
+                .await?;
+            let listener = upgrade
+                .tcp_listener(
+                    &format!("dns tcp {dns_listen}"),
+                    dns_proxy::bind_tcp(dns_listen),
+                )
+                .await?;
+            servers.push(tokio::spawn(dns_proxy::serve(
+                socket,
+                listener,
+                move |query| {
+                    let state = watched_state.borrow().clone();
+                    resolve_dns(state, query)
+                },
+            )));
+        }
+        if let Some(pac_listen) = &config.pac_listen {
+            let watched_state = watched_state.clone();
+            let listener = upgrade
+                .tcp_listener(&format!("pac {pac_listen}"), pac::bind(pac_listen))
+                .await?;
+            servers.push(tokio::spawn(pac::serve(listener, move |host| {
+                watched_state.borrow().pac_script(host)
+            })));
+        }
+        #[cfg(feature = "http3")]
+        if let Some(endpoint) = h3_endpoint {
+            let watched_state = watched_state.clone();
+            servers.push(tokio::spawn(http3::serve(
+                endpoint,
+                move |peer, request| {
+                    let state = watched_state.borrow().clone();
+                    handle_h3_request(state, peer, request)
+                },
+            )));
+        }
+        let limiter = Arc::new(limits::ConnectionLimiter::default());
+        let mut acceptors = tokio::task::JoinSet::new();
+        for listener in listeners {
+            acceptors.spawn(accept_loop(
+                listener,
+                watched_state.clone(),
+                limiter.clone(),
+                None,
+            ));
+        }
+        for (listener, mapping) in mapping_listeners {
+            acceptors.spawn(accept_loop(
+                listener,
+                watched_state.clone(),
+                limiter.clone(),
+                Some(mapping),
+            ));
+        }
+        let handed_over = upgrade.serve();
+        tokio::pin!(handed_over);
+        let mut upgrade_signal = upgrade::UpgradeSignal::new(config.upgrade_socket.is_some())?;
+
+        loop {
+            let triggered = async {
+                match &mut reload {
+                    Some(reload) => reload.triggered().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                reason = triggered => {
+                    let Some(reloader) = &reloader else { continue };
+                    match reload_state(&state, reloader).await {
+                        Ok(reloaded) => {
+                            state = Arc::new(reloaded);
+                            current.send_replace(state.clone());
+                            if let Some(reload) = &mut reload {
+                                reload.set_watch(state.config.watched_files());
+                            }
+                            info!("Configuration reloaded ({}); existing connections are unaffected", reason);
+                        }
+                        Err(e) => warn!("Configuration reload ({}) failed, keeping the current one: {}", reason, e),
+                    }
+                }
+                Some(result) = acceptors.join_next() => {
+                    return Err(result.unwrap_or_else(|e| FatalError::Runtime(format!("acceptor failed: {e}"))));
+                }
+                () = upgrade_signal.recv() => match upgrade::spawn_successor() {
+                    Ok(pid) => info!("Started process {} to take over (SIGUSR2)", pid),
+                    Err(e) => warn!("Failed to start a new process for the upgrade: {}", e),
+                },
+                () = &mut handed_over => {
+                    acceptors.abort_all();
+                    for server in &servers {
+                        server.abort();
+                    }
+                    drain(&limiter, Duration::from_secs(state.config.drain_timeout)).await;
+                    break;
+                }
+                _ = &mut shutdown => break,
+            }
+        }
+
+        info!("Shutting down");
+        Ok(())
+    }
+
+    /// One line of totals since the process started: connections, errors, bytes each way and
+    /// setup latency percentiles, as `--summary` prints on shutdown.
+    pub fn summary() -> String {
+        STATS.summary()
+    }
+}
+
+impl ProxyBuilder {
+    /// Adds an address to accept clients on; the first call replaces the default
+    /// 127.0.0.1:8080.
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        if !self.listen_set {
+            self.config.listen.clear();
+            self.listen_set = true;
+        }
+        self.config.listen.push(addr.into());
+        self
+    }
+
+    /// Adds a SOCKS server to tunnel through; the first call replaces the default
+    /// 127.0.0.1:1080. Several upstreams are balanced round-robin.
+    pub fn upstream(mut self, addr: impl Into<String>) -> Self {
+        if !self.upstream_set {
+            self.config.socks.clear();
+            self.upstream_set = true;
+        }
+        self.config.socks.push(addr.into());
+        self
+    }
+
+    /// SOCKS protocol version spoken to the upstreams (default: SOCKS5).
+    pub fn socks_version(mut self, version: SocksVersion) -> Self {
+        self.config.socks_version = version;
+        self
+    }
+
+    /// Username and password for RFC 1929 authentication to the upstreams.
+    pub fn socks_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.config.socks_user = Some(username.into());
+        self.config.socks_pass = Some(password.into());
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.config.forward = match mode {
+            Mode::Http | Mode::Socks2Http => None,
+            Mode::Forward => Some(ForwardMode::Raw),
+            Mode::ForwardSni => Some(ForwardMode::Sni),
+        };
+        self.config.mode = match mode {
+            Mode::Socks2Http => Bridge::Socks2http,
+            _ => Bridge::Http2socks,
+        };
+        self
+    }
+
+    /// Gives access to the remaining options.
+    pub fn configure(mut self, configure: impl FnOnce(&mut Config)) -> Self {
+        configure(&mut self.config);
+        self
+    }
+
+    /// Opens tunnels through the upstreams with `connector` instead of the SOCKS client,
+    /// e.g. to carry them over an SSH channel or in-memory streams.
+    pub fn connector(mut self, connector: impl Connector) -> Self {
+        self.connector = Some(Arc::new(connector));
+        self
+    }
+
+    /// Calls `hooks` as connections are accepted, connected and closed, e.g. for custom
+    /// logging, quotas or policy.
+    pub fn hooks(mut self, hooks: impl Hooks) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    pub fn build(self) -> Proxy {
+        let proxy = Proxy::from_config(self.config);
+        Proxy {
+            connector: self.connector.or(proxy.connector),
+            hooks: self.hooks,
+            ..proxy
+        }
+    }
+}
+
+// Waits up to `timeout` for the open connections to finish after a new process took over
+async fn drain(limiter: &limits::ConnectionLimiter, timeout: Duration) {
+    info!(
+        "Handed the listeners over; draining {} open connections",
+        limiter.open()
+    );
+    let deadline = Instant::now() + timeout;
+    while limiter.open() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    if limiter.open() > 0 {
+        warn!(
+            "Closing {} connections still open after --drain-timeout",
+            limiter.open()
+        );
+    }
+}
+
+// Builds a fresh state for new connections from the reloaded configuration
+async fn reload_state(current: &ProxyState, reloader: &Reloader) -> Result<ProxyState, FatalError> {
+    let state = ProxyState::new(
+        reloader()?,
+        current.connector.clone(),
+        current.hooks.clone(),
+    )?;
+    state.validate_upstreams().await?;
+
+    let (old, new) = (&current.config, &state.config);
+    if old.listen != new.listen
+        || old.udp_listen != new.udp_listen
+        || old.forward_udp != new.forward_udp
+        || old.forward_port != new.forward_port
+        || old.dns_listen != new.dns_listen
+        || old.h3_listen != new.h3_listen
+        || old.udp_timeout != new.udp_timeout
+        || old.threads != new.threads
+        || old.acceptors != new.acceptors
+        || old.transparent != new.transparent
+        || old.upgrade_socket != new.upgrade_socket
+    {
+        warn!(
+            "--listen, --acceptors, --transparent, --forward-port, --udp-listen, --forward-udp, --dns-listen, --udp-timeout, --h3-listen, --upgrade-socket and --threads changes take effect after a restart"
+        );
+    }
+    Ok(state)
+}
+
+// Whether a failed accept says nothing about the listener itself: the connection was gone
+// before it could be taken, or the process or system ran out of descriptors or memory for it
+fn is_transient_accept_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    if matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::OutOfMemory
+    ) {
+        return true;
+    }
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        return [
+            libc::EMFILE,
+            libc::ENFILE,
+            libc::ENOBUFS,
+            libc::ENOMEM,
+            libc::EPROTO,
+        ]
+        .contains(&code);
+    }
+    false
+}
+
+// Accepts connections on one listener for as long as the proxy runs, serving each with
+// whatever state is current when it arrives
+async fn accept_loop(
+    listener: TcpListener,
+    state: tokio::sync::watch::Receiver<Arc<ProxyState>>,
+    limiter: Arc<limits::ConnectionLimiter>,
+    mapping: Option<Arc<PortMapping>>,
+) -> FatalError {
+    loop {
+        let (mut client, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) if is_transient_accept_error(&e) => {
+                error!("Failed to accept connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+            Err(e) => return FatalError::Runtime(format!("failed to accept connection: {e}")),
+        };
+        let state = state.borrow().clone();
+        if !state.config.accept_proxy_protocol {
+            admit(client, addr, state, &limiter, mapping.clone());
+            continue;
+        }
+
+        // Anyone able to send a header could claim any address, so only trusted peers may
+        let peers = state
+            .proxy_protocol_peers
+            .as_ref()
+            .or(state.client_acl.as_ref());
+        if peers.is_some_and(|peers| !peers.permits(addr.ip())) {
+            Stats::inc(&STATS.denied_connections);
+            warn!("Dropping {}: not trusted to send PROXY headers", addr);
+            continue;
+        }
+        // The total cap is held while the header is read, so that peers stalling before it
+        // can't pile up tasks; the per-client cap applies to the client the header conveys,
+        // so it can only be checked once it has been read
+        let config = &state.config;
+        let reading = match limiter.try_acquire(addr.ip(), config.max_connections, None) {
+            Ok(permit) => permit,
+            Err(refusal) => {
+                Stats::inc(&STATS.rejected_connections);
+                refuse_connection(client, addr, refusal, &state, false);
+                continue;
+            }
+        };
+        let limiter = limiter.clone();
+        let mapping = mapping.clone();
+        tokio::spawn(async move {
+            let timeout = seconds(state.config.handshake_timeout);
+            let header = proxy_protocol::read_header(&mut client);
+            let addr = match timed(timeout, "PROXY header", header).await {
+                Ok(Ok(conveyed)) => {
+                    debug!("PROXY header from {} conveys {:?}", addr, conveyed);
+                    conveyed.unwrap_or(addr)
+                }
+                Ok(Err(e)) | Err(e) => {
+                    warn!("Dropping {}: no valid PROXY header: {}", addr, e);
+                    return;
+                }
+            };
+            drop(reading);
+            admit(client, addr, state, &limiter, mapping);
+        });
+    }
+}
+
+// Applies the connection caps to a client at `addr` and spawns the task serving it, which
+// forwards it to the target of `mapping` when it came in on a --forward-port listener
+fn admit(
+    client: TcpStream,
+    addr: SocketAddr,
+    state: Arc<ProxyState>,
+    limiter: &limits::ConnectionLimiter,
+    mapping: Option<Arc<PortMapping>>,
+) {
+    let config = &state.config;
+    let respond = mapping.is_none() && state.answers_refusals();
+    if state
+        .client_acl
+        .as_ref()
+        .is_some_and(|acl| !acl.permits(addr.ip()))
+    {
+        Stats::inc(&STATS.denied_connections);
+        warn!("Denying {}: not allowed by --allow/--deny", addr);
+        let response = state.error_page(FORBIDDEN_RESPONSE, "client not allowed", None);
+        turn_away(client, response, respond);
+        return;
+    }
+    if let Err(reason) = state.accept_hook(addr) {
+        Stats::inc(&STATS.denied_connections);
+        warn!("Denying {}: {}", addr, reason);
+        turn_away(
+            client,
+            state.error_page(FORBIDDEN_RESPONSE, &reason, None),
+            respond,
+        );
+        return;
+    }
+    let permit = match limiter.try_acquire(addr.ip(), config.max_connections, config.max_per_client)
+    {
+        Ok(permit) => permit,
+        Err(refusal) => {
+            Stats::inc(&STATS.rejected_connections);
+            refuse_connection(client, addr, refusal, &state, respond);
+            return;
+        }
+    };
+    Stats::inc(&STATS.connections);
+    state.tune_socket(&client);
+    let connection_span = tracing::info_span!("connection", client.addr = %addr);
+
+    tokio::spawn(
+        async move {
+            let _permit = permit;
+            let config = &state.config;
+            let mut client = match &state.tls {
+                Some(acceptor) => {
+                    let handshake = acceptor.accept(client);
+                    let timeout = seconds(config.handshake_timeout);
+                    match timed(timeout, "TLS handshake", handshake).await {
+                        Ok(Ok(stream)) => ClientStream::Tls(Box::new(stream)),
+                        Ok(Err(e)) | Err(e) => {
+                            warn!("TLS handshake failed: {}", e);
+                            return;
+                        }
+                    }
+                }
+                None => ClientStream::Plain(client),
+            };
+            if let Some(dir) = &config.dump_traffic {
+                match DumpFiles::create(dir, addr) {
+                    Ok(files) => client = ClientStream::Dumped(Box::new(Dump::new(client, files))),
+                    Err(e) => warn!("Failed to dump traffic to {}: {}", dir.display(), e),
+                }
+            }
+            let result = if mapping.is_some() || state.forwarding() {
+                handle_forward_client(&mut client, addr, &state, mapping.as_deref()).await
+            } else {
+                handle_client(&mut client, addr, &state).await
+            };
+
+            // Box<dyn Error> isn't Send, so consume it before awaiting the teardown
+            let failed = result.map_err(|e| log_client_error(&*e)).is_err();
+            if failed {
+                Stats::inc(&STATS.errors);
+                abort_connection(client, config.abort_mode, config.abort_linger).await;
+            } else {
+                // Sends any data TLS still buffers, then close_notify
+                let _ = client.shutdown().await;
+            }
+        }
+        .instrument(connection_span),
+    );
+}
+
+// Turns away a connection over the connection caps: a quick 503 when `respond` is set, or
+// just closing it in forward mode or behind TLS, where answering would need a handshake
+fn refuse_connection(
+    client: TcpStream,
+    addr: SocketAddr,
+    refusal: limits::Refusal,
+    state: &ProxyState,
+    respond: bool,
+) {
+    let reason = match refusal {
+        limits::Refusal::Total(max) => format!("{max} connections already open"),
+        limits::Refusal::PerClient(max) => {
+            format!("{max} connections already open from this client")
+        }
+    };
+    warn!("Refusing {}: {}", addr, reason);
+    turn_away(
+        client,
+        state.error_page(SERVICE_UNAVAILABLE_RESPONSE, &reason, None),
+        respond,
+    );
+}
+
+// Closes a refused connection, first sending `response` when `respond` is set
+fn turn_away(client: TcpStream, response: Cow<'static, [u8]>, respond: bool) {
+    if !respond {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut client = ClientStream::Plain(client);
+        if client.write_all(&response).await.is_ok() {
+            abort_connection(client, AbortMode::Fin, 1).await;
+        }
+    });
+}
+
+// Checks that the upstream address resolves and, if requested, that it answers a greeting
+async fn validate_upstream(upstream: &Upstream, check_greeting: bool) -> Result<(), FatalError> {
+    let upstream_error = |reason: String| FatalError::Upstream {
+        addr: upstream.addr.clone(),
+        reason,
+    };
+
+    // A unix:// socket has no name to resolve, but should already exist
+    if let Some(path) = upstream.unix_path() {
+        std::fs::metadata(path)
+            .map_err(|e| upstream_error(format!("cannot access socket: {e}")))?;
+    } else {
+        let mut addrs = tokio::net::lookup_host(&upstream.addr)
+            .await
+            .map_err(|e| upstream_error(format!("cannot resolve address: {e}")))?;
+        if addrs.next().is_none() {
+            return Err(upstream_error("address resolved to nothing".into()));
+        }
+    }
+
+    if check_greeting {
+        let mut socks = socks::connect_server(upstream)
+            .await
+            .map_err(|e| upstream_error(format!("connect failed: {e}")))?;
+        // SOCKS4 and HTTP have no greeting, so a successful connect is all that can be checked
+        if upstream.protocol != Protocol::Socks || upstream.version != SocksVersion::V5 {
+            return Ok(());
+        }
+        socks::negotiate_auth(&mut socks, upstream)
+            .await
+            .map_err(|e| upstream_error(format!("greeting failed: {e}")))?;
+    }
+
+    Ok(())
+}
+
+// Logs a client handling error together with its source chain
+fn log_client_error(e: &dyn Error) {
+    error!("Client handling error: {}", e);
+    // Print the error chain
+    let mut error_chain = String::new();
+    let mut source = e.source();
+    while let Some(e) = source {
+        let _ = writeln!(error_chain, "Caused by: {e}");
+        source = e.source();
+    }
+    if !error_chain.is_empty() {
+        error!("Error chain:\n{}", error_chain);
+    }
+}
+
+// Handles individual client connections and processes HTTP requests
+async fn handle_client(
+    client: &mut ClientStream,
+    peer: SocketAddr,
+    state: &ProxyState,
+) -> Result<(), Box<dyn Error>> {
+    let config = &state.config;
+    let mut client = BufferedStream::new(client);
+    if config.mode == Bridge::Socks2http {
+        return handle_socks_client(&mut client, peer, state).await;
+    }
+    if config.detect_protocol {
+        // A SOCKS5 greeting starts with the version byte, which no HTTP method does
+        let read = timed(
+            seconds(config.handshake_timeout),
+            "first byte",
+            client.fill(),
+        );
+        let Ok(read) = read.await else {
+            debug!("Closing connection idle for {}s", config.handshake_timeout);
+            return Ok(());
+        };
+        if read? == 0 {
+            return Ok(());
+        }
+        if client.buf[0] == socks::SOCKS5_VERSION {
+            return handle_socks_client(&mut client, peer, state).await;
+        }
+    }
+    // The origin connection of the previous request, kept for the next one if it is alive
+    let mut origin: Option<Origin> = None;
+    let result = serve_requests(&mut client, peer, state, &mut origin).await;
+    // However the client left, its healthy origin connection can serve other clients
+    if let Some(origin) = origin {
+        state.origins.put(origin);
+    }
+    result
+}
+
+// Serves requests until the client closes, asks to close, or framing can't be followed
+async fn serve_requests(
+    client: &mut BufferedStream<&mut ClientStream>,
+    peer: SocketAddr,
+    state: &ProxyState,
+    origin: &mut Option<Origin>,
+) -> Result<(), Box<dyn Error>> {
+    let config = &state.config;
+    let limits = RequestLimits {
+        max_request_line: config.max_request_line,
+        max_headers: config.max_headers,
+        max_header_line: config.max_header_line,
+        strict: config.strict,
+    };
+    let pace = HeadPace {
+        deadline: seconds(config.header_timeout),
+        min_rate: config.header_min_rate,
+    };
+    loop {
+        let started = Instant::now();
+
+        // Read until a complete request head has arrived, however many packets it spans
+        let read = client.read_head_paced(
+            config.max_header_size,
+            RequestHead::parse,
+            |head| head.len,
+            pace,
+        );
+        let Ok(head) = timed(seconds(config.handshake_timeout), "request head", read).await else {
+            // A client that went quiet between requests is simply closed
+            if client.buf.is_empty() {
+                debug!("Closing connection idle for {}s", config.handshake_timeout);
+            } else {
+                let reason = format!(
+                    "request head not received within {}s",
+                    config.handshake_timeout
+                );
+                warn!(
+                    "Request head not received within {}s",
+                    config.handshake_timeout
+                );
+                let response = state.error_page(REQUEST_TIMEOUT_RESPONSE, &reason, None);
+                client.inner.write_all(&response).await?;
+            }
+            return Ok(());
+        };
+        let head = match head {
+            Ok(Some(head)) => head,
+            Ok(None) => return Ok(()),
+            Err(HeadError::TooLarge(limit)) => {
+                warn!("Request head exceeds {} bytes or header count limit", limit);
+                let reason = format!("request head exceeds {limit} bytes or header count limit");
+                let response = state.error_page(HEADERS_TOO_LARGE_RESPONSE, &reason, None);
+                client.inner.write_all(&response).await?;
+                return Ok(());
+            }
+            Err(HeadError::TooSlow(e)) => {
+                warn!("Dropping slow client: {}", e);
+                let response = state.error_page(REQUEST_TIMEOUT_RESPONSE, &e.to_string(), None);
+                client.inner.write_all(&response).await?;
+                return Ok(());
+            }
+            Err(HeadError::Malformed(e)) => {
+                warn!("Malformed request: {}", e);
+                let response = state.error_page(BAD_REQUEST_RESPONSE, &e.to_string(), None);
+                client.inner.write_all(&response).await?;
+                return Ok(());
+            }
+            Err(e) => {
+                error!("Failed to read from client: {}", e);
+                return Err(e.into());
+            }
+        };
+        if let Err(refusal) = head.check(&client.buf[..head.len], &limits) {
+            warn!("Refusing request: {}", refusal.reason);
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                refusal.status
+            );
+            let response = state.error_page(response.as_bytes(), &refusal.reason, None);
+            client.inner.write_all(&response).await?;
+            return Ok(());
+        }
+        client.consume(head.len);
+
+        let mut record = Record::new(peer, started);
+        record.method = Some(head.method.clone());
+        record.request_line = Some(format!(
+            "{} {} HTTP/1.{}",
+            head.method, head.target, head.version
+        ));
+        record.referer = head.header("referer").map(str::to_string);
+        record.user_agent = head.header("user-agent").map(str::to_string);
+        // Scoped so the non-Send error is gone before the next await
+        let keep_alive = {
+            let result = handle_request(client, &head, origin, state, &mut record).await;
+            state.finish_record(&mut record, &result);
+            result?
+        };
+        // TLS holds on to written data until flushed
+        client.inner.flush().await?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Serves one request from --h3-listen. Only CONNECT and connect-udp are supported; they go
+// through the same access control and authentication as a CONNECT over HTTP/1.1
+#[cfg(feature = "http3")]
+#[instrument(skip_all, fields(client.addr = %peer, request_id, target, mode = "HTTP/3", user))]
+async fn handle_h3_request(state: Arc<ProxyState>, peer: SocketAddr, request: http3::Request) {
+    if state
+        .client_acl
+        .as_ref()
+        .is_some_and(|acl| !acl.permits(peer.ip()))
+    {
+        Stats::inc(&STATS.denied_connections);
+        warn!("Denying {}: not allowed by --allow/--deny", peer);
+        let mut request = request;
+        let response = state.error_page(FORBIDDEN_RESPONSE, "client not allowed", None);
+        if request.respond(&response).await.is_ok() {
+            let _ = request.finish().await;
+        }
+        return;
+    }
+    if let Err(reason) = state.accept_hook(peer) {
+        Stats::inc(&STATS.denied_connections);
+        warn!("Denying {}: {}", peer, reason);
+        let mut request = request;
+        let response = state.error_page(FORBIDDEN_RESPONSE, &reason, None);
+        if request.respond(&response).await.is_ok() {
+            let _ = request.finish().await;
+        }
+        return;
+    }
+    let mut record = Record::new(peer, Instant::now());
+    Span::current().record("request_id", record.request_id.as_str());
+    let head = &request.head;
+    record.method = Some(head.method.clone());
+    record.request_line = Some(format!("{} {} HTTP/3", head.method, head.target));
+    record.user_agent = head.header("user-agent").map(str::to_string);
+    let result = h3_connect(&state, request, &mut record).await;
+    state.finish_record(&mut record, &result);
+    if let Err(e) = result {
+        Stats::inc(&STATS.errors);
+        log_client_error(&*e);
+    }
+}
+
+#[cfg(feature = "http3")]
+async fn h3_connect(
+    state: &ProxyState,
+    mut request: http3::Request,
+    record: &mut Record,
+) -> Result<(), Box<dyn Error>> {
+    if !state.within_request_rate(record.client) {
+        record.reject(429);
+        let response = state.error_page(
+            TOO_MANY_REQUESTS_RESPONSE,
+            "too many requests from this client",
+            Some(&record.request_id),
+        );
+        return h3_refuse(request, &response).await;
+    }
+    let head = &request.head;
+    let udp = request.protocol.as_deref() == Some("connect-udp");
+    if !head.is_connect() || (request.protocol.is_some() && !udp) {
+        warn!(
+            "Unsupported HTTP/3 request: {} {}",
+            head.method, head.target
+        );
+        record.reject(501);
+        let reason = "only CONNECT and connect-udp are supported over HTTP/3";
+        return h3_refuse(
+            request,
+            &state.error_page(NOT_IMPLEMENTED_RESPONSE, reason, Some(&record.request_id)),
+        )
+        .await;
+    }
+
+    let certificate_user = state
+        .config
+        .tls_client_user
+        .zip(request.peer_certificate.as_ref())
+        .and_then(|(from, cert)| tls::certificate_user(cert, from));
+    if let Some(user) = certificate_user {
+        Span::current().record("user", user.as_str());
+        record.user = Some(user);
+    }
+    match state.authenticate(head) {
+        Ok(Some(user)) => {
+            Span::current().record("user", user.as_str());
+            record.user = Some(user);
+        }
+        Ok(None) => {}
+        Err(challenge) => {
+            record.reject(407);
+            let response = state.error_page(
+                challenge.as_bytes(),
+                "missing or invalid proxy credentials",
+                Some(&record.request_id),
+            );
+            return h3_refuse(request, &response).await;
+        }
+    }
+
+    let target = if udp {
+        request.udp_target()
+    } else {
+        head.destination()
+    };
+    let Some((host, port)) = target else {
+        warn!("CONNECT has no usable target: {}", request.head.target);
+        record.reject(400);
+        let response = state.error_page(
+            BAD_REQUEST_RESPONSE,
+            "no usable target",
+            Some(&record.request_id),
+        );
+        return h3_refuse(request, &response).await;
+    };
+    Span::current().record("target", http::join_host_port(&host, port));
+    record.target = Some(http::join_host_port(&host, port));
+    Stats::inc(&STATS.connect_requests);
+    let (host, port) = state.map_destination(&host, port).unwrap_or((host, port));
+    if let Err(reason) = state.check_request(record, &host, port) {
+        record.reject(403);
+        let response = state.error_page(FORBIDDEN_RESPONSE, &reason, Some(&record.request_id));
+        record.error = Some(reason);
+        return h3_refuse(request, &response).await;
+    }
+    if udp {
+        return h3_udp(state, request, record, &host, port).await;
+    }
+
+    // Box<dyn Error> isn't Send, so only its message is kept across the failure reply
+    let tunnel = open_tunnel(state, record.user.as_deref(), &host, port)
+        .await
+        .map_err(|e| ConnectFailure::new(connect_error(&host, port, e)));
+    let mut tunnel = match tunnel {
+        Ok(tunnel) => tunnel,
+        Err(failure) => {
+            let response = failure_response(state, record, failure);
+            return h3_refuse(request, &response).await;
+        }
+    };
+    record.upstream = Some(tunnel.upstream.clone());
+    state.connected(record);
+    record.status = Some(200);
+    request
+        .respond(&state.tag_response(b"HTTP/1.1 200 OK\r\n\r\n", record))
+        .await?;
+
+    let mut client = request.into_stream();
+    let (_tracked, relay_config) = state.tracked_relay(record);
+    let relayed = relay::relay(&mut client, &mut tunnel.stream, &relay_config).await?;
+    Stats::add(&STATS.bytes_from_client, relayed.a_to_b);
+    Stats::add(&STATS.bytes_from_upstream, relayed.b_to_a);
+    record.bytes_up = relayed.a_to_b;
+    record.bytes_down = relayed.b_to_a;
+    Ok(())
+}
+
+// Bridges a connect-udp flow to a SOCKS5 UDP association for `host:port`, until either side
+// closes it or it stays idle for --udp-timeout
+#[cfg(feature = "http3")]
+async fn h3_udp(
+    state: &ProxyState,
+    request: http3::Request,
+    record: &mut Record,
+    host: &str,
+    port: u16,
+) -> Result<(), Box<dyn Error>> {
+    Span::current().record("mode", "connect-udp");
+    let mut header = vec![0, 0, 0];
+    socks::encode_address(&mut header, host, port)?;
+    let mut lease = None;
+    let upstream = socks5_upstream(
+        state,
+        record.user.as_deref(),
+        host,
+        "connect-udp",
+        &mut lease,
+    );
+    let upstream = match upstream.await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            warn!("Refusing connect-udp to {}:{}: {}", host, port, e);
+            record.termination = Some(Termination::Rejected);
+            let response = state.error_page(BAD_GATEWAY_RESPONSE, &e, Some(&record.request_id));
+            record.error = Some(e);
+            record.status = Some(502);
+            return h3_refuse(request, &response).await;
+        }
+    };
+    record.upstream = Some(upstream.addr.to_string());
+    // Box<dyn Error> isn't Send, so only its message is kept across the failure reply
+    let association = udp::UdpAssociation::open(upstream)
+        .await
+        .map_err(|e| e.to_string());
+    let association = match association {
+        Ok(association) => association,
+        Err(e) => {
+            error!("UDP ASSOCIATE through {} failed: {}", upstream.addr, e);
+            Stats::inc(&STATS.upstream_errors);
+            record.termination = Some(Termination::UpstreamError);
+            let response = state.error_page(BAD_GATEWAY_RESPONSE, &e, Some(&record.request_id));
+            record.error = Some(e);
+            record.status = Some(502);
+            return h3_refuse(request, &response).await;
+        }
+    };
+    state.connected(record);
+    record.status = Some(200);
+    let mut request = request;
+    request
+        .respond(&state.tag_response(b"HTTP/1.1 200 OK\r\nCapsule-Protocol: ?1\r\n\r\n", record))
+        .await?;
+
+    let mut flow = request.into_udp_flow();
+    let idle_timeout = Duration::from_secs(state.config.udp_timeout);
+    let mut buf = vec![0u8; 65535];
+    loop {
+        tokio::select! {
+            payload = flow.recv() => {
+                let Some(payload) = payload else { break };
+                let mut datagram = header.clone();
+                datagram.extend_from_slice(&payload);
+                association.send_raw(&datagram).await?;
+                record.bytes_up += payload.len() as u64;
+            }
+            received = association.recv_raw(&mut buf) => {
+                if let Some((_, payload)) = udp::decapsulate(&buf[..received?]) {
+                    flow.send(payload)?;
+                    record.bytes_down += payload.len() as u64;
+                }
+            }
+            _ = association.closed() => {
+                info!("Upstream closed the UDP association");
+                break;
+            }
+            _ = tokio::time::sleep(idle_timeout) => {
+                debug!("UDP flow idle, closing");
+                break;
+            }
+        }
+    }
+    Stats::add(&STATS.bytes_from_client, record.bytes_up);
+    Stats::add(&STATS.bytes_from_upstream, record.bytes_down);
+    Ok(())
+}
+
+// Answers an HTTP/3 request with a final response and ends its stream
+#[cfg(feature = "http3")]
+async fn h3_refuse(mut request: http3::Request, response: &[u8]) -> Result<(), Box<dyn Error>> {
+    request.respond(response).await?;
+    request.finish().await?;
+    Ok(())
+}
+
+// Serves a SOCKS5 client, found on the HTTP listener by --detect-protocol or accepted in
+// socks2http mode, tunnelling its CONNECT request along the same routes as an HTTP CONNECT
+#[instrument(skip_all, fields(request_id, target, mode = "SOCKS5", user))]
+async fn handle_socks_client(
+    client: &mut BufferedStream<&mut ClientStream>,
+    peer: SocketAddr,
+    state: &ProxyState,
+) -> Result<(), Box<dyn Error>> {
+    let mut record = Record::new(peer, Instant::now());
+    Span::current().record("request_id", record.request_id.as_str());
+    record.method = Some("SOCKS5".to_string());
+    let result = socks_request(client, state, &mut record).await;
+    state.finish_record(&mut record, &result);
+    result
+}
+
+// Completes the SOCKS5 handshake, opens the requested tunnel and relays it
+async fn socks_request(
+    client: &mut BufferedStream<&mut ClientStream>,
+    state: &ProxyState,
+    record: &mut Record,
+) -> Result<(), Box<dyn Error>> {
+    let accept = socks_server::accept(client, state.auth.as_ref());
+    let request = timed(
+        seconds(state.config.handshake_timeout),
+        "SOCKS5 request",
+        accept,
+    )
+    .await??;
+    let user = request
+        .user
+        .or_else(|| state.certificate_user(client.inner));
+    if let Some(user) = &user {
+        Span::current().record("user", user.as_str());
+    }
+    record.user = user;
+    if !state.within_request_rate(record.client) {
+        record.termination = Some(Termination::Rejected);
+        socks_server::reply(client, socks_server::REPLY_NOT_ALLOWED).await?;
+        return Ok(());
+    }
+    let command = request.command;
+    let (host, port) = (request.host, request.port);
+    Span::current().record("target", http::join_host_port(&host, port));
+    record.target = Some(http::join_host_port(&host, port));
+    Stats::inc(&STATS.connect_requests);
+    let (host, port) = state.map_destination(&host, port).unwrap_or((host, port));
+
+    if let Err(reason) = state.check_request(record, &host, port) {
+        record.termination = Some(Termination::Rejected);
+        record.error = Some(reason);
+        socks_server::reply(client, socks_server::REPLY_NOT_ALLOWED).await?;
+        return Ok(());
+    }
+    if command == socks_server::Command::Bind {
+        record.method = Some("SOCKS5 BIND".to_string());
+        return socks_bind(client, state, record, &host, port).await;
+    }
+
+    // Box<dyn Error> isn't Send, so only its message is kept across the failure reply
+    let tunnel = open_tunnel(state, record.user.as_deref(), &host, port)
+        .await
+        .map_err(|e| {
+            let e = connect_error(&host, port, e);
+            record.termination = Some(termination(&*e));
+            // A SOCKS5 server's reply is passed on as is, and an HTTP proxy refusing on its own
+            // authority is not a failure to reach it
+            let code = match (
+                e.downcast_ref::<socks::ReplyError>(),
+                e.downcast_ref::<http_upstream::Refused>(),
+            ) {
+                (Some(reply), _) => reply.code,
+                (_, Some(refused)) if matches!(refused.status, 403 | 407) => {
+                    socks_server::REPLY_NOT_ALLOWED
+                }
+                _ => socks_server::REPLY_GENERAL_FAILURE,
+            };
+            (code, e.to_string())
+        });
+    let mut tunnel = match tunnel {
+        Ok(tunnel) => tunnel,
+        Err((code, e)) => {
+            socks_server::reply(client, code).await?;
+            return Err(e.into());
+        }
+    };
+    record.upstream = Some(tunnel.upstream.clone());
+    state.connected(record);
+    socks_server::reply(client, socks::SOCKS5_SUCCESS).await?;
+
+    // Data the client sent without waiting for the reply
+    if !client.buf.is_empty() {
+        tunnel.stream.write_all(&client.buf).await?;
+        record.bytes_up += client.buf.len() as u64;
+    }
+    let (_tracked, relay_config) = state.tracked_relay(record);
+    let relayed = proxy_data(client.inner, &mut tunnel.stream, &relay_config).await?;
+    record.bytes_up += relayed.a_to_b;
+    record.bytes_down += relayed.b_to_a;
+    Ok(())
+}
+
+// Serves a SOCKS5 BIND through the upstream: passes on where it listens, then who connected
+// to it, and relays that connection
+async fn socks_bind(
+    client: &mut BufferedStream<&mut ClientStream>,
+    state: &ProxyState,
+    record: &mut Record,
+    host: &str,
+    port: u16,
+) -> Result<(), Box<dyn Error>> {
+    // Box<dyn Error> isn't Send, so only the reply code and message are kept across the reply
+    let failure = |record: &mut Record, e: Box<dyn Error>| {
+        record.termination = Some(termination(&*e));
+        let code = match e.downcast_ref::<socks::ReplyError>() {
+            Some(reply) => reply.code,
+            None => socks_server::REPLY_GENERAL_FAILURE,
+        };
+        (code, e.to_string())
+    };
+    let bound = open_bind(state, record.user.as_deref(), host, port)
+        .await
+        .map_err(|e| failure(record, e));
+    let bound = match bound {
+        Ok(bound) => bound,
+        Err((code, e)) => {
+            socks_server::reply(client, code).await?;
+            return Err(e.into());
+        }
+    };
+    record.upstream = Some(bound.upstream.clone());
+    socks_server::reply_address(client, socks::SOCKS5_SUCCESS, bound.address()).await?;
+
+    let accepted = bound.accept().await.map_err(|e| failure(record, e));
+    let (mut tunnel, peer) = match accepted {
+        Ok(accepted) => accepted,
+        Err((code, e)) => {
+            socks_server::reply(client, code).await?;
+            return Err(e.into());
+        }
+    };
+    debug!("Accepted BIND connection from {:?}", peer);
+    state.connected(record);
+    socks_server::reply_address(client, socks::SOCKS5_SUCCESS, &peer).await?;
+
+    // Data the client sent without waiting for the replies
+    if !client.buf.is_empty() {
+        tunnel.stream.write_all(&client.buf).await?;
+        record.bytes_up += client.buf.len() as u64;
+    }
+    let (_tracked, relay_config) = state.tracked_relay(record);
+    let relayed = proxy_data(client.inner, &mut tunnel.stream, &relay_config).await?;
+    record.bytes_up += relayed.a_to_b;
+    record.bytes_down += relayed.b_to_a;
+    Ok(())
+}
+
+// Answers one request read from the client. Returns whether the client connection may
+// carry another request.
+#[instrument(skip_all, fields(request_id = %record.request_id, target, mode, user))]
+async fn handle_request(
+    client: &mut BufferedStream<&mut ClientStream>,
+    head: &RequestHead,
+    origin: &mut Option<Origin>,
+    state: &ProxyState,
+    record: &mut Record,
+) -> Result<bool, Box<dyn Error>> {
+    let config = &state.config;
+
+    if !state.within_request_rate(record.client) {
+        record.reject(429);
+        let response = state.error_page(
+            TOO_MANY_REQUESTS_RESPONSE,
+            "too many requests from this client",
+            Some(&record.request_id),
+        );
+        client.inner.write_all(&response).await?;
+        return Ok(false);
+    }
+
+    // Browsers fetch the PAC file from the proxy address itself, without credentials
+    if config.pac
+        && !head.is_connect()
+        && !head.is_absolute_form()
+        && head.target.split('?').next() == Some(pac::PATH)
+    {
+        record.status = Some(200);
+        let response = pac::response(&state.pac_script(head.header("host")));
+        client.inner.write_all(response.as_bytes()).await?;
+        return Ok(false);
+    }
+
+    if let Some(user) = state.certificate_user(client.inner) {
+        Span::current().record("user", user.as_str());
+        record.user = Some(user);
+    }
+    match state.authenticate(head) {
+        Ok(Some(user)) => {
+            Span::current().record("user", user.as_str());
+            record.user = Some(user);
+        }
+        Ok(None) => {}
+        Err(challenge) => {
+            record.reject(407);
+            let response = state.error_page(
+                challenge.as_bytes(),
+                "missing or invalid proxy credentials",
+                Some(&record.request_id),
+            );
+            client.inner.write_all(&response).await?;
+            return Ok(false);
+        }
+    }
+
+    let Some((host, port)) = head.destination() else {
+        warn!(
+            "Request has no usable target: {} {}",
+            head.method, head.target
+        );
+        record.reject(400);
+        let response = state.error_page(
+            BAD_REQUEST_RESPONSE,
+            "no usable target",
+            Some(&record.request_id),
+        );
+        client.inner.write_all(&response).await?;
+        return Ok(false);
+    };
+    Span::current().record("target", http::join_host_port(&host, port));
+    record.target = Some(http::join_host_port(&host, port));
+
+    // Origin-form requests have no target besides the Host header to compare it with
+    if (head.is_connect() || head.is_absolute_form())
+        && !host_header_consistent(head, &host, port, config.host_check)
+    {
+        record.reject(400);
+        let response = state.error_page(
+            BAD_REQUEST_RESPONSE,
+            "Host header disagrees with the request target",
+            Some(&record.request_id),
+        );
+        client.inner.write_all(&response).await?;
+        return Ok(false);
+    }
+
+    // TLS interception still presents a certificate for the name the client asked for
+    let requested_host = host.clone();
+    let mapped = state.map_destination(&host, port);
+    let (host, port) = mapped.clone().unwrap_or((host, port));
+
+    if let Err(reason) = state.check_request(record, &host, port) {
+        record.reject(403);
+        let response = state.error_page(FORBIDDEN_RESPONSE, &reason, Some(&record.request_id));
+        record.error = Some(reason);
+        client.inner.write_all(&response).await?;
+        return Ok(false);
+    }
+
+    if head.is_connect() {
+        // Handle CONNECT tunnel (HTTPS)
+        Span::current().record("mode", "CONNECT");
+        Stats::inc(&STATS.connect_requests);
+
+        let tunnel = open_tunnel(state, record.user.as_deref(), &host, port)
+            .await
+            .map_err(|e| ConnectFailure::new(connect_error(&host, port, e)));
+        let mut tunnel = match tunnel {
+            Ok(tunnel) => tunnel,
+            Err(failure) => return connect_failed(client, state, record, failure).await,
+        };
+        record.upstream = Some(tunnel.upstream.clone());
+        state.connected(record);
+        record.status = Some(200);
+
+        let established =
+            state.tag_response(b"HTTP/1.1 200 Connection Established\r\n\r\n", record);
+        client.inner.write_all(&established).await.map_err(|e| {
+            error!("Failed to send connection established: {}", e);
+            e
+        })?;
+        client.inner.flush().await?;
+
+        let (_tracked, relay_config) = state.tracked_relay(record);
+        // The client's TLS handshake may already be buffered behind the CONNECT head
+        if let Some(mitm) = &state.mitm {
+            let relayed = mitm::intercept(
+                client,
+                &mut tunnel.stream,
+                &requested_host,
+                mitm,
+                config.max_header_size,
+                &relay_config,
+            )
+            .await?;
+            record.bytes_up = relayed.a_to_b;
+            record.bytes_down = relayed.b_to_a;
+            return Ok(false);
+        }
+
+        // If we read more than headers (unlikely for CONNECT but possible), forward it
+        if !client.buf.is_empty() {
+            tunnel.stream.write_all(&client.buf).await?;
+            record.bytes_up += client.buf.len() as u64;
+        }
+
+        let relayed = proxy_data(client.inner, &mut tunnel.stream, &relay_config).await?;
+        record.bytes_up += relayed.a_to_b;
+        record.bytes_down += relayed.b_to_a;
+        return Ok(false);
+    }
+
+    if head.is_ftp() {
+        return ftp_request(client, head, &host, port, state, record).await;
+    }
+
+    // Handle regular HTTP request, which may ask to switch protocols
+    Stats::inc(&STATS.http_requests);
+    Span::current().record("mode", if head.is_upgrade() { "UPGRADE" } else { "HTTP" });
+    match mapped {
+        Some(_) => {
+            let head = head.redirected(&host, port);
+            forward_request(client, &head, &host, port, origin, state, record).await
+        }
+        None => forward_request(client, head, &host, port, origin, state, record).await,
+    }
+}
+
+// Relays one plain HTTP request and its response. Returns whether the client connection
+// may carry another request.
+async fn forward_request(
+    client: &mut BufferedStream<&mut ClientStream>,
+    head: &RequestHead,
+    host: &str,
+    port: u16,
+    origin: &mut Option<Origin>,
+    state: &ProxyState,
+    record: &mut Record,
+) -> Result<bool, Box<dyn Error>> {
+    let config = &state.config;
+    let Some(request_body) = head.body_length() else {
+        warn!("Invalid request body framing");
+        record.reject(400);
+        let response = state.error_page(
+            BAD_REQUEST_RESPONSE,
+            "invalid request body framing",
+            Some(&record.request_id),
+        );
+        client.inner.write_all(&response).await?;
+        return Ok(false);
+    };
+
+    // Send origin-form to the origin without the headers that only concern the client
+    // connection, such as Connection and our own Proxy-Authorization. Upgrade handshakes
+    // (e.g. WebSocket) need their Connection and Upgrade headers to reach the origin intact,
+    // but lose the other hop-by-hop ones like any request.
+    let mut hop_by_hop = head.hop_by_hop();
+    if head.is_upgrade() {
+        hop_by_hop.retain(|name| name != "connection" && name != "upgrade");
+    }
+    let mut strip: Vec<&str> = hop_by_hop.iter().map(String::as_str).collect();
+    if let Some(tokens) = &state.tokens {
+        strip.push(tokens.header());
+    }
+    let rules = &state.header_rules;
+    strip.extend(rules.remove.iter().map(String::as_str));
+    let mut add: Vec<(&str, String)> = rules
+        .add
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.clone()))
+        .collect();
+    if config.add_via {
+        add.push(("Via", format!("1.{} http2socks", head.version)));
+    }
+    if config.add_forwarded {
+        let peer = record.client.ip();
+        // Extend the chain of any proxies in front of us rather than replacing it
+        let forwarded_for = match head.header("x-forwarded-for") {
+            Some(chain) if !chain.is_empty() => format!("{chain}, {peer}"),
+            _ => peer.to_string(),
+        };
+        strip.push("x-forwarded-for");
+        add.push(("X-Forwarded-For", forwarded_for));
+        let node = match peer {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{ip}]\""),
+        };
+        add.push(("Forwarded", format!("for={node};proto=http")));
+    }
+    // An HTTP upstream taking the request itself wants it in absolute form, with its own
+    // credentials
+    let encode = |upstream: &Origin| match &upstream.forward_proxy {
+        Some(proxy) => {
+            let mut add = add.clone();
+            if let Some(authorization) = &proxy.authorization {
+                add.push(("Proxy-Authorization", authorization.clone()));
+            }
+            head.encode_for_proxy(host, port, &strip, &add)
+        }
+        None => head.encode_for_origin(&strip, &add),
+    };
+
+    // Fresh cached responses are served without contacting the origin
+    let cache = state
+        .cache
+        .as_ref()
+        .filter(|_| request_body == BodyLength::Empty);
+    let cache_key = cache.and_then(|_| Cache::key(head, &http::join_host_port(host, port)));
+    if let (Some(cache), Some(key)) = (cache, &cache_key) {
+        if let Some(hit) = cache.get(key).filter(|_| !Cache::must_revalidate(head)) {
+            client
+                .inner
+                .write_all(&state.tag_response(&hit.response, record))
+                .await?;
+            info!("{} {} -> {} (cached)", head.method, head.target, hit.status);
+            record.status = Some(hit.status);
+            record.upstream = Some("cache".to_string());
+            record.bytes_down = hit.response.len() as u64;
+            return Ok(head.keep_alive());
+        }
+    }
+
+    // Bodyless idempotent requests are fully buffered, so they can be replayed over a
+    // fresh tunnel if the upstream resets before answering
+    let replayable = is_idempotent(&head.method) && request_body == BodyLength::Empty;
+    // The expectation is forwarded, and the body held back until the origin answers it,
+    // unless the client already sent some
+    let expects_continue =
+        head.expects_continue() && request_body != BodyLength::Empty && client.buf.is_empty();
+    // The client's own connection from its previous request is preferred, then one
+    // another client left in the pool
+    let mut reused = match origin.take() {
+        Some(origin)
+            if origin.host == host
+                && origin.port == port
+                && origin.is_open()
+                && state.origins.is_fresh(&origin) =>
+        {
+            Some(origin)
+        }
+        previous => {
+            if let Some(previous) = previous {
+                state.origins.put(previous);
+            }
+            let country = state.country(host).await;
+            let pooled = state.origins.take(host, port, |upstream| {
+                state.routes_through(record.user.as_deref(), host, country.as_deref(), upstream)
+            });
+            if let Some(pooled) = &pooled {
+                debug!(
+                    "Reusing pooled connection to {}:{} via {}",
+                    host, port, pooled.upstream
+                );
+            }
+            pooled
+        }
+    };
+    let mut attempt = 0;
+
+    let (mut upstream, response, body_sent) = loop {
+        let mut upstream = match reused.take() {
+            Some(upstream) => upstream,
+            None => {
+                let tunnel = open_origin(state, record.user.as_deref(), host, port)
+                    .await
+                    .map_err(|e| ConnectFailure::new(connect_error(host, port, e)));
+                let Tunnel {
+                    stream,
+                    upstream,
+                    forward_proxy,
+                    _lease,
+                    _permit,
+                    _active,
+                } = match tunnel {
+                    Ok(tunnel) => tunnel,
+                    Err(failure) => return connect_failed(client, state, record, failure).await,
+                };
+                record.upstream = Some(upstream.clone());
+                state.connected(record);
+                Origin {
+                    host: host.to_string(),
+                    port,
+                    upstream,
+                    forward_proxy,
+                    conn: BufferedStream::new(stream),
+                    opened: Instant::now(),
+                    _lease,
+                    _permit,
+                    _active,
+                }
+            }
+        };
+
+        let request = encode(&upstream);
+        let result = async {
+            upstream.conn.inner.write_all(&request).await?;
+            Stats::add(&STATS.bytes_from_client, request.len() as u64);
+            record.bytes_up = request.len() as u64;
+            if expects_continue {
+                upstream.conn.inner.flush().await?;
+                if let Some(response) =
+                    await_continue(client, &mut upstream.conn, config.max_header_size, record)
+                        .await?
+                {
+                    return Ok((response, false));
+                }
+            }
+            let sent = client
+                .copy_body(&mut upstream.conn.inner, request_body)
+                .await?;
+            upstream.conn.inner.flush().await?;
+            Stats::add(&STATS.bytes_from_client, sent);
+            record.bytes_up += sent;
+            let sent_at = Instant::now();
+            let response = read_response_head(&mut upstream.conn, config.max_header_size).await?;
+            STATS.first_byte_latency.record(sent_at.elapsed());
+            Ok((response, true))
+        }
+        .await;
+
+        match result {
+            Ok((response, body_sent)) => {
+                record.upstream = Some(upstream.upstream.clone());
+                break (upstream, response, body_sent);
+            }
+            Err(HeadError::Io(e))
+                if replayable && is_upstream_reset(&e) && attempt < config.idempotent_retries =>
+            {
+                attempt += 1;
+                warn!(
+                    "Upstream reset before response ({}), retrying {} request ({}/{})",
+                    e, head.method, attempt, config.idempotent_retries
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    // Interim responses such as 100 Continue are passed on until the final one arrives
+    let mut response = response;
+    while response.is_interim() {
+        let interim = upstream.conn.consume(response.len);
+        client.inner.write_all(&interim).await?;
+        client.inner.flush().await?;
+        record.bytes_down += interim.len() as u64;
+        response = read_response_head(&mut upstream.conn, config.max_header_size).await?;
+    }
+
+    let response_head = upstream.conn.consume(response.len);
+    client
+        .inner
+        .write_all(&state.tag_response(&response_head, record))
+        .await?;
+    info!("{} {} -> {}", head.method, head.target, response.status);
+    record.status = Some(response.status);
+    record.bytes_down += response_head.len() as u64;
+
+    let response_body = match response.body_length(&head.method) {
+        // After 101 Switching Protocols the connection no longer speaks HTTP
+        _ if response.status == 101 => None,
+        Some(length) => Some(length),
+        None => return Err("Invalid response body framing".into()),
+    };
+    let Some(response_body) = response_body else {
+        info!("Switched protocols, tunneling the rest of the connection");
+        let (_tracked, relay_config) = state.tracked_relay(record);
+        let relayed = relay_rest(client, &mut upstream.conn, &relay_config).await?;
+        record.bytes_up += relayed.a_to_b;
+        record.bytes_down += relayed.b_to_a;
+        return Ok(false);
+    };
+
+    // A cacheable response is copied into the cache as it is passed on
+    let freshness = cache
+        .filter(|_| cache_key.is_some() && response_body != BodyLength::UntilClose)
+        .and_then(|cache| cache.freshness(&response));
+    let received = match (cache, cache_key, freshness) {
+        (Some(cache), Some(key), Some(freshness)) => {
+            let mut tee = cache.tee(&mut *client.inner);
+            let received = upstream.conn.copy_body(&mut tee, response_body).await?;
+            if let Some(body) = tee.into_copy() {
+                debug!("Caching {}", key);
+                cache.insert(key, response.status, &response_head, body, freshness);
+            }
+            received
+        }
+        _ => {
+            upstream
+                .conn
+                .copy_body(&mut *client.inner, response_body)
+                .await?
+        }
+    };
+    Stats::add(
+        &STATS.bytes_from_upstream,
+        response_head.len() as u64 + received,
+    );
+    record.bytes_down += received;
+
+    // A body delimited by the origin closing its connection ends ours with the client too.
+    // So does a body the origin declined with a final response to the expectation: the
+    // client may send it anyway, and the origin may still be waiting for it.
+    let delimited = response_body != BodyLength::UntilClose && body_sent;
+    if delimited && response.keep_alive() {
+        *origin = Some(upstream);
+    }
+    Ok(delimited && head.keep_alive())
+}
+
+// Serves a `GET ftp://` request as a gateway: logs in to the FTP server through a tunnel and
+// sends back the file or directory listing, fetched over a passive data connection through
+// another one, or over an active one accepted with SOCKS5 BIND when the server refuses
+// passive mode. The client connection is closed afterwards.
+async fn ftp_request(
+    client: &mut BufferedStream<&mut ClientStream>,
+    head: &RequestHead,
+    host: &str,
+    port: u16,
+    state: &ProxyState,
+    record: &mut Record,
+) -> Result<bool, Box<dyn Error>> {
+    Stats::inc(&STATS.http_requests);
+    Span::current().record("mode", "FTP");
+    if head.method != "GET" {
+        record.reject(501);
+        let response = state.error_page(
+            NOT_IMPLEMENTED_RESPONSE,
+            "only GET is supported for ftp:// URLs",
+            Some(&record.request_id),
+        );
+        client.inner.write_all(&response).await?;
+        return Ok(false);
+    }
+    let Some(url) = ftp::Url::parse(&head.target) else {
+        record.reject(400);
+        let response = state.error_page(
+            BAD_REQUEST_RESPONSE,
+            "malformed ftp:// URL",
+            Some(&record.request_id),
+        );
+        client.inner.write_all(&response).await?;
+        return Ok(false);
+    };
+
+    let tunnel = open_tunnel(state, record.user.as_deref(), host, port)
+        .await
+        .map_err(|e| ConnectFailure::new(connect_error(host, port, e)));
+    let tunnel = match tunnel {
+        Ok(tunnel) => tunnel,
+        Err(failure) => return connect_failed(client, state, record, failure).await,
+    };
+    record.upstream = Some(tunnel.upstream.clone());
+    state.connected(record);
+
+    // Box<dyn Error> isn't Send, so only the status and message are kept across the reply
+    let result = ftp_transfer(client, tunnel.stream, &url, head, host, state, record)
+        .await
+        .map_err(|e| {
+            let status = match e.downcast_ref::<ftp::Refused>() {
+                Some(refused) if refused.is_unavailable() => 404,
+                _ => 502,
+            };
+            (status, e.to_string())
+        });
+    let Err((status, reason)) = result else {
+        return Ok(false);
+    };
+    // Once the response head is out, the client can only be told by closing the connection
+    if record.status.is_some() {
+        return Err(reason.into());
+    }
+    warn!("FTP request for {} failed: {}", head.target, reason);
+    record.termination = Some(Termination::UpstreamError);
+    record.status = Some(status);
+    let phrase = if status == 404 {
+        "Not Found"
+    } else {
+        "Bad Gateway"
+    };
+    let body = format!("{reason}\n");
+    let response = format!(
+        "HTTP/1.1 {status} {phrase}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let response = state
+        .error_page(response.as_bytes(), &reason, Some(&record.request_id))
+        .into_owned();
+    record.error = Some(reason);
+    client.inner.write_all(&response).await?;
+    Ok(false)
+}
+
+// Logs in over `control` and sends the client the file or listing `url` names. Nothing is
+// written to the client until the server has agreed to the transfer, so failures before
+// then can still be answered with an error response.
+async fn ftp_transfer(
+    client: &mut BufferedStream<&mut ClientStream>,
+    control: UpstreamStream,
+    url: &ftp::Url,
+    head: &RequestHead,
+    host: &str,
+    state: &ProxyState,
+    record: &mut Record,
+) -> Result<(), Box<dyn Error>> {
+    let mut control = ftp::Control::login(control, &url.user, &url.password).await?;
+    let user = record.user.clone();
+
+    if !url.directory {
+        let size = control.command(&format!("SIZE {}", url.path)).await?;
+        let size = (size.code == 213)
+            .then(|| size.text.trim().parse::<u64>().ok())
+            .flatten();
+        let data = FtpData::open(&mut control, state, user.as_deref(), host).await?;
+        let retrieve = format!("RETR {}", url.path);
+        // Box<dyn Error> isn't Send, so it is gone before the next await
+        let unavailable = match control.expect(&retrieve, &[125, 150]).await {
+            Ok(_) => false,
+            Err(e) if is_unavailable(&*e) => true,
+            Err(e) => return Err(e),
+        };
+        if unavailable {
+            drop(data);
+            // Without its trailing slash the path may still name a directory, which is
+            // redirected to so that relative links in its listing resolve
+            control.expect(&format!("CWD {}", url.path), &[250]).await?;
+            let target = head.target.split(['?', '#']).next().unwrap_or_default();
+            let response = format!(
+                    "HTTP/1.1 301 Moved Permanently\r\nLocation: {target}/\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+            client
+                .inner
+                .write_all(&state.tag_response(response.as_bytes(), record))
+                .await?;
+            record.status = Some(301);
+            record.bytes_down = response.len() as u64;
+            let _ = control.command("QUIT").await;
+            return Ok(());
+        }
+        let mut data = data.connected().await?;
+        let length = match size {
+            Some(size) => format!("Content-Length: {size}\r\n"),
+            None => String::new(),
+        };
+        let response_head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n{length}Connection: close\r\n\r\n"
+        );
+        client
+            .inner
+            .write_all(&state.tag_response(response_head.as_bytes(), record))
+            .await?;
+        info!("{} {} -> 200", head.method, head.target);
+        record.status = Some(200);
+        record.bytes_down = response_head.len() as u64;
+        // The body goes through the same limits as a tunnel, and the same admin listing
+        let (_registration, relay_config) = state.tracked_relay(record);
+        let received = tokio::select! {
+            copied = relay::copy(&mut data.stream, &mut *client.inner, &relay_config) => copied?,
+            e = relay::limit(&relay_config) => return Err(e.into()),
+        };
+        Stats::add(&STATS.bytes_from_upstream, received);
+        record.bytes_down += received;
+        drop(data);
+        control.expect_reply(&[226, 250]).await?;
+        let _ = control.command("QUIT").await;
+        return Ok(());
+    }
+
+    if !url.path.is_empty() {
+        control.expect(&format!("CWD {}", url.path), &[250]).await?;
+    }
+    let data = FtpData::open(&mut control, state, user.as_deref(), host).await?;
+    control.expect("LIST", &[125, 150]).await?;
+    let mut data = data.connected().await?;
+    let mut listing = Vec::new();
+    (&mut data.stream)
+        .take(ftp::MAX_LISTING)
+        .read_to_end(&mut listing)
+        .await?;
+    drop(data);
+    control.expect_reply(&[226, 250]).await?;
+    let _ = control.command("QUIT").await;
+
+    let body = ftp::listing_html(&url.path, &listing);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    client
+        .inner
+        .write_all(&state.tag_response(response.as_bytes(), record))
+        .await?;
+    info!("{} {} -> 200", head.method, head.target);
+    record.status = Some(200);
+    Stats::add(&STATS.bytes_from_upstream, listing.len() as u64);
+    record.bytes_down = response.len() as u64;
+    Ok(())
+}
+
+// The data connection of an FTP transfer
+enum FtpData {
+    // Opened to the port the server listens on in passive mode
+    Passive(Tunnel),
+    // Made by the server in active mode, to the upstream listening with SOCKS5 BIND, once it
+    // has the transfer command
+    Active(Bound),
+}
+
+impl FtpData {
+    // Sets up the data connection for the next transfer command over `control`: passive
+    // mode, or active mode through the upstream for servers that refuse passive mode
+    async fn open(
+        control: &mut ftp::Control<UpstreamStream>,
+        state: &ProxyState,
+        user: Option<&str>,
+        host: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Box<dyn Error> isn't Send, so it is gone before the next await
+        let port = match control.passive().await {
+            Ok(port) => Some(port),
+            Err(e) if e.is::<ftp::Refused>() => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(port) = port {
+            return Ok(Self::Passive(open_tunnel(state, user, host, port).await?));
+        }
+        debug!("FTP server refused passive mode, trying active mode");
+        // The server's data connection may come from any port
+        let bound = open_bind(state, user, host, 0).await?;
+        let socks::Address::Ip(addr) = *bound.address() else {
+            return Err("SOCKS5 BIND reply names no IP address to send the FTP server".into());
+        };
+        control.active(addr).await?;
+        Ok(Self::Active(bound))
+    }
+
+    // The data connection, once the server has accepted the transfer command
+    async fn connected(self) -> Result<Tunnel, Box<dyn Error>> {
+        match self {
+            Self::Passive(tunnel) => Ok(tunnel),
+            Self::Active(bound) => Ok(bound.accept().await?.0),
+        }
+    }
+}
+
+fn is_unavailable(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<ftp::Refused>()
+        .is_some_and(ftp::Refused::is_unavailable)
+}
+
+// Waits for the origin to answer `Expect: 100-continue`, passing interim responses on to the
+// client. Returns the origin's final response if it rejects the body outright, or `None`
+// once the body should be sent: after `100 Continue`, or when the client stops waiting and
+// sends it anyway, as it must for origins that ignore the expectation.
+async fn await_continue(
+    client: &mut BufferedStream<&mut ClientStream>,
+    upstream: &mut BufferedStream<UpstreamStream>,
+    max_header_size: usize,
+    record: &mut Record,
+) -> Result<Option<ResponseHead>, HeadError> {
+    loop {
+        let response = tokio::select! {
+            response = read_response_head(upstream, max_header_size) => response?,
+            filled = client.fill() => {
+                if filled? == 0 {
+                    return Err(HeadError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                return Ok(None);
+            }
+        };
+        if !response.is_interim() {
+            return Ok(Some(response));
+        }
+        let interim = upstream.consume(response.len);
+        client.inner.write_all(&interim).await?;
+        client.inner.flush().await?;
+        record.bytes_down += interim.len() as u64;
+        if response.status == 100 {
+            return Ok(None);
+        }
+    }
+}
+
+// Why no connection to a destination could be opened. Box<dyn Error> isn't Send, so only
+// what the client is told is kept across the error response.
+struct ConnectFailure {
+    // Set when a SOCKS5 server refused the request
+    reply: Option<socks::ReplyError>,
+    // Set when --max-dest-connections refused it
+    full: bool,
+    reason: String,
+}
+
+impl ConnectFailure {
+    fn new(e: Box<dyn Error>) -> Self {
+        Self {
+            reply: e.downcast_ref::<socks::ReplyError>().copied(),
+            full: e.is::<limits::DestinationFull>(),
+            reason: e.to_string(),
+        }
+    }
+}
+
+// Logs and counts a failure to connect to host:port, passing it through for use in
+// `map_err`. A refusal by --max-dest-connections was logged when it was made and isn't an
+// upstream error.
+fn connect_error(host: &str, port: u16, e: Box<dyn Error>) -> Box<dyn Error> {
+    if e.is::<limits::DestinationFull>() {
+        return e;
+    }
+    error!("Failed to connect to {}:{}: {}", host, port, e);
+    stats::upstream_error(e)
+}
+
+// How a request that failed to connect ended
+fn termination(e: &(dyn Error + 'static)) -> Termination {
+    if e.is::<limits::DestinationFull>() {
+        Termination::Rejected
+    } else {
+        Termination::UpstreamError
+    }
+}
+
+// Answers a request whose destination could not be reached with failure_response. Returns
+// that the client connection is done.
+async fn connect_failed(
+    client: &mut BufferedStream<&mut ClientStream>,
+    state: &ProxyState,
+    record: &mut Record,
+    failure: ConnectFailure,
+) -> Result<bool, Box<dyn Error>> {
+    let response = failure_response(state, record, failure);
+    client.inner.write_all(&response).await?;
+    Ok(false)
+}
+
+// The response to a request whose destination could not be reached. A SOCKS5 refusal is
+// passed on with a body naming it: 403 for one by the server's ruleset, 504 for an expired
+// TTL and 502 otherwise; a destination over its --max-dest-connections cap gets 503, and any
+// other failure a bare 502. An --error-page for the status replaces the body.
+fn failure_response(state: &ProxyState, record: &mut Record, failure: ConnectFailure) -> Vec<u8> {
+    let response = if failure.full {
+        record.reject(503);
+        Cow::Borrowed(SERVICE_UNAVAILABLE_RESPONSE)
+    } else if let Some(reply) = &failure.reply {
+        let (status, phrase) = match reply.code {
+            0x02 => (403, "Forbidden"),
+            0x06 => (504, "Gateway Timeout"),
+            _ => (502, "Bad Gateway"),
+        };
+        record.termination = Some(Termination::UpstreamError);
+        record.status = Some(status);
+        let body = format!("SOCKS server error: {}\n", reply.description());
+        Cow::Owned(format!(
+            "HTTP/1.1 {status} {phrase}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .into_bytes())
+    } else {
+        record.termination = Some(Termination::UpstreamError);
+        record.status = Some(502);
+        Cow::Borrowed(BAD_GATEWAY_RESPONSE)
+    };
+    let response = state
+        .error_page(&response, &failure.reason, Some(&record.request_id))
+        .into_owned();
+    record.error = Some(failure.reason);
+    response
+}
+
+// Reads the origin's next response head; the origin closing first counts as a reset
+async fn read_response_head(
+    upstream: &mut BufferedStream<UpstreamStream>,
+    max_size: usize,
+) -> Result<ResponseHead, HeadError> {
+    upstream
+        .read_head(max_size, ResponseHead::parse, |head| head.len)
+        .await?
+        .ok_or_else(|| HeadError::Io(std::io::ErrorKind::UnexpectedEof.into()))
+}
+
+// Hands the rest of the connection to the raw relay once HTTP framing can't be followed
+async fn relay_rest(
+    client: &mut BufferedStream<&mut ClientStream>,
+    upstream: &mut BufferedStream<UpstreamStream>,
+    relay_config: &RelayConfig,
+) -> Result<RelayStats, Box<dyn Error>> {
+    let to_upstream = std::mem::take(&mut client.buf);
+    upstream.inner.write_all(&to_upstream).await?;
+    upstream.inner.flush().await?;
+    let to_client = std::mem::take(&mut upstream.buf);
+    client.inner.write_all(&to_client).await?;
+    client.inner.flush().await?;
+    let mut relayed = proxy_data(client.inner, &mut upstream.inner, relay_config).await?;
+    relayed.a_to_b += to_upstream.len() as u64;
+    relayed.b_to_a += to_client.len() as u64;
+    Ok(relayed)
+}
+
+fn is_idempotent(method: &str) -> bool {
+    method.eq_ignore_ascii_case("GET") || method.eq_ignore_ascii_case("HEAD")
+}
+
+// Errors that indicate the tunnel died before the origin produced any response
+fn is_upstream_reset(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+// Checks the Host header against the request target according to the configured policy.
+// Returns false if the request should be rejected.
+fn host_header_consistent(head: &RequestHead, host: &str, port: u16, policy: HostCheck) -> bool {
+    if policy == HostCheck::Off {
+        return true;
+    }
+
+    // A missing Host header leaves nothing to compare against
+    let Some(host_header) = head.header("host") else {
+        return true;
+    };
+
+    let matches = match http::split_host_port(host_header, port) {
+        Some((h, p)) => h.eq_ignore_ascii_case(host) && p == port,
+        None => false,
+    };
+
+    if !matches {
+        warn!(
+            "Host header '{}' does not match request target {}:{}",
+            host_header, host, port
+        );
+    }
+
+    matches || policy == HostCheck::Warn
+}
+
+// Handles forward mode - directly forwards TCP traffic to SOCKS5 proxy, or tunnels it to
+// --forward-target, the original destination in transparent mode or, with `--forward sni`,
+// the host named in the TLS ClientHello
+#[instrument(skip_all, fields(request_id, socks_addr))]
+async fn handle_forward_client(
+    client: &mut ClientStream,
+    peer: SocketAddr,
+    state: &ProxyState,
+    mapping: Option<&PortMapping>,
+) -> Result<(), Box<dyn Error>> {
+    let mut record = Record::new(peer, Instant::now());
+    Span::current().record("request_id", record.request_id.as_str());
+
+    let result = async {
+        let relayed = if let Some(mapping) = mapping {
+            record.target = Some(http::join_host_port(&mapping.host, mapping.port));
+            forward_to(client, state, &mut record, &mapping.host, mapping.port, &[]).await?
+        } else if state.config.transparent {
+            forward_transparent(client, state, &mut record).await?
+        } else if let Some((host, port)) = &state.forward_target {
+            record.target = Some(http::join_host_port(host, *port));
+            forward_to(client, state, &mut record, host, *port, &[]).await?
+        } else if state.config.forward == Some(ForwardMode::Sni) {
+            forward_sni(client, state, &mut record).await?
+        } else {
+            forward_raw(client, state, &mut record).await?
+        };
+        record.bytes_up = relayed.a_to_b;
+        record.bytes_down = relayed.b_to_a;
+        Ok(())
+    }
+    .await;
+
+    state.finish_record(&mut record, &result);
+    result
+}
+
+// Simply connects to the SOCKS server and forwards all traffic
+async fn forward_raw(
+    client: &mut ClientStream,
+    state: &ProxyState,
+    record: &mut Record,
+) -> Result<RelayStats, Box<dyn Error>> {
+    let upstream = state.upstreams.pick();
+    Span::current().record("socks_addr", upstream.addr.as_str());
+    record.upstream = Some(upstream.addr.clone());
+
+    let connect = socks::connect_server(&upstream);
+    let connected = timed(seconds(state.config.connect_timeout), "connect", connect).await;
+    let mut socks = connected
+        .map_err(Into::into)
+        .and_then(|socks| socks)
+        .map_err(|e| {
+            error!("Failed to connect to SOCKS5 server: {}", e);
+            record.termination = Some(Termination::UpstreamError);
+            stats::upstream_error(e)
+        })?;
+    state.connected(record);
+    let _active = ActiveTunnel::new();
+
+    info!("Forwarding connection to SOCKS5 server");
+    let (_tracked, relay_config) = state.tracked_relay(record);
+    proxy_data(client, &mut socks, &relay_config).await
+}
+
+// Tunnels a redirected connection to the destination it was originally addressed to
+async fn forward_transparent(
+    client: &mut ClientStream,
+    state: &ProxyState,
+    record: &mut Record,
+) -> Result<RelayStats, Box<dyn Error>> {
+    let destination = transparent::original_destination(client.tcp())?;
+    record.target = Some(destination.to_string());
+    // Without a redirect the destination is the listener itself, and tunneling would loop
+    let listening = state.config.listen.iter().any(|listen| {
+        http::split_host_port(listen, 0).is_some_and(|(_, port)| port == destination.port())
+    });
+    if destination == client.tcp().local_addr()? && listening {
+        record.termination = Some(Termination::Rejected);
+        return Err("connection was not redirected to the proxy".into());
+    }
+    forward_to(
+        client,
+        state,
+        record,
+        &destination.ip().to_string(),
+        destination.port(),
+        &[],
+    )
+    .await
+}
+
+// Tunnels a TLS connection to port 443 of the host named in its ClientHello
+async fn forward_sni(
+    client: &mut ClientStream,
+    state: &ProxyState,
+    record: &mut Record,
+) -> Result<RelayStats, Box<dyn Error>> {
+    let mut hello = Vec::new();
+    let read = sni::read_server_name(client, &mut hello);
+    let host = timed(seconds(state.config.handshake_timeout), "ClientHello", read).await??;
+    record.target = Some(format!("{host}:{SNI_PORT}"));
+    forward_to(client, state, record, &host, SNI_PORT, &hello).await
+}
+
+// Opens a tunnel to host:port along the routing rules, sends `prefix` (client data read
+// while choosing the destination) and relays the rest of the connection
+async fn forward_to(
+    client: &mut ClientStream,
+    state: &ProxyState,
+    record: &mut Record,
+    host: &str,
+    port: u16,
+    prefix: &[u8],
+) -> Result<RelayStats, Box<dyn Error>> {
+    let mapped = state.map_destination(host, port);
+    let (host, port) = match &mapped {
+        Some((host, port)) => (host.as_str(), *port),
+        None => (host, port),
+    };
+    if let Err(reason) = state.check_request(record, host, port) {
+        record.termination = Some(Termination::Rejected);
+        return Err(reason.into());
+    }
+    let mut tunnel = open_tunnel(state, record.user.as_deref(), host, port)
+        .await
+        .map_err(|e| {
+            let e = connect_error(host, port, e);
+            record.termination = Some(termination(&*e));
+            e
+        })?;
+    Span::current().record("socks_addr", tunnel.upstream.as_str());
+    record.upstream = Some(tunnel.upstream.clone());
+    state.connected(record);
+
+    info!(
+        "Forwarding connection to {}:{} via {}",
+        host, port, tunnel.upstream
+    );
+    tunnel.stream.write_all(prefix).await?;
+    let (_tracked, relay_config) = state.tracked_relay(record);
+    let mut relayed = proxy_data(client, &mut tunnel.stream, &relay_config).await?;
+    relayed.a_to_b += prefix.len() as u64;
+    Ok(relayed)
+}
+
+// An open connection to the request target, either direct or through an upstream
+struct Tunnel {
+    stream: UpstreamStream,
+    // `direct` or the upstream used, for the access log
+    upstream: String,
+    // Set when the stream leads to an HTTP upstream that takes plain requests itself
+    forward_proxy: Option<ForwardProxy>,
+    // Counts towards the balanced upstream's open tunnels while held
+    _lease: Option<Lease>,
+    // Likewise for a --max-dest-connections cap
+    _permit: Option<limits::DestinationPermit>,
+    _active: ActiveTunnel,
+}
+
+// Connects to host:port along the route the routing rules choose for `user`, falling back
+// to the HTTP upstream in socks2http mode, or else the balanced upstream pool, when no
+// rule matches
+async fn open_tunnel(
+    state: &ProxyState,
+    user: Option<&str>,
+    host: &str,
+    port: u16,
+) -> Result<Tunnel, Box<dyn Error>> {
+    open_connection(state, user, host, port, false).await
+}
+
+// Like `open_tunnel`, for plain HTTP requests: an HTTP upstream is connected to without
+// CONNECT, so that it can be sent the requests themselves
+async fn open_origin(
+    state: &ProxyState,
+    user: Option<&str>,
+    host: &str,
+    port: u16,
+) -> Result<Tunnel, Box<dyn Error>> {
+    open_connection(state, user, host, port, true).await
+}
+
+async fn open_connection(
+    state: &ProxyState,
+    user: Option<&str>,
+    host: &str,
+    port: u16,
+    plain_http: bool,
+) -> Result<Tunnel, Box<dyn Error>> {
+    let permit = state
+        .destination_caps
+        .try_acquire(host)
+        .inspect_err(|full| {
+            warn!("Refusing connection to {}:{}: {}", host, port, full);
+        })?;
+    let (stream, upstream, forward_proxy, lease) =
+        connect_route(state, user, host, port, plain_http).await?;
+    if let Some(tcp) = stream.tcp() {
+        state.tune_socket(tcp);
+    }
+    Ok(Tunnel {
+        stream,
+        upstream,
+        forward_proxy,
+        _lease: lease,
+        _permit: permit,
+        _active: ActiveTunnel::new(),
+    })
+}
+
+// A SOCKS5 BIND opened through an upstream, waiting for the destination to connect
+struct Bound {
+    bind: socks::Bind,
+    upstream: String,
+    lease: Option<Lease>,
+}
+
+impl Bound {
+    // Where the upstream listens for the destination
+    fn address(&self) -> &socks::Address {
+        &self.bind.address
+    }
+
+    // Waits for the destination to connect, returning the connection as a tunnel along with
+    // the destination's address
+    async fn accept(self) -> Result<(Tunnel, socks::Address), Box<dyn Error>> {
+        let (stream, peer) = self.bind.accept().await?;
+        let tunnel = Tunnel {
+            stream,
+            upstream: self.upstream,
+            forward_proxy: None,
+            _lease: self.lease,
+            _permit: None,
+            _active: ActiveTunnel::new(),
+        };
+        Ok((tunnel, peer))
+    }
+}
+
+// Asks the upstream that `host` is routed to for `user` to accept a connection from
+// `host:port` with SOCKS5 BIND. Only SOCKS5 upstreams can; direct routes and HTTP proxies
+// have no equivalent.
+async fn open_bind(
+    state: &ProxyState,
+    user: Option<&str>,
+    host: &str,
+    port: u16,
+) -> Result<Bound, Box<dyn Error>> {
+    let mut lease = None;
+    let upstream = socks5_upstream(state, user, host, "SOCKS5 BIND", &mut lease).await?;
+    debug!("Binding for {}:{} via {}", host, port, upstream.addr);
+    let timeout = seconds(state.config.connect_timeout);
+    let bind = timed(timeout, "bind", socks::bind_socks5(host, port, upstream)).await??;
+    Ok(Bound {
+        bind,
+        upstream: upstream.addr.clone(),
+        lease,
+    })
+}
+
+// The SOCKS5 server `host` is routed to for `user`, for `what` only SOCKS5 can carry: a rule
+// upstream, or one picked from the balanced pool and leased into `lease`. Direct routes, HTTP
+// proxies and older SOCKS versions are refused rather than passed over.
+async fn socks5_upstream<'a>(
+    state: &'a ProxyState,
+    user: Option<&str>,
+    host: &str,
+    what: &str,
+    lease: &'a mut Option<Lease>,
+) -> Result<&'a Upstream, String> {
+    let country = state.country(host).await;
+    let upstream: &Upstream = match state.router.route(user, host, country.as_deref()) {
+        Some(Route::Direct) => {
+            return Err(format!(
+                "{host} is routed directly, where {what} is unavailable"
+            ))
+        }
+        Some(Route::Upstream(upstream)) => upstream,
+        None if state.http_upstream.is_some() => {
+            return Err(format!("{what} is unavailable through --http-upstream"))
+        }
+        None => lease.insert(state.upstreams.pick()),
+    };
+    if upstream.protocol != Protocol::Socks || upstream.version != SocksVersion::V5 {
+        return Err(format!(
+            "upstream {} is not a SOCKS5 server, which {what} needs",
+            upstream.addr
+        ));
+    }
+    Ok(upstream)
+}
+
+// Connects along the chosen route within --connect-timeout, returning the stream, its
+// upstream label, whether it leads to an HTTP upstream forwarding plain requests, and any
+// pool lease
+async fn connect_route(
+    state: &ProxyState,
+    user: Option<&str>,
+    host: &str,
+    port: u16,
+    plain_http: bool,
+) -> Result<(UpstreamStream, String, Option<ForwardProxy>, Option<Lease>), Box<dyn Error>> {
+    let timeout = seconds(state.config.connect_timeout);
+    let country = state.country(host).await;
+    let upstream: &Upstream = match state.router.route(user, host, country.as_deref()) {
+        Some(Route::Direct) => {
+            debug!("Routing {}:{} directly", host, port);
+            let stream = timed(timeout, "connect", connect_direct(state, host, port)).await??;
+            return Ok((
+                UpstreamStream::Plain(stream),
+                "direct".to_string(),
+                None,
+                None,
+            ));
+        }
+        Some(Route::Upstream(upstream)) => {
+            debug!(
+                "Routing {}:{} via rule upstream {}",
+                host, port, upstream.addr
+            );
+            upstream
+        }
+        None => match &state.http_upstream {
+            Some(upstream) => upstream,
+            None => {
+                let error = match connect_pool(state, host, port, plain_http, timeout).await {
+                    Ok(connected) => return Ok(connected),
+                    Err(e) => e,
+                };
+                let reason = fallback_reason(state, error)?;
+                return connect_fallback(state, host, port, timeout, &reason).await;
+            }
+        },
+    };
+    let connect = connect_via(state, upstream, host, port, plain_http);
+    let reason = match timed(timeout, "connect", connect).await {
+        Ok(Ok((stream, forward_proxy))) => {
+            return Ok((stream, upstream.addr.clone(), forward_proxy, None))
+        }
+        Ok(Err(e)) => fallback_reason(state, e)?,
+        Err(e) => fallback_reason(state, e.into())?,
+    };
+    connect_fallback(state, host, port, timeout, &reason).await
+}
+
+// Passes a --dns-listen query on to --dns-server. It always goes through the SOCKS upstreams,
+// whatever the routing rules say and even with --fallback-direct, so lookups don't leak past
+// the tunnel.
+async fn resolve_dns(state: Arc<ProxyState>, query: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (host, port) = &state.dns_server;
+    match state.config.dns_transport {
+        DnsTransport::Tcp => {
+            let timeout = seconds(state.config.connect_timeout);
+            let (mut stream, _, _, _lease) =
+                connect_pool(&state, host, *port, false, timeout).await?;
+            Ok(encrypted_dns::exchange_tcp(&mut stream, &query).await?)
+        }
+        DnsTransport::Udp => {
+            let mut datagram = vec![0, 0, 0];
+            socks::encode_address(&mut datagram, host, *port)?;
+            datagram.extend_from_slice(&query);
+            let upstream = state.upstreams.pick();
+            let association = udp::UdpAssociation::open(&upstream)
+                .await
+                .map_err(|e| e.to_string())?;
+            association.send_raw(&datagram).await?;
+            let mut buf = vec![0u8; 65535];
+            loop {
+                let n = association.recv_raw(&mut buf).await?;
+                if let Some((_, answer)) = udp::decapsulate(&buf[..n]) {
+                    return Ok(answer.to_vec());
+                }
+            }
+        }
+    }
+}
+
+// Connects through the balanced upstreams. When the chosen one fails, the same destination
+// is tried through each of the others in turn, each attempt getting the full `timeout`, so a
+// single flaky exit doesn't fail the request. The last error is returned when all of them
+// fail; it is up to the caller whether to fall back to a direct connection.
+async fn connect_pool(
+    state: &ProxyState,
+    host: &str,
+    port: u16,
+    plain_http: bool,
+    timeout: Option<Duration>,
+) -> Result<(UpstreamStream, String, Option<ForwardProxy>, Option<Lease>), Box<dyn Error>> {
+    let mut tried = Vec::new();
+    let mut lease = state.upstreams.pick();
+    loop {
+        let connect = connect_via(state, &lease, host, port, plain_http);
+        let error = match timed(timeout, "connect", connect).await {
+            Ok(Ok((stream, forward_proxy))) => {
+                return Ok((stream, lease.addr.clone(), forward_proxy, Some(lease)))
+            }
+            Ok(Err(e)) => e,
+            Err(e) => e.into(),
+        };
+        tried.push(lease.addr.clone());
+        let Some(next) = state.upstreams.pick_except(&tried) else {
+            return Err(error);
+        };
+        warn!(
+            "Connecting to {}:{} through {} failed, failing over to {}: {}",
+            host, port, lease.addr, next.addr, error
+        );
+        Stats::inc(&STATS.upstream_errors);
+        lease = next;
+    }
+}
+
+// With --fallback-direct, why a request that failed through the upstreams is connected
+// directly instead: only when no upstream could be reached at all, as a refusal from one that
+// is up still fails the request. Otherwise the error is passed back.
+fn fallback_reason(state: &ProxyState, error: Box<dyn Error>) -> Result<String, Box<dyn Error>> {
+    if state.config.fallback_direct && error.is::<std::io::Error>() {
+        Ok(error.to_string())
+    } else {
+        Err(error)
+    }
+}
+
+// Connects straight to the destination after the upstreams failed for `reason`
+async fn connect_fallback(
+    state: &ProxyState,
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+    reason: &str,
+) -> Result<(UpstreamStream, String, Option<ForwardProxy>, Option<Lease>), Box<dyn Error>> {
+    warn!(
+        "No upstream reachable for {}:{} ({}), connecting directly without the proxy",
+        host, port, reason
+    );
+    Stats::inc(&STATS.upstream_errors);
+    Stats::inc(&STATS.direct_fallbacks);
+    let stream = timed(timeout, "connect", connect_direct(state, host, port)).await??;
+    Ok((
+        UpstreamStream::Plain(stream),
+        "direct".to_string(),
+        None,
+        None,
+    ))
+}
+
+// Connects to `host:port` through `upstream`, or just to `upstream` when it is an HTTP proxy
+// that plain HTTP requests are sent to as they are
+async fn connect_via(
+    state: &ProxyState,
+    upstream: &Upstream,
+    host: &str,
+    port: u16,
+    plain_http: bool,
+) -> Result<(UpstreamStream, Option<ForwardProxy>), Box<dyn Error>> {
+    if plain_http && upstream.protocol == Protocol::Http {
+        let stream = socks::connect_server(upstream).await?;
+        return Ok((stream, Some(ForwardProxy::new(upstream))));
+    }
+    let started = Instant::now();
+    let stream = if state.config.resolve == Resolve::Remote || host.parse::<IpAddr>().is_ok() {
+        state.connector().connect(host, port, upstream).await?
+    } else {
+        // --resolve local hands the upstream addresses, racing them as a direct connect would
+        let addrs = state.resolver.lookup(host).await?;
+        debug!("Resolved {} locally to {:?}", host, addrs);
+        happy_eyeballs::connect(addrs, |ip| async move {
+            state
+                .connector()
+                .connect(&ip.to_string(), port, upstream)
+                .await
+        })
+        .await?
+    };
+    STATS.handshake_latency.record(started.elapsed());
+    Ok((stream, None))
+}
+
+// Connects straight to the destination from the --bind-out address and --bind-interface,
+// racing the addresses it resolves to with Happy Eyeballs
+async fn connect_direct(state: &ProxyState, host: &str, port: u16) -> std::io::Result<TcpStream> {
+    let addrs: Vec<IpAddr> = state
+        .resolver
+        .lookup(host)
+        .await?
+        .into_iter()
+        .filter(|ip| state.egress.reaches(*ip))
+        .collect();
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no address of the --bind-out address family",
+        ));
+    }
+    happy_eyeballs::connect(addrs, |ip| state.egress.connect(SocketAddr::new(ip, port))).await
+}
+
+// Handles bidirectional data transfer between client and SOCKS connection
+#[instrument(skip_all)]
+async fn proxy_data(
+    client: &mut ClientStream,
+    socks: &mut UpstreamStream,
+    relay_config: &RelayConfig,
+) -> Result<RelayStats, Box<dyn Error>> {
+    let relay = async {
+        match (&*client, &*socks) {
+            (ClientStream::Plain(a), UpstreamStream::Plain(b)) if splice::applies(relay_config) => {
+                debug!("Relaying with splice");
+                splice::relay(a, b, relay_config).await
+            }
+            _ => relay::relay(client, socks, relay_config).await,
+        }
+    };
+    // Byte and time quotas and the admin endpoint cut a tunnel off from outside the relay
+    let relayed = tokio::select! {
+        relayed = relay => relayed,
+        e = relay::limit(relay_config) => Err(e),
+    };
+    match relayed {
+        Ok(stats) => {
+            Stats::add(&STATS.bytes_from_client, stats.a_to_b);
+            Stats::add(&STATS.bytes_from_upstream, stats.b_to_a);
+            info!(
+                "Proxied {} bytes from client, {} bytes from socks",
+                stats.a_to_b, stats.b_to_a
+            );
+            debug!(
+                "Peak tunnel buffer {} bytes; relays holding {} of {} allocated bytes",
+                stats.peak_buffered,
+                relay::buffered_bytes(),
+                relay::allocated_bytes()
+            );
+            Ok(stats)
+        }
+        Err(e) => {
+            error!("Proxy data error: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+// Tears down an errored client connection according to the configured abort mode
+#[instrument(skip(client))]
+async fn abort_connection(mut client: ClientStream, mode: AbortMode, linger_secs: u64) {
+    match mode {
+        AbortMode::Rst => {
+            // A zero linger timeout makes close() send RST instead of FIN
+            if let Err(e) = socket2::SockRef::from(client.tcp()).set_linger(Some(Duration::ZERO)) {
+                warn!("Failed to set SO_LINGER: {}", e);
+            }
+        }
+        AbortMode::Fin => {
+            if client.shutdown().await.is_err() {
+                return;
+            }
+            // Discard whatever the client still sends so the close doesn't turn into a RST
+            let mut sink = [0u8; 4096];
+            let drain = async {
+                while let Ok(n) = client.read(&mut sink).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            };
+            let _ = tokio::time::timeout(Duration::from_secs(linger_secs), drain).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn running_out_of_descriptors_is_transient() {
+        let transient = |code| is_transient_accept_error(&std::io::Error::from_raw_os_error(code));
+        assert!(transient(libc::EMFILE));
+        assert!(transient(libc::ENFILE));
+        assert!(transient(libc::ECONNABORTED));
+        assert!(!transient(libc::EBADF));
+        assert!(!transient(libc::EINVAL));
+    }
+}
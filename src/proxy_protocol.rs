@@ -0,0 +1,219 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// PROXY protocol constants (haproxy.org/download/2.9/doc/proxy-protocol.txt)
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION: u8 = 0x2;
+const V2_COMMAND_LOCAL: u8 = 0x0;
+const V2_COMMAND_PROXY: u8 = 0x1;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+// Longest version 1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `stream`, consuming exactly its
+/// bytes. Returns the original client address, or `None` when the header carries none
+/// (v1 `UNKNOWN`, v2 `LOCAL` or a non-TCP address family).
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least this long, so reading it can't take bytes past the header
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+
+    // The v1 line has no length prefix, so read it a byte at a time
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+// Parses `PROXY TCP4|TCP6 src dst sport dport` or `PROXY UNKNOWN ...`
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("malformed PROXY header"))?;
+    match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("malformed PROXY header source address"))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid("malformed PROXY header source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+// Reads the rest of a binary v2 header after its signature
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len @ ..] = header;
+    if version_command >> 4 != V2_VERSION {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let mut addresses = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    match (version_command & 0x0f, family) {
+        // Health checks from the proxy itself carry no client
+        (V2_COMMAND_LOCAL, _) => Ok(None),
+        // Source address, destination address, source port, destination port
+        (V2_COMMAND_PROXY, V2_FAMILY_TCP4) => {
+            let (ip, rest) = addresses
+                .split_first_chunk::<4>()
+                .ok_or_else(|| invalid("truncated PROXY header"))?;
+            let (port, _) = rest
+                .get(4..)
+                .and_then(|rest| rest.split_first_chunk::<2>())
+                .ok_or_else(|| invalid("truncated PROXY header"))?;
+            let ip = Ipv4Addr::from(*ip).into();
+            Ok(Some(SocketAddr::new(ip, u16::from_be_bytes(*port))))
+        }
+        (V2_COMMAND_PROXY, V2_FAMILY_TCP6) => {
+            let (ip, rest) = addresses
+                .split_first_chunk::<16>()
+                .ok_or_else(|| invalid("truncated PROXY header"))?;
+            let (port, _) = rest
+                .get(16..)
+                .and_then(|rest| rest.split_first_chunk::<2>())
+                .ok_or_else(|| invalid("truncated PROXY header"))?;
+            let ip = Ipv6Addr::from(*ip).into();
+            Ok(Some(SocketAddr::new(ip, u16::from_be_bytes(*port))))
+        }
+        (V2_COMMAND_PROXY, _) => Ok(None),
+        _ => Err(invalid("unknown PROXY protocol command")),
+    }
+}
+
+// A malformed header error
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_header(&mut header).await
+    }
+
+    // A v2 PROXY header with `command` and `family`, followed by `addresses`
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(V2_VERSION << 4 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4_and_tcp6() {
+        let addr = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let addr = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_carries_no_address() {
+        let addr = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(addr.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_header_is_consumed_exactly() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 1 2\r\nGET / HTTP/1.1\r\n";
+        read_header(&mut stream).await.unwrap();
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_malformed_headers_are_rejected() {
+        for header in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 example.com 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+        ] {
+            let e = read(header).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_overlong_line_is_rejected() {
+        let header = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LEN));
+        let e = read(header.as_bytes()).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4_and_tcp6() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let header = v2(V2_COMMAND_PROXY, V2_FAMILY_TCP4, &addresses);
+        let addr = read(&header).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let source = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+        let destination = "2001:db8::2".parse::<Ipv6Addr>().unwrap().octets();
+        let header = v2(
+            V2_COMMAND_PROXY,
+            V2_FAMILY_TCP6,
+            &[&source[..], &destination, &[0xdc, 0x04, 0x01, 0xbb]].concat(),
+        );
+        let addr = read(&header).await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_tlvs_after_the_addresses_are_consumed() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        // A PP2_TYPE_NOOP TLV
+        let tlv = [0x04, 0x00, 0x02, 0, 0];
+        let header = v2(
+            V2_COMMAND_PROXY,
+            V2_FAMILY_TCP4,
+            &[&addresses[..], &tlv].concat(),
+        );
+        let stream = [&header[..], b"data"].concat();
+        let mut stream = &stream[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"data");
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_other_families_carry_no_address() {
+        assert_eq!(read(&v2(V2_COMMAND_LOCAL, 0, &[])).await.unwrap(), None);
+        // AF_UNIX stream
+        let header = v2(V2_COMMAND_PROXY, 0x31, &[0; 216]);
+        assert_eq!(read(&header).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_bad_headers_are_rejected() {
+        let truncated = v2(V2_COMMAND_PROXY, V2_FAMILY_TCP4, &[192, 0, 2, 1]);
+        let unknown_command = v2(0x2, V2_FAMILY_TCP4, &[0; 12]);
+        let mut wrong_version = v2(V2_COMMAND_PROXY, V2_FAMILY_TCP4, &[0; 12]);
+        wrong_version[12] = 0x11;
+        for header in [truncated, unknown_command, wrong_version] {
+            let e = read(&header).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
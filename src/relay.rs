@@ -0,0 +1,359 @@
+use crate::throttle::{SharedBucket, Throttle};
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+// Bytes currently sitting in relay buffers across all tunnels
+static BUFFERED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+// Bytes allocated for relay buffers across all tunnels
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of bytes currently buffered by all relays.
+pub fn buffered_bytes() -> usize {
+    BUFFERED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Returns the number of bytes allocated for relay buffers by all relays.
+pub fn allocated_bytes() -> usize {
+    ALLOCATED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Buffering and rate limits applied to each direction of a relay.
+#[derive(Clone)]
+pub struct RelayConfig {
+    /// Reading from the sender pauses once this many bytes are waiting for the receiver
+    pub high_watermark: usize,
+    /// Reading resumes once the backlog has drained to this many bytes
+    pub low_watermark: usize,
+    /// The relay fails with `TimedOut` once neither direction has moved data for this long
+    pub idle_timeout: Option<Duration>,
+    /// Bytes per second allowed in each direction of this relay
+    pub rate_limit: Option<u64>,
+    /// Buckets shared with all other relays, for `a` to `b` and `b` to `a` respectively
+    pub global_rate_limit: Option<[SharedBucket; 2]>,
+    /// Kept up to date with the bytes transferred so far, for watching a relay in progress
+    pub progress: Option<Arc<Progress>>,
+    /// The relay is cut off once this many bytes have passed in both directions together;
+    /// needs `progress`
+    pub max_bytes: Option<u64>,
+    /// The relay is cut off once it has run this long, however busy it is
+    pub max_duration: Option<Duration>,
+    /// The relay is cut off when this is notified, e.g. from the admin endpoint
+    pub stop: Option<Arc<Notify>>,
+}
+
+/// Bytes a running relay has transferred in each direction.
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub a_to_b: AtomicU64,
+    pub b_to_a: AtomicU64,
+}
+
+/// Per-tunnel transfer totals reported when a relay finishes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RelayStats {
+    pub a_to_b: u64,
+    pub b_to_a: u64,
+    /// Largest backlog observed in either direction
+    pub peak_buffered: usize,
+}
+
+// One direction of a relay: a bounded buffer between a reader and a writer
+struct Pipe {
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    low_watermark: usize,
+    paused: bool,
+    read_done: bool,
+    need_flush: bool,
+    finished: bool,
+    throttle: Throttle,
+    received: u64,
+    transferred: u64,
+    peak: usize,
+}
+
+impl Pipe {
+    fn new(config: &RelayConfig, global: Option<SharedBucket>) -> Self {
+        ALLOCATED_BYTES.fetch_add(config.high_watermark, Ordering::Relaxed);
+        Self {
+            buf: vec![0u8; config.high_watermark].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            low_watermark: config.low_watermark,
+            paused: false,
+            read_done: false,
+            need_flush: false,
+            finished: false,
+            throttle: Throttle::new(config.rate_limit, global),
+            received: 0,
+            transferred: 0,
+            peak: 0,
+        }
+    }
+
+    fn buffered(&self) -> usize {
+        self.end - self.start
+    }
+
+    // Grows whenever data is read or written, for idle detection
+    fn activity(&self) -> u64 {
+        self.received + self.transferred
+    }
+
+    // Reads into the buffer unless paused, then writes out whatever is buffered
+    fn poll_copy<R, W>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut reader: Pin<&mut R>,
+        mut writer: Pin<&mut W>,
+    ) -> Poll<io::Result<u64>>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+    {
+        loop {
+            let mut progressed = false;
+
+            // Make room at the tail by moving the backlog to the front
+            if self.end == self.buf.len() && self.start > 0 {
+                self.buf.copy_within(self.start..self.end, 0);
+                self.end -= self.start;
+                self.start = 0;
+            }
+
+            let allowance = if !self.read_done && !self.paused && self.end < self.buf.len() {
+                self.throttle.poll_allowance(cx, self.buf.len() - self.end)
+            } else {
+                Poll::Pending
+            };
+            if let Poll::Ready(allowance) = allowance {
+                let mut read_buf = ReadBuf::new(&mut self.buf[self.end..self.end + allowance]);
+                match reader.as_mut().poll_read(cx, &mut read_buf) {
+                    Poll::Ready(Ok(())) => {
+                        let n = read_buf.filled().len();
+                        if n == 0 {
+                            self.read_done = true;
+                        } else {
+                            self.throttle.consume(n);
+                            self.end += n;
+                            self.received += n as u64;
+                            BUFFERED_BYTES.fetch_add(n, Ordering::Relaxed);
+                            self.peak = self.peak.max(self.buffered());
+                            // The buffer is sized to the high watermark, so a full buffer means pause
+                            self.paused = self.end == self.buf.len();
+                        }
+                        progressed = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {}
+                }
+            }
+
+            if self.start < self.end {
+                match writer
+                    .as_mut()
+                    .poll_write(cx, &self.buf[self.start..self.end])
+                {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    Poll::Ready(Ok(n)) => {
+                        self.start += n;
+                        self.transferred += n as u64;
+                        self.need_flush = true;
+                        BUFFERED_BYTES.fetch_sub(n, Ordering::Relaxed);
+                        if self.start == self.end {
+                            self.start = 0;
+                            self.end = 0;
+                        }
+                        if self.paused && self.buffered() <= self.low_watermark {
+                            self.paused = false;
+                        }
+                        progressed = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {}
+                }
+            } else if self.need_flush {
+                ready!(writer.as_mut().poll_flush(cx))?;
+                self.need_flush = false;
+            }
+
+            if self.read_done && self.start == self.end {
+                ready!(writer.as_mut().poll_shutdown(cx))?;
+                return Poll::Ready(Ok(self.transferred));
+            }
+
+            // Every branch that made no progress registered the waker
+            if !progressed {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        BUFFERED_BYTES.fetch_sub(self.buffered(), Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_sub(self.buf.len(), Ordering::Relaxed);
+    }
+}
+
+// How often `limit` checks the byte count of a relay against its quota
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Resolves once the relay run with `config` has to be cut off: its byte quota is used up,
+/// its time is up, or it was told to stop. Never resolves when none of these is set.
+pub async fn limit(config: &RelayConfig) -> io::Error {
+    let duration = async {
+        match config.max_duration {
+            Some(duration) => tokio::time::sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+    let quota = async {
+        let (Some(max_bytes), Some(progress)) = (config.max_bytes, &config.progress) else {
+            return std::future::pending().await;
+        };
+        let mut interval = tokio::time::interval(QUOTA_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let transferred =
+                progress.a_to_b.load(Ordering::Relaxed) + progress.b_to_a.load(Ordering::Relaxed);
+            if transferred >= max_bytes {
+                return transferred;
+            }
+        }
+    };
+    let stop = async {
+        match &config.stop {
+            Some(stop) => stop.notified().await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        () = duration => io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("tunnel open for {}s", config.max_duration.unwrap_or_default().as_secs()),
+        ),
+        transferred = quota => io::Error::other(format!("tunnel transferred {transferred} bytes")),
+        () = stop => io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "tunnel closed through the admin endpoint",
+        ),
+    }
+}
+
+/// Copies data from `reader` to `writer` until EOF, then shuts `writer` down, under the
+/// same buffering, rate limits and idle timeout as the `b` to `a` direction of a relay.
+pub async fn copy<R, W>(reader: &mut R, writer: &mut W, config: &RelayConfig) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let global = config.global_rate_limit.clone().map(|[_, b_to_a]| b_to_a);
+    let mut pipe = Pipe::new(config, global);
+    let mut idle = config
+        .idle_timeout
+        .map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+    let mut activity = 0;
+
+    poll_fn(|cx| {
+        let copied = pipe.poll_copy(cx, Pin::new(&mut *reader), Pin::new(&mut *writer));
+        if let Some(progress) = &config.progress {
+            progress.b_to_a.store(pipe.transferred, Ordering::Relaxed);
+        }
+        if let Poll::Ready(copied) = copied {
+            return Poll::Ready(copied);
+        }
+        if let Some((timeout, deadline)) = &mut idle {
+            if pipe.activity() != activity {
+                activity = pipe.activity();
+                deadline.as_mut().reset(Instant::now() + *timeout);
+            }
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("transfer idle for {}s", timeout.as_secs()),
+                )));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Copies data in both directions between `a` and `b` until both sides reach EOF,
+/// holding at most `high_watermark` bytes per direction in memory, or until the
+/// relay has been idle for `idle_timeout`.
+pub async fn relay<A, B>(a: &mut A, b: &mut B, config: &RelayConfig) -> io::Result<RelayStats>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let [global_a_to_b, global_b_to_a] = config
+        .global_rate_limit
+        .clone()
+        .map_or([None, None], |buckets| buckets.map(Some));
+    let mut a_to_b = Pipe::new(config, global_a_to_b);
+    let mut b_to_a = Pipe::new(config, global_b_to_a);
+    let mut idle = config
+        .idle_timeout
+        .map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+    let mut activity = 0;
+
+    poll_fn(|cx| {
+        if !a_to_b.finished {
+            if let Poll::Ready(n) = a_to_b.poll_copy(cx, Pin::new(&mut *a), Pin::new(&mut *b)) {
+                n?;
+                a_to_b.finished = true;
+            }
+        }
+        if !b_to_a.finished {
+            if let Poll::Ready(n) = b_to_a.poll_copy(cx, Pin::new(&mut *b), Pin::new(&mut *a)) {
+                n?;
+                b_to_a.finished = true;
+            }
+        }
+
+        if let Some(progress) = &config.progress {
+            progress.a_to_b.store(a_to_b.transferred, Ordering::Relaxed);
+            progress.b_to_a.store(b_to_a.transferred, Ordering::Relaxed);
+        }
+        if a_to_b.finished && b_to_a.finished {
+            return Poll::Ready(Ok::<(), io::Error>(()));
+        }
+
+        if let Some((timeout, deadline)) = &mut idle {
+            let current = a_to_b.activity() + b_to_a.activity();
+            if current != activity {
+                activity = current;
+                deadline.as_mut().reset(Instant::now() + *timeout);
+            }
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("tunnel idle for {}s", timeout.as_secs()),
+                )));
+            }
+        }
+        Poll::Pending
+    })
+    .await?;
+
+    Ok(RelayStats {
+        a_to_b: a_to_b.transferred,
+        b_to_a: b_to_a.transferred,
+        peak_buffered: a_to_b.peak.max(b_to_a.peak),
+    })
+}
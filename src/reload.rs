@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::time::{Interval, MissedTickBehavior};
+
+// How often watched files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Signals when the configuration should be re-read: on SIGHUP, and when watching,
+/// whenever one of the watched files changes.
+pub struct ReloadTrigger {
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+    watch: Option<Watch>,
+}
+
+// Files polled for modification and the state they were last seen in
+struct Watch {
+    interval: Interval,
+    files: Vec<PathBuf>,
+    seen: Vec<Option<SystemTime>>,
+}
+
+impl ReloadTrigger {
+    /// Installs the SIGHUP handler and, if `watch` is given, starts polling those files.
+    pub fn new(watch: Option<Vec<PathBuf>>) -> std::io::Result<Self> {
+        let mut trigger = Self {
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+            watch: None,
+        };
+        trigger.set_watch(watch);
+        Ok(trigger)
+    }
+
+    /// Replaces the set of watched files, e.g. after a reload changed which files are in use.
+    pub fn set_watch(&mut self, files: Option<Vec<PathBuf>>) {
+        self.watch = files.map(|files| {
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Watch {
+                interval,
+                seen: modified_times(&files),
+                files,
+            }
+        });
+    }
+
+    /// Resolves with the reason once a reload is due.
+    pub async fn triggered(&mut self) -> &'static str {
+        let watched = &mut self.watch;
+        let watch = async {
+            match watched {
+                Some(watch) => loop {
+                    watch.interval.tick().await;
+                    let current = modified_times(&watch.files);
+                    if current != watch.seen {
+                        watch.seen = current;
+                        return "file change";
+                    }
+                },
+                None => std::future::pending().await,
+            }
+        };
+
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.hangup.recv() => "SIGHUP",
+                reason = watch => reason,
+            }
+        }
+        #[cfg(not(unix))]
+        watch.await
+    }
+}
+
+// A missing file is recorded as None, so creating or deleting it also counts as a change
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
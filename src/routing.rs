@@ -0,0 +1,616 @@
+use crate::http;
+use crate::socks::{ConnectRetry, Protocol, SocksVersion, Upstream};
+use crate::tls;
+use crate::upstream::UpstreamUrl;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsConnector;
+
+/// A destination host pattern: `*`, an exact name or IP, `*.suffix` (subdomains only),
+/// `.suffix` (the domain and its subdomains) or a CIDR block such as `10.0.0.0/8`. Routing
+/// rules may also match the destination's country, `country:CN`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Any,
+    Exact(String),
+    Suffix {
+        suffix: String,
+        include_apex: bool,
+    },
+    Cidr(IpAddr, u8),
+    /// An uppercase ISO 3166-1 country code, looked up with --geoip-db
+    Country(String),
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = normalize_host(pattern);
+        if pattern.is_empty() {
+            return Err("empty host pattern".into());
+        }
+        if pattern == "*" {
+            return Ok(Self::Any);
+        }
+
+        if let Some((addr, prefix)) = pattern.split_once('/') {
+            let addr: IpAddr = addr
+                .parse()
+                .map_err(|_| format!("invalid CIDR address in '{pattern}'"))?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid CIDR prefix length in '{pattern}'"))?;
+            return Ok(Self::Cidr(addr, prefix));
+        }
+
+        if let Some(suffix) = pattern.strip_prefix("*.") {
+            return Ok(Self::Suffix {
+                suffix: suffix.to_string(),
+                include_apex: false,
+            });
+        }
+        if let Some(suffix) = pattern.strip_prefix('.') {
+            return Ok(Self::Suffix {
+                suffix: suffix.to_string(),
+                include_apex: true,
+            });
+        }
+        if pattern.contains('*') {
+            return Err(format!(
+                "'*' is only allowed as a leading '*.' label in '{pattern}'"
+            ));
+        }
+
+        Ok(Self::Exact(pattern))
+    }
+
+    /// Parses one `NO_PROXY`-style entry as curl interprets it: `*` matches everything, IPs and
+    /// CIDR blocks match addresses, and a domain (with or without a leading `.` or `*.`) matches
+    /// itself and all of its subdomains. `localhost` additionally covers the loopback addresses.
+    pub fn parse_no_proxy(entry: &str) -> Result<Vec<Self>, String> {
+        let entry = normalize_host(entry);
+        if entry == "*" || entry.contains('/') || entry.parse::<IpAddr>().is_ok() {
+            return Ok(vec![Self::parse(&entry)?]);
+        }
+
+        let domain = entry
+            .strip_prefix("*.")
+            .or_else(|| entry.strip_prefix('.'))
+            .unwrap_or(&entry);
+        if domain.is_empty() || domain.contains('*') {
+            return Err(format!("invalid no-proxy entry '{entry}'"));
+        }
+
+        let mut patterns = vec![Self::Suffix {
+            suffix: domain.to_string(),
+            include_apex: true,
+        }];
+        if domain == "localhost" {
+            patterns.push(Self::parse("127.0.0.0/8")?);
+            patterns.push(Self::parse("::1/128")?);
+        }
+        Ok(patterns)
+    }
+
+    // Parses `country:XX`, which only routing rules accept
+    fn parse_country(pattern: &str) -> Option<Result<Self, String>> {
+        let (prefix, code) = pattern.trim().split_once(':')?;
+        if !prefix.eq_ignore_ascii_case("country") {
+            return None;
+        }
+        let code = code.trim();
+        Some(
+            if code.len() == 2 && code.bytes().all(|b| b.is_ascii_alphabetic()) {
+                Ok(Self::Country(code.to_ascii_uppercase()))
+            } else {
+                Err(format!("expected a two-letter country code, got '{code}'"))
+            },
+        )
+    }
+
+    /// Whether `host` (a hostname or IP literal, IPv6 optionally bracketed) matches.
+    /// CIDR patterns only match IP literals; hostnames are never resolved for matching, so
+    /// country patterns never match.
+    pub fn matches(&self, host: &str) -> bool {
+        self.matches_in(host, None)
+    }
+
+    /// Like [`matches`](Self::matches), with the destination's `country` if it is known.
+    pub fn matches_in(&self, host: &str, country: Option<&str>) -> bool {
+        let host = normalize_host(host);
+        match self {
+            Self::Any => true,
+            Self::Exact(name) => match (name.parse::<IpAddr>(), host.parse::<IpAddr>()) {
+                // Compare addresses so that e.g. ::1 and 0:0::1 are the same host
+                (Ok(a), Ok(b)) => a == b,
+                _ => *name == host,
+            },
+            Self::Suffix {
+                suffix,
+                include_apex,
+            } => {
+                (*include_apex && host == *suffix)
+                    || host
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|label| label.ends_with('.') && label.len() > 1)
+            }
+            Self::Cidr(network, prefix) => host
+                .parse::<IpAddr>()
+                .is_ok_and(|addr| cidr_contains(*network, *prefix, addr)),
+            Self::Country(code) => country == Some(code.as_str()),
+        }
+    }
+}
+
+// Lowercases, strips IPv6 brackets and a trailing root dot
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether `addr` lies inside the block `network/prefix`.
+pub(crate) fn cidr_contains(network: IpAddr, prefix: u8, addr: IpAddr) -> bool {
+    // IPv4-mapped IPv6 addresses are matched against IPv4 blocks
+    let addr = match (network, addr) {
+        (IpAddr::V4(_), IpAddr::V6(v6)) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        _ => addr,
+    };
+    match (network, addr) {
+        (IpAddr::V4(network), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(network) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(network) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Where a matching request is sent.
+#[derive(Debug, Clone)]
+pub enum Route {
+    /// Connect to the destination directly, bypassing the upstreams
+    Direct,
+    /// Tunnel through this SOCKS server or HTTP proxy instead of the balanced default upstreams
+    Upstream(Arc<Upstream>),
+}
+
+impl Route {
+    // Parses `DIRECT` or an upstream URL such as `socks5://[user:pass@]host:port` or
+    // `http://host:port`
+    fn parse(target: &str) -> Result<Self, String> {
+        if target.eq_ignore_ascii_case("direct") {
+            return Ok(Self::Direct);
+        }
+        if !target.contains("://") {
+            return Err(format!(
+                "expected DIRECT or an upstream such as socks://host:port, got '{target}'"
+            ));
+        }
+
+        let url = UpstreamUrl::parse(target, Protocol::Socks)?;
+        // Rule upstreams verify TLS servers against the bundled roots until --socks-ca says
+        // otherwise
+        let tls = if url.tls {
+            Some(url.tls_with(&tls::connector(None)?, None)?)
+        } else {
+            None
+        };
+        Ok(Self::Upstream(Arc::new(Upstream {
+            addr: url.addr,
+            protocol: url.protocol,
+            version: url.version.unwrap_or(SocksVersion::V5),
+            credentials: url.credentials,
+            gssapi: None,
+            tls,
+            retry: ConnectRetry::default(),
+            fast_open: false,
+        })))
+    }
+}
+
+/// One `[user@]pattern -> target` routing rule.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// Only requests authenticated as this user match
+    pub user: Option<String>,
+    pub pattern: HostPattern,
+    pub route: Route,
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Self, String> {
+        let (pattern, target) = rule
+            .split_once("->")
+            .ok_or_else(|| format!("expected 'pattern -> target', got '{rule}'"))?;
+        // Patterns never contain '@', so a user name may
+        let (user, pattern) = match pattern.trim().rsplit_once('@') {
+            Some(("", _)) => return Err(format!("empty user name in '{rule}'")),
+            Some((user, pattern)) => (Some(user.to_string()), pattern),
+            None => (None, pattern),
+        };
+        let pattern = match HostPattern::parse_country(pattern) {
+            Some(country) => country?,
+            None => HostPattern::parse(pattern)?,
+        };
+        Ok(Self {
+            user,
+            pattern,
+            route: Route::parse(target.trim())?,
+        })
+    }
+
+    /// Whether a request by `user` (the authenticated user, if any) to `host`, located in
+    /// `country` if known, matches.
+    pub fn matches(&self, user: Option<&str>, host: &str, country: Option<&str>) -> bool {
+        (self.user.is_none() || self.user.as_deref() == user)
+            && self.pattern.matches_in(host, country)
+    }
+}
+
+/// Ordered routing rules; the first rule whose pattern matches the destination wins.
+#[derive(Debug, Default)]
+pub struct Router {
+    rules: Vec<Rule>,
+}
+
+impl Router {
+    /// Builds the rule list from `--rule` entries followed by an optional rules file
+    /// containing one rule per line (blank lines and `#` comments are ignored).
+    pub fn load(entries: &[String], file: Option<&Path>) -> Result<Self, String> {
+        let mut rules = Vec::new();
+
+        for entry in entries {
+            rules.push(Rule::parse(entry).map_err(|e| format!("--rule: {e}"))?);
+        }
+
+        if let Some(path) = file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("--rules {}: {e}", path.display()))?;
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let rule = Rule::parse(line)
+                    .map_err(|e| format!("--rules {}:{}: {e}", path.display(), number + 1))?;
+                rules.push(rule);
+            }
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Sends destinations matching any `--no-proxy` entry directly, ahead of all other rules.
+    pub fn bypass(&mut self, entries: &[String]) -> Result<(), String> {
+        let mut bypass = Vec::new();
+        for entry in entries.iter().filter(|entry| !entry.trim().is_empty()) {
+            let patterns =
+                HostPattern::parse_no_proxy(entry).map_err(|e| format!("--no-proxy: {e}"))?;
+            bypass.extend(patterns.into_iter().map(|pattern| Rule {
+                user: None,
+                pattern,
+                route: Route::Direct,
+            }));
+        }
+        self.rules.splice(0..0, bypass);
+        Ok(())
+    }
+
+    /// Appends `USER -> target` entries as rules matching any destination of that user, so
+    /// that they give the user's route when no other rule applies.
+    pub fn user_routes(&mut self, entries: &[String]) -> Result<(), String> {
+        for entry in entries {
+            let (user, target) = entry
+                .split_once("->")
+                .ok_or_else(|| format!("--user-route: expected 'user -> target', got '{entry}'"))?;
+            let user = user.trim();
+            if user.is_empty() {
+                return Err(format!("--user-route: empty user name in '{entry}'"));
+            }
+            self.rules.push(Rule {
+                user: Some(user.to_string()),
+                pattern: HostPattern::Any,
+                route: Route::parse(target.trim()).map_err(|e| format!("--user-route: {e}"))?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Retries connecting to the upstreams named by rules as `retry` allows.
+    pub fn retry(&mut self, retry: ConnectRetry) {
+        for rule in &mut self.rules {
+            if let Route::Upstream(upstream) = &mut rule.route {
+                Arc::make_mut(upstream).retry = retry;
+            }
+        }
+    }
+
+    /// Connects to the upstreams named by rules with TCP Fast Open, for --tcp-fastopen.
+    pub fn fast_open(&mut self, fast_open: bool) {
+        for rule in &mut self.rules {
+            if let Route::Upstream(upstream) = &mut rule.route {
+                Arc::make_mut(upstream).fast_open = fast_open;
+            }
+        }
+    }
+
+    /// Verifies the TLS upstreams named by rules with `connector`, e.g. one trusting --socks-ca.
+    pub fn tls_connector(&mut self, connector: &TlsConnector) {
+        for rule in &mut self.rules {
+            if let Route::Upstream(upstream) = &mut rule.route {
+                if let Some(tls) = &mut Arc::make_mut(upstream).tls {
+                    tls.connector = connector.clone();
+                }
+            }
+        }
+    }
+
+    /// The route for a request by `user` to `host` in `country` (as far as it is known), or
+    /// `None` when no rule matches and the default upstreams apply.
+    pub fn route(&self, user: Option<&str>, host: &str, country: Option<&str>) -> Option<&Route> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(user, host, country))
+            .map(|rule| &rule.route)
+    }
+
+    /// Whether any rule matches destinations by country, so they must be looked up.
+    pub fn has_country_rules(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| matches!(rule.pattern, HostPattern::Country(_)))
+    }
+
+    /// Whether any rule only applies to one user.
+    pub fn has_user_rules(&self) -> bool {
+        self.rules.iter().any(|rule| rule.user.is_some())
+    }
+
+    /// All rules in the order they are tried.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Upstreams referenced by rules, for startup validation.
+    pub fn upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        self.rules.iter().filter_map(|rule| match &rule.route {
+            Route::Upstream(upstream) => Some(&**upstream),
+            Route::Direct => None,
+        })
+    }
+}
+
+/// One `pattern -> host[:port]` mapping of destinations to another one.
+#[derive(Debug, Clone)]
+pub struct HostMapping {
+    pub pattern: HostPattern,
+    pub host: String,
+    /// Keeps the requested port when unset
+    pub port: Option<u16>,
+}
+
+impl HostMapping {
+    fn parse(mapping: &str) -> Result<Self, String> {
+        let (pattern, target) = mapping
+            .split_once("->")
+            .ok_or_else(|| format!("expected 'pattern -> host[:port]', got '{mapping}'"))?;
+        let (host, port) = http::split_host_port(target.trim(), 0)
+            .filter(|(host, _)| !host.is_empty())
+            .ok_or_else(|| format!("invalid destination in '{mapping}'"))?;
+        Ok(Self {
+            pattern: HostPattern::parse(pattern)?,
+            host,
+            port: (port != 0).then_some(port),
+        })
+    }
+}
+
+/// Rewrites of destinations to others, applied before they are checked and routed; the
+/// first mapping whose pattern matches wins.
+#[derive(Debug, Default)]
+pub struct HostMap {
+    mappings: Vec<HostMapping>,
+}
+
+impl HostMap {
+    /// Builds the map from `--map` entries.
+    pub fn load(entries: &[String]) -> Result<Self, String> {
+        let mappings = entries
+            .iter()
+            .map(|entry| HostMapping::parse(entry).map_err(|e| format!("--map: {e}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { mappings })
+    }
+
+    /// Where a request for `host:port` goes instead, if a mapping matches.
+    pub fn map(&self, host: &str, port: u16) -> Option<(String, u16)> {
+        let mapping = self
+            .mappings
+            .iter()
+            .find(|mapping| mapping.pattern.matches(host))?;
+        Some((mapping.host.clone(), mapping.port.unwrap_or(port)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(rules: &[&str]) -> Router {
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        Router::load(&rules, None).unwrap()
+    }
+
+    // The address of the upstream `host` is routed to, "DIRECT", or "" for the default ones
+    fn route(router: &Router, user: Option<&str>, host: &str, country: Option<&str>) -> String {
+        match router.route(user, host, country) {
+            Some(Route::Direct) => "DIRECT".to_string(),
+            Some(Route::Upstream(upstream)) => upstream.addr.clone(),
+            None => String::new(),
+        }
+    }
+
+    #[test]
+    fn host_patterns() {
+        let pattern = HostPattern::parse("*.Example.com.").unwrap();
+        assert!(pattern.matches("a.example.com"));
+        assert!(pattern.matches("A.B.EXAMPLE.COM"));
+        assert!(!pattern.matches("example.com"));
+        assert!(!pattern.matches("badexample.com"));
+
+        let pattern = HostPattern::parse(".example.com").unwrap();
+        assert!(pattern.matches("example.com"));
+        assert!(pattern.matches("a.example.com"));
+
+        let pattern = HostPattern::parse("10.0.0.0/8").unwrap();
+        assert!(pattern.matches("10.1.2.3"));
+        assert!(pattern.matches("::ffff:10.1.2.3"));
+        assert!(!pattern.matches("11.0.0.1"));
+        assert!(!pattern.matches("10.example"));
+
+        let e = HostPattern::parse("[2001:db8::]/32").unwrap_err();
+        assert!(e.contains("CIDR"));
+        assert!(HostPattern::parse("2001:db8::/32")
+            .unwrap()
+            .matches("[2001:db8::1]"));
+        assert!(HostPattern::parse("::1").unwrap().matches("0:0::1"));
+    }
+
+    #[test]
+    fn malformed_patterns_are_rejected() {
+        for pattern in ["", "a.*.com", "10.0.0.0/33", "example.com/8"] {
+            assert!(HostPattern::parse(pattern).is_err(), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let router = router(&[
+            "*.internal.example -> DIRECT",
+            "10.0.0.0/8 -> socks5://10.0.0.1:1080",
+            "* -> http://proxy.example:3128",
+        ]);
+        assert_eq!(route(&router, None, "db.internal.example", None), "DIRECT");
+        assert_eq!(route(&router, None, "10.9.9.9", None), "10.0.0.1:1080");
+        assert_eq!(
+            route(&router, None, "example.com", None),
+            "proxy.example:3128"
+        );
+        assert_eq!(route(&Router::default(), None, "example.com", None), "");
+    }
+
+    #[test]
+    fn rule_upstreams_keep_their_protocol() {
+        let router = router(&[
+            "a.example -> socks4://127.0.0.1:1080",
+            "b.example -> http://127.0.0.1:3128",
+        ]);
+        let upstreams: Vec<_> = router.upstreams().collect();
+        assert_eq!(upstreams[0].protocol, Protocol::Socks);
+        assert_eq!(upstreams[0].version, SocksVersion::V4);
+        assert_eq!(upstreams[1].protocol, Protocol::Http);
+    }
+
+    #[test]
+    fn malformed_rules_are_rejected() {
+        for rule in ["example.com", "example.com -> 127.0.0.1:1080"] {
+            let e = Router::load(&[rule.to_string()], None).unwrap_err();
+            assert!(e.starts_with("--rule: "), "{e}");
+        }
+    }
+
+    #[test]
+    fn no_proxy_entries() {
+        let patterns = HostPattern::parse_no_proxy("*.example.com").unwrap();
+        assert!(patterns[0].matches("example.com"));
+        assert!(patterns[0].matches("a.example.com"));
+
+        let patterns = HostPattern::parse_no_proxy("localhost").unwrap();
+        for host in ["localhost", "127.0.0.2", "::1"] {
+            assert!(
+                patterns.iter().any(|pattern| pattern.matches(host)),
+                "{host}"
+            );
+        }
+    }
+
+    #[test]
+    fn no_proxy_comes_before_rules() {
+        let mut router = router(&["* -> socks5://127.0.0.1:1081"]);
+        router.bypass(&["localhost".to_string()]).unwrap();
+        assert_eq!(route(&router, None, "127.0.0.1", None), "DIRECT");
+        assert_eq!(route(&router, None, "example.com", None), "127.0.0.1:1081");
+    }
+
+    #[test]
+    fn user_rules() {
+        let mut router = router(&[
+            "alice@*.example -> DIRECT",
+            "*.example.cn -> socks5://127.0.0.1:1081",
+        ]);
+        router
+            .user_routes(&["bob -> socks5://127.0.0.1:1082".to_string()])
+            .unwrap();
+        assert!(router.has_user_rules());
+
+        assert_eq!(route(&router, Some("alice"), "a.example", None), "DIRECT");
+        assert_eq!(route(&router, Some("carol"), "a.example", None), "");
+        assert_eq!(route(&router, None, "a.example", None), "");
+        // Per-host rules come before the user's route
+        assert_eq!(
+            route(&router, Some("bob"), "www.example.cn", None),
+            "127.0.0.1:1081"
+        );
+        assert_eq!(
+            route(&router, Some("bob"), "example.com", None),
+            "127.0.0.1:1082"
+        );
+
+        let e = Router::load(&["@example.com -> DIRECT".to_string()], None).unwrap_err();
+        assert!(e.contains("empty user name"), "{e}");
+    }
+
+    #[test]
+    fn host_map_keeps_the_port_unless_given() {
+        let map = HostMap::load(&[
+            "api.old.example -> api.new.example:8443".to_string(),
+            "*.old.example -> [::1]".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(
+            map.map("api.old.example", 443),
+            Some(("api.new.example".to_string(), 8443))
+        );
+        assert_eq!(
+            map.map("www.old.example", 80),
+            Some(("::1".to_string(), 80))
+        );
+        assert_eq!(map.map("example.com", 80), None);
+    }
+
+    #[test]
+    fn country_rules() {
+        let router = router(&["country:cn -> socks5://127.0.0.1:1081"]);
+        assert!(router.has_country_rules());
+        assert_eq!(
+            route(&router, None, "example.cn", Some("CN")),
+            "127.0.0.1:1081"
+        );
+        assert_eq!(route(&router, None, "example.com", Some("US")), "");
+        // Unknown countries match no country rule
+        assert_eq!(route(&router, None, "example.cn", None), "");
+
+        let e = Router::load(&["country:china -> DIRECT".to_string()], None).unwrap_err();
+        assert!(e.contains("two-letter country code"), "{e}");
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{build_runtime, run};
+use http2socks::{Config, FatalError, ServiceAction};
+use std::ffi::OsString;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{error, info};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+// Name the service is registered under with the service control manager
+const NAME: &str = "http2socks";
+const DISPLAY_NAME: &str = "http2socks proxy";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Carries out `action` against the local service control manager.
+pub fn control(action: ServiceAction) -> Result<(), FatalError> {
+    match action {
+        ServiceAction::Install => install(),
+        ServiceAction::Uninstall => uninstall(),
+        // Blocks until the service stops; the control manager calls `service_main` meanwhile
+        ServiceAction::Run => service_dispatcher::start(NAME, ffi_service_main).map_err(|e| {
+            FatalError::Runtime(format!("failed to start the service dispatcher: {e}"))
+        }),
+    }
+}
+
+// Registers a service that starts at boot and runs this executable with the options given
+// to `service install`, ending in `service run` instead
+fn install() -> Result<(), FatalError> {
+    let mut launch_arguments: Vec<OsString> = std::env::args_os().skip(1).collect();
+    launch_arguments.pop();
+    launch_arguments.push("run".into());
+    let executable_path = std::env::current_exe()
+        .map_err(|e| FatalError::Runtime(format!("failed to locate the executable: {e}")))?;
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(scm_error)?;
+    let info = ServiceInfo {
+        name: NAME.into(),
+        display_name: DISPLAY_NAME.into(),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(scm_error)?;
+    service
+        .set_description(env!("CARGO_PKG_DESCRIPTION"))
+        .map_err(scm_error)?;
+    info!("Installed the {} service", NAME);
+    Ok(())
+}
+
+// Stops the service if it is running and removes it
+fn uninstall() -> Result<(), FatalError> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(scm_error)?;
+    let service = manager
+        .open_service(
+            NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(scm_error)?;
+    // Deletion takes effect once the service has stopped
+    service.delete().map_err(scm_error)?;
+    if service.query_status().map_err(scm_error)?.current_state != ServiceState::Stopped {
+        service.stop().map_err(scm_error)?;
+    }
+    info!("Uninstalled the {} service", NAME);
+    Ok(())
+}
+
+fn scm_error(e: windows_service::Error) -> FatalError {
+    FatalError::Runtime(format!("service control manager: {e}"))
+}
+
+// Runs the proxy on the control manager's thread until it asks the service to stop, and
+// reports the outcome as the service's exit code
+fn service_main(_arguments: Vec<OsString>) {
+    let (stop, stopped) = oneshot::channel();
+    let mut stop = Some(stop);
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(stop) = stop.take() {
+                let _ = stop.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status_handle = match service_control_handler::register(NAME, handler) {
+        Ok(status_handle) => status_handle,
+        Err(e) => {
+            error!("Failed to register the service control handler: {}", e);
+            return;
+        }
+    };
+    let report = |current_state, controls_accepted, exit_code| {
+        let status = ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        };
+        if let Err(e) = status_handle.set_service_status(status) {
+            error!("Failed to report the service status: {}", e);
+        }
+    };
+
+    report(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ServiceExitCode::NO_ERROR,
+    );
+    // The launch arguments registered by `install` are the process's own
+    let result = Config::load().and_then(|config| {
+        build_runtime(config.threads).and_then(|runtime| {
+            runtime.block_on(run(config, async {
+                let _ = stopped.await;
+            }))
+        })
+    });
+    let exit_code = match result {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(e) => {
+            error!("{}", e);
+            ServiceExitCode::ServiceSpecific(e.exit_code().into())
+        }
+    };
+    report(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    );
+}
@@ -0,0 +1,107 @@
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// TLS record and handshake constants (RFC 8446 section 5.1 and 4, RFC 6066 section 3)
+const RECORD_HANDSHAKE: u8 = 0x16;
+const RECORD_HEADER_LEN: usize = 5;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Reads the first TLS record from `stream` into `buf` and returns the host name from the
+/// ClientHello's server_name extension. `buf` keeps the bytes read so they can be replayed
+/// to the destination.
+pub async fn read_server_name<S: AsyncRead + Unpin + ?Sized>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> Result<String, Box<dyn Error>> {
+    fill(stream, buf, RECORD_HEADER_LEN).await?;
+    if buf[0] != RECORD_HANDSHAKE {
+        return Err("client did not start a TLS handshake".into());
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    fill(stream, buf, RECORD_HEADER_LEN + record_len).await?;
+
+    server_name(&buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len])
+        .ok_or_else(|| "ClientHello carries no server name".into())
+}
+
+// Reads until `buf` holds at least `len` bytes
+async fn fill<S: AsyncRead + Unpin + ?Sized>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    len: usize,
+) -> std::io::Result<()> {
+    let mut chunk = [0u8; 4096];
+    while buf.len() < len {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(())
+}
+
+// Finds the host name in a ClientHello handshake message. Only the part of the message
+// inside the first record is examined, which in practice holds the whole ClientHello.
+fn server_name(handshake: &[u8]) -> Option<String> {
+    let mut reader = Reader(handshake);
+    if reader.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    reader.take(3)?; // handshake length
+    reader.take(2 + 32)?; // legacy version, random
+    let session_id = reader.u8()? as usize;
+    reader.take(session_id)?;
+    let cipher_suites = reader.u16()? as usize;
+    reader.take(cipher_suites)?;
+    let compression_methods = reader.u8()? as usize;
+    reader.take(compression_methods)?;
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut list = Reader(data);
+        let list_len = list.u16()? as usize;
+        let mut names = Reader(list.take(list_len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_string);
+            }
+        }
+    }
+    None
+}
+
+// A cursor over big-endian TLS wire data
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
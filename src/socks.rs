@@ -0,0 +1,904 @@
+use crate::connector::Transport;
+#[cfg(feature = "gssapi")]
+use crate::gssapi;
+use crate::http_upstream;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tracing::{instrument, warn};
+
+// SOCKS Protocol Constants
+pub const SOCKS5_VERSION: u8 = 0x05;
+pub(crate) const SOCKS5_AUTH_NONE: u8 = 0x00;
+pub(crate) const SOCKS5_AUTH_GSSAPI: u8 = 0x01;
+pub(crate) const SOCKS5_AUTH_USERPASS: u8 = 0x02;
+pub(crate) const SOCKS5_AUTH_NO_ACCEPTABLE: u8 = 0xFF;
+pub(crate) const SOCKS5_USERPASS_VERSION: u8 = 0x01;
+pub(crate) const SOCKS5_CMD_CONNECT: u8 = 0x01;
+pub(crate) const SOCKS5_CMD_BIND: u8 = 0x02;
+pub const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 0x03;
+pub(crate) const SOCKS5_RSV: u8 = 0x00;
+pub(crate) const SOCKS5_ATYP_IPV4: u8 = 0x01;
+pub(crate) const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+pub(crate) const SOCKS5_ATYP_IPV6: u8 = 0x04;
+pub(crate) const SOCKS5_SUCCESS: u8 = 0x00;
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CMD_CONNECT: u8 = 0x01;
+const SOCKS4_REPLY_VERSION: u8 = 0x00;
+const SOCKS4_GRANTED: u8 = 0x5A;
+
+// Longest wait between two connection attempts, however many have failed
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// SOCKS protocol version spoken to the upstream server.
+#[derive(clap::ValueEnum, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocksVersion {
+    /// SOCKS4: IPv4 destinations only, hostnames are resolved locally
+    #[value(name = "4")]
+    #[serde(rename = "4")]
+    V4,
+    /// SOCKS4a: hostnames are resolved by the SOCKS server
+    #[value(name = "4a")]
+    #[serde(rename = "4a")]
+    V4a,
+    /// SOCKS5
+    #[value(name = "5")]
+    #[serde(rename = "5")]
+    V5,
+}
+
+/// Which proxy protocol an upstream speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// A SOCKS server, in the upstream's [`SocksVersion`]
+    Socks,
+    /// An HTTP proxy: CONNECT for tunnels, absolute-form requests for plain HTTP
+    Http,
+}
+
+/// Username/password credentials for the upstream: RFC 1929 for SOCKS servers, Basic
+/// `Proxy-Authorization` for HTTP proxies.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// An upstream SOCKS server or HTTP proxy and how to authenticate to it.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    /// `host:port`, or `unix://PATH` for a server listening on a Unix domain socket
+    pub addr: String,
+    pub protocol: Protocol,
+    /// Only used by SOCKS upstreams
+    pub version: SocksVersion,
+    pub credentials: Option<Credentials>,
+    /// GSSAPI target name (`service@host`) to authenticate to a SOCKS5 server with, when
+    /// built with the `gssapi` feature
+    pub gssapi: Option<String>,
+    /// Set for `tls://` and `https://` upstreams, whose negotiation runs inside TLS
+    pub tls: Option<UpstreamTls>,
+    pub retry: ConnectRetry,
+    /// Connect with TCP Fast Open, where the platform supports it
+    pub fast_open: bool,
+}
+
+/// How connecting to a SOCKS server is retried when it refuses or drops the connection, as
+/// while the daemon restarts. The default gives up after the first attempt.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectRetry {
+    /// Attempts made after the first one fails
+    pub attempts: u32,
+    /// Wait before the first retry; it doubles for each later one, with random jitter
+    pub backoff: Duration,
+}
+
+impl ConnectRetry {
+    // The wait before retry number `attempt` (0-based): the doubled backoff, capped, then
+    // scaled into its upper half so that clients retrying together spread out
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_BACKOFF);
+        let jitter = RandomState::new().build_hasher().finish() % 1000;
+        delay / 2 + delay / 2 * jitter as u32 / 1000
+    }
+}
+
+/// How to secure the connection to a `tls://` upstream.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    pub connector: TlsConnector,
+    /// Name sent as SNI and checked against the server certificate
+    pub server_name: ServerName<'static>,
+}
+
+impl Upstream {
+    /// The socket path of a `unix://` upstream.
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.addr.strip_prefix("unix://").map(Path::new)
+    }
+}
+
+impl fmt::Debug for UpstreamTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpstreamTls")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A connection to the destination: direct, or through a SOCKS server reached over plain
+/// TCP, TLS or a Unix domain socket, possibly with its data encapsulated by GSSAPI, or a
+/// stream opened by a custom [`Connector`](crate::Connector).
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "gssapi")]
+    Gssapi(Box<gssapi::Stream>),
+    Custom(Box<dyn Transport>),
+}
+
+impl UpstreamStream {
+    /// The remote address of the underlying TCP connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Plain(stream) => stream.peer_addr(),
+            Self::Tls(stream) => stream.get_ref().0.peer_addr(),
+            #[cfg(unix)]
+            Self::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain socket has no IP address",
+            )),
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(stream) => stream.inner.peer_addr(),
+            Self::Custom(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "custom transport has no IP address",
+            )),
+        }
+    }
+
+    /// The underlying TCP connection, unless the upstream is on a Unix domain socket or a
+    /// custom transport.
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Plain(stream) => Some(stream),
+            Self::Tls(stream) => Some(stream.get_ref().0),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(stream) => stream.inner.tcp(),
+            Self::Custom(_) => None,
+        }
+    }
+
+    /// Whether the connection is still open with nothing waiting to be read. The socket is
+    /// peeked rather than read, so a TLS record stays intact for the TLS layer. A custom
+    /// transport can't be peeked, so it never counts as idle.
+    pub fn is_idle(&self) -> bool {
+        if let Self::Custom(_) = self {
+            return false;
+        }
+        #[cfg(feature = "gssapi")]
+        if let Self::Gssapi(stream) = self {
+            return !stream.has_buffered() && stream.inner.is_idle();
+        }
+        let mut probe = [MaybeUninit::uninit(); 1];
+        let peeked = match self {
+            Self::Plain(stream) => socket2::SockRef::from(stream).peek(&mut probe),
+            Self::Tls(stream) => socket2::SockRef::from(stream.get_ref().0).peek(&mut probe),
+            #[cfg(unix)]
+            Self::Unix(stream) => socket2::SockRef::from(stream).peek(&mut probe),
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(_) => unreachable!(),
+            Self::Custom(_) => unreachable!(),
+        };
+        matches!(peeked, Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    /// Waits until the underlying socket has data or has been closed.
+    pub async fn readable(&self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.readable().await,
+            Self::Tls(stream) => stream.get_ref().0.readable().await,
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.readable().await,
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(stream) => Box::pin(stream.inner.readable()).await,
+            Self::Custom(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Reads from the underlying socket without waiting, bypassing any TLS layer.
+    pub fn try_read_raw(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.try_read(buf),
+            Self::Tls(stream) => stream.get_ref().0.try_read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.try_read(buf),
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(stream) => stream.inner.try_read_raw(buf),
+            Self::Custom(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            Self::Custom(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+// TLS buffers written data, so callers flush before waiting on the server
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            Self::Custom(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            Self::Custom(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "gssapi")]
+            Self::Gssapi(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            Self::Custom(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Address reported by the SOCKS server in a reply or carried in a UDP datagram header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+/// Connects to the upstream itself, completing the TLS handshake for `tls://` and `https://`
+/// upstreams.
+/// A refused or reset connection is retried as the upstream's [`ConnectRetry`] allows.
+pub async fn connect_server(upstream: &Upstream) -> Result<UpstreamStream, Box<dyn Error>> {
+    let mut attempt = 0;
+    let stream = loop {
+        match connect_socket(upstream).await {
+            Ok(stream) => break stream,
+            Err(e) if attempt < upstream.retry.attempts && is_transient(&e) => {
+                let delay = upstream.retry.delay(attempt);
+                attempt += 1;
+                warn!(
+                    "Connecting to upstream {} failed ({}), retrying in {}ms ({}/{})",
+                    upstream.addr,
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    upstream.retry.attempts
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    match (&upstream.tls, stream) {
+        (Some(tls), UpstreamStream::Plain(tcp)) => {
+            let stream = tls.connector.connect(tls.server_name.clone(), tcp).await?;
+            Ok(UpstreamStream::Tls(Box::new(stream)))
+        }
+        (_, stream) => Ok(stream),
+    }
+}
+
+// Opens the TCP or Unix domain socket connection to the upstream
+async fn connect_socket(upstream: &Upstream) -> io::Result<UpstreamStream> {
+    #[cfg(unix)]
+    if let Some(path) = upstream.unix_path() {
+        return Ok(UpstreamStream::Unix(UnixStream::connect(path).await?));
+    }
+    if upstream.fast_open {
+        return Ok(UpstreamStream::Plain(
+            connect_fast_open(&upstream.addr).await?,
+        ));
+    }
+    Ok(UpstreamStream::Plain(
+        TcpStream::connect(&upstream.addr).await?,
+    ))
+}
+
+// Connects with TCP_FASTOPEN_CONNECT, which holds the SYN back until the first write so
+// that the greeting rides in it once the server has handed out a Fast Open cookie
+#[cfg(target_os = "linux")]
+async fn connect_fast_open(addr: &str) -> io::Result<TcpStream> {
+    use std::os::fd::AsRawFd;
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        let socket = match addr {
+            SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+        };
+        let enable: libc::c_int = 1;
+        // SAFETY: the descriptor belongs to `socket`, and the option value is a c_int of
+        // the given size
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN_CONNECT,
+                (&enable as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result == -1 {
+            warn!(
+                "Cannot enable TCP Fast Open to {}: {}",
+                addr,
+                io::Error::last_os_error()
+            );
+        }
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing")))
+}
+
+// Elsewhere, Fast Open needs sendto or connectx in place of connect; connect normally
+#[cfg(not(target_os = "linux"))]
+async fn connect_fast_open(addr: &str) -> io::Result<TcpStream> {
+    TcpStream::connect(addr).await
+}
+
+// Failures that a SOCKS server coming back up resolves: nothing listening yet, a connection
+// dropped during shutdown, or a Unix socket that has yet to be recreated
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotFound
+    )
+}
+
+/// Opens a tunnel to `host:port` through the upstream using its protocol: the configured
+/// SOCKS version, or CONNECT for an HTTP proxy.
+pub async fn connect_upstream(
+    host: &str,
+    port: u16,
+    upstream: &Upstream,
+) -> Result<UpstreamStream, Box<dyn Error>> {
+    match (upstream.protocol, upstream.version) {
+        (Protocol::Http, _) => http_upstream::connect(host, port, upstream).await,
+        (Protocol::Socks, SocksVersion::V5) => connect_socks5(host, port, upstream).await,
+        (Protocol::Socks, SocksVersion::V4 | SocksVersion::V4a) => {
+            connect_socks4(host, port, upstream).await
+        }
+    }
+}
+
+// Establishes connection to SOCKS5 proxy server
+#[instrument(skip(upstream), fields(dst = %host, port = %port))]
+pub async fn connect_socks5(
+    host: &str,
+    port: u16,
+    upstream: &Upstream,
+) -> Result<UpstreamStream, Box<dyn Error>> {
+    let mut socks = open_socks5(upstream).await?;
+    send_command(&mut socks, SOCKS5_CMD_CONNECT, host, port).await?;
+    Ok(socks)
+}
+
+/// Asks the SOCKS5 server `upstream` to listen for one connection from `host:port`, for
+/// protocols where the server calls the client back, such as active-mode FTP (RFC 1928
+/// section 4). The server's first reply, naming where it listens, is read here; the second,
+/// sent once the connection arrives, by [`Bind::accept`].
+#[instrument(skip(upstream), fields(dst = %host, port = %port))]
+pub async fn bind_socks5(
+    host: &str,
+    port: u16,
+    upstream: &Upstream,
+) -> Result<Bind, Box<dyn Error>> {
+    let mut socks = open_socks5(upstream).await?;
+    let address = match send_command(&mut socks, SOCKS5_CMD_BIND, host, port).await? {
+        // Servers listening on all their addresses say so; the one reached here is the
+        // likeliest to be reachable from elsewhere too
+        Address::Ip(addr) if addr.ip().is_unspecified() => match socks.peer_addr() {
+            Ok(server) => Address::Ip(SocketAddr::new(server.ip(), addr.port())),
+            Err(_) => Address::Ip(addr),
+        },
+        address => address,
+    };
+    Ok(Bind {
+        stream: socks,
+        address,
+    })
+}
+
+/// A SOCKS5 BIND the server has agreed to, waiting for the incoming connection.
+pub struct Bind {
+    stream: UpstreamStream,
+    /// Where the SOCKS server listens for the connection
+    pub address: Address,
+}
+
+impl Bind {
+    /// Waits for the server's second reply, sent once the expected host has connected, and
+    /// returns the stream now relayed to that connection along with the host's address.
+    pub async fn accept(mut self) -> Result<(UpstreamStream, Address), Box<dyn Error>> {
+        let peer = read_reply(&mut self.stream).await?;
+        Ok((self.stream, peer))
+    }
+}
+
+// Connects to the SOCKS5 server and completes the greeting and authentication
+async fn open_socks5(upstream: &Upstream) -> Result<UpstreamStream, Box<dyn Error>> {
+    // Connect to SOCKS5 server
+    let mut socks = connect_server(upstream).await?;
+
+    // Perform SOCKS5 handshake
+    match negotiate_auth(&mut socks, upstream).await? {
+        Negotiated::Plain => {}
+        #[cfg(feature = "gssapi")]
+        Negotiated::Gssapi(security) => {
+            socks = UpstreamStream::Gssapi(Box::new(gssapi::Stream::new(socks, security)));
+        }
+    }
+    Ok(socks)
+}
+
+/// What SOCKS5 authentication leaves in place for the rest of the connection.
+pub enum Negotiated {
+    /// The data that follows is sent as is
+    Plain,
+    /// The data that follows must be encapsulated with this GSSAPI security context
+    #[cfg(feature = "gssapi")]
+    Gssapi(gssapi::Security),
+}
+
+/// Sends the SOCKS5 greeting and completes whichever authentication method the server selects
+/// among those `upstream` is configured for.
+pub async fn negotiate_auth<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
+    socks: &mut S,
+    upstream: &Upstream,
+) -> Result<Negotiated, Box<dyn Error>> {
+    let credentials = upstream.credentials.as_ref();
+    // Send client greeting: version 5, then the auth methods we can perform
+    let mut methods = vec![SOCKS5_AUTH_NONE];
+    if upstream.gssapi.is_some() {
+        methods.push(SOCKS5_AUTH_GSSAPI);
+    }
+    if credentials.is_some() {
+        methods.push(SOCKS5_AUTH_USERPASS);
+    }
+    let mut greeting = vec![SOCKS5_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(&methods);
+    socks.write_all(&greeting).await?;
+    socks.flush().await?;
+    let mut response = [0u8; 2];
+    socks.read_exact(&mut response).await?;
+
+    if response[0] != SOCKS5_VERSION {
+        return Err(format!("Not a SOCKS5 server (version byte {:#04x})", response[0]).into());
+    }
+
+    match (response[1], credentials) {
+        (SOCKS5_AUTH_NONE, _) => Ok(Negotiated::Plain),
+        (SOCKS5_AUTH_USERPASS, Some(credentials)) => {
+            authenticate(socks, credentials).await?;
+            Ok(Negotiated::Plain)
+        }
+        #[cfg(feature = "gssapi")]
+        (SOCKS5_AUTH_GSSAPI, _) if upstream.gssapi.is_some() => {
+            let target = upstream.gssapi.as_deref().unwrap_or_default();
+            Ok(Negotiated::Gssapi(
+                gssapi::authenticate(socks, target).await?,
+            ))
+        }
+        (SOCKS5_AUTH_NO_ACCEPTABLE, _) => {
+            Err("SOCKS5 server accepted none of the offered authentication methods".into())
+        }
+        (method, _) => Err(format!(
+            "SOCKS5 server selected unsupported authentication method {method:#04x}"
+        )
+        .into()),
+    }
+}
+
+// Performs the RFC 1929 username/password sub-negotiation
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
+    socks: &mut S,
+    credentials: &Credentials,
+) -> Result<(), Box<dyn Error>> {
+    let username = credentials.username.as_bytes();
+    let password = credentials.password.as_bytes();
+    let (Ok(username_len), Ok(password_len)) =
+        (u8::try_from(username.len()), u8::try_from(password.len()))
+    else {
+        return Err("SOCKS5 username and password must be at most 255 bytes".into());
+    };
+
+    let mut request = Vec::with_capacity(3 + username.len() + password.len());
+    request.push(SOCKS5_USERPASS_VERSION);
+    request.push(username_len);
+    request.extend_from_slice(username);
+    request.push(password_len);
+    request.extend_from_slice(password);
+    socks.write_all(&request).await?;
+    socks.flush().await?;
+
+    // Response: sub-negotiation version, status (0 = success)
+    let mut response = [0u8; 2];
+    socks.read_exact(&mut response).await?;
+    if response[1] != SOCKS5_SUCCESS {
+        return Err("SOCKS5 username/password authentication failed".into());
+    }
+
+    Ok(())
+}
+
+// Establishes connection through a SOCKS4 or SOCKS4a proxy server
+#[instrument(skip(upstream), fields(dst = %host, port = %port))]
+async fn connect_socks4(
+    host: &str,
+    port: u16,
+    upstream: &Upstream,
+) -> Result<UpstreamStream, Box<dyn Error>> {
+    // SOCKS4 carries only IPv4 addresses; SOCKS4a can pass the hostname through instead
+    let (ip, hostname) = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => (ip, None),
+        Ok(IpAddr::V6(_)) => return Err("SOCKS4 does not support IPv6 destinations".into()),
+        // 0.0.0.x with x != 0 tells a SOCKS4a server to read the hostname
+        Err(_) if upstream.version == SocksVersion::V4a => (Ipv4Addr::new(0, 0, 0, 1), Some(host)),
+        Err(_) => (resolve_ipv4(host, port).await?, None),
+    };
+
+    let mut socks = connect_server(upstream).await?;
+
+    // Format: version 4, connect command, dst port, dst ip, user id, NUL[, hostname, NUL]
+    let mut request = vec![SOCKS4_VERSION, SOCKS4_CMD_CONNECT];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&ip.octets());
+    if let Some(credentials) = &upstream.credentials {
+        request.extend_from_slice(credentials.username.as_bytes());
+    }
+    request.push(0);
+    if let Some(hostname) = hostname {
+        request.extend_from_slice(hostname.as_bytes());
+        request.push(0);
+    }
+    socks.write_all(&request).await?;
+    socks.flush().await?;
+
+    // Reply: version 0, status, port, ip (the last two are ignored for CONNECT)
+    let mut reply = [0u8; 8];
+    socks.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS4_REPLY_VERSION {
+        return Err(format!("Not a SOCKS4 server (reply version {:#04x})", reply[0]).into());
+    }
+    if reply[1] != SOCKS4_GRANTED {
+        return Err(format!("SOCKS4 connection rejected (status {:#04x})", reply[1]).into());
+    }
+
+    Ok(socks)
+}
+
+// Resolves a hostname locally for plain SOCKS4, which cannot carry names
+async fn resolve_ipv4(host: &str, port: u16) -> Result<Ipv4Addr, Box<dyn Error>> {
+    let ip = tokio::net::lookup_host((host, port))
+        .await?
+        .find_map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        });
+    ip.ok_or_else(|| {
+        warn!("No IPv4 address for {}", host);
+        format!("{host} has no IPv4 address for SOCKS4").into()
+    })
+}
+
+// Sends a SOCKS5 request for the given command and destination, returning the bound address
+// from the server's reply
+pub async fn send_command<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
+    socks: &mut S,
+    command: u8,
+    host: &str,
+    port: u16,
+) -> Result<Address, Box<dyn Error>> {
+    // Format: version 5, command, reserved byte, dst address, dst port
+    let mut request = vec![SOCKS5_VERSION, command, SOCKS5_RSV];
+    encode_address(&mut request, host, port)?;
+    socks.write_all(&request).await?;
+    socks.flush().await?;
+    read_reply(socks).await
+}
+
+// Reads a SOCKS5 reply, returning the address it carries
+async fn read_reply<S: AsyncRead + Unpin + ?Sized>(
+    socks: &mut S,
+) -> Result<Address, Box<dyn Error>> {
+    // Read connection response header
+    let mut header = [0u8; 4];
+    socks.read_exact(&mut header).await?;
+
+    if header[1] != SOCKS5_SUCCESS {
+        return Err(ReplyError { code: header[1] }.into());
+    }
+
+    read_address(socks, header[3]).await
+}
+
+/// A SOCKS5 server answered a request with a failure reply.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("SOCKS5 request failed: {} (reply {:#04x})", self.description(), self.code)]
+pub struct ReplyError {
+    pub code: u8,
+}
+
+impl ReplyError {
+    /// What the reply code means (RFC 1928 section 6).
+    pub fn description(&self) -> &'static str {
+        match self.code {
+            0x01 => "general SOCKS server failure",
+            0x02 => "connection not allowed by ruleset",
+            0x03 => "network unreachable",
+            0x04 => "host unreachable",
+            0x05 => "connection refused",
+            0x06 => "TTL expired",
+            0x07 => "command not supported",
+            0x08 => "address type not supported",
+            _ => "unassigned reply code",
+        }
+    }
+}
+
+/// Reads the variable-length address and port that follow an ATYP byte of `atyp`.
+pub(crate) async fn read_address<S: AsyncRead + Unpin + ?Sized>(
+    socks: &mut S,
+    atyp: u8,
+) -> Result<Address, Box<dyn Error>> {
+    let ip = match atyp {
+        SOCKS5_ATYP_IPV4 => {
+            // IPv4
+            let mut addr = [0u8; 4];
+            socks.read_exact(&mut addr).await?;
+            IpAddr::from(addr)
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            // Domain name
+            let mut len = [0u8; 1];
+            socks.read_exact(&mut len).await?;
+            let mut addr = vec![0u8; len[0] as usize];
+            socks.read_exact(&mut addr).await?;
+            let mut port = [0u8; 2];
+            socks.read_exact(&mut port).await?;
+            let domain = String::from_utf8_lossy(&addr).into_owned();
+            return Ok(Address::Domain(domain, u16::from_be_bytes(port)));
+        }
+        SOCKS5_ATYP_IPV6 => {
+            // IPv6
+            let mut addr = [0u8; 16];
+            socks.read_exact(&mut addr).await?;
+            IpAddr::from(addr)
+        }
+        _ => return Err("Unknown address type".into()),
+    };
+
+    // Read port
+    let mut port = [0u8; 2];
+    socks.read_exact(&mut port).await?;
+
+    Ok(Address::Ip(SocketAddr::new(ip, u16::from_be_bytes(port))))
+}
+
+/// Appends ATYP, address and port for `host:port` in SOCKS5 wire format.
+pub fn encode_address(buf: &mut Vec<u8>, host: &str, port: u16) -> Result<(), Box<dyn Error>> {
+    // Check if host is an IP address
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        match ip {
+            IpAddr::V4(ipv4) => {
+                buf.push(SOCKS5_ATYP_IPV4); // IPv4 address type
+                buf.extend_from_slice(&ipv4.octets());
+            }
+            IpAddr::V6(ipv6) => {
+                buf.push(SOCKS5_ATYP_IPV6); // IPv6 address type
+                buf.extend_from_slice(&ipv6.octets());
+            }
+        }
+    } else {
+        // Domain name type
+        let addr_bytes = host.as_bytes();
+        let len = u8::try_from(addr_bytes.len()).map_err(|_| "Domain name too long for SOCKS5")?;
+        buf.push(SOCKS5_ATYP_DOMAIN); // Domain name type
+        buf.push(len);
+        buf.extend_from_slice(addr_bytes);
+    }
+    buf.extend_from_slice(&port.to_be_bytes());
+    Ok(())
+}
+
+/// Parses ATYP, address and port from the front of `buf`, returning the rest.
+pub fn decode_address(buf: &[u8]) -> Option<(Address, &[u8])> {
+    let (&atyp, rest) = buf.split_first()?;
+    let (address, rest) = match atyp {
+        SOCKS5_ATYP_IPV4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            let (port, rest) = rest.split_first_chunk::<2>()?;
+            let addr = SocketAddr::new(Ipv4Addr::from(*ip).into(), u16::from_be_bytes(*port));
+            (Address::Ip(addr), rest)
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            let (port, rest) = rest.split_first_chunk::<2>()?;
+            let addr = SocketAddr::new(Ipv6Addr::from(*ip).into(), u16::from_be_bytes(*port));
+            (Address::Ip(addr), rest)
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let (&len, rest) = rest.split_first()?;
+            let name = rest.get(..len as usize)?;
+            let (port, rest) = rest[len as usize..].split_first_chunk::<2>()?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            (Address::Domain(name, u16::from_be_bytes(*port)), rest)
+        }
+        _ => return None,
+    };
+    Some((address, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks_server::{self, Command};
+    use tokio::net::TcpListener;
+
+    // A plain SOCKS5 upstream at `addr`
+    fn upstream(addr: SocketAddr) -> Upstream {
+        Upstream {
+            addr: addr.to_string(),
+            protocol: Protocol::Socks,
+            version: SocksVersion::V5,
+            credentials: None,
+            gssapi: None,
+            tls: None,
+            retry: ConnectRetry::default(),
+            fast_open: false,
+        }
+    }
+
+    #[test]
+    fn ipv6_literal_is_encoded_as_ipv6() {
+        let mut buf = Vec::new();
+        encode_address(&mut buf, "2001:db8::1", 443).unwrap();
+        let mut expected = vec![SOCKS5_ATYP_IPV6];
+        expected.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn ipv4_and_domain_encoding() {
+        let mut buf = Vec::new();
+        encode_address(&mut buf, "10.0.0.1", 80).unwrap();
+        assert_eq!(buf, [SOCKS5_ATYP_IPV4, 10, 0, 0, 1, 0, 80]);
+
+        let mut buf = Vec::new();
+        encode_address(&mut buf, "a.test", 80).unwrap();
+        assert_eq!(
+            buf,
+            [&[SOCKS5_ATYP_DOMAIN, 6][..], b"a.test", &[0, 80]].concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn bind_reports_both_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = socks_server::accept(&mut stream, None).await.unwrap();
+            assert_eq!(request.command, Command::Bind);
+            assert_eq!((request.host.as_str(), request.port), ("ftp.example", 21));
+            // Listening on all addresses, then connected to from 192.0.2.7
+            let listening = Address::Ip("0.0.0.0:50021".parse().unwrap());
+            socks_server::reply_address(&mut stream, SOCKS5_SUCCESS, &listening)
+                .await
+                .unwrap();
+            let peer = Address::Ip("192.0.2.7:4660".parse().unwrap());
+            socks_server::reply_address(&mut stream, SOCKS5_SUCCESS, &peer)
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let bind = bind_socks5("ftp.example", 21, &upstream(addr))
+            .await
+            .unwrap();
+        // The unspecified address is replaced with the server's own
+        assert_eq!(
+            bind.address,
+            Address::Ip("127.0.0.1:50021".parse().unwrap())
+        );
+        let (mut stream, peer) = bind.accept().await.unwrap();
+        assert_eq!(peer, Address::Ip("192.0.2.7:4660".parse().unwrap()));
+        let mut data = String::new();
+        stream.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "hello");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_bind_fails_with_the_reply_code() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            socks_server::accept(&mut stream, None).await.unwrap();
+            socks_server::reply(&mut stream, socks_server::REPLY_COMMAND_NOT_SUPPORTED)
+                .await
+                .unwrap();
+        });
+
+        let e = bind_socks5("192.0.2.7", 21, &upstream(addr))
+            .await
+            .err()
+            .unwrap();
+        let reply = e.downcast_ref::<ReplyError>().unwrap();
+        assert_eq!(reply.code, socks_server::REPLY_COMMAND_NOT_SUPPORTED);
+    }
+}
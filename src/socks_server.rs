@@ -0,0 +1,181 @@
+use crate::auth::ProxyAuth;
+use crate::socks::{
+    self, Address, SOCKS5_ATYP_DOMAIN, SOCKS5_ATYP_IPV4, SOCKS5_ATYP_IPV6, SOCKS5_AUTH_NONE,
+    SOCKS5_AUTH_NO_ACCEPTABLE, SOCKS5_AUTH_USERPASS, SOCKS5_CMD_BIND, SOCKS5_CMD_CONNECT,
+    SOCKS5_RSV, SOCKS5_SUCCESS, SOCKS5_USERPASS_VERSION, SOCKS5_VERSION,
+};
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Reply codes sent to SOCKS5 clients (RFC 1928 section 6)
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+// RFC 1929 sub-negotiation status for rejected credentials
+const USERPASS_FAILURE: u8 = 0x01;
+
+/// A CONNECT or BIND request accepted from an inbound SOCKS5 client.
+pub struct Request {
+    pub command: Command,
+    pub host: String,
+    pub port: u16,
+    /// The authenticated user, when credentials are required
+    pub user: Option<String>,
+}
+
+/// Performs the server side of the SOCKS5 handshake up to the client's request: method
+/// selection, RFC 1929 username/password checked against `auth` when it is set, then the
+/// request itself. Only CONNECT and BIND are accepted; anything else is answered with an error
+/// reply. The caller answers the request with [`reply`] once the tunnel is open, or a BIND with
+/// [`reply_address`] twice.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: Option<&ProxyAuth>,
+) -> Result<Request, Box<dyn Error>> {
+    // Greeting: version, number of methods, methods
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != SOCKS5_VERSION {
+        return Err(format!("Not a SOCKS5 client (version byte {:#04x})", greeting[0]).into());
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let wanted = if auth.is_some() {
+        SOCKS5_AUTH_USERPASS
+    } else {
+        SOCKS5_AUTH_NONE
+    };
+    if !methods.contains(&wanted) {
+        stream
+            .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NO_ACCEPTABLE])
+            .await?;
+        stream.flush().await?;
+        return Err("SOCKS5 client offered no acceptable authentication method".into());
+    }
+    stream.write_all(&[SOCKS5_VERSION, wanted]).await?;
+    stream.flush().await?;
+
+    let user = match auth {
+        Some(auth) => Some(authenticate(stream, auth).await?),
+        None => None,
+    };
+
+    // Request: version, command, reserved byte, dst address, dst port
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(format!("Not a SOCKS5 request (version byte {:#04x})", header[0]).into());
+    }
+    if ![SOCKS5_ATYP_IPV4, SOCKS5_ATYP_DOMAIN, SOCKS5_ATYP_IPV6].contains(&header[3]) {
+        reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+        return Err(format!("Unsupported SOCKS5 address type {:#04x}", header[3]).into());
+    }
+    let address = socks::read_address(stream, header[3]).await?;
+    let command = match header[1] {
+        SOCKS5_CMD_CONNECT => Command::Connect,
+        SOCKS5_CMD_BIND => Command::Bind,
+        _ => {
+            reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+            return Err(format!("Unsupported SOCKS5 command {:#04x}", header[1]).into());
+        }
+    };
+
+    let (host, port) = match address {
+        Address::Ip(addr) => (addr.ip().to_string(), addr.port()),
+        Address::Domain(name, port) => (name, port),
+    };
+    Ok(Request {
+        command,
+        host,
+        port,
+        user,
+    })
+}
+
+/// The SOCKS5 commands the proxy serves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// A tunnel to the destination
+    Connect,
+    /// A connection from the destination, taken through an upstream SOCKS5 server's BIND
+    Bind,
+}
+
+// Performs the server side of the RFC 1929 sub-negotiation, returning the user name
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: &ProxyAuth,
+) -> Result<String, Box<dyn Error>> {
+    // Format: sub-negotiation version, username length, username, password length, password
+    let mut version = [0u8; 2];
+    stream.read_exact(&mut version).await?;
+    if version[0] != SOCKS5_USERPASS_VERSION {
+        return Err("Invalid SOCKS5 username/password version".into());
+    }
+    let mut username = vec![0u8; version[1] as usize];
+    stream.read_exact(&mut username).await?;
+    let mut password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username);
+    let password = String::from_utf8_lossy(&password);
+    match auth.verify(&username, &password) {
+        Some(user) => {
+            let user = user.to_string();
+            stream
+                .write_all(&[SOCKS5_USERPASS_VERSION, SOCKS5_SUCCESS])
+                .await?;
+            stream.flush().await?;
+            Ok(user)
+        }
+        None => {
+            stream
+                .write_all(&[SOCKS5_USERPASS_VERSION, USERPASS_FAILURE])
+                .await?;
+            stream.flush().await?;
+            Err("SOCKS5 client failed username/password authentication".into())
+        }
+    }
+}
+
+/// Answers the client's request with `code`. The bound address is left unspecified, since
+/// the tunnel's local end is on the far side of the SOCKS server.
+pub async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, code: u8) -> std::io::Result<()> {
+    // Format: version 5, reply code, reserved byte, 0.0.0.0, port 0
+    let reply = [
+        SOCKS5_VERSION,
+        code,
+        SOCKS5_RSV,
+        SOCKS5_ATYP_IPV4,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    stream.write_all(&reply).await?;
+    stream.flush().await
+}
+
+/// Answers a BIND request with `code` and `address`: where the upstream listens, in the first
+/// reply, and who connected, in the second.
+pub async fn reply_address<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    code: u8,
+    address: &Address,
+) -> Result<(), Box<dyn Error>> {
+    // Format: version 5, reply code, reserved byte, bound address, bound port
+    let mut reply = vec![SOCKS5_VERSION, code, SOCKS5_RSV];
+    match address {
+        Address::Ip(addr) => {
+            socks::encode_address(&mut reply, &addr.ip().to_string(), addr.port())?
+        }
+        Address::Domain(name, port) => socks::encode_address(&mut reply, name, *port)?,
+    }
+    stream.write_all(&reply).await?;
+    stream.flush().await?;
+    Ok(())
+}
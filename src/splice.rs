@@ -0,0 +1,191 @@
+use crate::relay::{RelayConfig, RelayStats};
+use std::io;
+use tokio::net::TcpStream;
+
+/// Whether `relay` can carry a tunnel with `config`: on Linux, when no rate limit needs the
+/// data to pass through a userspace buffer.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn applies(config: &RelayConfig) -> bool {
+    config.rate_limit.is_none() && config.global_rate_limit.is_none()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn applies(_config: &RelayConfig) -> bool {
+    false
+}
+
+/// Copies data in both directions between `a` and `b` like `relay::relay`, but moves it
+/// through a kernel pipe with splice(2) so that it never enters userspace. Each pipe holds up
+/// to `high_watermark` bytes.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub async fn relay(a: &TcpStream, b: &TcpStream, config: &RelayConfig) -> io::Result<RelayStats> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::time::Instant;
+
+    let started = Instant::now();
+    // Milliseconds since `started` when either direction last moved data
+    let last_activity = AtomicU64::new(0);
+    let (a_to_b_progress, b_to_a_progress) = match &config.progress {
+        Some(progress) => (Some(&progress.a_to_b), Some(&progress.b_to_a)),
+        None => (None, None),
+    };
+    let activity = || {
+        last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    };
+    let a_to_b = copy(a, b, config.high_watermark, a_to_b_progress, &activity);
+    let b_to_a = copy(b, a, config.high_watermark, b_to_a_progress, &activity);
+    let both = async { tokio::try_join!(a_to_b, b_to_a) };
+
+    let ((a_to_b, a_to_b_peak), (b_to_a, b_to_a_peak)) = match config.idle_timeout {
+        None => both.await?,
+        Some(timeout) => {
+            let idle = async {
+                loop {
+                    let last = started
+                        + std::time::Duration::from_millis(last_activity.load(Ordering::Relaxed));
+                    if last.elapsed() >= timeout {
+                        return io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("tunnel idle for {}s", timeout.as_secs()),
+                        );
+                    }
+                    tokio::time::sleep_until(last + timeout).await;
+                }
+            };
+            tokio::select! {
+                result = both => result?,
+                e = idle => return Err(e),
+            }
+        }
+    };
+    Ok(RelayStats {
+        a_to_b,
+        b_to_a,
+        peak_buffered: a_to_b_peak.max(b_to_a_peak),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub async fn relay(
+    _a: &TcpStream,
+    _b: &TcpStream,
+    _config: &RelayConfig,
+) -> io::Result<RelayStats> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "splice is only supported on Linux",
+    ))
+}
+
+// Moves data from `src` to `dst` through a pipe until `src` reaches EOF, then shuts down
+// writing on `dst`. Returns the bytes moved and the most held in the pipe at once.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn copy(
+    src: &TcpStream,
+    dst: &TcpStream,
+    capacity: usize,
+    progress: Option<&std::sync::atomic::AtomicU64>,
+    activity: &impl Fn(),
+) -> io::Result<(u64, usize)> {
+    use std::os::fd::AsRawFd;
+    use std::sync::atomic::Ordering;
+    use tokio::io::Interest;
+
+    let pipe = Pipe::new(capacity)?;
+    let mut pending = 0;
+    let mut transferred = 0u64;
+    let mut peak = 0;
+    loop {
+        if pending == 0 {
+            src.readable().await?;
+            match src.try_io(Interest::READABLE, || {
+                splice(src.as_raw_fd(), pipe.write.as_raw_fd(), pipe.capacity)
+            }) {
+                Ok(0) => break,
+                Ok(n) => {
+                    pending = n;
+                    peak = peak.max(n);
+                    activity();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        } else {
+            dst.writable().await?;
+            match dst.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), dst.as_raw_fd(), pending)
+            }) {
+                Ok(n) => {
+                    pending -= n;
+                    transferred += n as u64;
+                    if let Some(progress) = progress {
+                        progress.store(transferred, Ordering::Relaxed);
+                    }
+                    activity();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    socket2::SockRef::from(dst).shutdown(std::net::Shutdown::Write)?;
+    Ok((transferred, peak))
+}
+
+// A non-blocking pipe that data passes through between the sockets
+#[cfg(any(target_os = "linux", target_os = "android"))]
+struct Pipe {
+    read: std::os::fd::OwnedFd,
+    write: std::os::fd::OwnedFd,
+    capacity: usize,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Pipe {
+    // Opens a pipe, asking the kernel to size it to `capacity` (rounded to whole pages)
+    fn new(capacity: usize) -> io::Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both descriptors are open and owned by nobody else
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let requested = libc::c_int::try_from(capacity).unwrap_or(libc::c_int::MAX);
+        // SAFETY: F_SETPIPE_SZ and F_GETPIPE_SZ only take an integer argument
+        let capacity = unsafe {
+            libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, requested);
+            libc::fcntl(write.as_raw_fd(), libc::F_GETPIPE_SZ)
+        };
+        if capacity <= 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            read,
+            write,
+            capacity: capacity as usize,
+        })
+    }
+}
+
+// Moves up to `len` bytes from `from` to `to` without blocking
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn splice(from: std::os::fd::RawFd, to: std::os::fd::RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both descriptors stay open for the call, and null offsets use the file positions
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
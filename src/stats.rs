@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Sub-buckets per power of two in the latency histogram
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+// Enough buckets to cover every u64 microsecond value
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Destination tracked for every host beyond --max-tracked-destinations
+pub const OTHER_DESTINATIONS: &str = "(other)";
+
+/// Traffic per destination host, kept across reloads.
+pub static DESTINATIONS: Destinations = Destinations::new();
+
+/// Process-wide counters, updated with relaxed atomics so they are cheap on the hot path.
+pub static STATS: Stats = Stats::new();
+
+/// Aggregate proxy counters.
+pub struct Stats {
+    pub connections: AtomicU64,
+    pub rejected_connections: AtomicU64,
+    pub denied_connections: AtomicU64,
+    pub rate_limited_requests: AtomicU64,
+    pub errors: AtomicU64,
+    pub upstream_errors: AtomicU64,
+    pub direct_fallbacks: AtomicU64,
+    pub connect_requests: AtomicU64,
+    pub http_requests: AtomicU64,
+    pub active_tunnels: AtomicU64,
+    pub bytes_from_client: AtomicU64,
+    pub bytes_from_upstream: AtomicU64,
+    pub setup_latency: LatencyHistogram,
+    /// Time to connect to an upstream and open a tunnel through it
+    pub handshake_latency: LatencyHistogram,
+    /// Time from a plain HTTP request being sent until its response head arrived
+    pub first_byte_latency: LatencyHistogram,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            denied_connections: AtomicU64::new(0),
+            rate_limited_requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            upstream_errors: AtomicU64::new(0),
+            direct_fallbacks: AtomicU64::new(0),
+            connect_requests: AtomicU64::new(0),
+            http_requests: AtomicU64::new(0),
+            active_tunnels: AtomicU64::new(0),
+            bytes_from_client: AtomicU64::new(0),
+            bytes_from_upstream: AtomicU64::new(0),
+            setup_latency: LatencyHistogram::new(),
+            handshake_latency: LatencyHistogram::new(),
+            first_byte_latency: LatencyHistogram::new(),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Single-line end-of-run summary.
+    pub fn summary(&self) -> String {
+        format!(
+            "connections={} errors={} upstream_errors={} bytes_from_client={} bytes_from_upstream={} setup_p50={:?} setup_p99={:?}",
+            self.connections.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.upstream_errors.load(Ordering::Relaxed),
+            self.bytes_from_client.load(Ordering::Relaxed),
+            self.bytes_from_upstream.load(Ordering::Relaxed),
+            self.setup_latency.quantile(0.50),
+            self.setup_latency.quantile(0.99),
+        )
+    }
+}
+
+/// Counts a failed upstream connection attempt, passing the error through for use in `map_err`.
+pub fn upstream_error<E>(e: E) -> E {
+    Stats::inc(&STATS.upstream_errors);
+    e
+}
+
+/// Counts one open upstream connection in `STATS.active_tunnels` until dropped.
+pub struct ActiveTunnel(());
+
+impl ActiveTunnel {
+    pub fn new() -> Self {
+        Stats::inc(&STATS.active_tunnels);
+        Self(())
+    }
+}
+
+impl Drop for ActiveTunnel {
+    fn drop(&mut self) {
+        STATS.active_tunnels.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What went to one destination host.
+#[derive(Debug, Default, Clone)]
+pub struct DestinationStats {
+    /// Requests, tunnels and forwarded connections that got past the proxy's own checks
+    pub tunnels: u64,
+    /// Those that failed to connect or ended with an error
+    pub errors: u64,
+    pub bytes_from_client: u64,
+    pub bytes_from_upstream: u64,
+}
+
+/// Counters keyed by destination host. Once `limit` hosts are tracked, further ones are
+/// counted together under [`OTHER_DESTINATIONS`]; the limit is passed in on every update.
+pub struct Destinations {
+    hosts: Mutex<BTreeMap<String, DestinationStats>>,
+}
+
+impl Destinations {
+    const fn new() -> Self {
+        Self {
+            hosts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Adds one finished exchange with `host`; a `limit` of 0 disables tracking.
+    pub fn record(
+        &self,
+        host: &str,
+        bytes_from_client: u64,
+        bytes_from_upstream: u64,
+        failed: bool,
+        limit: usize,
+    ) {
+        if limit == 0 {
+            return;
+        }
+        let host = host.to_ascii_lowercase();
+        let mut hosts = self.hosts.lock().unwrap();
+        let key = if hosts.contains_key(&host) || hosts.len() < limit {
+            host
+        } else {
+            OTHER_DESTINATIONS.to_string()
+        };
+        let stats = hosts.entry(key).or_default();
+        stats.tunnels += 1;
+        stats.errors += u64::from(failed);
+        stats.bytes_from_client += bytes_from_client;
+        stats.bytes_from_upstream += bytes_from_upstream;
+    }
+
+    /// The tracked hosts with their counters, busiest (most bytes) first.
+    pub fn snapshot(&self) -> Vec<(String, DestinationStats)> {
+        let mut hosts: Vec<_> = self
+            .hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(host, stats)| (host.clone(), stats.clone()))
+            .collect();
+        hosts.sort_by_key(|(_, stats)| {
+            std::cmp::Reverse(stats.bytes_from_client + stats.bytes_from_upstream)
+        });
+        hosts
+    }
+}
+
+/// Lock-free log-linear histogram of durations with microsecond resolution.
+/// Each power of two is split into 8 buckets, bounding the relative error to 12.5%.
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, value: Duration) {
+        let micros = u64::try_from(value.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket_index(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Total of all recorded values.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// How many recorded values are certainly no greater than `bound`: those in buckets whose
+    /// upper bound is within it.
+    pub fn count_at_most(&self, bound: Duration) -> u64 {
+        let bound = u64::try_from(bound.as_micros()).unwrap_or(u64::MAX);
+        self.buckets
+            .iter()
+            .enumerate()
+            .take_while(|(index, _)| bucket_upper_bound(*index) <= bound)
+            .map(|(_, bucket)| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Upper bound of the bucket containing the `q` quantile, or zero when empty.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((count as f64 * q).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Duration::from_micros(bucket_upper_bound(index));
+            }
+        }
+        Duration::from_micros(u64::MAX)
+    }
+}
+
+// Values below SUB_BUCKETS get exact buckets; above that, the top bits select the bucket
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let mantissa = (value >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + mantissa
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let exponent = (index / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let mantissa = (index % SUB_BUCKETS) as u64;
+    let lower = (SUB_BUCKETS as u64 + mantissa) << (exponent - SUB_BUCKET_BITS);
+    lower.saturating_add((1u64 << (exponent - SUB_BUCKET_BITS)) - 1)
+}
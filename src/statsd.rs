@@ -0,0 +1,122 @@
+use crate::error::FatalError;
+use crate::stats::STATS;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+// Largest datagram sent, so packets fit a typical path MTU without fragmenting
+const MAX_PACKET: usize = 1432;
+
+// Setup latency quantiles pushed as gauges
+const QUANTILES: [(f64, &str); 3] = [(0.5, "p50"), (0.9, "p90"), (0.99, "p99")];
+
+/// Opens the socket metrics are pushed from, connected to the StatsD server at `addr`.
+pub async fn connect(addr: &str) -> Result<UdpSocket, FatalError> {
+    let server = tokio::net::lookup_host(addr)
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| FatalError::Config(format!("--statsd: cannot resolve '{addr}'")))?;
+    let local = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .map_err(|source| FatalError::Bind {
+            addr: local.to_string(),
+            source,
+        })?;
+    socket
+        .connect(server)
+        .await
+        .map_err(|e| FatalError::Config(format!("--statsd {addr}: {e}")))?;
+    info!("Pushing StatsD metrics to {}", server);
+    Ok(socket)
+}
+
+/// Sends the counters as StatsD metrics named `prefix.NAME` every `interval`: counters as
+/// `|c` increments since the previous push, the open tunnels and setup latency quantiles (in
+/// milliseconds) as `|g` gauges.
+pub async fn push(socket: UdpSocket, prefix: String, interval: Duration) {
+    let counters: [(&str, &AtomicU64); 11] = [
+        ("connections", &STATS.connections),
+        ("rejected_connections", &STATS.rejected_connections),
+        ("denied_connections", &STATS.denied_connections),
+        ("rate_limited_requests", &STATS.rate_limited_requests),
+        ("requests.connect", &STATS.connect_requests),
+        ("requests.http", &STATS.http_requests),
+        ("upstream_errors", &STATS.upstream_errors),
+        ("direct_fallbacks", &STATS.direct_fallbacks),
+        ("errors", &STATS.errors),
+        ("bytes.from_client", &STATS.bytes_from_client),
+        ("bytes.from_upstream", &STATS.bytes_from_upstream),
+    ];
+    let mut previous = counters.map(|(_, counter)| counter.load(Ordering::Relaxed));
+    let mut failing = false;
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let mut lines = Vec::new();
+        for ((name, counter), previous) in counters.iter().zip(&mut previous) {
+            let value = counter.load(Ordering::Relaxed);
+            lines.push(format!("{prefix}.{name}:{}|c", value - *previous));
+            *previous = value;
+        }
+        lines.push(format!(
+            "{prefix}.active_tunnels:{}|g",
+            STATS.active_tunnels.load(Ordering::Relaxed)
+        ));
+        let latencies = [
+            ("setup_latency", &STATS.setup_latency),
+            ("handshake_latency", &STATS.handshake_latency),
+            ("first_byte_latency", &STATS.first_byte_latency),
+        ];
+        for (metric, histogram) in latencies {
+            for (q, name) in QUANTILES {
+                let latency = histogram.quantile(q).as_secs_f64() * 1000.0;
+                lines.push(format!("{prefix}.{metric}.{name}:{latency:.3}|g"));
+            }
+        }
+
+        let result = async {
+            for packet in packets(&lines) {
+                socket.send(packet.as_bytes()).await?;
+            }
+            std::io::Result::Ok(())
+        }
+        .await;
+        // Warn when the server becomes unreachable, not on every push while it stays so
+        match result {
+            Err(e) if !failing => {
+                warn!("Failed to push StatsD metrics: {}", e);
+                failing = true;
+            }
+            Ok(()) if failing => {
+                info!("Pushing StatsD metrics again");
+                failing = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Joins metric lines into newline-separated packets of at most MAX_PACKET bytes
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = vec![String::new()];
+    for line in lines {
+        let packet = packets.last_mut().unwrap();
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+            packets.push(line.clone());
+        } else {
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            let _ = write!(packet, "{line}");
+        }
+    }
+    packets
+}
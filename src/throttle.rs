@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+/// Token bucket refilled at `rate` bytes per second, holding at most one second's worth.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A bucket shared by every tunnel counting against a global limit.
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+impl TokenBucket {
+    /// Creates a full bucket; `rate` must be non-zero.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn shared(rate: u64) -> SharedBucket {
+        Arc::new(Mutex::new(Self::new(rate)))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    // Whole bytes that may be sent now
+    fn available(&mut self, now: Instant) -> usize {
+        self.refill(now);
+        self.tokens as usize
+    }
+
+    // Time until at least one byte may be sent
+    fn delay(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.rate).max(0.0))
+    }
+
+    fn take(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// Rate limits applied to one direction of a relay: an optional bucket of its own and
+/// an optional global bucket shared with other tunnels.
+pub struct Throttle {
+    tunnel: Option<TokenBucket>,
+    global: Option<SharedBucket>,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    pub fn new(rate: Option<u64>, global: Option<SharedBucket>) -> Self {
+        Self {
+            tunnel: rate.map(TokenBucket::new),
+            global,
+            wait: None,
+        }
+    }
+
+    /// How many bytes, up to `max`, may be read now. When the buckets are empty this
+    /// returns `Pending` and wakes the task once tokens are available again.
+    pub fn poll_allowance(&mut self, cx: &mut Context<'_>, max: usize) -> Poll<usize> {
+        if self.tunnel.is_none() && self.global.is_none() {
+            return Poll::Ready(max);
+        }
+
+        loop {
+            let now = Instant::now();
+            let mut allowance = max;
+            let mut delay = Duration::ZERO;
+            if let Some(bucket) = &mut self.tunnel {
+                allowance = allowance.min(bucket.available(now));
+                delay = delay.max(bucket.delay());
+            }
+            if let Some(bucket) = &self.global {
+                let mut bucket = bucket.lock().unwrap();
+                allowance = allowance.min(bucket.available(now));
+                delay = delay.max(bucket.delay());
+            }
+            if allowance > 0 {
+                return Poll::Ready(allowance);
+            }
+
+            let wait = self
+                .wait
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+            wait.as_mut().reset(now + delay);
+            if wait.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Takes `n` bytes worth of tokens after they were read.
+    pub fn consume(&mut self, n: usize) {
+        if let Some(bucket) = &mut self.tunnel {
+            bucket.take(n);
+        }
+        if let Some(bucket) = &self.global {
+            bucket.lock().unwrap().take(n);
+        }
+    }
+}
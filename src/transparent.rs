@@ -0,0 +1,50 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// The destination a connection was addressed to before the firewall redirected it to us:
+/// `SO_ORIGINAL_DST` for iptables REDIRECT, or the local address for TPROXY, which leaves
+/// the destination untouched.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    let local = stream.local_addr()?;
+    let original = match local {
+        SocketAddr::V4(_) => socket.original_dst_v4(),
+        SocketAddr::V6(_) => socket.original_dst_v6(),
+    };
+    match original {
+        Ok(addr) => addr
+            .as_socket()
+            .ok_or_else(|| io::Error::other("SO_ORIGINAL_DST is not an IP address")),
+        // No NAT entry: a TPROXYed connection, or one made to the listener directly
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(local),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn original_destination(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxying is only supported on Linux",
+    ))
+}
+
+/// Sets IP_TRANSPARENT so the listener accepts TPROXYed connections for foreign addresses.
+/// This needs CAP_NET_ADMIN; REDIRECT works without it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_transparent(socket: &socket2::Socket, addr: SocketAddr) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => socket.set_ip_transparent_v4(true),
+        SocketAddr::V6(_) => socket.set_ip_transparent_v6(true),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_transparent(_socket: &socket2::Socket, _addr: SocketAddr) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxying is only supported on Linux",
+    ))
+}
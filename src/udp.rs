@@ -0,0 +1,272 @@
+use crate::config::PortMapping;
+use crate::error::FatalError;
+use crate::socks::{self, Address, Upstream, UpstreamStream};
+use crate::upstream::UpstreamPool;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, instrument, warn, Instrument};
+
+// Largest UDP payload we relay
+const MAX_DATAGRAM: usize = 65535;
+
+// Datagrams queued per client session before new ones are dropped
+const SESSION_QUEUE: usize = 256;
+
+/// A SOCKS5 UDP ASSOCIATE session: the control connection that keeps the association alive
+/// and a UDP socket connected to the server's relay address.
+pub struct UdpAssociation {
+    // Closing the control connection ends the association on the server
+    control: UpstreamStream,
+    socket: UdpSocket,
+}
+
+impl UdpAssociation {
+    /// Asks the upstream for a UDP relay and connects a local socket to it.
+    #[instrument(skip_all, fields(upstream = %upstream.addr))]
+    pub async fn open(upstream: &Upstream) -> Result<Self, Box<dyn Error>> {
+        if upstream.protocol != socks::Protocol::Socks
+            || upstream.version != socks::SocksVersion::V5
+        {
+            return Err("UDP ASSOCIATE requires a SOCKS5 upstream".into());
+        }
+
+        let mut control = socks::connect_server(upstream).await?;
+        if !matches!(
+            socks::negotiate_auth(&mut control, upstream).await?,
+            socks::Negotiated::Plain
+        ) {
+            return Err("UDP ASSOCIATE is not supported with GSSAPI authentication".into());
+        }
+        // We don't know which address our datagrams will come from, so send 0.0.0.0:0
+        let relay =
+            socks::send_command(&mut control, socks::SOCKS5_CMD_UDP_ASSOCIATE, "0.0.0.0", 0)
+                .await?;
+
+        let relay = match relay {
+            // An unspecified address means "same host as the control connection"
+            Address::Ip(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::new(control.peer_addr()?.ip(), addr.port())
+            }
+            Address::Ip(addr) => addr,
+            Address::Domain(host, port) => tokio::net::lookup_host((host.as_str(), port))
+                .await?
+                .next()
+                .ok_or("SOCKS5 relay address did not resolve")?,
+        };
+
+        let bind_addr: SocketAddr = if relay.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(relay).await?;
+        debug!("UDP association relaying via {}", relay);
+
+        Ok(Self { control, socket })
+    }
+
+    /// Sends a datagram that already carries the SOCKS5 UDP request header.
+    pub async fn send_raw(&self, datagram: &[u8]) -> std::io::Result<()> {
+        self.socket.send(datagram).await.map(|_| ())
+    }
+
+    /// Receives the next datagram from the relay, header included.
+    pub async fn recv_raw(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.socket.recv(buf).await
+    }
+
+    /// Resolves when the server closes the control connection, ending the association.
+    pub async fn closed(&self) {
+        // Nothing is read from the association afterwards, so even under TLS the raw
+        // bytes can be discarded
+        let mut buf = [0u8; 64];
+        loop {
+            if self.control.readable().await.is_err() {
+                return;
+            }
+            match self.control.try_read_raw(&mut buf) {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Splits a SOCKS5 UDP datagram into its address and payload. Fragments are not supported.
+pub fn decapsulate(datagram: &[u8]) -> Option<(Address, &[u8])> {
+    match datagram {
+        [0, 0, 0, rest @ ..] => socks::decode_address(rest),
+        _ => None,
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+/// Binds the listening socket of the UDP relay or a `--forward-udp` mapping.
+pub async fn bind(listen: &str) -> Result<UdpSocket, FatalError> {
+    UdpSocket::bind(listen)
+        .await
+        .map_err(|source| FatalError::Bind {
+            addr: listen.to_string(),
+            source,
+        })
+}
+
+// Relays SOCKS5-encapsulated datagrams from local clients through per-client UDP associations
+pub async fn run_relay(socket: UdpSocket, upstreams: Arc<UpstreamPool>, idle_timeout: Duration) {
+    info!("UDP relay listening on: {}", local_addr(&socket));
+    serve(socket, None, upstreams, idle_timeout).await
+}
+
+// Relays plain datagrams from local clients to the one destination of a --forward-udp
+// mapping, adding the SOCKS5 UDP header on the way out and removing it from the replies
+pub async fn run_forward(
+    socket: UdpSocket,
+    forward: PortMapping,
+    upstreams: Arc<UpstreamPool>,
+    idle_timeout: Duration,
+) {
+    info!(
+        "Forwarding UDP from {} to {}:{}",
+        local_addr(&socket),
+        forward.host,
+        forward.port
+    );
+    let mut header = vec![0, 0, 0];
+    // The target was checked when the mapping was parsed
+    let _ = socks::encode_address(&mut header, &forward.host, forward.port);
+    serve(socket, Some(Arc::new(header)), upstreams, idle_timeout).await
+}
+
+fn local_addr(socket: &UdpSocket) -> String {
+    socket
+        .local_addr()
+        .map_or_else(|_| "?".to_string(), |addr| addr.to_string())
+}
+
+// Hands each client's datagrams to its session. With `header`, they are bare payloads for
+// the destination it names; otherwise they must carry a SOCKS5 UDP header of their own.
+async fn serve(
+    socket: UdpSocket,
+    header: Option<Arc<Vec<u8>>>,
+    upstreams: Arc<UpstreamPool>,
+    idle_timeout: Duration,
+) {
+    let socket = Arc::new(socket);
+    let sessions: Sessions = Arc::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        let (n, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // ICMP errors from earlier sends surface here on some platforms; keep serving
+            Err(e) => {
+                warn!("UDP receive error: {}", e);
+                continue;
+            }
+        };
+
+        let datagram = match &header {
+            Some(header) => [header.as_slice(), &buf[..n]].concat(),
+            None if decapsulate(&buf[..n]).is_none() => {
+                debug!(
+                    "Dropping datagram without a valid SOCKS5 UDP header from {}",
+                    client
+                );
+                continue;
+            }
+            None => buf[..n].to_vec(),
+        };
+
+        let sender = {
+            let mut active = sessions.lock().unwrap();
+            active
+                .entry(client)
+                .or_insert_with(|| {
+                    let (tx, rx) = mpsc::channel(SESSION_QUEUE);
+                    let span = tracing::info_span!("udp_session", client.addr = %client);
+                    tokio::spawn(
+                        run_session(
+                            client,
+                            rx,
+                            socket.clone(),
+                            header.is_some(),
+                            upstreams.clone(),
+                            sessions.clone(),
+                            idle_timeout,
+                        )
+                        .instrument(span),
+                    );
+                    tx
+                })
+                .clone()
+        };
+
+        if sender.try_send(datagram).is_err() {
+            debug!("UDP session queue full, dropping datagram from {}", client);
+        }
+    }
+}
+
+// Owns one client's association and shuttles datagrams until it goes idle or the upstream
+// closes it. With `bare`, replies are passed on without their SOCKS5 UDP header.
+async fn run_session(
+    client: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    socket: Arc<UdpSocket>,
+    bare: bool,
+    upstreams: Arc<UpstreamPool>,
+    sessions: Sessions,
+    idle_timeout: Duration,
+) {
+    let result = async {
+        let upstream = upstreams.pick();
+        let association = UdpAssociation::open(&upstream).await?;
+        info!("UDP association established");
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+
+        loop {
+            tokio::select! {
+                datagram = datagrams.recv() => match datagram {
+                    Some(datagram) => association.send_raw(&datagram).await?,
+                    None => break,
+                },
+                received = association.recv_raw(&mut buf) => {
+                    let n = received?;
+                    let reply = if bare {
+                        match decapsulate(&buf[..n]) {
+                            Some((_, payload)) => payload,
+                            None => continue,
+                        }
+                    } else {
+                        &buf[..n]
+                    };
+                    socket.send_to(reply, client).await?;
+                }
+                _ = association.closed() => {
+                    info!("Upstream closed the UDP association");
+                    break;
+                }
+                _ = tokio::time::sleep(idle_timeout) => {
+                    debug!("UDP session idle, closing");
+                    break;
+                }
+            }
+        }
+        Ok::<(), Box<dyn Error>>(())
+    }
+    .await;
+
+    sessions.lock().unwrap().remove(&client);
+    if let Err(e) = result {
+        error!("UDP session error: {}", e);
+    }
+}
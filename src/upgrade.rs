@@ -0,0 +1,398 @@
+use crate::error::FatalError;
+use socket2::{SockRef, Socket};
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{info, warn};
+
+// Most descriptors one message may carry (Linux's SCM_MAX_FD)
+#[cfg(unix)]
+const MAX_SOCKETS: usize = 253;
+
+// Sent by the new process once it serves on the sockets it took over
+#[cfg(unix)]
+const READY: u8 = b'1';
+
+/// The sockets passed between an old and a new process during a `--upgrade-socket`
+/// upgrade, each under a role naming what it listens for, such as `listen 0.0.0.0:8080`.
+pub struct Upgrade {
+    path: Option<PathBuf>,
+    // Taken over from the process this one replaces, until claimed
+    inherited: Vec<(String, Socket)>,
+    // Copies of the sockets in use, for the process that replaces this one
+    handoff: Vec<(String, Socket)>,
+    // Connection to the replaced process, told when this one is ready
+    #[cfg(unix)]
+    previous: Option<std::os::unix::net::UnixStream>,
+}
+
+impl Upgrade {
+    /// Prepares for upgrades through the Unix socket at `path`, first taking over the
+    /// sockets of the process already serving there, if any.
+    pub fn start(path: Option<&Path>) -> Result<Self, FatalError> {
+        let mut upgrade = Self {
+            path: path.map(Path::to_path_buf),
+            inherited: Vec::new(),
+            handoff: Vec::new(),
+            #[cfg(unix)]
+            previous: None,
+        };
+        let Some(path) = path else {
+            return Ok(upgrade);
+        };
+        #[cfg(unix)]
+        {
+            let error = |e: io::Error| {
+                FatalError::Config(format!("--upgrade-socket {}: {e}", path.display()))
+            };
+            let mut previous = match std::os::unix::net::UnixStream::connect(path) {
+                Ok(previous) => previous,
+                // Nothing to take over: the first process, or one left a stale socket behind
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    return Ok(upgrade)
+                }
+                Err(e) => return Err(error(e)),
+            };
+            upgrade.inherited = receive_sockets(&mut previous).map_err(error)?;
+            upgrade.previous = Some(previous);
+            info!(
+                "Taking over {} sockets from the previous process",
+                upgrade.inherited.len()
+            );
+            Ok(upgrade)
+        }
+        #[cfg(not(unix))]
+        Err(FatalError::Config(format!(
+            "--upgrade-socket {}: only available on Unix",
+            path.display()
+        )))
+    }
+
+    /// Claims the sockets taken over for `role`, in the order the previous process had them.
+    pub fn inherited<T: From<Socket>>(&mut self, role: &str) -> Vec<T> {
+        let (claimed, rest) = std::mem::take(&mut self.inherited)
+            .into_iter()
+            .partition(|(inherited, _)| inherited == role);
+        self.inherited = rest;
+        claimed
+            .into_iter()
+            .map(|(_, socket)| T::from(socket))
+            .collect()
+    }
+
+    /// Keeps a copy of `socket` to hand to the next process under `role`.
+    pub fn keep(&mut self, role: &str, socket: SockRef<'_>) -> Result<(), FatalError> {
+        if self.path.is_none() {
+            return Ok(());
+        }
+        let copy = socket
+            .try_clone()
+            .map_err(|e| FatalError::Runtime(format!("failed to keep {role} for upgrades: {e}")))?;
+        self.handoff.push((role.to_string(), copy));
+        Ok(())
+    }
+
+    /// The TCP listeners taken over for `role`, or else the ones `bind` opens, kept for the
+    /// next process either way.
+    pub async fn tcp_listeners(
+        &mut self,
+        role: &str,
+        bind: impl Future<Output = Result<Vec<TcpListener>, FatalError>>,
+    ) -> Result<Vec<TcpListener>, FatalError> {
+        let inherited: Vec<std::net::TcpListener> = self.inherited(role);
+        let listeners = if inherited.is_empty() {
+            bind.await?
+        } else {
+            inherited
+                .into_iter()
+                .map(TcpListener::from_std)
+                .collect::<io::Result<_>>()
+                .map_err(|e| FatalError::Runtime(format!("failed to take over {role}: {e}")))?
+        };
+        for listener in &listeners {
+            self.keep(role, SockRef::from(listener))?;
+        }
+        Ok(listeners)
+    }
+
+    /// Like `tcp_listeners`, for a single listener.
+    pub async fn tcp_listener(
+        &mut self,
+        role: &str,
+        bind: impl Future<Output = Result<TcpListener, FatalError>>,
+    ) -> Result<TcpListener, FatalError> {
+        let bind = async { Ok(vec![bind.await?]) };
+        let mut listeners = self.tcp_listeners(role, bind).await?;
+        // Only one was ever kept under a role like this
+        Ok(listeners.swap_remove(0))
+    }
+
+    /// Like `tcp_listener`, for a UDP socket.
+    pub async fn udp_socket(
+        &mut self,
+        role: &str,
+        bind: impl Future<Output = Result<UdpSocket, FatalError>>,
+    ) -> Result<UdpSocket, FatalError> {
+        let socket = match self.inherited::<std::net::UdpSocket>(role).pop() {
+            Some(socket) => UdpSocket::from_std(socket)
+                .map_err(|e| FatalError::Runtime(format!("failed to take over {role}: {e}")))?,
+            None => bind.await?,
+        };
+        self.keep(role, SockRef::from(&socket))?;
+        Ok(socket)
+    }
+
+    /// Called once every socket is bound and served: closes the taken over sockets no role
+    /// claimed, listens for the next process, and tells the previous one to stop accepting.
+    /// Resolves when a new process has taken over in turn, after which this one should
+    /// stop accepting and drain; never when upgrades are off.
+    pub async fn serve(mut self) {
+        for (role, _) in self.inherited.drain(..) {
+            info!("Closing {} taken over but no longer configured", role);
+        }
+        let Some(path) = self.path.take() else {
+            return std::future::pending().await;
+        };
+        #[cfg(unix)]
+        {
+            // The previous process keeps its connection to the old socket file either way
+            let _ = std::fs::remove_file(&path);
+            let listener = match tokio::net::UnixListener::bind(&path) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    warn!(
+                        "Upgrades are off: cannot listen on {}: {}",
+                        path.display(),
+                        e
+                    );
+                    return std::future::pending().await;
+                }
+            };
+            if let Some(previous) = self.previous.take() {
+                if let Err(e) = io::Write::write_all(&mut &previous, &[READY]) {
+                    warn!("Failed to tell the previous process to stop: {}", e);
+                }
+            }
+            loop {
+                let Ok((next, _)) = listener.accept().await else {
+                    continue;
+                };
+                let handoff = std::mem::take(&mut self.handoff);
+                let next = match next.into_std() {
+                    Ok(next) => next,
+                    Err(e) => {
+                        warn!("Upgrade connection failed: {}", e);
+                        self.handoff = handoff;
+                        continue;
+                    }
+                };
+                let handed =
+                    tokio::task::spawn_blocking(move || (hand_over(next, &handoff), handoff)).await;
+                match handed {
+                    Ok((Ok(()), _)) => return,
+                    Ok((Err(e), handoff)) => {
+                        warn!("Upgrade failed, keeping on serving: {}", e);
+                        self.handoff = handoff;
+                    }
+                    Err(e) => {
+                        warn!("Upgrade failed, keeping on serving: {}", e);
+                        return std::future::pending().await;
+                    }
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        std::future::pending().await
+    }
+}
+
+/// SIGUSR2, which starts an upgrade. Nothing is installed when upgrades are off, leaving
+/// the signal's default action alone.
+pub struct UpgradeSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl UpgradeSignal {
+    pub fn new(enabled: bool) -> Result<Self, FatalError> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = enabled
+                .then(|| signal(SignalKind::user_defined2()))
+                .transpose()
+                .map_err(|e| {
+                    FatalError::Runtime(format!("failed to install SIGUSR2 handler: {e}"))
+                })?;
+            Ok(Self { signal })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = enabled;
+            Ok(Self {})
+        }
+    }
+
+    /// Resolves on each SIGUSR2.
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending().await
+    }
+}
+
+/// Starts the current binary again with the same arguments, as SIGUSR2 asks for. With
+/// `--upgrade-socket` the new process takes over this one's sockets.
+pub fn spawn_successor() -> io::Result<u32> {
+    let child = std::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .spawn()?;
+    Ok(child.id())
+}
+
+// Sends the sockets to a new process, then waits until it serves on them. A process that
+// exits or fails before that leaves this one in charge.
+#[cfg(unix)]
+fn hand_over(
+    mut next: std::os::unix::net::UnixStream,
+    sockets: &[(String, Socket)],
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    next.set_nonblocking(false)?;
+    if sockets.len() > MAX_SOCKETS {
+        return Err(io::Error::other(format!(
+            "{} sockets are more than one message can pass",
+            sockets.len()
+        )));
+    }
+    let roles = sockets
+        .iter()
+        .map(|(role, _)| role.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let fds: Vec<i32> = sockets
+        .iter()
+        .map(|(_, socket)| socket.as_raw_fd())
+        .collect();
+    send_fds(&next, roles.as_bytes(), &fds)?;
+    info!("Passed {} sockets to the new process", fds.len());
+
+    let mut ready = [0u8; 1];
+    match io::Read::read(&mut next, &mut ready)? {
+        1 if ready[0] == READY => Ok(()),
+        _ => Err(io::Error::other("the new process exited before serving")),
+    }
+}
+
+#[cfg(unix)]
+fn receive_sockets(
+    previous: &mut std::os::unix::net::UnixStream,
+) -> io::Result<Vec<(String, Socket)>> {
+    use std::os::fd::FromRawFd;
+
+    let (roles, fds) = recv_fds(previous)?;
+    // SAFETY: the descriptors were just received, so nothing else owns them
+    let sockets: Vec<Socket> = fds
+        .into_iter()
+        .map(|fd| unsafe { Socket::from_raw_fd(fd) })
+        .collect();
+    for socket in &sockets {
+        // Not for the process after next, and ready for Tokio
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+    }
+    let roles = String::from_utf8_lossy(&roles).into_owned();
+    let roles = roles.split('\n').filter(|role| !role.is_empty());
+    Ok(roles.map(str::to_string).zip(sockets).collect())
+}
+
+#[cfg(unix)]
+fn send_fds(stream: &std::os::unix::net::UnixStream, data: &[u8], fds: &[i32]) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fds_len = std::mem::size_of_val(fds);
+    // SAFETY: the control buffer is sized with CMSG_SPACE for the header and descriptors,
+    // and every pointer handed to sendmsg outlives the call
+    unsafe {
+        let mut control = vec![0u8; libc::CMSG_SPACE(fds_len as u32) as usize];
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control.len() as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                libc::CMSG_DATA(cmsg),
+                fds_len,
+            );
+        }
+        if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn recv_fds(stream: &std::os::unix::net::UnixStream) -> io::Result<(Vec<u8>, Vec<i32>)> {
+    use std::os::fd::AsRawFd;
+
+    let mut data = vec![0u8; 64 * 1024];
+    let mut fds = Vec::new();
+    // SAFETY: as in send_fds; the descriptors are read from within the received control data
+    unsafe {
+        let space = libc::CMSG_SPACE((MAX_SOCKETS * std::mem::size_of::<i32>()) as u32);
+        let mut control = vec![0u8; space as usize];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        let received = libc::recvmsg(stream.as_raw_fd(), &mut msg, 0);
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        data.truncate(received as usize);
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let mut received = vec![0i32; len / std::mem::size_of::<i32>()];
+                std::ptr::copy_nonoverlapping(
+                    libc::CMSG_DATA(cmsg),
+                    received.as_mut_ptr() as *mut u8,
+                    len,
+                );
+                fds.extend(received);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::other("too many sockets passed"));
+        }
+    }
+    Ok((data, fds))
+}
@@ -0,0 +1,396 @@
+use crate::proxy::timed;
+use crate::socks::{
+    self, ConnectRetry, Credentials, Protocol, SocksVersion, Upstream, UpstreamTls,
+};
+use std::error::Error;
+use std::hash::BuildHasher;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tracing::{info, warn};
+
+/// How an upstream is chosen for each new tunnel.
+#[derive(clap::ValueEnum, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    /// Cycle through the upstreams in order
+    RoundRobin,
+    /// Pick an upstream uniformly at random
+    Random,
+    /// Pick the upstream with the fewest open tunnels
+    LeastConnections,
+    /// Prefer the upstream with the fastest health-check handshake
+    Latency,
+}
+
+// Weight of a new handshake time in an upstream's smoothed latency
+const LATENCY_SMOOTHING: f64 = 0.3;
+// How much faster than the preferred upstream another one must be for --balance latency to
+// switch to it, so that routing doesn't flap between upstreams of similar speed
+const LATENCY_HYSTERESIS: f64 = 0.8;
+
+// When a health check last reached an upstream. Kept across reloads, which replace the pool
+// but not the servers' reachability.
+static LAST_REACHABLE: Mutex<Option<Instant>> = Mutex::new(None);
+
+// An upstream together with the number of tunnels currently using it, whether its last
+// health check passed and how long its handshakes take
+struct Entry {
+    upstream: Arc<Upstream>,
+    active: Arc<AtomicUsize>,
+    healthy: AtomicBool,
+    // Smoothed health-check handshake time in microseconds, 0 until measured
+    latency: AtomicU64,
+}
+
+/// An upstream proxy URL, `[scheme://][user:pass@]host:port`, split into its parts.
+pub struct UpstreamUrl {
+    pub protocol: Protocol,
+    /// The SOCKS version the scheme names, if it names one
+    pub version: Option<SocksVersion>,
+    /// Whether the connection to the upstream is wrapped in TLS
+    pub tls: bool,
+    pub credentials: Option<Credentials>,
+    /// `host:port`, or `unix://PATH`
+    pub addr: String,
+}
+
+impl UpstreamUrl {
+    /// Parses an upstream URL. The scheme selects the protocol: `socks`, `socks5` or `socks5h`
+    /// for SOCKS5, `socks4`, `socks4a`, `tls` for SOCKS over TLS, `http`, or `https` for an
+    /// HTTP proxy reached over TLS. `unix://PATH` is a SOCKS server on a Unix domain socket,
+    /// and a URL without a scheme speaks `default`.
+    pub fn parse(url: &str, default: Protocol) -> Result<Self, String> {
+        let (scheme, rest) = match url.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme.to_ascii_lowercase()), rest),
+            None => (None, url),
+        };
+        let (protocol, version, tls) = match scheme.as_deref() {
+            None => (default, None, false),
+            Some("unix") => {
+                if !cfg!(unix) {
+                    return Err("Unix domain sockets are not supported on this platform".into());
+                }
+                return Ok(Self {
+                    protocol: Protocol::Socks,
+                    version: None,
+                    tls: false,
+                    credentials: None,
+                    addr: url.to_string(),
+                });
+            }
+            Some("tls") => (Protocol::Socks, None, true),
+            Some("socks" | "socks5" | "socks5h") => {
+                (Protocol::Socks, Some(SocksVersion::V5), false)
+            }
+            Some("socks4a") => (Protocol::Socks, Some(SocksVersion::V4a), false),
+            Some("socks4") => (Protocol::Socks, Some(SocksVersion::V4), false),
+            Some("http") => (Protocol::Http, None, false),
+            Some("https") => (Protocol::Http, None, true),
+            Some(scheme) => return Err(format!("unsupported upstream scheme '{scheme}'")),
+        };
+        if tls && rest.starts_with("unix://") {
+            return Err("TLS over a Unix domain socket is not supported".into());
+        }
+
+        let (credentials, addr) = match rest.rsplit_once('@') {
+            Some((userinfo, addr)) => {
+                let (username, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                // RFC 1929 length fields are one byte
+                if protocol == Protocol::Socks && (username.len() > 255 || password.len() > 255) {
+                    return Err("upstream username and password must be at most 255 bytes".into());
+                }
+                let credentials = Credentials {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                };
+                (Some(credentials), addr)
+            }
+            None => (None, rest),
+        };
+        let addr = addr.trim_end_matches('/');
+        if addr
+            .rsplit_once(':')
+            .is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err())
+        {
+            return Err(format!("expected host:port in upstream '{url}'"));
+        }
+
+        Ok(Self {
+            protocol,
+            version,
+            tls,
+            credentials,
+            addr: addr.to_string(),
+        })
+    }
+
+    /// TLS settings for reaching the upstream through `connector`, verifying `server_name`
+    /// or else the host of the address.
+    pub fn tls_with(
+        &self,
+        connector: &TlsConnector,
+        server_name: Option<&str>,
+    ) -> Result<UpstreamTls, String> {
+        let host = match server_name {
+            Some(name) => name,
+            None => self
+                .addr
+                .rsplit_once(':')
+                .map_or(&*self.addr, |(host, _)| host),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| format!("invalid server name: {e}"))?;
+        Ok(UpstreamTls {
+            connector: connector.clone(),
+            server_name,
+        })
+    }
+}
+
+/// The configured upstream SOCKS servers and the strategy for spreading tunnels over them.
+pub struct UpstreamPool {
+    entries: Vec<Entry>,
+    strategy: Balance,
+    next: AtomicUsize,
+    // The upstream --balance latency sends tunnels to while it is healthy
+    preferred: AtomicUsize,
+}
+
+/// An upstream chosen for one tunnel. It counts as an active connection until dropped.
+pub struct Lease {
+    upstream: Arc<Upstream>,
+    active: Arc<AtomicUsize>,
+}
+
+impl UpstreamPool {
+    /// Creates a pool; `upstreams` must not be empty.
+    pub fn new(upstreams: Vec<Upstream>, strategy: Balance) -> Self {
+        assert!(!upstreams.is_empty(), "at least one upstream is required");
+        let entries = upstreams
+            .into_iter()
+            .map(|upstream| Entry {
+                upstream: Arc::new(upstream),
+                active: Arc::default(),
+                healthy: AtomicBool::new(true),
+                latency: AtomicU64::new(0),
+            })
+            .collect();
+        Self {
+            entries,
+            strategy,
+            next: AtomicUsize::new(0),
+            preferred: AtomicUsize::new(0),
+        }
+    }
+
+    /// All configured upstreams, in configuration order.
+    pub fn upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        self.entries.iter().map(|entry| &*entry.upstream)
+    }
+
+    /// Chooses the upstream for a new tunnel according to the balancing strategy, among the
+    /// upstreams passing their health checks. When none does, all of them are candidates.
+    pub fn pick(&self) -> Lease {
+        self.pick_except(&[])
+            .expect("an upstream pool is never empty")
+    }
+
+    /// Like [`pick`](Self::pick), among the upstreams whose address is not in `tried`, e.g.
+    /// to fail over after connecting through those failed. `None` once all were tried.
+    pub fn pick_except(&self, tried: &[String]) -> Option<Lease> {
+        let untried: Vec<usize> = (0..self.entries.len())
+            .filter(|&index| !tried.contains(&self.entries[index].upstream.addr))
+            .collect();
+        let mut candidates: Vec<usize> = untried
+            .iter()
+            .copied()
+            .filter(|&index| self.entries[index].healthy.load(Ordering::Relaxed))
+            .collect();
+        if candidates.is_empty() {
+            candidates = untried;
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = match self.strategy {
+            Balance::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            Balance::Random => {
+                // RandomState is seeded per instance, which is all the randomness we need
+                let seed = self.next.fetch_add(1, Ordering::Relaxed);
+                candidates[std::collections::hash_map::RandomState::new().hash_one(seed) as usize
+                    % candidates.len()]
+            }
+            Balance::LeastConnections => candidates
+                .into_iter()
+                .min_by_key(|&index| self.entries[index].active.load(Ordering::Relaxed))
+                .unwrap_or(0),
+            Balance::Latency => {
+                let preferred = self.preferred.load(Ordering::Relaxed);
+                if candidates.contains(&preferred) {
+                    preferred
+                } else {
+                    self.fastest(&candidates).unwrap_or(candidates[0])
+                }
+            }
+        };
+
+        let entry = &self.entries[index];
+        entry.active.fetch_add(1, Ordering::Relaxed);
+        Some(Lease {
+            upstream: entry.upstream.clone(),
+            active: entry.active.clone(),
+        })
+    }
+}
+
+impl UpstreamPool {
+    // The measured candidate with the lowest latency
+    fn fastest(&self, candidates: &[usize]) -> Option<usize> {
+        candidates
+            .iter()
+            .copied()
+            .filter(|&index| self.latency(index) > 0)
+            .min_by_key(|&index| self.latency(index))
+    }
+
+    fn latency(&self, index: usize) -> u64 {
+        self.entries[index].latency.load(Ordering::Relaxed)
+    }
+
+    // Folds a handshake time into the upstream's smoothed latency
+    fn record_latency(&self, index: usize, elapsed: Duration) {
+        let sample = (elapsed.as_micros() as u64).max(1);
+        let previous = self.latency(index);
+        let smoothed = match previous {
+            0 => sample,
+            _ => {
+                (previous as f64 * (1.0 - LATENCY_SMOOTHING) + sample as f64 * LATENCY_SMOOTHING)
+                    as u64
+            }
+        };
+        self.entries[index]
+            .latency
+            .store(smoothed.max(1), Ordering::Relaxed);
+    }
+
+    // After a round of health checks, moves --balance latency to the fastest healthy upstream
+    // if it is clearly faster than the preferred one, or the preferred one is down
+    fn update_preferred(&self) {
+        let healthy: Vec<usize> = (0..self.entries.len())
+            .filter(|&index| self.entries[index].healthy.load(Ordering::Relaxed))
+            .collect();
+        let Some(fastest) = self.fastest(&healthy) else {
+            return;
+        };
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let keep = healthy.contains(&preferred)
+            && self.latency(preferred) > 0
+            && self.latency(fastest) as f64 >= self.latency(preferred) as f64 * LATENCY_HYSTERESIS;
+        if preferred != fastest && !keep {
+            info!(
+                "Preferring upstream {} ({:.1} ms) over {} ({:.1} ms)",
+                self.entries[fastest].upstream.addr,
+                self.latency(fastest) as f64 / 1000.0,
+                self.entries[preferred].upstream.addr,
+                self.latency(preferred) as f64 / 1000.0
+            );
+            self.preferred.store(fastest, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether a health check reached any upstream within the last `window`.
+pub fn reachable_within(window: Duration) -> bool {
+    LAST_REACHABLE
+        .lock()
+        .unwrap()
+        .is_some_and(|reached| reached.elapsed() <= window)
+}
+
+/// Probes every upstream of `pool` right away and then each `interval` with a connect and, for
+/// SOCKS5, a greeting that must complete within `timeout`. An upstream failing its probe is
+/// left out of [`UpstreamPool::pick`] until a later probe succeeds, and the time successful
+/// probes take feeds `--balance latency`. Returns once the pool is dropped, e.g. replaced by a
+/// configuration reload.
+pub async fn check_health(pool: Weak<UpstreamPool>, interval: Duration, timeout: Option<Duration>) {
+    let mut first = true;
+    loop {
+        if !std::mem::take(&mut first) {
+            tokio::time::sleep(interval).await;
+        }
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+
+        let mut probes = tokio::task::JoinSet::new();
+        for (index, entry) in pool.entries.iter().enumerate() {
+            let upstream = entry.upstream.clone();
+            probes.spawn(async move {
+                let started = Instant::now();
+                // Box<dyn Error> isn't Send, so only its message leaves the task
+                let result = match timed(timeout, "health check", probe(&upstream)).await {
+                    Ok(result) => result.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                (index, result.map(|()| started.elapsed()))
+            });
+        }
+        while let Some(Ok((index, result))) = probes.join_next().await {
+            let entry = &pool.entries[index];
+            if let Ok(elapsed) = result {
+                *LAST_REACHABLE.lock().unwrap() = Some(Instant::now());
+                pool.record_latency(index, elapsed);
+            }
+            let was_healthy = entry.healthy.swap(result.is_ok(), Ordering::Relaxed);
+            match result {
+                Err(e) if was_healthy => warn!(
+                    "Upstream {} failed its health check, taking it out of rotation: {}",
+                    entry.upstream.addr, e
+                ),
+                Ok(_) if !was_healthy => info!(
+                    "Upstream {} passed its health check, returning it to rotation",
+                    entry.upstream.addr
+                ),
+                _ => {}
+            }
+        }
+        if pool.strategy == Balance::Latency {
+            pool.update_preferred();
+        }
+    }
+}
+
+// Connects to the upstream and, for SOCKS5, completes the greeting and authentication
+async fn probe(upstream: &Upstream) -> Result<(), Box<dyn Error>> {
+    // The probe reports the server as it is now; riding out restarts is left to tunnels
+    let upstream = Upstream {
+        retry: ConnectRetry::default(),
+        ..upstream.clone()
+    };
+    let mut socks = socks::connect_server(&upstream).await?;
+    if upstream.protocol == Protocol::Socks && upstream.version == SocksVersion::V5 {
+        socks::negotiate_auth(&mut socks, &upstream).await?;
+    }
+    Ok(())
+}
+
+impl Deref for Lease {
+    type Target = Upstream;
+
+    fn deref(&self) -> &Upstream {
+        &self.upstream
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
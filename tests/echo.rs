@@ -0,0 +1,174 @@
+//! End-to-end tests: clients talk to a `Proxy`, which tunnels through a minimal SOCKS5
+//! server to the echo origin server of `http2socks echo-server`.
+
+use http2socks::{echo, Mode, Proxy};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener as StdTcpListener, TcpStream};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// Header added to plain HTTP requests with --set-header
+const SET_HEADER: &str = "X-Proxied-By: http2socks-test";
+
+struct Servers {
+    proxy: SocketAddr,
+    echo: SocketAddr,
+}
+
+// The proxy and echo server, started once on a runtime of their own and shared by all
+// tests, as only one proxy may run per process
+fn servers() -> &'static Servers {
+    static SERVERS: OnceLock<Servers> = OnceLock::new();
+    SERVERS.get_or_init(|| {
+        let echo = unused_addr();
+        let proxy = unused_addr();
+        let socks = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = socks.local_addr().unwrap();
+        socks.set_nonblocking(true).unwrap();
+
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                tokio::spawn(socks5_server(TcpListener::from_std(socks).unwrap()));
+                tokio::spawn(
+                    async move { echo::run(&echo.to_string(), std::future::pending()).await },
+                );
+                Proxy::builder()
+                    .listen(proxy.to_string())
+                    .upstream(upstream.to_string())
+                    .mode(Mode::Http)
+                    .configure(|config| config.set_header = vec![SET_HEADER.to_string()])
+                    .build()
+                    .run(std::future::pending())
+                    .await
+                    .unwrap();
+            });
+        });
+
+        for addr in [echo, proxy] {
+            wait_for(addr);
+        }
+        Servers { proxy, echo }
+    })
+}
+
+// An address nothing listens on, for a server to bind
+fn unused_addr() -> SocketAddr {
+    StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+// Waits until `addr` accepts connections
+fn wait_for(addr: SocketAddr) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(addr).is_err() {
+        assert!(Instant::now() < deadline, "{addr} never started listening");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Serves SOCKS5 CONNECT requests for IPv4 addresses without authentication, which is all
+// the proxy sends for the echo server's address
+async fn socks5_server(listener: TcpListener) {
+    loop {
+        let (mut client, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let mut greeting = [0u8; 2];
+            client.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0u8; greeting[1] as usize];
+            client.read_exact(&mut methods).await.unwrap();
+            assert!(methods.contains(&0));
+            client.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            client.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 1]);
+            let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+            let port = u16::from_be_bytes([request[8], request[9]]);
+            let mut destination = tokio::net::TcpStream::connect((ip, port)).await.unwrap();
+            client
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut destination).await;
+        });
+    }
+}
+
+// A connection to the proxy
+fn connect() -> TcpStream {
+    let stream = TcpStream::connect(servers().proxy).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream
+}
+
+// Sends a plain HTTP request for `path` on the echo server, returning the whole response
+fn request(method: &str, path: &str, body: &str) -> String {
+    let echo = servers().echo;
+    let mut stream = connect();
+    write!(
+        stream,
+        "{method} http://{echo}{path} HTTP/1.1\r\nHost: {echo}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn connect_tunnel_relays_bytes() {
+    let echo = servers().echo;
+    let mut stream = connect();
+    write!(stream, "CONNECT {echo} HTTP/1.1\r\nHost: {echo}\r\n\r\n").unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+
+    // Not a request line, so the echo server sends it straight back
+    for message in ["ping\n", "and again\n"] {
+        stream.write_all(message.as_bytes()).unwrap();
+        let mut echoed = vec![0u8; message.len()];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, message.as_bytes());
+    }
+}
+
+#[test]
+fn plain_request_body_round_trips() {
+    let response = request("POST", "/echo", "hello through socks");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(
+        response.ends_with("\r\n\r\nhello through socks"),
+        "{response}"
+    );
+}
+
+#[test]
+fn plain_request_carries_set_header() {
+    let response = request("GET", "/headers", "");
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    let (name, value) = SET_HEADER.split_once(": ").unwrap();
+    assert!(
+        response.contains(&format!(r#""{name}":"{value}""#)),
+        "{response}"
+    );
+}
+
+#[test]
+fn origin_status_is_passed_on() {
+    let response = request("GET", "/status/418", "");
+    assert!(response.starts_with("HTTP/1.1 418"), "{response}");
+}